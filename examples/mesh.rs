@@ -78,25 +78,28 @@ const CUBE_VERTICES: &[Vertex] = &[
 pub struct MeshRenderer {
     pipeline: lazy_vulkan::Pipeline,
     buffer: BufferAllocation<Vertex>,
-    initial_upload: TransferToken,
+    /// Complete once the vertices and the logo are both on the GPU.
+    assets_uploaded: TransferToken,
     rotation: glam::Quat,
     position: glam::Vec3,
     logo_image: Image,
 }
 
 impl MeshRenderer {
-    pub fn new(renderer: &mut lazy_vulkan::Renderer<RenderStateFamily>) -> Self {
+    pub fn new(
+        renderer: &mut lazy_vulkan::Renderer<RenderStateFamily>,
+    ) -> lazy_vulkan::Result<Self> {
         // Create our pipeline
         let pipeline = renderer.create_pipeline::<Registers>(
             Path::new("examples/shaders/mesh.vert.spv"),
             Path::new("examples/shaders/texture.frag.spv"),
-        );
+        )?;
 
         // Allocate some resources
         let allocator = &mut renderer.allocator;
         let mut buffer =
-            allocator.allocate_buffer(10 * 1024 * 1024, vk::BufferUsageFlags::STORAGE_BUFFER);
-        let initial_upload = allocator.append_to_buffer(&CUBE_VERTICES, &mut buffer);
+            allocator.allocate_buffer(10 * 1024 * 1024, vk::BufferUsageFlags::STORAGE_BUFFER)?;
+        let initial_upload = allocator.append_to_buffer(&CUBE_VERTICES, &mut buffer)?;
         let (image_bytes, extent) = decode_png(Path::new("examples/vulkan.png"));
         let logo_image = renderer.create_image(
             "Vulkan Logo",
@@ -104,16 +107,19 @@ impl MeshRenderer {
             extent,
             image_bytes,
             vk::ImageUsageFlags::SAMPLED,
-        );
+        )?;
 
-        Self {
+        let assets_uploaded =
+            TransferToken::join_all([initial_upload, logo_image.transfer_complete.clone()]);
+
+        Ok(Self {
             pipeline,
             buffer,
-            initial_upload,
+            assets_uploaded,
             rotation: glam::Quat::IDENTITY,
             position: glam::Vec3::ZERO,
             logo_image,
-        }
+        })
     }
 }
 
@@ -121,11 +127,7 @@ impl<'s> SubRenderer<'s> for MeshRenderer {
     type State = RenderState<'s>;
     fn draw_opaque(&mut self, state: &Self::State, context: &Context) {
         // Make sure our resources are available before we use them
-        if !self.initial_upload.is_complete() {
-            return;
-        }
-
-        if !self.logo_image.transfer_complete.is_complete() {
+        if !self.assets_uploaded.is_complete() {
             return;
         }
 
@@ -146,7 +148,7 @@ impl<'s> SubRenderer<'s> for MeshRenderer {
             });
             context
                 .device
-                .cmd_draw(context.draw_command_buffer, vertex_count, 1, 0, 0)
+                .cmd_draw(context.draw_command_buffer(), vertex_count, 1, 0, 0)
         }
     }
 
@@ -241,8 +243,8 @@ impl<'a> ApplicationHandler for App {
             )
             .unwrap();
 
-        let mut lazy_vulkan = LazyVulkan::from_window(&window);
-        let sub_renderer = MeshRenderer::new(&mut lazy_vulkan.renderer);
+        let mut lazy_vulkan = LazyVulkan::from_window(&window).unwrap();
+        let sub_renderer = MeshRenderer::new(&mut lazy_vulkan.renderer).unwrap();
         lazy_vulkan.add_sub_renderer(Box::new(sub_renderer));
 
         self.state = Some(State {
@@ -277,16 +279,19 @@ impl<'a> ApplicationHandler for App {
 
             WindowEvent::Resized(size) => {
                 let state = self.state.as_mut().unwrap();
-                state.lazy_vulkan.resize(size);
+                state.lazy_vulkan.resize(size).unwrap();
             }
             WindowEvent::RedrawRequested => {
                 let lazy_vulkan = &mut state.lazy_vulkan;
-                let extent = lazy_vulkan.get_drawable().extent;
-                lazy_vulkan.draw(&RenderState {
+                let extent = lazy_vulkan.get_drawable().unwrap().extent;
+                if let Err(e) = lazy_vulkan.draw(&RenderState {
                     last_render_time: &state.last_render_time,
                     t: state.t,
                     extent,
-                });
+                }) {
+                    log::error!("Failed to draw: {e}");
+                    event_loop.exit();
+                }
                 state.t += state.last_render_time.elapsed().as_secs_f32();
                 state.last_render_time = Instant::now();
             }
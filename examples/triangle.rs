@@ -17,16 +17,16 @@ pub struct TriangleRenderer {
 }
 
 impl TriangleRenderer {
-    pub fn new(renderer: &lazy_vulkan::Renderer<RenderStateFamily>) -> Self {
+    pub fn new(renderer: &lazy_vulkan::Renderer<RenderStateFamily>) -> lazy_vulkan::Result<Self> {
         let pipeline = renderer.create_pipeline::<Registers>(
             Path::new("examples/shaders/triangle.vert.spv"),
             Path::new("examples/shaders/colour.frag.spv"),
-        );
+        )?;
 
-        Self {
+        Ok(Self {
             pipeline,
             colour: glam::Vec4::ONE,
-        }
+        })
     }
 }
 
@@ -41,7 +41,7 @@ impl<'a> SubRenderer<'a> for TriangleRenderer {
             });
             context
                 .device
-                .cmd_draw(context.draw_command_buffer, 3, 1, 0, 0)
+                .cmd_draw(context.draw_command_buffer(), 3, 1, 0, 0)
         }
     }
 
@@ -94,8 +94,8 @@ impl<'a> ApplicationHandler for App {
             )
             .unwrap();
 
-        let mut lazy_vulkan = LazyVulkan::from_window(&window);
-        let sub_renderer = TriangleRenderer::new(&lazy_vulkan.renderer);
+        let mut lazy_vulkan = LazyVulkan::from_window(&window).unwrap();
+        let sub_renderer = TriangleRenderer::new(&lazy_vulkan.renderer).unwrap();
         lazy_vulkan.add_sub_renderer(Box::new(sub_renderer));
 
         self.state = Some(State {
@@ -127,14 +127,17 @@ impl<'a> ApplicationHandler for App {
             WindowEvent::Resized(size) => {
                 println!("Resizing!");
                 let state = self.state.as_mut().unwrap();
-                state.lazy_vulkan.resize(size);
+                state.lazy_vulkan.resize(size).unwrap();
             }
             WindowEvent::RedrawRequested => {
                 println!("Drawing!");
                 let state = self.state.as_mut().unwrap();
                 state.t += state.last_render_time.elapsed().as_secs_f32();
                 let lazy_vulkan = &mut state.lazy_vulkan;
-                lazy_vulkan.draw(&RenderState { t: state.t });
+                if let Err(e) = lazy_vulkan.draw(&RenderState { t: state.t }) {
+                    eprintln!("Failed to draw: {e}");
+                    event_loop.exit();
+                }
                 state.last_render_time = Instant::now();
             }
             _ => (),
@@ -0,0 +1,456 @@
+use std::collections::HashMap;
+
+use ash::vk;
+
+use super::{Allocator, BufferAllocation, PendingFree};
+use crate::Result;
+
+/// Acceleration structures must be placed at a 256 byte aligned offset in their buffer.
+const ACCELERATION_STRUCTURE_ALIGNMENT: u64 = 256;
+
+/// A bottom or top level acceleration structure, and the buffer that holds it.
+///
+/// Requires [`crate::ContextBuilder::ray_tracing`].
+pub struct AccelerationStructure {
+    pub handle: vk::AccelerationStructureKHR,
+    pub device_address: vk::DeviceAddress,
+    pub ty: vk::AccelerationStructureTypeKHR,
+    pub buffer: BufferAllocation<u8>,
+    /// Only present if the structure was built with `ALLOW_COMPACTION`.
+    compacted_size_query: Option<vk::QueryPool>,
+}
+
+impl AccelerationStructure {
+    /// Create an instance of this (bottom level) acceleration structure, ready to be appended to
+    /// a buffer and passed to [`Allocator::build_tlas`].
+    pub fn instance(
+        &self,
+        transform: glam::Mat4,
+        custom_index: u32,
+    ) -> vk::AccelerationStructureInstanceKHR {
+        // Vulkan wants the top three rows, row major.
+        let rows = transform.transpose().to_cols_array();
+        let mut matrix = [0.; 12];
+        matrix.copy_from_slice(&rows[..12]);
+
+        vk::AccelerationStructureInstanceKHR {
+            transform: vk::TransformMatrixKHR { matrix },
+            instance_custom_index_and_mask: vk::Packed24_8::new(custom_index, 0xFF),
+            instance_shader_binding_table_record_offset_and_flags: vk::Packed24_8::new(
+                0,
+                vk::GeometryInstanceFlagsKHR::TRIANGLE_FACING_CULL_DISABLE.as_raw() as u8,
+            ),
+            acceleration_structure_reference: vk::AccelerationStructureReferenceKHR {
+                device_handle: self.device_address,
+            },
+        }
+    }
+}
+
+/// Triangles to build a bottom level acceleration structure from.
+///
+/// ## NOTE
+/// The buffers the data lives in must have been created with
+/// `ACCELERATION_STRUCTURE_BUILD_INPUT_READ_ONLY_KHR`.
+#[derive(Debug, Clone, Copy)]
+pub struct TriangleGeometry {
+    pub vertex_address: vk::DeviceAddress,
+    pub vertex_stride: vk::DeviceSize,
+    pub vertex_format: vk::Format,
+    /// The highest index that can be used to look up a vertex.
+    pub max_vertex: u32,
+    pub index_address: vk::DeviceAddress,
+    pub index_type: vk::IndexType,
+    pub triangle_count: u32,
+    pub flags: vk::GeometryFlagsKHR,
+}
+
+impl TriangleGeometry {
+    /// Opaque triangles from every vertex and index in the buffers. The position must be the
+    /// first field of `V`, and be three `f32`s.
+    pub fn new<V: Copy>(vertices: &BufferAllocation<V>, indices: &BufferAllocation<u32>) -> Self {
+        Self {
+            vertex_address: vertices.device_address,
+            vertex_stride: std::mem::size_of::<V>() as _,
+            vertex_format: vk::Format::R32G32B32_SFLOAT,
+            max_vertex: (vertices.len() as u32).saturating_sub(1),
+            index_address: indices.device_address,
+            index_type: vk::IndexType::UINT32,
+            triangle_count: (indices.len() / 3) as u32,
+            flags: vk::GeometryFlagsKHR::OPAQUE,
+        }
+    }
+}
+
+/// The scratch buffer shared by every build, and every structure that's still alive.
+#[derive(Default)]
+pub(crate) struct AccelerationStructureState {
+    scratch_buffer: Option<BufferAllocation<u8>>,
+    /// Every structure that hasn't been destroyed yet, along with its query pool (if any).
+    live: HashMap<vk::AccelerationStructureKHR, Option<vk::QueryPool>>,
+}
+
+impl Allocator {
+    /// Record a build of a bottom level acceleration structure into `command_buffer`.
+    ///
+    /// Any transfers to the vertex or index buffers must have already been recorded with
+    /// [`Allocator::execute_transfers`]. Pass `ALLOW_COMPACTION` in `flags` if you intend to
+    /// call [`Allocator::compact_acceleration_structure`].
+    pub fn build_blas(
+        &mut self,
+        command_buffer: vk::CommandBuffer,
+        geometries: &[TriangleGeometry],
+        flags: vk::BuildAccelerationStructureFlagsKHR,
+    ) -> Result<AccelerationStructure> {
+        let vk_geometries = geometries
+            .iter()
+            .map(|geometry| {
+                vk::AccelerationStructureGeometryKHR::default()
+                    .geometry_type(vk::GeometryTypeKHR::TRIANGLES)
+                    .flags(geometry.flags)
+                    .geometry(vk::AccelerationStructureGeometryDataKHR {
+                        triangles: vk::AccelerationStructureGeometryTrianglesDataKHR::default()
+                            .vertex_data(vk::DeviceOrHostAddressConstKHR {
+                                device_address: geometry.vertex_address,
+                            })
+                            .vertex_stride(geometry.vertex_stride)
+                            .vertex_format(geometry.vertex_format)
+                            .max_vertex(geometry.max_vertex)
+                            .index_data(vk::DeviceOrHostAddressConstKHR {
+                                device_address: geometry.index_address,
+                            })
+                            .index_type(geometry.index_type),
+                    })
+            })
+            .collect::<Vec<_>>();
+
+        let primitive_counts = geometries
+            .iter()
+            .map(|geometry| geometry.triangle_count)
+            .collect::<Vec<_>>();
+
+        self.build_acceleration_structure(
+            command_buffer,
+            vk::AccelerationStructureTypeKHR::BOTTOM_LEVEL,
+            &vk_geometries,
+            &primitive_counts,
+            flags,
+        )
+    }
+
+    /// Record a build of a top level acceleration structure into `command_buffer`.
+    ///
+    /// `instances` must have been created with `ACCELERATION_STRUCTURE_BUILD_INPUT_READ_ONLY_KHR`
+    /// and an alignment of at least 16, and every bottom level structure it refers to must be
+    /// built before this one. See [`AccelerationStructure::instance`].
+    pub fn build_tlas(
+        &mut self,
+        command_buffer: vk::CommandBuffer,
+        instances: &BufferAllocation<vk::AccelerationStructureInstanceKHR>,
+        flags: vk::BuildAccelerationStructureFlagsKHR,
+    ) -> Result<AccelerationStructure> {
+        let geometry = vk::AccelerationStructureGeometryKHR::default()
+            .geometry_type(vk::GeometryTypeKHR::INSTANCES)
+            .geometry(vk::AccelerationStructureGeometryDataKHR {
+                instances: vk::AccelerationStructureGeometryInstancesDataKHR::default().data(
+                    vk::DeviceOrHostAddressConstKHR {
+                        device_address: instances.device_address,
+                    },
+                ),
+            });
+
+        self.build_acceleration_structure(
+            command_buffer,
+            vk::AccelerationStructureTypeKHR::TOP_LEVEL,
+            &[geometry],
+            &[instances.len() as u32],
+            flags,
+        )
+    }
+
+    /// Record a copy of `acceleration_structure` into a new, smaller one.
+    ///
+    /// ## NOTE
+    /// This blocks until the GPU has finished building `acceleration_structure`, so make sure
+    /// that build has been submitted! If it wasn't built with `ALLOW_COMPACTION`, it's returned
+    /// as is.
+    pub fn compact_acceleration_structure(
+        &mut self,
+        command_buffer: vk::CommandBuffer,
+        acceleration_structure: AccelerationStructure,
+    ) -> Result<AccelerationStructure> {
+        let Some(query_pool) = acceleration_structure.compacted_size_query else {
+            return Ok(acceleration_structure);
+        };
+
+        let mut compacted_size = [0u64];
+        unsafe {
+            self.context.device.get_query_pool_results(
+                query_pool,
+                0,
+                &mut compacted_size,
+                vk::QueryResultFlags::TYPE_64 | vk::QueryResultFlags::WAIT,
+            )
+        }?;
+
+        let compacted =
+            self.create_acceleration_structure(acceleration_structure.ty, compacted_size[0])?;
+
+        unsafe {
+            self.context
+                .acceleration_structure_pfn
+                .cmd_copy_acceleration_structure(
+                    command_buffer,
+                    &vk::CopyAccelerationStructureInfoKHR::default()
+                        .src(acceleration_structure.handle)
+                        .dst(compacted.handle)
+                        .mode(vk::CopyAccelerationStructureModeKHR::COMPACT),
+                );
+            self.acceleration_structure_barrier(command_buffer);
+        }
+
+        log::debug!(
+            "[lazy_vulkan] Compacted acceleration structure from {} to {} bytes",
+            acceleration_structure.buffer.size,
+            compacted_size[0]
+        );
+
+        self.destroy_acceleration_structure(acceleration_structure);
+        Ok(compacted)
+    }
+
+    /// The structure is destroyed once the GPU has finished with the frame that's currently
+    /// being recorded.
+    pub fn destroy_acceleration_structure(
+        &mut self,
+        acceleration_structure: AccelerationStructure,
+    ) {
+        self.free_after_frame(PendingFree::AccelerationStructure(acceleration_structure));
+    }
+
+    fn build_acceleration_structure(
+        &mut self,
+        command_buffer: vk::CommandBuffer,
+        ty: vk::AccelerationStructureTypeKHR,
+        geometries: &[vk::AccelerationStructureGeometryKHR],
+        primitive_counts: &[u32],
+        flags: vk::BuildAccelerationStructureFlagsKHR,
+    ) -> Result<AccelerationStructure> {
+        let mut build_info = vk::AccelerationStructureBuildGeometryInfoKHR::default()
+            .ty(ty)
+            .flags(flags)
+            .mode(vk::BuildAccelerationStructureModeKHR::BUILD)
+            .geometries(geometries);
+
+        let mut sizes = vk::AccelerationStructureBuildSizesInfoKHR::default();
+        unsafe {
+            self.context
+                .acceleration_structure_pfn
+                .get_acceleration_structure_build_sizes(
+                    vk::AccelerationStructureBuildTypeKHR::DEVICE,
+                    &build_info,
+                    primitive_counts,
+                    &mut sizes,
+                )
+        };
+
+        let mut acceleration_structure =
+            self.create_acceleration_structure(ty, sizes.acceleration_structure_size)?;
+        let scratch_address = match self.scratch_address(sizes.build_scratch_size) {
+            Ok(scratch_address) => scratch_address,
+            Err(e) => {
+                self.destroy_acceleration_structure(acceleration_structure);
+                return Err(e);
+            }
+        };
+
+        build_info = build_info
+            .dst_acceleration_structure(acceleration_structure.handle)
+            .scratch_data(vk::DeviceOrHostAddressKHR {
+                device_address: scratch_address,
+            });
+
+        let build_ranges = primitive_counts
+            .iter()
+            .map(|&count| {
+                vk::AccelerationStructureBuildRangeInfoKHR::default().primitive_count(count)
+            })
+            .collect::<Vec<_>>();
+
+        let device = &self.context.device;
+        unsafe {
+            // Make sure any transfers to the inputs have landed
+            self.context.cmd_pipeline_barrier2(
+                command_buffer,
+                &vk::DependencyInfo::default().memory_barriers(&[vk::MemoryBarrier2::default()
+                    .src_access_mask(vk::AccessFlags2::TRANSFER_WRITE)
+                    .src_stage_mask(vk::PipelineStageFlags2::TRANSFER)
+                    .dst_access_mask(vk::AccessFlags2::SHADER_READ)
+                    .dst_stage_mask(vk::PipelineStageFlags2::ACCELERATION_STRUCTURE_BUILD_KHR)]),
+            );
+
+            self.context
+                .acceleration_structure_pfn
+                .cmd_build_acceleration_structures(command_buffer, &[build_info], &[&build_ranges]);
+
+            // The scratch buffer is shared, so every build has to wait for the previous one.
+            self.acceleration_structure_barrier(command_buffer);
+        }
+
+        if flags.contains(vk::BuildAccelerationStructureFlagsKHR::ALLOW_COMPACTION) {
+            let query_pool = unsafe {
+                device.create_query_pool(
+                    &vk::QueryPoolCreateInfo::default()
+                        .query_type(vk::QueryType::ACCELERATION_STRUCTURE_COMPACTED_SIZE_KHR)
+                        .query_count(1),
+                    None,
+                )
+            }?;
+            acceleration_structure.compacted_size_query = Some(query_pool);
+            self.acceleration_structures
+                .live
+                .insert(acceleration_structure.handle, Some(query_pool));
+
+            unsafe {
+                device.cmd_reset_query_pool(command_buffer, query_pool, 0, 1);
+                self.context
+                    .acceleration_structure_pfn
+                    .cmd_write_acceleration_structures_properties(
+                        command_buffer,
+                        &[acceleration_structure.handle],
+                        vk::QueryType::ACCELERATION_STRUCTURE_COMPACTED_SIZE_KHR,
+                        query_pool,
+                        0,
+                    );
+            }
+        }
+
+        Ok(acceleration_structure)
+    }
+
+    fn create_acceleration_structure(
+        &mut self,
+        ty: vk::AccelerationStructureTypeKHR,
+        size: vk::DeviceSize,
+    ) -> Result<AccelerationStructure> {
+        let buffer = self.allocate_buffer_with_alignment::<u8>(
+            size as usize,
+            ACCELERATION_STRUCTURE_ALIGNMENT,
+            vk::BufferUsageFlags::ACCELERATION_STRUCTURE_STORAGE_KHR,
+        )?;
+
+        let acceleration_structure_pfn = &self.context.acceleration_structure_pfn;
+        let handle = match unsafe {
+            acceleration_structure_pfn.create_acceleration_structure(
+                &vk::AccelerationStructureCreateInfoKHR::default()
+                    .buffer(buffer.handle)
+                    .size(size)
+                    .ty(ty),
+                None,
+            )
+        } {
+            Ok(handle) => handle,
+            Err(e) => {
+                self.free_buffer_now(buffer);
+                return Err(e.into());
+            }
+        };
+
+        let device_address = unsafe {
+            acceleration_structure_pfn.get_acceleration_structure_device_address(
+                &vk::AccelerationStructureDeviceAddressInfoKHR::default()
+                    .acceleration_structure(handle),
+            )
+        };
+
+        self.acceleration_structures.live.insert(handle, None);
+
+        let label = format!("[lazy_vulkan] AccelerationStructure ({ty:?})");
+        self.context.set_debug_label(handle, &label);
+
+        Ok(AccelerationStructure {
+            handle,
+            device_address,
+            ty,
+            buffer,
+            compacted_size_query: None,
+        })
+    }
+
+    /// Get the address of a scratch buffer that's at least `size` bytes, growing it if needed.
+    fn scratch_address(&mut self, size: vk::DeviceSize) -> Result<vk::DeviceAddress> {
+        if let Some(scratch_buffer) = &self.acceleration_structures.scratch_buffer {
+            if scratch_buffer.size >= size {
+                return Ok(scratch_buffer.device_address);
+            }
+        }
+
+        // Earlier builds may still be using the old one.
+        if let Some(old_scratch_buffer) = self.acceleration_structures.scratch_buffer.take() {
+            self.free_after_frame(PendingFree::Buffer(old_scratch_buffer));
+        }
+
+        let alignment = self
+            .context
+            .raytracing_properties
+            .min_acceleration_structure_scratch_offset_alignment as u64;
+        let scratch_buffer = self.allocate_buffer_with_alignment::<u8>(
+            size as usize,
+            alignment.max(1),
+            vk::BufferUsageFlags::STORAGE_BUFFER,
+        )?;
+        let device_address = scratch_buffer.device_address;
+        self.acceleration_structures.scratch_buffer = Some(scratch_buffer);
+
+        Ok(device_address)
+    }
+
+    /// Wait for any acceleration structure builds or copies to complete before anything else
+    /// touches them.
+    unsafe fn acceleration_structure_barrier(&self, command_buffer: vk::CommandBuffer) {
+        self.context.cmd_pipeline_barrier2(
+            command_buffer,
+            &vk::DependencyInfo::default().memory_barriers(&[vk::MemoryBarrier2::default()
+                .src_access_mask(vk::AccessFlags2::ACCELERATION_STRUCTURE_WRITE_KHR)
+                .src_stage_mask(vk::PipelineStageFlags2::ACCELERATION_STRUCTURE_BUILD_KHR)
+                .dst_access_mask(
+                    vk::AccessFlags2::ACCELERATION_STRUCTURE_READ_KHR
+                        | vk::AccessFlags2::ACCELERATION_STRUCTURE_WRITE_KHR,
+                )
+                .dst_stage_mask(vk::PipelineStageFlags2::ALL_COMMANDS)]),
+        );
+    }
+
+    /// Destroy the structure and free its buffer immediately. The GPU must be done with it!
+    pub(crate) fn free_acceleration_structure_now(
+        &mut self,
+        acceleration_structure: AccelerationStructure,
+    ) {
+        self.acceleration_structures
+            .live
+            .remove(&acceleration_structure.handle);
+        unsafe {
+            self.context
+                .acceleration_structure_pfn
+                .destroy_acceleration_structure(acceleration_structure.handle, None);
+            if let Some(query_pool) = acceleration_structure.compacted_size_query {
+                self.context.device.destroy_query_pool(query_pool, None);
+            }
+        }
+        self.free_buffer_now(acceleration_structure.buffer);
+    }
+
+    /// Called when the allocator is dropped. The buffers are taken care of by the allocator.
+    pub(crate) fn destroy_all_acceleration_structures(&mut self) {
+        for (handle, query_pool) in self.acceleration_structures.live.drain() {
+            unsafe {
+                self.context
+                    .acceleration_structure_pfn
+                    .destroy_acceleration_structure(handle, None);
+                if let Some(query_pool) = query_pool {
+                    self.context.device.destroy_query_pool(query_pool, None);
+                }
+            }
+        }
+    }
+}
@@ -1,49 +1,225 @@
-use crate::allocator::Offset;
-use crate::allocator::GLOBAL_MEMORY_SIZE;
+use crate::allocator::{align_offset, AllocatorConfig, MemoryUsage, Offset};
 use crate::FULL_IMAGE;
 use std::ptr::NonNull;
 use std::sync::Arc;
 
 use ash::vk;
 
-use crate::Context;
+use crate::{Context, Error, Result};
 
+use super::sharing_mode;
 use super::staging_buffer::StagingBuffer;
 use super::PendingTransfer;
 use super::TransferDestination;
 
-pub enum DeviceBuffer {
-    Discrete(DiscreteDeviceBuffer),
-    Integrated(IntegratedDeviceBuffer),
+/// Every stage our post-transfer barriers make uploads visible to. Anything waiting on a transfer
+/// queue submission should wait at these stages.
+pub const UPLOAD_CONSUMER_STAGES: vk::PipelineStageFlags2 = vk::PipelineStageFlags2::from_raw(
+    vk::PipelineStageFlags2::COPY.as_raw()
+        | vk::PipelineStageFlags2::INDEX_INPUT.as_raw()
+        | vk::PipelineStageFlags2::VERTEX_SHADER.as_raw()
+        | vk::PipelineStageFlags2::FRAGMENT_SHADER.as_raw(),
+);
+
+/// Where transfers get recorded.
+#[derive(Debug, Clone, Copy)]
+pub struct TransferCommands {
+    /// Copies are recorded into this.
+    pub command_buffer: vk::CommandBuffer,
+    /// Set if `command_buffer` will be submitted to a different queue family to the one that'll
+    /// use the results, in which case ownership of every destination has to be transferred.
+    pub ownership_transfer: Option<OwnershipTransfer>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct OwnershipTransfer {
+    /// Acquire barriers are recorded into this.
+    pub graphics_command_buffer: vk::CommandBuffer,
+    pub transfer_family: u32,
+    pub graphics_family: u32,
+    /// Buffers are shared with every queue family (see [`Context::buffer_queue_families`]), so
+    /// only images need their ownership transferred.
+    pub concurrent_buffers: bool,
+}
+
+impl TransferCommands {
+    /// Make the results of a buffer transfer visible. `barrier` should describe the dependency as
+    /// if everything happened on one queue - it's split into a release and an acquire if needed.
+    unsafe fn buffer_barrier(&self, context: &Context, barrier: vk::BufferMemoryBarrier2) {
+        let Some(ownership) = self.ownership_transfer else {
+            context.cmd_pipeline_barrier2(
+                self.command_buffer,
+                &vk::DependencyInfo::default().buffer_memory_barriers(&[barrier]),
+            );
+            return;
+        };
+
+        // Waiting on the transfer queue's semaphore is all a shared buffer needs.
+        if ownership.concurrent_buffers {
+            return;
+        }
+
+        let barrier = barrier
+            .src_queue_family_index(ownership.transfer_family)
+            .dst_queue_family_index(ownership.graphics_family);
+
+        // Release on the transfer queue..
+        context.cmd_pipeline_barrier2(
+            self.command_buffer,
+            &vk::DependencyInfo::default().buffer_memory_barriers(&[barrier
+                .dst_access_mask(vk::AccessFlags2::NONE)
+                .dst_stage_mask(vk::PipelineStageFlags2::NONE)]),
+        );
+
+        // ..and acquire on the graphics queue, once it's waited for the transfer.
+        context.cmd_pipeline_barrier2(
+            ownership.graphics_command_buffer,
+            &vk::DependencyInfo::default().buffer_memory_barriers(&[barrier
+                .src_access_mask(vk::AccessFlags2::NONE)
+                .src_stage_mask(barrier.dst_stage_mask)]),
+        );
+    }
+
+    /// Like [`TransferCommands::buffer_barrier`], but for images. `concurrent` images are shared
+    /// with every queue family, like buffers.
+    unsafe fn image_barrier(
+        &self,
+        context: &Context,
+        barrier: vk::ImageMemoryBarrier2,
+        concurrent: bool,
+    ) {
+        let Some(ownership) = self.ownership_transfer else {
+            context.cmd_pipeline_barrier2(
+                self.command_buffer,
+                &vk::DependencyInfo::default().image_memory_barriers(&[barrier]),
+            );
+            return;
+        };
+
+        // A shared image still needs its layout transitioned, but waiting on the transfer
+        // queue's semaphore does the rest.
+        if concurrent {
+            context.cmd_pipeline_barrier2(
+                self.command_buffer,
+                &vk::DependencyInfo::default().image_memory_barriers(&[barrier
+                    .dst_access_mask(vk::AccessFlags2::NONE)
+                    .dst_stage_mask(vk::PipelineStageFlags2::NONE)]),
+            );
+            return;
+        }
+
+        let barrier = barrier
+            .src_queue_family_index(ownership.transfer_family)
+            .dst_queue_family_index(ownership.graphics_family);
+
+        // The layout transition happens once, between the release and the acquire.
+        context.cmd_pipeline_barrier2(
+            self.command_buffer,
+            &vk::DependencyInfo::default().image_memory_barriers(&[barrier
+                .dst_access_mask(vk::AccessFlags2::NONE)
+                .dst_stage_mask(vk::PipelineStageFlags2::NONE)]),
+        );
+
+        context.cmd_pipeline_barrier2(
+            ownership.graphics_command_buffer,
+            &vk::DependencyInfo::default().image_memory_barriers(&[barrier
+                .src_access_mask(vk::AccessFlags2::NONE)
+                .src_stage_mask(barrier.dst_stage_mask)]),
+        );
+    }
+}
+
+/// All of our device memory: a list of blocks, each sub-allocated with its own
+/// `offset_allocator`. Another block is added whenever an allocation doesn't fit in the existing
+/// ones, and blocks are never moved or freed until we're dropped - so device addresses stay put.
+pub struct DeviceBuffer {
+    context: Arc<Context>,
+    strategy: MemoryStrategy,
+    config: AllocatorConfig,
+    blocks: Vec<MemoryBlock>,
 }
 
 impl DeviceBuffer {
-    pub fn new(context: Arc<Context>) -> DeviceBuffer {
-        match context.device_type {
-            vk::PhysicalDeviceType::DISCRETE_GPU => {
-                DeviceBuffer::Discrete(DiscreteDeviceBuffer::new(context))
-            }
-            vk::PhysicalDeviceType::INTEGRATED_GPU => {
-                DeviceBuffer::Integrated(IntegratedDeviceBuffer::new(context))
+    pub fn new(context: Arc<Context>, config: AllocatorConfig) -> Result<DeviceBuffer> {
+        assert!(
+            config.block_size > 0 && config.block_size <= u32::MAX as vk::DeviceSize,
+            "Block size must be between 1 byte and 4GB"
+        );
+
+        // NOTE: We deliberately don't look at `device_type` here - software rasterizers (lavapipe,
+        // SwiftShader) and virtual GPUs report all sorts of things. Instead, look at what memory
+        // the device actually has.
+        let strategy = MemoryStrategy::new(&context.memory_properties)?;
+
+        Ok(DeviceBuffer {
+            context,
+            strategy,
+            config,
+            blocks: Vec::new(),
+        })
+    }
+
+    /// Find `size` bytes aligned to `align`, adding a block if none of them have room.
+    pub fn allocate(&mut self, size: u64, align: u64) -> Result<Offset> {
+        for (block, memory_block) in self.blocks.iter_mut().enumerate() {
+            if let Some((allocation, bind_offset)) = memory_block.allocate(size, align) {
+                return Ok(Offset {
+                    block,
+                    allocation,
+                    bind_offset,
+                });
             }
-            _ => unreachable!("Impossible device type"),
         }
-    }
 
-    pub fn device_memory(&self) -> vk::DeviceMemory {
-        match self {
-            DeviceBuffer::Discrete(discrete_allocator) => discrete_allocator.device_memory,
-            DeviceBuffer::Integrated(integrated_allocator) => integrated_allocator.global_memory,
+        // Anything bigger than a block gets one to itself, with enough room to align it.
+        let block_size = self.config.block_size.max(size + align);
+        let allocated = self.usage().allocated;
+        let over_limit = self
+            .config
+            .max_device_memory
+            .is_some_and(|max_device_memory| allocated + block_size > max_device_memory);
+        if block_size > u32::MAX as vk::DeviceSize || over_limit {
+            return Err(Error::OutOfDeviceMemory { requested: size });
         }
+
+        let mut memory_block = MemoryBlock::new(&self.context, self.strategy, block_size)?;
+        let Some((allocation, bind_offset)) = memory_block.allocate(size, align) else {
+            memory_block.destroy(&self.context.device);
+            return Err(Error::OutOfDeviceMemory { requested: size });
+        };
+        self.blocks.push(memory_block);
+
+        Ok(Offset {
+            block: self.blocks.len() - 1,
+            allocation,
+            bind_offset,
+        })
+    }
+
+    pub fn free(&mut self, offset: Offset) {
+        self.blocks[offset.block]
+            .offset_allocator
+            .free(offset.allocation);
+    }
+
+    pub fn device_memory(&self, block: usize) -> vk::DeviceMemory {
+        self.blocks[block].memory
     }
 
     pub fn get_device_address(&self, offset: Offset) -> vk::DeviceAddress {
-        let base_address = match self {
-            DeviceBuffer::Discrete(discrete_allocator) => discrete_allocator.slab_address,
-            DeviceBuffer::Integrated(integrated_allocator) => integrated_allocator.slab_address,
-        };
+        self.blocks[offset.block].slab_address + offset.total_offset()
+    }
 
-        base_address + offset.allocation.offset as u64 + offset.bind_offset
+    pub fn usage(&self) -> MemoryUsage {
+        MemoryUsage {
+            blocks: self.blocks.len(),
+            allocated: self.blocks.iter().map(|block| block.size).sum(),
+            free: self
+                .blocks
+                .iter()
+                .map(|block| block.offset_allocator.storage_report().total_free_space as u64)
+                .sum(),
+        }
     }
 
     pub fn execute_transfers(
@@ -51,58 +227,75 @@ impl DeviceBuffer {
         context: &Context,
         mut pending_transfers: Vec<PendingTransfer>,
         staging_buffer: &mut StagingBuffer,
-        command_buffer: vk::CommandBuffer,
+        commands: &TransferCommands,
     ) {
         for pending in pending_transfers.drain(..) {
             match pending.destination {
                 TransferDestination::Slab | TransferDestination::Buffer(_) => {
-                    match self {
-                        DeviceBuffer::Discrete(discrete_allocator) => discrete_allocator
-                            .buffer_transfer(context, pending, staging_buffer, command_buffer),
-                        DeviceBuffer::Integrated(integrated_allocator) => {
-                            integrated_allocator.buffer_transfer(pending, staging_buffer)
-                        }
-                    };
+                    let block = &self.blocks[pending.global_offset.block];
+                    match block.mapped {
+                        Some(mapped) => mapped_buffer_transfer(mapped, pending, staging_buffer),
+                        None => block.buffer_transfer(context, pending, staging_buffer, commands),
+                    }
                 }
-                TransferDestination::Image(image, extent) => {
-                    image_transfer(
-                        context,
-                        staging_buffer,
-                        command_buffer,
-                        pending,
-                        image,
-                        extent,
-                    );
+                TransferDestination::Image { .. } => {
+                    image_transfer(context, staging_buffer, commands, pending);
                 }
             }
         }
     }
 }
 
+impl Drop for DeviceBuffer {
+    fn drop(&mut self) {
+        for block in self.blocks.drain(..) {
+            block.destroy(&self.context.device);
+        }
+    }
+}
+
+/// Copy a piece of an image - a range of rows - out of the staging buffer. The image is moved into
+/// `TRANSFER_DST_OPTIMAL` by the first piece, and stays there until the last one.
 fn image_transfer(
     context: &Context,
     staging_buffer: &mut StagingBuffer,
-    command_buffer: vk::CommandBuffer,
+    commands: &TransferCommands,
     pending: PendingTransfer,
-    image: vk::Image,
-    extent: vk::Extent2D,
 ) {
+    let TransferDestination::Image {
+        image,
+        extent,
+        block,
+        first_row,
+        concurrent,
+    } = pending.destination
+    else {
+        return;
+    };
+    let rows = match block {
+        Some(block) => block.rows(extent, first_row, pending.transfer_size),
+        None => extent.height,
+    };
     let device = &context.device;
+    let command_buffer = commands.command_buffer;
 
     unsafe {
         // Transition the image into the TRANSFER DST layout
-        context.cmd_pipeline_barrier2(
-            command_buffer,
-            &vk::DependencyInfo::default().image_memory_barriers(&[
-                vk::ImageMemoryBarrier2::default()
-                    .subresource_range(FULL_IMAGE)
-                    .image(image)
-                    .dst_access_mask(vk::AccessFlags2::TRANSFER_WRITE)
-                    .dst_stage_mask(vk::PipelineStageFlags2::TRANSFER)
-                    .old_layout(vk::ImageLayout::UNDEFINED)
-                    .new_layout(vk::ImageLayout::TRANSFER_DST_OPTIMAL),
-            ]),
-        );
+        if first_row == 0 {
+            context.cmd_pipeline_barrier2(
+                command_buffer,
+                &vk::DependencyInfo::default().image_memory_barriers(&[
+                    vk::ImageMemoryBarrier2::default()
+                        .subresource_range(FULL_IMAGE)
+                        .image(image)
+                        .dst_access_mask(vk::AccessFlags2::TRANSFER_WRITE)
+                        .dst_stage_mask(vk::PipelineStageFlags2::TRANSFER)
+                        .old_layout(vk::ImageLayout::UNDEFINED)
+                        .new_layout(vk::ImageLayout::TRANSFER_DST_OPTIMAL),
+                ]),
+            );
+        }
+
         // Copy data from our buffer to the target image
         device.cmd_copy_buffer_to_image(
             command_buffer,
@@ -116,81 +309,223 @@ fn image_transfer(
                         .aspect_mask(vk::ImageAspectFlags::COLOR)
                         .layer_count(1),
                 )
-                .image_extent(extent.into())],
+                .image_offset(vk::Offset3D {
+                    x: 0,
+                    y: first_row as i32,
+                    z: 0,
+                })
+                .image_extent(vk::Extent3D {
+                    width: extent.width,
+                    height: rows,
+                    depth: 1,
+                })],
         );
 
+        if first_row + rows < extent.height {
+            return;
+        }
+
         // Transition the image back to SHADER READ ONLY OPTIMAL layout with the
         // appropriate barriers.
-        context.cmd_pipeline_barrier2(
-            command_buffer,
-            &vk::DependencyInfo::default().image_memory_barriers(&[
-                vk::ImageMemoryBarrier2::default()
-                    .subresource_range(FULL_IMAGE)
-                    .image(image)
-                    .src_access_mask(vk::AccessFlags2::TRANSFER_WRITE)
-                    .src_stage_mask(vk::PipelineStageFlags2::TRANSFER)
-                    .dst_access_mask(vk::AccessFlags2::SHADER_READ)
-                    .dst_stage_mask(vk::PipelineStageFlags2::FRAGMENT_SHADER)
-                    .old_layout(vk::ImageLayout::TRANSFER_DST_OPTIMAL)
-                    .new_layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL),
-            ]),
+        commands.image_barrier(
+            context,
+            vk::ImageMemoryBarrier2::default()
+                .subresource_range(FULL_IMAGE)
+                .image(image)
+                .src_access_mask(vk::AccessFlags2::TRANSFER_WRITE)
+                .src_stage_mask(vk::PipelineStageFlags2::TRANSFER)
+                .dst_access_mask(vk::AccessFlags2::SHADER_READ)
+                .dst_stage_mask(vk::PipelineStageFlags2::FRAGMENT_SHADER)
+                .old_layout(vk::ImageLayout::TRANSFER_DST_OPTIMAL)
+                .new_layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL),
+            concurrent,
         );
     };
-
-    pending.transfer_token.mark_completed();
 }
 
-pub struct DiscreteDeviceBuffer {
-    device_memory: vk::DeviceMemory,
-    #[allow(unused)]
-    slab_buffer: vk::Buffer,
-    slab_address: vk::DeviceAddress,
+/// How the global memory should be allocated and written to, along with the memory type to use.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemoryStrategy {
+    /// The largest device local heap is not visible to the host, so everything needs to go
+    /// through the staging buffer.
+    Discrete(u32),
+    /// The largest device local heap can be mapped (integrated GPUs, software rasterizers, some
+    /// virtual GPUs), so we can just `memcpy` into it.
+    Unified(u32),
 }
 
-impl DiscreteDeviceBuffer {
-    pub fn new(context: Arc<Context>) -> DiscreteDeviceBuffer {
-        let device = &context.device;
-        let memory_properties = &context.memory_properties;
-
-        // Search through the available memory types to find the one we want
-        let mut memory_type_index = None;
-        let mut memory_heap_index = None;
-        for (index, memory_type) in memory_properties.memory_types_as_slice().iter().enumerate() {
-            if memory_type
-                .property_flags
-                .contains(vk::MemoryPropertyFlags::DEVICE_LOCAL)
+impl MemoryStrategy {
+    pub fn new(memory_properties: &vk::PhysicalDeviceMemoryProperties) -> Result<MemoryStrategy> {
+        let memory_types = memory_properties.memory_types_as_slice();
+        let memory_heaps = memory_properties.memory_heaps_as_slice();
+        let host_visible =
+            vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_COHERENT;
+
+        // Find the biggest device local heap - this is where we'd like our memory to live.
+        let device_local_heap = memory_heaps
+            .iter()
+            .enumerate()
+            .filter(|(_, heap)| heap.flags.contains(vk::MemoryHeapFlags::DEVICE_LOCAL))
+            .max_by_key(|(_, heap)| heap.size)
+            .map(|(index, _)| index as u32);
+
+        let find_memory_type = |required: vk::MemoryPropertyFlags, heap_index: Option<u32>| {
+            memory_types
+                .iter()
+                .enumerate()
+                .find(|(_, memory_type)| {
+                    memory_type.property_flags.contains(required)
+                        && heap_index.is_none_or(|heap_index| memory_type.heap_index == heap_index)
+                })
+                .map(|(index, _)| index as u32)
+        };
+
+        if let Some(heap_index) = device_local_heap {
+            // If the main device local heap is also host visible, the device has unified memory.
+            //
+            // NOTE: We check the heap here rather than just looking for any DEVICE_LOCAL |
+            // HOST_VISIBLE memory type, as discrete GPUs will often expose a small (256MB)
+            // host visible window into VRAM.
+            if let Some(index) = find_memory_type(
+                vk::MemoryPropertyFlags::DEVICE_LOCAL | host_visible,
+                Some(heap_index),
+            ) {
+                return Ok(MemoryStrategy::Unified(index));
+            }
+
+            if let Some(index) =
+                find_memory_type(vk::MemoryPropertyFlags::DEVICE_LOCAL, Some(heap_index))
             {
-                memory_type_index = Some(index as u32);
-                memory_heap_index = Some(memory_type.heap_index);
-                break;
+                return Ok(MemoryStrategy::Discrete(index));
             }
         }
 
-        let memory_type_index = memory_type_index.expect("No device memory? Impossible");
-        let memory_heap_index = memory_heap_index.expect("No device memory? Impossible");
+        // No device local memory at all? Just use whatever host visible memory we can find.
+        let index = find_memory_type(host_visible, None)
+            .ok_or(Error::NoSuitableMemoryType(host_visible))?;
+        Ok(MemoryStrategy::Unified(index))
+    }
+}
+
+/// One `vkAllocateMemory`, with a buffer covering all of it so that slab uploads have an address.
+struct MemoryBlock {
+    memory: vk::DeviceMemory,
+    size: vk::DeviceSize,
+    /// Only set if the memory is host visible (see [`MemoryStrategy::Unified`]), in which case
+    /// transfers are just a `memcpy`.
+    mapped: Option<NonNull<u8>>,
+    slab_buffer: vk::Buffer,
+    slab_address: vk::DeviceAddress,
+    offset_allocator: offset_allocator::Allocator,
+}
+
+impl MemoryBlock {
+    fn new(context: &Context, strategy: MemoryStrategy, size: vk::DeviceSize) -> Result<Self> {
+        let device = &context.device;
+        let memory_type_index = match strategy {
+            MemoryStrategy::Discrete(index) | MemoryStrategy::Unified(index) => index,
+        };
+        let memory_heap_index =
+            context.memory_properties.memory_types[memory_type_index as usize].heap_index;
 
-        let device_memory = unsafe {
-            log::debug!("Allocating {GLOBAL_MEMORY_SIZE} from memory type / heap : {memory_type_index}, {memory_heap_index}");
+        let memory = unsafe {
+            log::debug!("Allocating a {size} byte block from memory type / heap : {memory_type_index}, {memory_heap_index}");
             device.allocate_memory(
                 &vk::MemoryAllocateInfo::default()
                     .memory_type_index(memory_type_index)
-                    .allocation_size(GLOBAL_MEMORY_SIZE).push_next(&mut vk::MemoryAllocateFlagsInfo::default().flags(vk::MemoryAllocateFlags::DEVICE_ADDRESS)),
+                    .allocation_size(size)
+                    .push_next(
+                        &mut vk::MemoryAllocateFlagsInfo::default()
+                            .flags(vk::MemoryAllocateFlags::DEVICE_ADDRESS),
+                    ),
                 None,
             )
-        }
-        .unwrap();
+        }?;
+
+        let map_and_create_slab = || -> Result<_> {
+            let mapped = match strategy {
+                MemoryStrategy::Discrete(_) => None,
+                MemoryStrategy::Unified(_) => Some(unsafe {
+                    NonNull::new_unchecked(device.map_memory(
+                        memory,
+                        0,
+                        vk::WHOLE_SIZE,
+                        vk::MemoryMapFlags::empty(),
+                    )? as *mut u8)
+                }),
+            };
+            let (slab_buffer, slab_address) = create_slab_buffer(context, memory, size)?;
+            Ok((mapped, slab_buffer, slab_address))
+        };
 
-        let (slab_buffer, slab_address) = create_slab_buffer(&context, device_memory);
+        let (mapped, slab_buffer, slab_address) = match map_and_create_slab() {
+            Ok(block) => block,
+            Err(e) => {
+                unsafe { device.free_memory(memory, None) };
+                return Err(e);
+            }
+        };
 
-        Self {
-            device_memory,
+        Ok(MemoryBlock {
+            memory,
+            size,
+            mapped,
             slab_buffer,
             slab_address,
+            offset_allocator: offset_allocator::Allocator::new(size as u32),
+        })
+    }
+
+    fn destroy(&self, device: &ash::Device) {
+        unsafe {
+            device.destroy_buffer(self.slab_buffer, None);
+            device.free_memory(self.memory, None);
         }
     }
 
-    pub fn buffer_transfer(
+    /// Returns the allocation, and how far into it the aligned offset is.
+    fn allocate(
         &mut self,
+        size: u64,
+        align: u64,
+    ) -> Option<(offset_allocator::Allocation, vk::DeviceSize)> {
+        let allocation = self.offset_allocator.allocate(size as u32)?;
+        let aligned = align_offset(align, allocation);
+
+        // Happy case: the offset is already aligned!
+        if aligned == allocation.offset as u64 {
+            log::trace!(
+                "[ALIGNED]: offset:{}, align:{align}, size: {size}",
+                allocation.offset
+            );
+            return Some((allocation, 0));
+        }
+
+        // Not aligned. First, see how much padding we need:
+        let padding = align - (allocation.offset as u64 % align);
+
+        log::trace!(
+            "[NOT ALIGNED]: offset:{}, align:{align}, pad: {padding}, size: {size}",
+            allocation.offset
+        );
+
+        // Free the offset we just got
+        self.offset_allocator.free(allocation);
+
+        // Ask for a new allocation with the padding we need
+        let new_size = (padding + size) as u32;
+        let allocation = self.offset_allocator.allocate(new_size)?;
+
+        log::trace!(
+            "[FIXED]: offset:{}, align:{align}, pad: {padding}, size: {new_size}",
+            allocation.offset
+        );
+
+        Some((allocation, padding))
+    }
+
+    fn buffer_transfer(
+        &self,
         context: &Context,
         PendingTransfer {
             destination,
@@ -201,14 +536,18 @@ impl DiscreteDeviceBuffer {
             ..
         }: PendingTransfer,
         staging_buffer: &mut StagingBuffer,
-        command_buffer: vk::CommandBuffer,
+        commands: &TransferCommands,
     ) {
         context.begin_marker("Buffer Transfer", glam::vec4(0., 1., 1., 1.));
         let device = &context.device;
+        let command_buffer = commands.command_buffer;
 
         let (destination_offset, destination_buffer) = match destination {
             TransferDestination::Buffer(buffer) => (allocation_offset, buffer),
-            TransferDestination::Slab => (global_offset.total_offset() as usize, self.slab_buffer),
+            TransferDestination::Slab => (
+                global_offset.total_offset() as usize + allocation_offset,
+                self.slab_buffer,
+            ),
             _ => return,
         };
 
@@ -230,28 +569,26 @@ impl DiscreteDeviceBuffer {
         // Place a barrier
         // TODO(these are a bit pessimistic, they could potentially be inferred from usage flags)
         unsafe {
-            device.cmd_pipeline_barrier2(
-                command_buffer,
-                &vk::DependencyInfo::default().buffer_memory_barriers(&[
-                    vk::BufferMemoryBarrier2::default()
-                        .src_access_mask(vk::AccessFlags2::TRANSFER_WRITE)
-                        .src_stage_mask(
-                            vk::PipelineStageFlags2::TRANSFER | vk::PipelineStageFlags2::COPY,
-                        )
-                        .dst_access_mask(
-                            vk::AccessFlags2::INDEX_READ
-                                | vk::AccessFlags2::SHADER_READ
-                                | vk::AccessFlags2::TRANSFER_WRITE,
-                        )
-                        .dst_stage_mask(
-                            vk::PipelineStageFlags2::COPY
-                                | vk::PipelineStageFlags2::VERTEX_SHADER
-                                | vk::PipelineStageFlags2::INDEX_INPUT,
-                        )
-                        .buffer(destination_buffer)
-                        .offset(destination_offset as _)
-                        .size(transfer_size),
-                ]),
+            commands.buffer_barrier(
+                context,
+                vk::BufferMemoryBarrier2::default()
+                    .src_access_mask(vk::AccessFlags2::TRANSFER_WRITE)
+                    .src_stage_mask(
+                        vk::PipelineStageFlags2::TRANSFER | vk::PipelineStageFlags2::COPY,
+                    )
+                    .dst_access_mask(
+                        vk::AccessFlags2::INDEX_READ
+                            | vk::AccessFlags2::SHADER_READ
+                            | vk::AccessFlags2::TRANSFER_WRITE,
+                    )
+                    .dst_stage_mask(
+                        vk::PipelineStageFlags2::COPY
+                            | vk::PipelineStageFlags2::VERTEX_SHADER
+                            | vk::PipelineStageFlags2::INDEX_INPUT,
+                    )
+                    .buffer(destination_buffer)
+                    .offset(destination_offset as _)
+                    .size(transfer_size),
             )
         };
 
@@ -259,28 +596,37 @@ impl DiscreteDeviceBuffer {
     }
 }
 
-fn create_slab_buffer(context: &Context, device_memory: vk::DeviceMemory) -> (vk::Buffer, u64) {
+fn create_slab_buffer(
+    context: &Context,
+    device_memory: vk::DeviceMemory,
+    size: vk::DeviceSize,
+) -> Result<(vk::Buffer, u64)> {
     let device = &context.device;
 
     // Create the buffer
+    let queue_family_indices = context.buffer_queue_families();
     let slab_buffer = unsafe {
         device.create_buffer(
             &vk::BufferCreateInfo::default()
-                .size(GLOBAL_MEMORY_SIZE)
+                .size(size)
                 .usage(
                     vk::BufferUsageFlags::STORAGE_BUFFER
                         | vk::BufferUsageFlags::SHADER_DEVICE_ADDRESS
                         | vk::BufferUsageFlags::TRANSFER_DST,
-                ),
+                )
+                .sharing_mode(sharing_mode(&queue_family_indices))
+                .queue_family_indices(&queue_family_indices),
             None,
         )
-    }
-    .unwrap();
+    }?;
 
     context.set_debug_label(slab_buffer, "Slab Buffer");
 
     // Bind it!
-    unsafe { device.bind_buffer_memory(slab_buffer, device_memory, 0) }.unwrap();
+    if let Err(e) = unsafe { device.bind_buffer_memory(slab_buffer, device_memory, 0) } {
+        unsafe { device.destroy_buffer(slab_buffer, None) };
+        return Err(e.into());
+    }
 
     // Now rew.. I mean, get its address:
     let slab_address = unsafe {
@@ -288,102 +634,38 @@ fn create_slab_buffer(context: &Context, device_memory: vk::DeviceMemory) -> (vk
             .get_buffer_device_address(&vk::BufferDeviceAddressInfo::default().buffer(slab_buffer))
     };
 
-    (slab_buffer, slab_address)
+    Ok((slab_buffer, slab_address))
 }
 
-pub struct IntegratedDeviceBuffer {
-    global_memory: vk::DeviceMemory,
-    global_ptr: NonNull<u8>,
-    #[allow(unused)]
-    slab_buffer: vk::Buffer,
-    slab_address: vk::DeviceAddress,
-}
-
-impl IntegratedDeviceBuffer {
-    pub fn new(context: Arc<Context>) -> IntegratedDeviceBuffer {
-        let device = &context.device;
-        let memory_properties = &context.memory_properties;
-
-        // Search through the available memory types to find the one we want
-        let mut memory_type_index = None;
-        let mut memory_heap_index = None;
-        for (index, memory_type) in memory_properties.memory_types_as_slice().iter().enumerate() {
-            if memory_type.property_flags.contains(
-                vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_COHERENT,
-            ) {
-                memory_type_index = Some(index as u32);
-                memory_heap_index = Some(memory_type.heap_index);
-                break;
-            }
-        }
-
-        let memory_type_index = memory_type_index.expect("No global memory? Impossible");
-        let memory_heap_index = memory_heap_index.expect("No global memory? Impossible");
-
-        let global_memory = unsafe {
-            log::debug!("Allocating {GLOBAL_MEMORY_SIZE} from memory type / heap : {memory_type_index}, {memory_heap_index}");
-            device.allocate_memory(
-                &vk::MemoryAllocateInfo::default()
-                    .memory_type_index(memory_type_index)
-                    .allocation_size(GLOBAL_MEMORY_SIZE).push_next(&mut vk::MemoryAllocateFlagsInfo::default().flags(vk::MemoryAllocateFlags::DEVICE_ADDRESS)),
-                None,
-            )
-        }
-        .unwrap();
-
-        // Map its memory
-        let global_ptr = unsafe {
-            std::ptr::NonNull::new_unchecked(
-                device
-                    .map_memory(
-                        global_memory,
-                        0,
-                        vk::WHOLE_SIZE,
-                        vk::MemoryMapFlags::empty(),
-                    )
-                    .unwrap() as *mut u8,
-            )
-        };
-
-        let (slab_buffer, slab_address) = create_slab_buffer(&context, global_memory);
-
-        IntegratedDeviceBuffer {
-            global_memory,
-            global_ptr,
-            slab_buffer,
-            slab_address,
-        }
-    }
-
-    pub fn buffer_transfer(
-        &mut self,
-        PendingTransfer {
-            allocation_offset,
-            staging_buffer_offset,
-            transfer_size,
-            global_offset,
-            transfer_token,
-            ..
-        }: PendingTransfer,
-        staging_buffer: &mut StagingBuffer,
-    ) {
-        // We get the source pointer by taking the base address of the **staging buffer** and
-        // adding the offset
-        let source = unsafe { staging_buffer.ptr.add(staging_buffer_offset).as_ptr() };
-
-        // We get the destination pointer by taking the base address of the **global buffer**,
-        // and then finally adding the offset within the allocation itself
-        let destination = unsafe {
-            self.global_ptr
-                .add(global_offset.total_offset() as usize + allocation_offset)
-                .as_ptr()
-        };
+/// Copy a buffer transfer straight into mapped device memory.
+fn mapped_buffer_transfer(
+    mapped: NonNull<u8>,
+    PendingTransfer {
+        allocation_offset,
+        staging_buffer_offset,
+        transfer_size,
+        global_offset,
+        transfer_token,
+        ..
+    }: PendingTransfer,
+    staging_buffer: &StagingBuffer,
+) {
+    // We get the source pointer by taking the base address of the **staging buffer** and
+    // adding the offset
+    let source = unsafe { staging_buffer.ptr.add(staging_buffer_offset).as_ptr() };
+
+    // We get the destination pointer by taking the base address of the **global buffer**,
+    // and then finally adding the offset within the allocation itself
+    let destination = unsafe {
+        mapped
+            .add(global_offset.total_offset() as usize + allocation_offset)
+            .as_ptr()
+    };
 
-        // Integrated memory don't care, baby!
-        unsafe {
-            std::ptr::copy_nonoverlapping(source, destination, transfer_size as usize);
-        };
+    // Integrated memory don't care, baby!
+    unsafe {
+        std::ptr::copy_nonoverlapping(source, destination, transfer_size as usize);
+    };
 
-        transfer_token.mark_completed();
-    }
+    transfer_token.mark_completed();
 }
@@ -0,0 +1,200 @@
+use std::{marker::PhantomData, ptr::NonNull};
+
+use ash::vk;
+
+use super::sharing_mode;
+use crate::{Context, Error, Result};
+
+/// A buffer in persistently mapped memory that the CPU writes to directly, rather than through
+/// the staging buffer - for small data that changes every frame, like camera matrices. Made with
+/// [`crate::Allocator::allocate_mapped_buffer`].
+///
+/// There's one copy of the buffer per frame in flight. [`MappedBuffer::slice_mut`],
+/// [`MappedBuffer::device_address`] and [`MappedBuffer::offset`] all refer to the copy for the
+/// frame being recorded, so writing it never races with the GPU reading an earlier frame's.
+///
+/// Device local memory that's also host visible (eg. resizable BAR) is used if there is any,
+/// otherwise the GPU reads it from system memory.
+pub struct MappedBuffer<T> {
+    pub handle: vk::Buffer,
+    memory: vk::DeviceMemory,
+    ptr: NonNull<u8>,
+    /// The address of the first frame's copy.
+    device_address: vk::DeviceAddress,
+    len: usize,
+    /// The distance between each frame's copy, in bytes.
+    stride: vk::DeviceSize,
+    device_local: bool,
+    _phantom: PhantomData<T>,
+}
+
+// SAFETY: `ptr` is only written through `&mut self`, and points to memory we own.
+unsafe impl<T: Send> Send for MappedBuffer<T> {}
+
+impl<T: bytemuck::Pod> MappedBuffer<T> {
+    pub(crate) fn new(
+        context: &Context,
+        len: usize,
+        usage_flags: vk::BufferUsageFlags,
+    ) -> Result<Self> {
+        let device = &context.device;
+        let limits = &context.device_properties.limits;
+
+        // Each copy has to be usable as a uniform or storage buffer on its own.
+        let align = limits
+            .min_uniform_buffer_offset_alignment
+            .max(limits.min_storage_buffer_offset_alignment)
+            .max(std::mem::align_of::<T>() as vk::DeviceSize);
+        let size = (len * std::mem::size_of::<T>()).max(1) as vk::DeviceSize;
+        let stride = size.next_multiple_of(align);
+
+        let queue_family_indices = context.buffer_queue_families();
+        let handle = unsafe {
+            device.create_buffer(
+                &vk::BufferCreateInfo::default()
+                    .size(stride * context.frames_in_flight as vk::DeviceSize)
+                    .usage(usage_flags | vk::BufferUsageFlags::SHADER_DEVICE_ADDRESS)
+                    .sharing_mode(sharing_mode(&queue_family_indices))
+                    .queue_family_indices(&queue_family_indices),
+                None,
+            )
+        }?;
+
+        let (memory, device_local) = match allocate_memory(context, handle) {
+            Ok(memory) => memory,
+            Err(e) => {
+                unsafe { device.destroy_buffer(handle, None) };
+                return Err(e);
+            }
+        };
+
+        let mapped = unsafe {
+            device.bind_buffer_memory(handle, memory, 0).and_then(|_| {
+                device.map_memory(memory, 0, vk::WHOLE_SIZE, vk::MemoryMapFlags::empty())
+            })
+        };
+        let ptr = match mapped {
+            Ok(pointer) => NonNull::new(pointer.cast()).unwrap(),
+            Err(e) => {
+                unsafe {
+                    device.destroy_buffer(handle, None);
+                    device.free_memory(memory, None);
+                }
+                return Err(e.into());
+            }
+        };
+
+        let device_address = unsafe {
+            device.get_buffer_device_address(&vk::BufferDeviceAddressInfo::default().buffer(handle))
+        };
+        context.set_debug_label(
+            handle,
+            &format!("[lazy_vulkan] MappedBuffer<{}>", std::any::type_name::<T>()),
+        );
+
+        Ok(Self {
+            handle,
+            memory,
+            ptr,
+            device_address,
+            len,
+            stride,
+            device_local,
+            _phantom: PhantomData,
+        })
+    }
+
+    /// The current frame's copy. Anything written here is seen by the GPU in the frame being
+    /// recorded, without any transfers.
+    ///
+    /// ## NOTE
+    /// Each copy keeps whatever was last written to it, `frames_in_flight` frames ago - so data
+    /// that isn't rewritten every frame has to be written to every copy.
+    pub fn slice_mut(&mut self, context: &Context) -> &mut [T] {
+        let offset = self.offset(context) as usize;
+        // SAFETY: The GPU is done with the last frame that used this copy, and any bit pattern
+        // is a valid `T`.
+        unsafe { std::slice::from_raw_parts_mut(self.ptr.add(offset).as_ptr().cast(), self.len) }
+    }
+}
+
+impl<T> MappedBuffer<T> {
+    /// Returns the number of `T`s in each copy.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Whether the buffer ended up in device local memory.
+    pub fn is_device_local(&self) -> bool {
+        self.device_local
+    }
+
+    /// Where the current frame's copy starts in [`MappedBuffer::handle`], for descriptors and
+    /// vertex / index bindings.
+    pub fn offset(&self, context: &Context) -> vk::DeviceSize {
+        context.frame_index() as vk::DeviceSize * self.stride
+    }
+
+    /// The address of the current frame's copy.
+    pub fn device_address(&self, context: &Context) -> vk::DeviceAddress {
+        self.device_address + self.offset(context)
+    }
+
+    /// The handle and memory to destroy, once the GPU is done with every copy.
+    pub(crate) fn raw(&self) -> (vk::Buffer, vk::DeviceMemory) {
+        (self.handle, self.memory)
+    }
+}
+
+/// Allocate memory for `buffer`, preferring device local memory if it's host visible.
+/// Returns whether it's device local.
+fn allocate_memory(context: &Context, buffer: vk::Buffer) -> Result<(vk::DeviceMemory, bool)> {
+    let device = &context.device;
+    let requirements = unsafe { device.get_buffer_memory_requirements(buffer) };
+    let host_visible =
+        vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_COHERENT;
+
+    let mut result = Err(Error::NoSuitableMemoryType(host_visible));
+    for memory_properties in [
+        vk::MemoryPropertyFlags::DEVICE_LOCAL | host_visible,
+        host_visible,
+    ] {
+        let Some(memory_type_index) =
+            context.find_memory_type_index(&requirements, memory_properties)
+        else {
+            continue;
+        };
+
+        let memory = unsafe {
+            device.allocate_memory(
+                &vk::MemoryAllocateInfo::default()
+                    .memory_type_index(memory_type_index)
+                    .allocation_size(requirements.size)
+                    .push_next(
+                        &mut vk::MemoryAllocateFlagsInfo::default()
+                            .flags(vk::MemoryAllocateFlags::DEVICE_ADDRESS),
+                    ),
+                None,
+            )
+        };
+
+        // Without resizable BAR, the device local window is small and can run out - system
+        // memory is slower, but better than nothing.
+        match memory {
+            Ok(memory) => {
+                let device_local = context.memory_properties.memory_types
+                    [memory_type_index as usize]
+                    .property_flags
+                    .contains(vk::MemoryPropertyFlags::DEVICE_LOCAL);
+                return Ok((memory, device_local));
+            }
+            Err(e) => result = Err(e.into()),
+        }
+    }
+
+    result
+}
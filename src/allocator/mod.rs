@@ -1,49 +1,151 @@
+#[cfg(not(target_vendor = "apple"))]
+mod acceleration_structure;
 mod device_buffer;
+mod mapped_buffer;
+mod readback;
+mod ring;
 mod staging_buffer;
-use device_buffer::DeviceBuffer;
+mod texel_block;
+mod transfer_queue;
+mod transfer_token;
+#[cfg(not(target_vendor = "apple"))]
+pub use acceleration_structure::{AccelerationStructure, TriangleGeometry};
+use device_buffer::{DeviceBuffer, OwnershipTransfer, TransferCommands, UPLOAD_CONSUMER_STAGES};
+pub use mapped_buffer::MappedBuffer;
+pub use readback::ReadbackToken;
+use readback::{ReadbackBuffer, ReadbackResult};
 use staging_buffer::StagingBuffer;
 use std::{
+    collections::{HashMap, HashSet, VecDeque},
     fmt::Debug,
     marker::PhantomData,
-    sync::{
-        atomic::{AtomicBool, Ordering},
-        Arc,
-    },
+    ops::Range,
+    sync::Arc,
 };
+use texel_block::TexelBlock;
+use transfer_queue::TransferQueue;
+use transfer_token::CompletionTracker;
+pub use transfer_token::TransferToken;
 
 use ash::vk;
 
 use super::context::Context;
+use crate::{Error, Image, Result};
 
-pub const GLOBAL_MEMORY_SIZE: u64 = 2u64 << 30; // 2GB
+pub const DEFAULT_BLOCK_SIZE: u64 = 256u64 << 20; // 256MB
 pub const STAGING_MEMORY_SIZE: u64 = 200u64 << 20; // 200MB
+pub const READBACK_MEMORY_SIZE: u64 = 16u64 << 20; // 16MB
+
+/// How much memory the [`Allocator`] sets aside, set with [`crate::ContextBuilder::allocator`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AllocatorConfig {
+    /// How much device memory to allocate at a time. Allocations bigger than this get a block to
+    /// themselves. Must be no more than 4GB.
+    pub block_size: vk::DeviceSize,
+    /// Stop allocating blocks once this much device memory is in use, and return
+    /// [`crate::Error::OutOfDeviceMemory`] instead. `None` means we keep going until the driver
+    /// says no.
+    pub max_device_memory: Option<vk::DeviceSize>,
+    /// The size of the staging buffer, shared between the frames in flight. Uploads that don't
+    /// fit are spread out over later frames.
+    pub staging_size: vk::DeviceSize,
+    /// The size of the buffer [`Allocator::read_buffer`] and [`Allocator::read_image`] copy into,
+    /// which is only allocated once they're first used. Limits how much can be read back by the
+    /// frames in flight at once.
+    pub readback_size: vk::DeviceSize,
+}
+
+impl Default for AllocatorConfig {
+    fn default() -> Self {
+        Self {
+            block_size: DEFAULT_BLOCK_SIZE,
+            max_device_memory: None,
+            staging_size: STAGING_MEMORY_SIZE,
+            readback_size: READBACK_MEMORY_SIZE,
+        }
+    }
+}
+
+/// How much device memory the [`Allocator`] is holding on to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct MemoryUsage {
+    /// How many `vkAllocateMemory` calls that's taken.
+    pub blocks: usize,
+    pub allocated: vk::DeviceSize,
+    /// How much of `allocated` isn't in use.
+    pub free: vk::DeviceSize,
+}
 
 pub struct Allocator {
     pub context: Arc<Context>,
     pub pending_transfers: Vec<PendingTransfer>,
-    #[allow(unused)]
-    pub pending_frees: Vec<PendingFree>,
-    offset_allocator: offset_allocator::Allocator,
+    /// Uploads that didn't fit in the staging buffer, oldest first.
+    deferred_uploads: VecDeque<DeferredUpload>,
     backend: DeviceBuffer,
     staging_buffer: StagingBuffer,
-    pending_tokens: Vec<TransferToken>,
+    /// Only present once something has been read back.
+    readback_buffer: Option<ReadbackBuffer>,
+    /// Readbacks that haven't been recorded yet.
+    pending_readbacks: Vec<PendingReadback>,
+    /// Only present if the device has a dedicated transfer queue - otherwise transfers are
+    /// recorded straight into the graphics command buffer.
+    transfer_queue: Option<TransferQueue>,
+    /// The timeline value the next graphics submission needs to wait for.
+    transfer_wait: Option<u64>,
+    /// Completes transfer tokens once the submission that executed them has finished.
+    completion: CompletionTracker,
+    /// The frame being recorded, as passed to [`Allocator::begin_frame`].
+    frame: u64,
+    /// One per frame in flight: what has to wait for that frame to finish.
+    frames: Vec<FrameResources>,
+    /// Every buffer we've handed out that hasn't been freed, so we can clean up after ourselves.
+    buffers: HashSet<vk::Buffer>,
+    /// The memory of every [`MappedBuffer`] that hasn't been freed - each has its own.
+    mapped_buffers: HashMap<vk::Buffer, vk::DeviceMemory>,
+    /// Where each image we've bound is, so its memory can be freed with it.
+    images: HashMap<vk::Image, Offset>,
+    /// The offsets of every slab upload that hasn't been freed, so a clone can't be freed twice.
+    slab_uploads: HashSet<(usize, u32)>,
+    #[cfg(not(target_vendor = "apple"))]
+    acceleration_structures: acceleration_structure::AccelerationStructureState,
 }
 
 impl Allocator {
-    pub fn new(context: Arc<Context>) -> Self {
-        let backend = DeviceBuffer::new(context.clone());
-        let staging_buffer = StagingBuffer::new(&context);
-        let offset_allocator = offset_allocator::Allocator::new(GLOBAL_MEMORY_SIZE as u32);
-
-        Self {
+    pub fn new(context: Arc<Context>) -> Result<Self> {
+        let config = context.allocator_config;
+        let backend = DeviceBuffer::new(context.clone(), config)?;
+        let staging_buffer = StagingBuffer::new(context.clone(), config.staging_size)?;
+        let transfer_queue = TransferQueue::new(context.clone())?;
+        let completion = CompletionTracker::new(context.clone())?;
+        let frames = std::iter::repeat_with(FrameResources::default)
+            .take(context.frames_in_flight)
+            .collect();
+
+        Ok(Self {
             backend,
             context,
-            offset_allocator,
-            pending_frees: Default::default(),
             pending_transfers: Default::default(),
+            deferred_uploads: Default::default(),
             staging_buffer,
-            pending_tokens: Default::default(),
-        }
+            readback_buffer: None,
+            pending_readbacks: Default::default(),
+            transfer_queue,
+            transfer_wait: None,
+            completion,
+            frame: 0,
+            frames,
+            buffers: Default::default(),
+            mapped_buffers: Default::default(),
+            images: Default::default(),
+            slab_uploads: Default::default(),
+            #[cfg(not(target_vendor = "apple"))]
+            acceleration_structures: Default::default(),
+        })
+    }
+
+    /// How much device memory has been allocated so far, and how much of it is free.
+    pub fn memory_usage(&self) -> MemoryUsage {
+        self.backend.usage()
     }
 
     /// Allocates a buffer of `max_size`.
@@ -54,22 +156,26 @@ impl Allocator {
         &mut self,
         max_size: usize,
         usage_flags: vk::BufferUsageFlags,
-    ) -> BufferAllocation<T> {
+    ) -> Result<BufferAllocation<T>> {
         let device = &self.context.device;
         let device_size = (max_size * std::mem::size_of::<T>()) as vk::DeviceSize;
 
         // Create the buffer
+        let queue_family_indices = self.context.buffer_queue_families();
         let handle = unsafe {
             device.create_buffer(
-                &vk::BufferCreateInfo::default().size(device_size).usage(
-                    usage_flags
-                        | vk::BufferUsageFlags::SHADER_DEVICE_ADDRESS
-                        | vk::BufferUsageFlags::TRANSFER_DST,
-                ),
+                &vk::BufferCreateInfo::default()
+                    .size(device_size)
+                    .usage(
+                        usage_flags
+                            | vk::BufferUsageFlags::SHADER_DEVICE_ADDRESS
+                            | vk::BufferUsageFlags::TRANSFER_DST,
+                    )
+                    .sharing_mode(sharing_mode(&queue_family_indices))
+                    .queue_family_indices(&queue_family_indices),
                 None,
             )
-        }
-        .unwrap();
+        }?;
 
         let memory_requirements = unsafe { device.get_buffer_memory_requirements(handle) };
         let align = memory_requirements.alignment;
@@ -83,22 +189,26 @@ impl Allocator {
         max_size: usize,
         align: u64,
         usage_flags: vk::BufferUsageFlags,
-    ) -> BufferAllocation<T> {
+    ) -> Result<BufferAllocation<T>> {
         let device = &self.context.device;
         let device_size = (max_size * std::mem::size_of::<T>()) as vk::DeviceSize;
 
         // Create the buffer
+        let queue_family_indices = self.context.buffer_queue_families();
         let handle = unsafe {
             device.create_buffer(
-                &vk::BufferCreateInfo::default().size(device_size).usage(
-                    usage_flags
-                        | vk::BufferUsageFlags::SHADER_DEVICE_ADDRESS
-                        | vk::BufferUsageFlags::TRANSFER_DST,
-                ),
+                &vk::BufferCreateInfo::default()
+                    .size(device_size)
+                    .usage(
+                        usage_flags
+                            | vk::BufferUsageFlags::SHADER_DEVICE_ADDRESS
+                            | vk::BufferUsageFlags::TRANSFER_DST,
+                    )
+                    .sharing_mode(sharing_mode(&queue_family_indices))
+                    .queue_family_indices(&queue_family_indices),
                 None,
             )
-        }
-        .unwrap();
+        }?;
 
         let memory_requirements = unsafe { device.get_buffer_memory_requirements(handle) };
         let size = memory_requirements.size;
@@ -106,96 +216,144 @@ impl Allocator {
         self.allocate_buffer_inner(align, handle, size)
     }
 
+    /// Allocates a [`MappedBuffer`] of `len` `T`s, which the CPU can write to directly every
+    /// frame. Unlike [`Allocator::allocate_buffer`], it gets its own memory rather than coming
+    /// out of the device memory blocks, so it's best kept for small things.
+    pub fn allocate_mapped_buffer<T: bytemuck::Pod>(
+        &mut self,
+        len: usize,
+        usage_flags: vk::BufferUsageFlags,
+    ) -> Result<MappedBuffer<T>> {
+        let buffer = MappedBuffer::new(&self.context, len, usage_flags)?;
+        let (handle, memory) = buffer.raw();
+        self.mapped_buffers.insert(handle, memory);
+        Ok(buffer)
+    }
+
     fn allocate_buffer_inner<T: Sized>(
         &mut self,
         align: u64,
         handle: vk::Buffer,
         size: u64,
-    ) -> BufferAllocation<T> {
+    ) -> Result<BufferAllocation<T>> {
         // Allocate an offset into our device local memory
-        let offset = self.allocate_offset(size, align);
+        let offset = match self.backend.allocate(size, align) {
+            Ok(offset) => offset,
+            Err(e) => {
+                unsafe { self.context.device.destroy_buffer(handle, None) };
+                return Err(e);
+            }
+        };
         let device = &self.context.device;
 
         let label = format!(
-            "[lazy_vulkan] BufferAllocation<{}> at offset {:?}",
+            "[lazy_vulkan] BufferAllocation<{}> at block {} offset {:?}",
             std::any::type_name::<T>(),
+            offset.block,
             offset.total_offset(),
         );
         self.context.set_debug_label(handle, &label);
 
         // Bind its memory
-        unsafe {
-            device.bind_buffer_memory(handle, self.backend.device_memory(), offset.total_offset())
+        let bound = unsafe {
+            device.bind_buffer_memory(
+                handle,
+                self.backend.device_memory(offset.block),
+                offset.total_offset(),
+            )
+        };
+        if let Err(e) = bound {
+            unsafe { device.destroy_buffer(handle, None) };
+            self.backend.free(offset);
+            return Err(e.into());
         }
-        .unwrap();
 
         // Get its device address
         let device_address = unsafe {
             device.get_buffer_device_address(&vk::BufferDeviceAddressInfo::default().buffer(handle))
         };
 
-        BufferAllocation {
+        self.buffers.insert(handle);
+
+        Ok(BufferAllocation {
             size,
             device_address,
             len: 0,
             handle,
             global_offset: offset,
             _phantom: PhantomData,
-        }
+        })
     }
 
+    /// Bind `image` to device memory, and stage `data` to be copied into it. `format` and `usage`
+    /// are what the image was created with - its sharing mode must match
+    /// [`Context::image_queue_families`] for `usage`.
+    ///
+    /// ## NOTE
+    /// If the staging buffer is short on space, big images are copied a few rows (or rows of
+    /// compressed blocks) at a time over several frames - so don't use `image` until the returned
+    /// token is complete.
     pub fn allocate_image(
         &mut self,
         data: &[u8],
+        format: vk::Format,
         extent: vk::Extent2D,
+        usage: vk::ImageUsageFlags,
         image: vk::Image,
-    ) -> TransferToken {
+    ) -> Result<TransferToken> {
         let memory_requirements =
             unsafe { self.context.device.get_image_memory_requirements(image) };
         let size = memory_requirements.size;
         let align = memory_requirements.alignment;
 
         // Allocate an offset into our device local memory
-        let global_offset = self.allocate_offset(size, align);
+        let global_offset = self.backend.allocate(size, align)?;
         let device = &self.context.device;
 
         // Bind the image to the memory at this offset
-        unsafe {
+        let bound = unsafe {
             device.bind_image_memory(
                 image,
-                self.backend.device_memory(),
+                self.backend.device_memory(global_offset.block),
                 global_offset.total_offset(),
             )
+        };
+        if let Err(e) = bound {
+            self.backend.free(global_offset);
+            return Err(e.into());
         }
-        .unwrap();
+        self.images.insert(image, global_offset);
 
-        // Stage the transfer
-        let (ours, theirs) = TransferToken::create_pair();
+        // Stage the transfer. Images are split up by rows of texel blocks, which only works if we
+        // know the format's layout - anything else has to be staged in one go.
+        let transfer_token = TransferToken::new();
+        let target = UploadTarget {
+            destination: TransferDestination::Image {
+                image,
+                extent,
+                block: TexelBlock::of(format),
+                first_row: 0,
+                concurrent: !self.context.image_queue_families(usage).is_empty(),
+            },
+            global_offset,
+            allocation_offset: 0,
+            transfer_token: transfer_token.clone(),
+        };
 
-        if !data.is_empty() {
-            let staging_buffer_offset = self.staging_buffer.stage(data);
-            self.pending_transfers.push(PendingTransfer {
-                destination: TransferDestination::Image(image, extent),
-                transfer_size: data.len() as _,
-                transfer_token: ours,
-                staging_buffer_offset,
-                global_offset,
-                allocation_offset: 0,
-            });
-        } else {
-            // No data? Nothing to do
-            ours.mark_completed();
-            theirs.mark_completed();
+        if let Err(e) = self.upload(data, target) {
+            self.images.remove(&image);
+            self.backend.free(global_offset);
+            return Err(e);
         }
 
-        theirs
+        Ok(transfer_token)
     }
 
     pub fn append_to_buffer<T: bytemuck::Pod>(
         &mut self,
         data: &[T],
         allocation: &mut BufferAllocation<T>,
-    ) -> TransferToken {
+    ) -> Result<TransferToken> {
         let bytes = bytemuck::cast_slice(data);
         self.append_to_buffer_inner(bytes, allocation)
     }
@@ -204,7 +362,7 @@ impl Allocator {
         &mut self,
         data: &[T],
         allocation: &mut BufferAllocation<T>,
-    ) -> TransferToken {
+    ) -> Result<TransferToken> {
         let bytes =
             std::slice::from_raw_parts(data.as_ptr() as *const u8, std::mem::size_of_val(data));
         self.append_to_buffer_inner(bytes, allocation)
@@ -214,151 +372,753 @@ impl Allocator {
         &mut self,
         bytes: &[u8],
         allocation: &mut BufferAllocation<T>,
-    ) -> TransferToken {
-        let staging_buffer_offset = self.staging_buffer.stage(bytes);
-
-        let (ours, theirs) = TransferToken::create_pair();
-
-        self.pending_transfers.push(PendingTransfer {
-            destination: TransferDestination::Buffer(allocation.handle),
-            staging_buffer_offset,
-            transfer_size: bytes.len() as _,
-            global_offset: allocation.global_offset,
-            transfer_token: ours,
-            allocation_offset: allocation.current_size() as usize,
-        });
+    ) -> Result<TransferToken> {
+        let transfer_token = TransferToken::new();
+
+        self.upload(
+            bytes,
+            UploadTarget {
+                destination: TransferDestination::Buffer(allocation.handle),
+                global_offset: allocation.global_offset,
+                allocation_offset: allocation.current_size() as usize,
+                transfer_token: transfer_token.clone(),
+            },
+        )?;
 
         allocation.len += bytes.len() as vk::DeviceSize;
 
-        theirs
+        Ok(transfer_token)
     }
 
-    pub fn execute_transfers(&mut self, command_buffer: vk::CommandBuffer) {
+    /// Execute every staged transfer.
+    ///
+    /// If the device has a dedicated transfer queue, the copies are submitted to it and
+    /// `command_buffer` only gets the barriers that take ownership of the results: whatever
+    /// `command_buffer` is submitted with must wait on [`Allocator::take_transfer_wait`].
+    /// Otherwise, the copies are recorded straight into `command_buffer`.
+    ///
+    /// Either way, the transfers' tokens are completed once the submission that signals
+    /// [`Allocator::take_transfer_signal`] has finished, or failing that, once this frame has.
+    pub fn execute_transfers(&mut self, command_buffer: vk::CommandBuffer) -> Result<()> {
+        self.stage_deferred_uploads();
+        if self.pending_transfers.is_empty() {
+            return Ok(());
+        }
+
         self.context
             .begin_marker("Execute Transfers", glam::vec4(0., 0., 1., 1.));
 
+        let frame_index = self.frame_index();
         for transfer in &self.pending_transfers {
-            self.pending_tokens.push(transfer.transfer_token.clone());
+            self.frames[frame_index]
+                .transfer_tokens
+                .push(transfer.transfer_token.clone());
+            self.completion.executed(transfer.transfer_token.clone());
+            self.staging_buffer
+                .mark_executed(transfer.staging_buffer_offset, self.frame);
         }
 
+        let commands = match &mut self.transfer_queue {
+            Some(transfer_queue) => TransferCommands {
+                command_buffer: transfer_queue.begin()?,
+                ownership_transfer: Some(OwnershipTransfer {
+                    graphics_command_buffer: command_buffer,
+                    transfer_family: transfer_queue.family,
+                    graphics_family: self.context.queue_families.graphics,
+                    concurrent_buffers: !self.context.buffer_queue_families().is_empty(),
+                }),
+            },
+            None => TransferCommands {
+                command_buffer,
+                ownership_transfer: None,
+            },
+        };
+
         self.backend.execute_transfers(
             &self.context,
             std::mem::take(&mut self.pending_transfers),
             &mut self.staging_buffer,
-            command_buffer,
+            &commands,
         );
 
+        if let Some(transfer_queue) = &mut self.transfer_queue {
+            self.transfer_wait = Some(transfer_queue.submit(commands.command_buffer)?);
+        }
+
         self.context.end_marker();
+        Ok(())
+    }
+
+    /// The tokens of every transfer that's been staged, but not yet executed.
+    pub(crate) fn pending_transfer_tokens(&self) -> Vec<TransferToken> {
+        self.pending_transfers
+            .iter()
+            .map(|transfer| transfer.transfer_token.clone())
+            .collect()
+    }
+
+    /// If transfers have been submitted to the transfer queue since this was last called, the
+    /// timeline semaphore wait the next graphics submission needs.
+    pub fn take_transfer_wait(&mut self) -> Option<vk::SemaphoreSubmitInfo<'static>> {
+        let transfer_queue = self.transfer_queue.as_ref()?;
+        let value = self.transfer_wait.take()?;
+
+        let mut stage_mask = UPLOAD_CONSUMER_STAGES;
+        if self.context.ray_tracing_enabled() {
+            stage_mask |= vk::PipelineStageFlags2::ACCELERATION_STRUCTURE_BUILD_KHR;
+        }
+
+        Some(
+            vk::SemaphoreSubmitInfo::default()
+                .semaphore(transfer_queue.semaphore)
+                .value(value)
+                .stage_mask(stage_mask),
+        )
+    }
+
+    /// If transfers have been executed since this was last called, the timeline semaphore signal
+    /// that completes their tokens. Add it to the submission containing the command buffer that
+    /// was passed to [`Allocator::execute_transfers`].
+    pub fn take_transfer_signal(&mut self) -> Option<vk::SemaphoreSubmitInfo<'static>> {
+        let value = self.completion.take_signal()?;
+
+        Some(
+            vk::SemaphoreSubmitInfo::default()
+                .semaphore(self.completion.semaphore)
+                .value(value)
+                .stage_mask(vk::PipelineStageFlags2::ALL_COMMANDS),
+        )
+    }
+
+    /// Like [`Allocator::take_transfer_wait`], but for another queue that needs the same
+    /// transfers to have landed by `stage_mask`. The graphics submission still has to take its own
+    /// wait.
+    pub fn transfer_wait_at(
+        &self,
+        stage_mask: vk::PipelineStageFlags2,
+    ) -> Option<vk::SemaphoreSubmitInfo<'static>> {
+        let transfer_queue = self.transfer_queue.as_ref()?;
+        let value = self.transfer_wait?;
+
+        Some(
+            vk::SemaphoreSubmitInfo::default()
+                .semaphore(transfer_queue.semaphore)
+                .value(value)
+                .stage_mask(stage_mask),
+        )
+    }
+
+    /// Called before recording `frame`, once the GPU has finished with the frame that last used
+    /// the same slot (ie. `frame - frames_in_flight`). Transfers executed in that frame are
+    /// marked complete and anything it was holding on to is freed.
+    pub fn begin_frame(&mut self, frame: u64) {
+        self.frame = frame;
+        // Anything the last frame didn't signal is completed when its slot comes around again.
+        self.completion.clear_unsignalled();
+        let frame_index = self.frame_index();
+        let resources = std::mem::take(&mut self.frames[frame_index]);
+        self.release_frame_resources(resources);
+        self.staging_buffer.begin_frame(frame);
+        if let Some(readback_buffer) = &mut self.readback_buffer {
+            readback_buffer.begin_frame(frame);
+        }
     }
 
     /// This should only be called when all transfers issued with `execute_transfers` have been
-    /// actually completed.
+    /// actually completed, in every frame.
     pub fn transfers_complete(&mut self) {
-        for token in self.pending_tokens.drain(..) {
-            token.mark_completed();
+        self.completion.clear_unsignalled();
+        for resources in std::mem::take(&mut self.frames) {
+            self.release_frame_resources(resources);
+            self.frames.push(Default::default());
         }
 
         self.staging_buffer.clear();
+        if let Some(readback_buffer) = &mut self.readback_buffer {
+            readback_buffer.clear();
+        }
+    }
+
+    fn frame_index(&self) -> usize {
+        (self.frame % self.frames.len() as u64) as usize
+    }
+
+    /// Free something once the GPU has finished with the frame that's currently being recorded.
+    fn free_after_frame(&mut self, pending_free: PendingFree) {
+        let frame_index = self.frame_index();
+        self.frames[frame_index].pending_frees.push(pending_free);
+    }
+
+    fn release_frame_resources(&mut self, resources: FrameResources) {
+        for token in resources.transfer_tokens {
+            token.mark_completed();
+        }
+
+        self.complete_readbacks(&resources.readbacks);
+
+        for pending_free in resources.pending_frees {
+            match pending_free {
+                PendingFree::Buffer(buffer) => self.free_buffer_now(buffer),
+                PendingFree::Slab(offset) => self.backend.free(offset),
+                PendingFree::MappedBuffer { handle, memory } => unsafe {
+                    self.context.device.destroy_buffer(handle, None);
+                    self.context.device.free_memory(memory, None);
+                },
+                PendingFree::Image { image, offset } => {
+                    let device = &self.context.device;
+                    unsafe {
+                        // Not every image has a sampler, but destroying a null handle is fine.
+                        device.destroy_sampler(image.sampler, None);
+                        device.destroy_image_view(image.view, None);
+                        device.destroy_image(image.handle, None);
+                    }
+                    self.backend.free(offset);
+                }
+                #[cfg(not(target_vendor = "apple"))]
+                PendingFree::AccelerationStructure(acceleration_structure) => {
+                    self.free_acceleration_structure_now(acceleration_structure)
+                }
+            }
+        }
     }
 
-    pub fn upload_to_slab<T: bytemuck::Pod + Debug>(&mut self, data: &[T]) -> SlabUpload<T> {
+    pub fn upload_to_slab<T: bytemuck::Pod + Debug>(
+        &mut self,
+        data: &[T],
+    ) -> Result<SlabUpload<T>> {
         let bytes = bytemuck::cast_slice(data);
         let size = bytes.len() as vk::DeviceSize;
 
         // Allocate an offset into our device local memory
         const SLAB_ALIGNMENT: u64 = 8;
-        let global_offset = self.allocate_offset(size, SLAB_ALIGNMENT);
-
-        let staging_buffer_offset = self.staging_buffer.stage(bytes);
-        let device_address = self.backend.get_device_address(global_offset);
+        let global_offset = self.backend.allocate(size, SLAB_ALIGNMENT)?;
 
-        let (ours, theirs) = TransferToken::create_pair();
-
-        self.pending_transfers.push(PendingTransfer {
+        let transfer_token = TransferToken::new();
+        let target = UploadTarget {
             destination: TransferDestination::Slab,
-            staging_buffer_offset,
-            transfer_size: bytes.len() as _,
             global_offset,
-            transfer_token: ours,
             allocation_offset: 0,
-        });
+            transfer_token: transfer_token.clone(),
+        };
+
+        if let Err(e) = self.upload(bytes, target) {
+            self.backend.free(global_offset);
+            return Err(e);
+        }
+        let device_address = self.backend.get_device_address(global_offset);
 
-        SlabUpload {
+        self.slab_uploads.insert(global_offset.key());
+        Ok(SlabUpload {
             device_address,
             size,
             offset: global_offset,
-            transfer_token: theirs,
+            transfer_token,
             _phantom: Default::default(),
+        })
+    }
+
+    /// Read `range` back from `allocation` - in `T`s, not bytes. It can go past
+    /// [`BufferAllocation::len`], eg. for data the GPU wrote. The buffer must have been created
+    /// with `TRANSFER_SRC` usage.
+    ///
+    /// The copy is recorded at the end of the frame (or [`Context::immediate_submit`]), after
+    /// everything else, and the token has the data once the GPU has finished it.
+    pub fn read_buffer<T: bytemuck::Pod>(
+        &mut self,
+        allocation: &BufferAllocation<T>,
+        range: Range<usize>,
+    ) -> Result<ReadbackToken<T>> {
+        let element_size = std::mem::size_of::<T>() as vk::DeviceSize;
+        let offset = range.start as vk::DeviceSize * element_size;
+        let size = range.len() as vk::DeviceSize * element_size;
+        assert!(
+            offset + size <= allocation.size,
+            "Readback range {range:?} is out of bounds"
+        );
+
+        let optimal_alignment = self.optimal_copy_alignment();
+        self.schedule_readback(
+            ReadbackSource::Buffer {
+                handle: allocation.handle,
+                offset,
+            },
+            size,
+            lcm(optimal_alignment, element_size.max(1)),
+        )
+    }
+
+    /// Read back all of `image`, which must have been created with `TRANSFER_SRC` usage and be
+    /// in `layout` when the copy is recorded - it's put back in `layout` afterwards. `T` is the
+    /// type of one texel (eg. `[u8; 4]` for `R8G8B8A8_UNORM`). Images from
+    /// [`crate::Renderer::create_image`] can always be read back.
+    ///
+    /// Like [`Allocator::read_buffer`], the copy is recorded at the end of the frame.
+    pub fn read_image<T: bytemuck::Pod>(
+        &mut self,
+        image: vk::Image,
+        extent: vk::Extent2D,
+        layout: vk::ImageLayout,
+        aspect_mask: vk::ImageAspectFlags,
+    ) -> Result<ReadbackToken<T>> {
+        assert_ne!(
+            layout,
+            vk::ImageLayout::UNDEFINED,
+            "Can't read back an image with undefined contents"
+        );
+
+        let texel_size = std::mem::size_of::<T>() as vk::DeviceSize;
+        let size = extent.width as vk::DeviceSize * extent.height as vk::DeviceSize * texel_size;
+
+        // Image copies have to start on a texel, and depth / stencil ones on 4 bytes.
+        let optimal_alignment = self.optimal_copy_alignment();
+        self.schedule_readback(
+            ReadbackSource::Image {
+                image,
+                extent,
+                layout,
+                aspect_mask,
+            },
+            size,
+            lcm(lcm(optimal_alignment, texel_size.max(1)), 4),
+        )
+    }
+
+    /// Record the copies for every readback scheduled since this was last called, followed by a
+    /// barrier that makes them visible to the host. Returns them, so that they can be completed
+    /// early with [`Allocator::complete_readbacks`] if the submission is waited on.
+    pub(crate) fn record_readbacks(
+        &mut self,
+        command_buffer: vk::CommandBuffer,
+    ) -> Vec<RecordedReadback> {
+        let Some(readback_buffer) = &mut self.readback_buffer else {
+            return Vec::new();
+        };
+        if self.pending_readbacks.is_empty() {
+            return Vec::new();
+        }
+
+        self.context
+            .begin_marker("Record Readbacks", glam::vec4(0., 1., 0., 1.));
+        let device = &self.context.device;
+        let mut recorded = Vec::with_capacity(self.pending_readbacks.len());
+
+        for readback in self.pending_readbacks.drain(..) {
+            readback_buffer.mark_executed(readback.offset, self.frame);
+            unsafe {
+                match readback.source {
+                    ReadbackSource::Buffer { handle, offset } => {
+                        self.context.cmd_pipeline_barrier2(
+                            command_buffer,
+                            &vk::DependencyInfo::default().buffer_memory_barriers(&[
+                                vk::BufferMemoryBarrier2::default()
+                                    .buffer(handle)
+                                    .offset(offset)
+                                    .size(readback.size)
+                                    .src_access_mask(vk::AccessFlags2::MEMORY_WRITE)
+                                    .src_stage_mask(vk::PipelineStageFlags2::ALL_COMMANDS)
+                                    .dst_access_mask(vk::AccessFlags2::TRANSFER_READ)
+                                    .dst_stage_mask(vk::PipelineStageFlags2::COPY),
+                            ]),
+                        );
+                        device.cmd_copy_buffer(
+                            command_buffer,
+                            handle,
+                            readback_buffer.handle,
+                            &[vk::BufferCopy::default()
+                                .src_offset(offset)
+                                .dst_offset(readback.offset as _)
+                                .size(readback.size)],
+                        );
+                    }
+                    ReadbackSource::Image {
+                        image,
+                        extent,
+                        layout,
+                        aspect_mask,
+                    } => {
+                        let subresource_range = vk::ImageSubresourceRange::default()
+                            .aspect_mask(aspect_mask)
+                            .level_count(1)
+                            .layer_count(1);
+                        self.context.cmd_pipeline_barrier2(
+                            command_buffer,
+                            &vk::DependencyInfo::default().image_memory_barriers(&[
+                                vk::ImageMemoryBarrier2::default()
+                                    .image(image)
+                                    .subresource_range(subresource_range)
+                                    .src_access_mask(vk::AccessFlags2::MEMORY_WRITE)
+                                    .src_stage_mask(vk::PipelineStageFlags2::ALL_COMMANDS)
+                                    .dst_access_mask(vk::AccessFlags2::TRANSFER_READ)
+                                    .dst_stage_mask(vk::PipelineStageFlags2::COPY)
+                                    .old_layout(layout)
+                                    .new_layout(vk::ImageLayout::TRANSFER_SRC_OPTIMAL),
+                            ]),
+                        );
+                        device.cmd_copy_image_to_buffer(
+                            command_buffer,
+                            image,
+                            vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+                            readback_buffer.handle,
+                            &[vk::BufferImageCopy::default()
+                                .buffer_offset(readback.offset as _)
+                                .image_subresource(
+                                    vk::ImageSubresourceLayers::default()
+                                        .aspect_mask(aspect_mask)
+                                        .layer_count(1),
+                                )
+                                .image_extent(extent.into())],
+                        );
+                        // Put it back the way we found it.
+                        self.context.cmd_pipeline_barrier2(
+                            command_buffer,
+                            &vk::DependencyInfo::default().image_memory_barriers(&[
+                                vk::ImageMemoryBarrier2::default()
+                                    .image(image)
+                                    .subresource_range(subresource_range)
+                                    .src_stage_mask(vk::PipelineStageFlags2::COPY)
+                                    .dst_access_mask(
+                                        vk::AccessFlags2::MEMORY_READ
+                                            | vk::AccessFlags2::MEMORY_WRITE,
+                                    )
+                                    .dst_stage_mask(vk::PipelineStageFlags2::ALL_COMMANDS)
+                                    .old_layout(vk::ImageLayout::TRANSFER_SRC_OPTIMAL)
+                                    .new_layout(layout),
+                            ]),
+                        );
+                    }
+                }
+            }
+
+            recorded.push(RecordedReadback {
+                offset: readback.offset,
+                size: readback.size as usize,
+                result: readback.result,
+            });
+        }
+
+        // Make the copies visible to the CPU once the submission has finished.
+        unsafe {
+            self.context.cmd_pipeline_barrier2(
+                command_buffer,
+                &vk::DependencyInfo::default().memory_barriers(&[vk::MemoryBarrier2::default()
+                    .src_access_mask(vk::AccessFlags2::TRANSFER_WRITE)
+                    .src_stage_mask(vk::PipelineStageFlags2::COPY)
+                    .dst_access_mask(vk::AccessFlags2::HOST_READ)
+                    .dst_stage_mask(vk::PipelineStageFlags2::HOST)]),
+            )
+        };
+        self.context.end_marker();
+
+        let frame_index = self.frame_index();
+        self.frames[frame_index]
+            .readbacks
+            .extend(recorded.iter().cloned());
+        recorded
+    }
+
+    /// Hand the data to every readback in `readbacks` that doesn't have it yet. The GPU must be
+    /// done with the submissions that recorded them!
+    pub(crate) fn complete_readbacks(&mut self, readbacks: &[RecordedReadback]) {
+        let Some(readback_buffer) = &self.readback_buffer else {
+            return;
+        };
+
+        for readback in readbacks {
+            if !readback.result.is_complete() {
+                let data = readback_buffer.read(readback.offset, readback.size);
+                readback.result.complete(Some(data));
+            }
         }
     }
 
-    pub fn free<T: Sized>(&mut self, _allocation: BufferAllocation<T>) {
-        unimplemented!("Free is not yet implemented");
+    fn schedule_readback<T: bytemuck::Pod>(
+        &mut self,
+        source: ReadbackSource,
+        size: vk::DeviceSize,
+        align: vk::DeviceSize,
+    ) -> Result<ReadbackToken<T>> {
+        let result = ReadbackResult::default();
+        if size == 0 {
+            result.complete(Some(Vec::new()));
+            return Ok(ReadbackToken::new(result));
+        }
+
+        let readback_buffer = match &mut self.readback_buffer {
+            Some(readback_buffer) => readback_buffer,
+            None => self.readback_buffer.insert(ReadbackBuffer::new(
+                self.context.clone(),
+                self.context.allocator_config.readback_size,
+            )?),
+        };
+        let offset = readback_buffer.reserve(size, align)?;
+
+        self.pending_readbacks.push(PendingReadback {
+            source,
+            offset,
+            size,
+            result: result.clone(),
+        });
+        Ok(ReadbackToken::new(result))
     }
 
-    pub fn free_from_slab<T: Sized>(&mut self, _allocation: BufferAllocation<T>) {
-        unimplemented!("Free is not yet implemented");
+    fn optimal_copy_alignment(&self) -> vk::DeviceSize {
+        self.context
+            .device_properties
+            .limits
+            .optimal_buffer_copy_offset_alignment
+            .max(1)
     }
 
-    fn allocate_offset(&mut self, size: u64, align: u64) -> Offset {
-        let allocation = self
-            .offset_allocator
-            .allocate(size as u32)
-            .expect("COULD NOT ALLOCATE AN OFFSET - THIS SHOULD BE IMPOSSIBLE");
-        let aligned = align_offset(align, allocation);
+    /// Destroy the buffer and release its memory, once the GPU has finished with the frame that's
+    /// being recorded. Any transfers to it that haven't been executed yet are dropped.
+    pub fn free<T: Sized>(&mut self, allocation: BufferAllocation<T>) {
+        let handle = allocation.handle;
+        self.cancel_transfers(|destination, _| {
+            matches!(destination, TransferDestination::Buffer(buffer) if *buffer == handle)
+        });
+        self.cancel_readbacks(|source| {
+            matches!(source, ReadbackSource::Buffer { handle: buffer, .. } if *buffer == handle)
+        });
+        self.free_after_frame(PendingFree::Buffer(allocation.untyped()));
+    }
 
-        // Happy case: the offset is already aligned!
-        if aligned == allocation.offset as u64 {
-            log::trace!(
-                "[ALIGNED]: offset:{}, align:{align}, size: {size}",
-                allocation.offset
+    /// Destroy the buffer and release its memory, once the GPU has finished with the frame that's
+    /// being recorded - which is the last one that could be using any of its copies.
+    pub fn free_mapped_buffer<T>(&mut self, buffer: MappedBuffer<T>) {
+        let (handle, memory) = buffer.raw();
+        assert!(
+            self.mapped_buffers.remove(&handle).is_some(),
+            "Mapped buffer wasn't allocated with this allocator"
+        );
+        self.free_after_frame(PendingFree::MappedBuffer { handle, memory });
+    }
+
+    /// Release the upload's memory, once the GPU has finished with the frame that's being
+    /// recorded. If the upload hasn't been executed yet, it never will be.
+    ///
+    /// ## NOTE
+    /// Clones of `upload` point to the same memory, so only one of them can be freed.
+    pub fn free_from_slab<T: Sized>(&mut self, upload: SlabUpload<T>) {
+        let offset = upload.offset;
+        assert!(
+            self.slab_uploads.remove(&offset.key()),
+            "Slab upload at block {} offset {:#x} has already been freed",
+            offset.block,
+            offset.total_offset()
+        );
+
+        self.cancel_transfers(|destination, global_offset| {
+            matches!(destination, TransferDestination::Slab) && global_offset.key() == offset.key()
+        });
+        self.free_after_frame(PendingFree::Slab(offset));
+    }
+
+    /// Destroy `image`, its view and sampler, and release its memory, once the GPU has finished
+    /// with the frame that's being recorded. Use [`crate::Renderer::destroy_image`] rather than
+    /// calling this directly.
+    pub(crate) fn free_image(&mut self, image: Image) {
+        let handle = image.handle;
+        let offset = self
+            .images
+            .remove(&handle)
+            .expect("Image wasn't allocated with this allocator");
+
+        self.cancel_transfers(|destination, _| {
+            matches!(destination, TransferDestination::Image { image, .. } if *image == handle)
+        });
+        self.cancel_readbacks(
+            |source| matches!(source, ReadbackSource::Image { image, .. } if *image == handle),
+        );
+        self.free_after_frame(PendingFree::Image { image, offset });
+    }
+
+    /// Drop every staged or deferred transfer to a destination that's about to be freed.
+    fn cancel_transfers(&mut self, is_freed: impl Fn(&TransferDestination, &Offset) -> bool) {
+        for transfer in self.pending_transfers.extract_if(.., |transfer| {
+            is_freed(&transfer.destination, &transfer.global_offset)
+        }) {
+            self.staging_buffer
+                .mark_cancelled(transfer.staging_buffer_offset);
+            // There's nothing left to wait for.
+            transfer.transfer_token.mark_completed();
+        }
+
+        self.deferred_uploads.retain(|upload| {
+            let target = &upload.target;
+            let freed = is_freed(&target.destination, &target.global_offset);
+            if freed {
+                target.transfer_token.mark_completed();
+            }
+            !freed
+        });
+    }
+
+    /// Drop every readback from a source that's about to be freed. Their tokens complete without
+    /// any data.
+    fn cancel_readbacks(&mut self, is_freed: impl Fn(&ReadbackSource) -> bool) {
+        for readback in self
+            .pending_readbacks
+            .extract_if(.., |readback| is_freed(&readback.source))
+        {
+            if let Some(readback_buffer) = &mut self.readback_buffer {
+                readback_buffer.mark_cancelled(readback.offset);
+            }
+            readback.result.complete(None);
+        }
+    }
+
+    /// Stage as much of `data` as there's room for, and defer the rest until the staging buffer
+    /// has space again. `target`'s token is completed once the last piece has been transferred.
+    fn upload(&mut self, data: &[u8], mut target: UploadTarget) -> Result<()> {
+        if data.is_empty() {
+            // No data? Nothing to do
+            target.transfer_token.mark_completed();
+            return Ok(());
+        }
+
+        let (_, granularity) = target.copy_layout(&self.context, data.len());
+        if granularity as vk::DeviceSize > self.staging_buffer.size() {
+            return Err(Error::StagingBufferFull {
+                requested: granularity as _,
+                available: self.staging_buffer.size(),
+            });
+        }
+
+        // Keep uploads in order: if anything is already waiting for space, so are we.
+        let staged = if self.deferred_uploads.is_empty() {
+            self.stage_pieces(data, &mut target)
+        } else {
+            0
+        };
+
+        if staged < data.len() {
+            log::debug!(
+                "[lazy_vulkan] Staging buffer full, deferring {} bytes",
+                data.len() - staged
             );
-            return Offset {
-                allocation,
-                bind_offset: 0,
+            self.deferred_uploads.push_back(DeferredUpload {
+                data: data[staged..].to_vec(),
+                target,
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Stage the deferred uploads, oldest first, until we run out of room again.
+    fn stage_deferred_uploads(&mut self) {
+        while let Some(mut upload) = self.deferred_uploads.pop_front() {
+            let staged = self.stage_pieces(&upload.data, &mut upload.target);
+            if staged < upload.data.len() {
+                upload.data.drain(..staged);
+                self.deferred_uploads.push_front(upload);
+                return;
+            }
+        }
+    }
+
+    /// Stage `data` a piece at a time for as long as there's room, returning how many bytes made
+    /// it in.
+    fn stage_pieces(&mut self, data: &[u8], target: &mut UploadTarget) -> usize {
+        let (align, granularity) = target.copy_layout(&self.context, data.len());
+        let mut staged = 0;
+
+        while staged < data.len() {
+            let Some((staging_buffer_offset, transfer_size)) =
+                self.staging_buffer
+                    .stage(&data[staged..], align, granularity)
+            else {
+                break;
+            };
+            staged += transfer_size;
+
+            // Pieces are executed in order, so by the time the last one is complete the rest are
+            // too - only it needs the caller's token.
+            let transfer_token = if staged == data.len() {
+                target.transfer_token.clone()
+            } else {
+                TransferToken::new()
             };
+
+            self.pending_transfers.push(PendingTransfer {
+                destination: target.destination,
+                staging_buffer_offset,
+                global_offset: target.global_offset,
+                allocation_offset: target.allocation_offset,
+                transfer_size: transfer_size as _,
+                transfer_token,
+            });
+            target.advance(transfer_size);
         }
 
-        // Not aligned. First, see how much padding we need:
-        let padding = align - (allocation.offset as u64 % align);
+        staged
+    }
 
-        log::trace!(
-            "[NOT ALIGNED]: offset:{}, align:{align}, pad: {padding}, size: {size}",
-            allocation.offset
-        );
+    /// Destroy the buffer and release its memory immediately. The GPU must be done with it!
+    fn free_buffer_now<T>(&mut self, allocation: BufferAllocation<T>) {
+        self.buffers.remove(&allocation.handle);
+        unsafe { self.context.device.destroy_buffer(allocation.handle, None) };
+        self.backend.free(allocation.global_offset);
+    }
+}
 
-        // Free the offset we just got
-        self.offset_allocator.free(allocation);
+impl Drop for Allocator {
+    fn drop(&mut self) {
+        // We have no idea what the GPU is doing with our memory, so wait for it to finish. Our
+        // fields rely on this, and destroy their Vulkan objects without waiting. Freeing memory
+        // also unmaps it.
+        unsafe {
+            let _ = self.context.device.device_wait_idle();
+        }
 
-        // Ask for a new allocation with the padding we need
-        let new_size = (padding + size) as u32;
-        let allocation = self
-            .offset_allocator
-            .allocate(new_size)
-            .expect("COULD NOT ALLOCATE AN OFFSET - THIS SHOULD BE IMPOSSIBLE");
+        // Everything has finished, so there's no need to wait for any frames.
+        self.transfers_complete();
 
-        log::trace!(
-            "[FIXED]: offset:{}, align:{align}, pad: {padding}, size: {new_size}",
-            allocation.offset
-        );
+        #[cfg(not(target_vendor = "apple"))]
+        self.destroy_all_acceleration_structures();
 
-        Offset {
-            allocation,
-            bind_offset: padding,
+        for buffer in self.buffers.drain() {
+            unsafe { self.context.device.destroy_buffer(buffer, None) };
         }
+
+        for (buffer, memory) in self.mapped_buffers.drain() {
+            unsafe {
+                self.context.device.destroy_buffer(buffer, None);
+                self.context.device.free_memory(memory, None);
+            }
+        }
+
+        // `backend` and `staging_buffer` free their own memory.
     }
 }
 
 #[derive(Clone, Copy)]
 pub struct Offset {
+    /// Which of the device buffer's memory blocks this is in.
+    pub block: usize,
     pub allocation: offset_allocator::Allocation,
     pub bind_offset: vk::DeviceSize,
 }
 
 impl Offset {
+    /// The offset within the block.
     pub fn total_offset(&self) -> vk::DeviceSize {
         self.allocation.offset as u64 + self.bind_offset
     }
+
+    /// Identifies the allocation, across every block.
+    fn key(&self) -> (usize, u32) {
+        (self.block, self.allocation.offset)
+    }
+}
+
+/// Buffers shared between several queue families have to be created as concurrent.
+pub(crate) fn sharing_mode(queue_family_indices: &[u32]) -> vk::SharingMode {
+    if queue_family_indices.is_empty() {
+        vk::SharingMode::EXCLUSIVE
+    } else {
+        vk::SharingMode::CONCURRENT
+    }
 }
 
 fn align_offset(align: u64, offset: offset_allocator::Allocation) -> u64 {
@@ -370,37 +1130,10 @@ pub struct SlabUpload<T> {
     pub device_address: vk::DeviceAddress,
     pub size: vk::DeviceSize,
     pub transfer_token: TransferToken,
-    #[allow(unused)]
     offset: Offset,
     _phantom: PhantomData<T>,
 }
 
-#[derive(Clone, Debug, Default)]
-pub struct TransferToken {
-    complete: Arc<AtomicBool>,
-}
-
-impl TransferToken {
-    /// TODO: need to be clear about under what conditions this is true
-    pub fn is_complete(&self) -> bool {
-        self.complete.load(Ordering::Relaxed)
-    }
-
-    fn create_pair() -> (TransferToken, TransferToken) {
-        let complete = Arc::new(AtomicBool::new(false));
-        (
-            TransferToken {
-                complete: complete.clone(),
-            },
-            TransferToken { complete },
-        )
-    }
-
-    fn mark_completed(&self) {
-        self.complete.store(true, Ordering::Relaxed);
-    }
-}
-
 pub struct PendingTransfer {
     destination: TransferDestination,
     staging_buffer_offset: usize, // offset within the staging buffer
@@ -410,13 +1143,146 @@ pub struct PendingTransfer {
     transfer_token: TransferToken,
 }
 
+#[derive(Clone, Copy)]
 enum TransferDestination {
     Buffer(vk::Buffer),
-    Image(vk::Image, vk::Extent2D),
+    Image {
+        image: vk::Image,
+        extent: vk::Extent2D,
+        /// `None` if we don't know the format's layout, so it has to be transferred in one piece.
+        block: Option<TexelBlock>,
+        /// The first row of texels this transfer writes to.
+        first_row: u32,
+        /// Whether the image is shared between queue families, so it doesn't need its ownership
+        /// transferred.
+        concurrent: bool,
+    },
     Slab,
 }
 
-pub struct PendingFree;
+/// Where the next piece of an upload will be transferred to.
+struct UploadTarget {
+    destination: TransferDestination,
+    global_offset: Offset,
+    allocation_offset: usize,
+    /// Completed once the last piece has been transferred.
+    transfer_token: TransferToken,
+}
+
+impl UploadTarget {
+    /// What each piece's offset in the staging buffer has to be a multiple of, and what its size
+    /// has to be a multiple of, with `remaining` bytes left to upload.
+    fn copy_layout(&self, context: &Context, remaining: usize) -> (vk::DeviceSize, usize) {
+        let optimal_alignment = context
+            .device_properties
+            .limits
+            .optimal_buffer_copy_offset_alignment
+            .max(1);
+
+        match self.destination {
+            // Buffer -> image copies have to start on a texel (or block), and we split them up by
+            // rows of blocks.
+            TransferDestination::Image {
+                extent,
+                block: Some(block),
+                ..
+            } => (
+                lcm(optimal_alignment, block.size as vk::DeviceSize),
+                block.row_size(extent.width) as usize,
+            ),
+            // 16 bytes is the biggest texel there is, that we don't know about.
+            TransferDestination::Image { block: None, .. } => {
+                (lcm(optimal_alignment, 16), remaining)
+            }
+            TransferDestination::Buffer(_) | TransferDestination::Slab => (optimal_alignment, 1),
+        }
+    }
+
+    /// Move past the `transfer_size` bytes that have just been staged.
+    fn advance(&mut self, transfer_size: usize) {
+        match &mut self.destination {
+            TransferDestination::Image {
+                extent,
+                block: Some(block),
+                first_row,
+                ..
+            } => *first_row += block.rows(*extent, *first_row, transfer_size as _),
+            TransferDestination::Image { .. } => {}
+            TransferDestination::Buffer(_) | TransferDestination::Slab => {
+                self.allocation_offset += transfer_size
+            }
+        }
+    }
+}
+
+/// The rest of an upload that didn't fit in the staging buffer.
+struct DeferredUpload {
+    data: Vec<u8>,
+    target: UploadTarget,
+}
+
+fn lcm(a: vk::DeviceSize, b: vk::DeviceSize) -> vk::DeviceSize {
+    let (mut x, mut y) = (a, b);
+    while y != 0 {
+        (x, y) = (y, x % y);
+    }
+    a / x * b
+}
+
+/// Something that can't be destroyed until the GPU has finished with the frame that used it.
+pub enum PendingFree {
+    Buffer(BufferAllocation<u8>),
+    Slab(Offset),
+    MappedBuffer {
+        handle: vk::Buffer,
+        memory: vk::DeviceMemory,
+    },
+    Image {
+        image: Image,
+        offset: Offset,
+    },
+    #[cfg(not(target_vendor = "apple"))]
+    AccelerationStructure(AccelerationStructure),
+}
+
+/// Everything that has to wait for a frame in flight to finish.
+#[derive(Default)]
+struct FrameResources {
+    transfer_tokens: Vec<TransferToken>,
+    pending_frees: Vec<PendingFree>,
+    readbacks: Vec<RecordedReadback>,
+}
+
+/// A copy into the readback buffer that hasn't been recorded yet.
+struct PendingReadback {
+    source: ReadbackSource,
+    /// Where the copy lands in the readback buffer.
+    offset: usize,
+    size: vk::DeviceSize,
+    result: ReadbackResult,
+}
+
+enum ReadbackSource {
+    Buffer {
+        handle: vk::Buffer,
+        offset: vk::DeviceSize,
+    },
+    Image {
+        image: vk::Image,
+        extent: vk::Extent2D,
+        layout: vk::ImageLayout,
+        aspect_mask: vk::ImageAspectFlags,
+    },
+}
+
+/// A readback that's been recorded, and can be read once its submission has finished.
+#[derive(Clone)]
+pub(crate) struct RecordedReadback {
+    offset: usize,
+    size: usize,
+    result: ReadbackResult,
+}
+
 pub struct BufferAllocation<T> {
     #[allow(unused)]
     pub size: vk::DeviceSize,
@@ -427,6 +1293,20 @@ pub struct BufferAllocation<T> {
     _phantom: PhantomData<T>,
 }
 
+impl<T> BufferAllocation<T> {
+    /// The same buffer, forgetting what type it holds.
+    fn untyped(self) -> BufferAllocation<u8> {
+        BufferAllocation {
+            size: self.size,
+            device_address: self.device_address,
+            handle: self.handle,
+            len: self.len,
+            global_offset: self.global_offset,
+            _phantom: PhantomData,
+        }
+    }
+}
+
 impl<T> BufferAllocation<T>
 where
     T: Copy,
@@ -445,8 +1325,9 @@ where
         self.len = 0;
     }
 
-    pub unsafe fn append_unsafe(&mut self, data: &[T], allocator: &mut Allocator) {
-        allocator.append_unsafe(data, self);
+    pub unsafe fn append_unsafe(&mut self, data: &[T], allocator: &mut Allocator) -> Result<()> {
+        allocator.append_unsafe(data, self)?;
+        Ok(())
     }
 
     pub fn tip_address(&self) -> vk::DeviceAddress {
@@ -458,195 +1339,87 @@ impl<T> BufferAllocation<T>
 where
     T: bytemuck::Pod,
 {
-    pub fn append(&mut self, data: &[T], allocator: &mut Allocator) {
-        allocator.append_to_buffer(data, self);
+    pub fn append(&mut self, data: &[T], allocator: &mut Allocator) -> Result<()> {
+        allocator.append_to_buffer(data, self)?;
+        Ok(())
     }
 
-    pub fn append_one(&mut self, data: &T, allocator: &mut Allocator) {
-        allocator.append_to_buffer(std::slice::from_ref(data), self);
+    pub fn append_one(&mut self, data: &T, allocator: &mut Allocator) -> Result<()> {
+        allocator.append_to_buffer(std::slice::from_ref(data), self)?;
+        Ok(())
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::{allocator::STAGING_MEMORY_SIZE, Context, Core, LazyVulkan};
+    use super::AllocatorConfig;
+    use crate::{Context, ContextBuilder, Core, LazyVulkan};
     use ash::vk;
-    use std::{sync::Arc, u64};
+    use std::{collections::HashSet, sync::Arc, u64};
 
     #[test]
     fn test_allocate_single_buffer_roundtrip() {
-        let mut lazy_vulkan = get_vulkan();
+        let mut lazy_vulkan = get_vulkan(Default::default());
 
         let context = &lazy_vulkan.context;
-        let device = &context.device;
         let allocator = &mut lazy_vulkan.renderer.allocator;
 
-        let command_buffer = context.draw_command_buffer;
-        unsafe {
-            device.begin_command_buffer(
-                command_buffer,
-                &vk::CommandBufferBeginInfo::default()
-                    .flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT),
-            )
-        }
-        .unwrap();
-
-        let mut buffer_a = allocator.allocate_buffer(1024, vk::BufferUsageFlags::TRANSFER_SRC);
+        let mut buffer_a = allocator
+            .allocate_buffer(1024, vk::BufferUsageFlags::TRANSFER_SRC)
+            .unwrap();
         let data_a: [u8; 4] = [1, 2, 3, 4];
-        buffer_a.append(&data_a, allocator);
-        allocator.execute_transfers(command_buffer);
-        // Barrier
-        unsafe {
-            context.cmd_pipeline_barrier2(
-                command_buffer,
-                &vk::DependencyInfo::default().buffer_memory_barriers(&[
-                    vk::BufferMemoryBarrier2::default()
-                        .buffer(buffer_a.handle)
-                        .size(data_a.len() as _)
-                        .src_access_mask(vk::AccessFlags2::TRANSFER_WRITE)
-                        .src_stage_mask(vk::PipelineStageFlags2::TRANSFER)
-                        .dst_access_mask(vk::AccessFlags2::TRANSFER_READ)
-                        .dst_stage_mask(vk::PipelineStageFlags2::COPY),
-                ]),
-            )
-        };
+        buffer_a.append(&data_a, allocator).unwrap();
 
-        let readback = create_readback_buffer(context);
-        unsafe {
-            device.cmd_copy_buffer(
-                command_buffer,
-                buffer_a.handle,
-                readback.handle,
-                &[vk::BufferCopy::default().size(data_a.len() as u64)],
-            );
-        }
+        let readback = allocator.read_buffer(&buffer_a, 0..data_a.len()).unwrap();
+        context.immediate_submit(allocator, |_| {}).unwrap();
+        assert_no_validation_errors(&lazy_vulkan.core);
 
-        // Submit and wait
-        submit_and_wait(context, command_buffer);
-        allocator.transfers_complete();
-
-        let readback_data =
-            unsafe { std::slice::from_raw_parts(readback.ptr.as_ptr(), data_a.len()) };
-
-        assert_eq!(&data_a, readback_data);
+        assert_eq!(readback.take().unwrap(), data_a);
     }
 
     #[test]
     fn test_allocate_multiple_buffers_roundtrip() {
-        let mut lazy_vulkan = get_vulkan();
+        let mut lazy_vulkan = get_vulkan(Default::default());
 
         let context = &lazy_vulkan.context;
-        let device = &context.device;
         let allocator = &mut lazy_vulkan.renderer.allocator;
 
-        let command_buffer = context.draw_command_buffer;
-        unsafe {
-            device.begin_command_buffer(
-                command_buffer,
-                &vk::CommandBufferBeginInfo::default()
-                    .flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT),
-            )
-        }
-        .unwrap();
-
-        let mut buffer_a = allocator.allocate_buffer(1024, vk::BufferUsageFlags::TRANSFER_SRC);
+        let mut buffer_a = allocator
+            .allocate_buffer(1024, vk::BufferUsageFlags::TRANSFER_SRC)
+            .unwrap();
         let data_a: [u8; 4] = [1, 2, 3, 4];
-        buffer_a.append(&data_a, allocator);
+        let token_a = allocator.append_to_buffer(&data_a, &mut buffer_a).unwrap();
 
-        allocator.execute_transfers(command_buffer);
-        // Barrier
-        unsafe {
-            context.cmd_pipeline_barrier2(
-                command_buffer,
-                &vk::DependencyInfo::default().buffer_memory_barriers(&[
-                    vk::BufferMemoryBarrier2::default()
-                        .buffer(buffer_a.handle)
-                        .size(data_a.len() as _)
-                        .src_access_mask(vk::AccessFlags2::TRANSFER_WRITE)
-                        .src_stage_mask(vk::PipelineStageFlags2::TRANSFER)
-                        .dst_access_mask(
-                            vk::AccessFlags2::TRANSFER_READ | vk::AccessFlags2::TRANSFER_WRITE,
-                        )
-                        .dst_stage_mask(vk::PipelineStageFlags2::COPY),
-                ]),
-            )
-        };
+        context.immediate_submit(allocator, |_| {}).unwrap();
+        assert!(token_a.is_complete());
 
-        let mut buffer_b = allocator.allocate_buffer(1024, vk::BufferUsageFlags::TRANSFER_SRC);
+        let mut buffer_b = allocator
+            .allocate_buffer(1024, vk::BufferUsageFlags::TRANSFER_SRC)
+            .unwrap();
         let data_b: [u8; 4] = [5, 6, 7, 8];
-        buffer_b.append(&data_b, allocator);
-
-        allocator.execute_transfers(command_buffer);
-        // Barrier
-        unsafe {
-            context.cmd_pipeline_barrier2(
-                command_buffer,
-                &vk::DependencyInfo::default().buffer_memory_barriers(&[
-                    vk::BufferMemoryBarrier2::default()
-                        .buffer(buffer_b.handle)
-                        .size(data_b.len() as _)
-                        .src_access_mask(vk::AccessFlags2::TRANSFER_WRITE)
-                        .src_stage_mask(vk::PipelineStageFlags2::TRANSFER)
-                        .dst_access_mask(
-                            vk::AccessFlags2::TRANSFER_READ | vk::AccessFlags2::TRANSFER_WRITE,
-                        )
-                        .dst_stage_mask(vk::PipelineStageFlags2::COPY),
-                ]),
-            )
-        };
-
-        let readback = create_readback_buffer(context);
-        unsafe {
-            device.cmd_copy_buffer(
-                command_buffer,
-                buffer_a.handle,
-                readback.handle,
-                &[vk::BufferCopy::default().size(data_a.len() as u64)],
-            );
-            device.cmd_copy_buffer(
-                command_buffer,
-                buffer_b.handle,
-                readback.handle,
-                &[vk::BufferCopy::default()
-                    .dst_offset(data_a.len() as u64) // IMPORTANT! Offset by how much transferrred so far
-                    .size(data_b.len() as u64)],
-            );
-        }
-
-        // Submit and wait
-        submit_and_wait(context, command_buffer);
-        allocator.transfers_complete();
+        buffer_b.append(&data_b, allocator).unwrap();
 
-        let readback_data = unsafe { std::slice::from_raw_parts(readback.ptr.as_ptr(), 1024) };
+        let readback_a = allocator.read_buffer(&buffer_a, 0..data_a.len()).unwrap();
+        let readback_b = allocator.read_buffer(&buffer_b, 0..data_b.len()).unwrap();
+        context.immediate_submit(allocator, |_| {}).unwrap();
+        assert_no_validation_errors(&lazy_vulkan.core);
 
-        assert_eq!(&data_a, &readback_data[..data_a.len()]);
-        assert_eq!(
-            &data_b,
-            &readback_data[data_a.len()..data_a.len() + data_b.len()]
-        );
+        assert_eq!(readback_a.take().unwrap(), data_a);
+        assert_eq!(readback_b.take().unwrap(), data_b);
     }
 
     #[test]
     fn test_append_buffer() {
-        let mut lazy_vulkan = get_vulkan();
+        let mut lazy_vulkan = get_vulkan(Default::default());
 
         let context = &lazy_vulkan.context;
-        let device = &context.device;
         let allocator = &mut lazy_vulkan.renderer.allocator;
 
-        let command_buffer = context.draw_command_buffer;
-        unsafe {
-            device.begin_command_buffer(
-                command_buffer,
-                &vk::CommandBufferBeginInfo::default()
-                    .flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT),
-            )
-        }
-        .unwrap();
-
-        let mut buffer_a = allocator.allocate_buffer(1024, vk::BufferUsageFlags::TRANSFER_SRC);
+        let mut buffer_a = allocator
+            .allocate_buffer(1024, vk::BufferUsageFlags::TRANSFER_SRC)
+            .unwrap();
         let data_a: [u64; 4] = [1, 2, 3, 4];
-        buffer_a.append(&data_a, allocator);
+        buffer_a.append(&data_a, allocator).unwrap();
         assert_eq!(buffer_a.len(), data_a.len());
         assert_eq!(
             buffer_a.current_size() as usize,
@@ -654,66 +1427,92 @@ mod tests {
         );
 
         let data_b: [u64; 4] = [5, 6, 7, 8];
-        buffer_a.append(&data_b, allocator);
+        buffer_a.append(&data_b, allocator).unwrap();
         assert_eq!(buffer_a.len(), data_a.len() + data_b.len());
         assert_eq!(
             buffer_a.current_size() as usize,
             std::mem::size_of_val(&data_a) + std::mem::size_of_val(&data_b)
         );
 
-        allocator.execute_transfers(command_buffer);
-        let total_size = std::mem::size_of_val(&data_a) + std::mem::size_of_val(&data_b);
+        let readback = allocator.read_buffer(&buffer_a, 0..buffer_a.len()).unwrap();
+        context.immediate_submit(allocator, |_| {}).unwrap();
+        assert_no_validation_errors(&lazy_vulkan.core);
 
-        // Barrier
-        unsafe {
-            context.cmd_pipeline_barrier2(
-                command_buffer,
-                &vk::DependencyInfo::default().buffer_memory_barriers(&[
-                    vk::BufferMemoryBarrier2::default()
-                        .buffer(buffer_a.handle)
-                        .size(total_size as u64)
-                        .src_access_mask(vk::AccessFlags2::TRANSFER_WRITE)
-                        .src_stage_mask(vk::PipelineStageFlags2::TRANSFER)
-                        .dst_access_mask(vk::AccessFlags2::TRANSFER_READ)
-                        .dst_stage_mask(vk::PipelineStageFlags2::COPY),
-                ]),
-            )
-        };
+        assert_eq!(readback.take().unwrap(), [data_a, data_b].concat());
+    }
 
-        let readback = create_readback_buffer(context);
-        unsafe {
-            device.cmd_copy_buffer(
-                command_buffer,
-                buffer_a.handle,
-                readback.handle,
-                &[vk::BufferCopy::default().size(total_size as _)],
-            );
-        }
+    #[test]
+    fn test_append_buffer_unsafe() {
+        let mut lazy_vulkan = get_vulkan(Default::default());
+
+        let context = &lazy_vulkan.context;
+        let allocator = &mut lazy_vulkan.renderer.allocator;
 
-        // Submit and wait
-        submit_and_wait(context, command_buffer);
-        allocator.transfers_complete();
+        let mut buffer_a = allocator
+            .allocate_buffer(1024, vk::BufferUsageFlags::TRANSFER_SRC)
+            .unwrap();
+        let data_a: [u64; 4] = [1, 2, 3, 4];
+        unsafe { buffer_a.append_unsafe(&data_a, allocator).unwrap() };
 
-        let readback_data = unsafe {
-            std::slice::from_raw_parts(readback.ptr.as_ptr().cast(), data_a.len() + data_b.len())
-        };
+        let data_b: [u64; 4] = [5, 6, 7, 8];
+        unsafe { buffer_a.append_unsafe(&data_b, allocator).unwrap() };
 
-        assert_eq!(&data_a, &readback_data[..data_a.len()]);
-        assert_eq!(
-            &data_b,
-            &readback_data[data_a.len()..data_a.len() + data_b.len()]
-        );
+        let readback = allocator.read_buffer(&buffer_a, 0..buffer_a.len()).unwrap();
+        context.immediate_submit(allocator, |_| {}).unwrap();
+        assert_no_validation_errors(&lazy_vulkan.core);
+
+        assert_eq!(readback.take().unwrap(), [data_a, data_b].concat());
     }
 
     #[test]
-    fn test_append_buffer_unsafe() {
-        let mut lazy_vulkan = get_vulkan();
+    fn test_alignment() {
+        let mut lazy_vulkan = get_vulkan(Default::default());
+
+        let context = &lazy_vulkan.context;
+        let allocator = &mut lazy_vulkan.renderer.allocator;
+
+        let mut buffer_a = allocator
+            .allocate_buffer(32, vk::BufferUsageFlags::TRANSFER_SRC)
+            .unwrap();
+        let data_a: [u8; 4] = [1, 2, 3, 4];
+        buffer_a.append(&data_a, allocator).unwrap();
+
+        let mut buffer_b = allocator
+            .allocate_buffer_with_alignment(1024, 64, vk::BufferUsageFlags::TRANSFER_SRC)
+            .unwrap();
+        let data_b: [u8; 4] = [5, 6, 7, 8];
+        buffer_b.append(&data_b, allocator).unwrap();
+
+        let readback_a = allocator.read_buffer(&buffer_a, 0..data_a.len()).unwrap();
+        let readback_b = allocator.read_buffer(&buffer_b, 0..data_b.len()).unwrap();
+        context.immediate_submit(allocator, |_| {}).unwrap();
+        assert_no_validation_errors(&lazy_vulkan.core);
+
+        assert_eq!(readback_a.take().unwrap(), data_a);
+        assert_eq!(readback_b.take().unwrap(), data_b);
+    }
+
+    #[test]
+    fn test_frames_in_flight() {
+        let mut lazy_vulkan = get_vulkan(Default::default());
 
         let context = &lazy_vulkan.context;
         let device = &context.device;
         let allocator = &mut lazy_vulkan.renderer.allocator;
+        let frames_in_flight = context.frames_in_flight as u64;
+
+        // Stage some data after frame 0 has been recorded..
+        allocator.begin_frame(0);
+        let mut buffer_a = allocator
+            .allocate_buffer(1024, vk::BufferUsageFlags::TRANSFER_SRC)
+            .unwrap();
+        let data_a: [u8; 4] = [1, 2, 3, 4];
+        let token = allocator.append_to_buffer(&data_a, &mut buffer_a).unwrap();
 
-        let command_buffer = context.draw_command_buffer;
+        // ..and make sure starting frame 1 doesn't throw it away before it's executed.
+        allocator.begin_frame(1);
+        context.set_frame_index(1 % frames_in_flight as usize);
+        let command_buffer = context.draw_command_buffer();
         unsafe {
             device.begin_command_buffer(
                 command_buffer,
@@ -723,146 +1522,379 @@ mod tests {
         }
         .unwrap();
 
-        let mut buffer_a = allocator.allocate_buffer(1024, vk::BufferUsageFlags::TRANSFER_SRC);
-        let data_a: [u64; 4] = [1, 2, 3, 4];
-        unsafe { buffer_a.append_unsafe(&data_a, allocator) };
-
-        let data_b: [u64; 4] = [5, 6, 7, 8];
-        unsafe { buffer_a.append_unsafe(&data_b, allocator) };
-
-        allocator.execute_transfers(command_buffer);
-        let total_size = std::mem::size_of_val(&data_a) + std::mem::size_of_val(&data_b);
+        allocator.execute_transfers(command_buffer).unwrap();
+        let readback = allocator.read_buffer(&buffer_a, 0..data_a.len()).unwrap();
+        allocator.record_readbacks(command_buffer);
 
-        // Barrier
-        unsafe {
-            context.cmd_pipeline_barrier2(
-                command_buffer,
-                &vk::DependencyInfo::default().buffer_memory_barriers(&[
-                    vk::BufferMemoryBarrier2::default()
-                        .buffer(buffer_a.handle)
-                        .size(total_size as u64)
-                        .src_access_mask(vk::AccessFlags2::TRANSFER_WRITE)
-                        .src_stage_mask(vk::PipelineStageFlags2::TRANSFER)
-                        .dst_access_mask(vk::AccessFlags2::TRANSFER_READ)
-                        .dst_stage_mask(vk::PipelineStageFlags2::COPY),
-                ]),
-            )
-        };
+        submit_and_wait(context, command_buffer, allocator.take_transfer_wait());
 
-        let readback = create_readback_buffer(context);
-        unsafe {
-            device.cmd_copy_buffer(
-                command_buffer,
-                buffer_a.handle,
-                readback.handle,
-                &[vk::BufferCopy::default().size(total_size as _)],
-            );
+        // The transfer and readback belong to frame 1, so they're complete once that slot comes
+        // around again.
+        for frame in 2..=frames_in_flight + 1 {
+            allocator.begin_frame(frame);
         }
+        assert!(token.is_complete());
+        assert_no_validation_errors(&lazy_vulkan.core);
+
+        assert_eq!(readback.take().unwrap(), data_a);
+    }
 
-        // Submit and wait
-        submit_and_wait(context, command_buffer);
-        allocator.transfers_complete();
+    #[test]
+    fn test_free_after_frame() {
+        use super::Allocator;
 
-        let readback_data = unsafe {
-            std::slice::from_raw_parts(readback.ptr.as_ptr().cast(), data_a.len() + data_b.len())
-        };
+        let mut lazy_vulkan = get_vulkan(Default::default());
+        let frames_in_flight = lazy_vulkan.context.frames_in_flight as u64;
+        let allocator = &mut lazy_vulkan.renderer.allocator;
+        let free_space = |allocator: &Allocator| allocator.memory_usage().free;
+
+        allocator.begin_frame(0);
+        let before = free_space(allocator);
+        let mut buffer = allocator
+            .allocate_buffer(1024, vk::BufferUsageFlags::STORAGE_BUFFER)
+            .unwrap();
+        let token = allocator
+            .append_to_buffer(&[1u8, 2, 3, 4], &mut buffer)
+            .unwrap();
+        let upload = allocator.upload_to_slab(&[1u32, 2, 3, 4]).unwrap();
+
+        // Freeing drops any transfers that haven't been executed..
+        allocator.free(buffer);
+        allocator.free_from_slab(upload);
+        assert!(allocator.pending_transfers.is_empty());
+        assert!(token.is_complete());
+
+        // ..but the memory isn't released until the GPU is done with frame 0.
+        for frame in 1..frames_in_flight {
+            allocator.begin_frame(frame);
+            assert!(free_space(allocator) < before);
+        }
+        allocator.begin_frame(frames_in_flight);
+        assert_eq!(free_space(allocator), before);
+        assert_no_validation_errors(&lazy_vulkan.core);
+    }
 
-        assert_eq!(&data_a, &readback_data[..data_a.len()]);
+    #[test]
+    fn test_heap_growth() {
+        use super::Allocator;
+        use crate::Error;
+
+        const BLOCK_SIZE: u64 = 1 << 20;
+        let lazy_vulkan = get_vulkan(AllocatorConfig {
+            block_size: BLOCK_SIZE,
+            max_device_memory: Some(3 * BLOCK_SIZE),
+            staging_size: BLOCK_SIZE,
+            ..Default::default()
+        });
+        // A fresh allocator, so the renderer's allocations don't get in the way.
+        let mut allocator = Allocator::new(lazy_vulkan.context.clone()).unwrap();
+
+        // Nothing is allocated until it's needed.
+        assert_eq!(allocator.memory_usage().blocks, 0);
+        let first = allocator
+            .allocate_buffer::<u8>(
+                BLOCK_SIZE as usize / 2,
+                vk::BufferUsageFlags::STORAGE_BUFFER,
+            )
+            .unwrap();
+        let upload = allocator.upload_to_slab(&[1u32, 2, 3, 4]).unwrap();
+        assert_eq!(allocator.memory_usage().blocks, 1);
+
+        // This doesn't fit in the first block, so we get another one..
+        let second = allocator
+            .allocate_buffer::<u8>(
+                BLOCK_SIZE as usize / 2 + 1,
+                vk::BufferUsageFlags::STORAGE_BUFFER,
+            )
+            .unwrap();
+        let usage = allocator.memory_usage();
+        assert_eq!(usage.blocks, 2);
+        assert_eq!(usage.allocated, 2 * BLOCK_SIZE);
         assert_eq!(
-            &data_b,
-            &readback_data[data_a.len()..data_a.len() + data_b.len()]
+            allocator.backend.get_device_address(upload.offset),
+            upload.device_address
+        );
+        assert_ne!(first.device_address, second.device_address);
+
+        // ..but not one that would take us over the limit.
+        let too_big = allocator.allocate_buffer::<u8>(
+            BLOCK_SIZE as usize * 2,
+            vk::BufferUsageFlags::STORAGE_BUFFER,
         );
+        assert!(matches!(too_big, Err(Error::OutOfDeviceMemory { .. })));
+        assert_eq!(allocator.memory_usage().blocks, 2);
+
+        drop(allocator);
+        assert_no_validation_errors(&lazy_vulkan.core);
     }
 
     #[test]
-    fn test_alignment() {
-        let mut lazy_vulkan = get_vulkan();
-
-        let context = &lazy_vulkan.context;
-        let device = &context.device;
+    fn test_deferred_upload() {
+        const STAGING_SIZE: u64 = 64 << 10;
+        let mut lazy_vulkan = get_vulkan(AllocatorConfig {
+            staging_size: STAGING_SIZE,
+            ..Default::default()
+        });
+        let context = lazy_vulkan.context.clone();
         let allocator = &mut lazy_vulkan.renderer.allocator;
 
-        let command_buffer = context.draw_command_buffer;
-        unsafe {
-            device.begin_command_buffer(
-                command_buffer,
-                &vk::CommandBufferBeginInfo::default()
-                    .flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT),
-            )
+        // Four times the size of the staging buffer..
+        let data = (0..STAGING_SIZE as u32).collect::<Vec<_>>();
+        let mut buffer = allocator
+            .allocate_buffer::<u32>(data.len(), vk::BufferUsageFlags::TRANSFER_SRC)
+            .unwrap();
+        let token = allocator.append_to_buffer(&data, &mut buffer).unwrap();
+
+        // ..is spread out over several frames.
+        let mut frame = 0;
+        while !token.is_complete() {
+            assert!(frame < 32, "Upload never completed");
+            allocator.begin_frame(frame);
+            context.immediate_submit(allocator, |_| {}).unwrap();
+            frame += 1;
         }
-        .unwrap();
+        assert!(frame > 1);
 
-        let mut buffer_a = allocator.allocate_buffer(32, vk::BufferUsageFlags::TRANSFER_SRC);
-        let data_a: [u8; 4] = [1, 2, 3, 4];
-        buffer_a.append(&data_a, allocator);
+        let readback = allocator.read_buffer(&buffer, 0..data.len()).unwrap();
+        context.immediate_submit(allocator, |_| {}).unwrap();
+        assert_no_validation_errors(&lazy_vulkan.core);
 
-        let mut buffer_b =
-            allocator.allocate_buffer_with_alignment(1024, 64, vk::BufferUsageFlags::TRANSFER_SRC);
-        let data_b: [u8; 4] = [5, 6, 7, 8];
-        buffer_b.append(&data_b, allocator);
+        assert_eq!(readback.take().unwrap(), data);
+    }
 
-        allocator.execute_transfers(command_buffer);
-        // Barrier
-        unsafe {
-            context.cmd_pipeline_barrier2(
-                command_buffer,
-                &vk::DependencyInfo::default().buffer_memory_barriers(&[
-                    vk::BufferMemoryBarrier2::default()
-                        .buffer(buffer_a.handle)
-                        .size(data_a.len() as _)
-                        .src_access_mask(vk::AccessFlags2::TRANSFER_WRITE)
-                        .src_stage_mask(vk::PipelineStageFlags2::TRANSFER)
-                        .dst_access_mask(
-                            vk::AccessFlags2::TRANSFER_READ | vk::AccessFlags2::TRANSFER_WRITE,
-                        )
-                        .dst_stage_mask(vk::PipelineStageFlags2::COPY),
-                    vk::BufferMemoryBarrier2::default()
-                        .buffer(buffer_b.handle)
-                        .size(data_b.len() as _)
-                        .src_access_mask(vk::AccessFlags2::TRANSFER_WRITE)
-                        .src_stage_mask(vk::PipelineStageFlags2::TRANSFER)
-                        .dst_access_mask(
-                            vk::AccessFlags2::TRANSFER_READ | vk::AccessFlags2::TRANSFER_WRITE,
-                        )
-                        .dst_stage_mask(vk::PipelineStageFlags2::COPY),
-                ]),
-            )
+    #[test]
+    fn test_read_buffer() {
+        let mut lazy_vulkan = get_vulkan(Default::default());
+        let context = lazy_vulkan.context.clone();
+        let allocator = &mut lazy_vulkan.renderer.allocator;
+
+        let data = (0..1024u32).collect::<Vec<_>>();
+        let mut buffer = allocator
+            .allocate_buffer::<u32>(data.len(), vk::BufferUsageFlags::TRANSFER_SRC)
+            .unwrap();
+        allocator.append_to_buffer(&data, &mut buffer).unwrap();
+
+        let all = allocator.read_buffer(&buffer, 0..data.len()).unwrap();
+        let some = allocator.read_buffer(&buffer, 100..200).unwrap();
+        assert!(!all.is_complete());
+
+        context.immediate_submit(allocator, |_| {}).unwrap();
+        assert_no_validation_errors(&lazy_vulkan.core);
+
+        assert_eq!(all.take().unwrap(), data);
+        assert_eq!(some.take().unwrap(), &data[100..200]);
+        assert!(all.take().is_none());
+    }
+
+    #[test]
+    fn test_read_image() {
+        let mut lazy_vulkan = get_vulkan(Default::default());
+        let context = lazy_vulkan.context.clone();
+        let renderer = &mut lazy_vulkan.renderer;
+
+        let extent = vk::Extent2D {
+            width: 64,
+            height: 32,
         };
+        let texels = (0..extent.width * extent.height)
+            .map(|i| [i as u8, (i >> 8) as u8, 0, 255])
+            .collect::<Vec<_>>();
+        let image = renderer
+            .create_image(
+                "Readback",
+                vk::Format::R8G8B8A8_UNORM,
+                extent,
+                bytemuck::cast_slice::<_, u8>(&texels),
+                vk::ImageUsageFlags::SAMPLED,
+            )
+            .unwrap();
+
+        let token = renderer
+            .allocator
+            .read_image::<[u8; 4]>(
+                image.handle,
+                extent,
+                vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+                vk::ImageAspectFlags::COLOR,
+            )
+            .unwrap();
+        context
+            .immediate_submit(&mut renderer.allocator, |_| {})
+            .unwrap();
+        assert_no_validation_errors(&lazy_vulkan.core);
+
+        assert!(image.transfer_complete.is_complete());
+        assert_eq!(token.take().unwrap(), texels);
+        renderer.destroy_image(image.handle);
+    }
 
-        let readback = create_readback_buffer(context);
-        unsafe {
-            device.cmd_copy_buffer(
-                command_buffer,
-                buffer_a.handle,
-                readback.handle,
-                &[vk::BufferCopy::default().size(data_a.len() as u64)],
-            );
-            device.cmd_copy_buffer(
-                command_buffer,
-                buffer_b.handle,
-                readback.handle,
-                &[vk::BufferCopy::default()
-                    .dst_offset(data_a.len() as u64) // IMPORTANT! Offset by how much transferrred so far
-                    .size(data_b.len() as u64)],
-            );
+    #[test]
+    fn test_read_freed_buffer() {
+        let mut lazy_vulkan = get_vulkan(Default::default());
+        let context = lazy_vulkan.context.clone();
+        let allocator = &mut lazy_vulkan.renderer.allocator;
+
+        let buffer = allocator
+            .allocate_buffer::<u32>(256, vk::BufferUsageFlags::TRANSFER_SRC)
+            .unwrap();
+        let token = allocator.read_buffer(&buffer, 0..256).unwrap();
+
+        // The copy was never recorded, so there's nothing to read.
+        allocator.free(buffer);
+        assert!(token.is_complete());
+        assert!(token.take().is_none());
+
+        context.immediate_submit(allocator, |_| {}).unwrap();
+        assert_no_validation_errors(&lazy_vulkan.core);
+    }
+
+    #[test]
+    fn test_mapped_buffer() {
+        let mut lazy_vulkan = get_vulkan(Default::default());
+        let context = lazy_vulkan.context.clone();
+        let allocator = &mut lazy_vulkan.renderer.allocator;
+
+        let mut buffer = allocator
+            .allocate_mapped_buffer::<u32>(256, vk::BufferUsageFlags::TRANSFER_SRC)
+            .unwrap();
+        assert_eq!(buffer.len(), 256);
+
+        // Each frame in flight gets its own copy..
+        let mut addresses = HashSet::new();
+        for frame_index in 0..context.frames_in_flight {
+            context.set_frame_index(frame_index);
+            buffer.slice_mut(&context).fill(frame_index as u32);
+            addresses.insert(buffer.device_address(&context));
         }
+        assert_eq!(addresses.len(), context.frames_in_flight);
+
+        // ..which the GPU sees without any transfers.
+        let readback = allocator
+            .allocate_buffer::<u32>(buffer.len(), vk::BufferUsageFlags::TRANSFER_SRC)
+            .unwrap();
+        let size = (buffer.len() * std::mem::size_of::<u32>()) as vk::DeviceSize;
+        let last_frame_index = context.frames_in_flight - 1;
+        context.set_frame_index(last_frame_index);
+        let token = allocator.read_buffer(&readback, 0..buffer.len()).unwrap();
+        context
+            .immediate_submit(allocator, |command_buffer| unsafe {
+                context.device.cmd_copy_buffer(
+                    command_buffer,
+                    buffer.handle,
+                    readback.handle,
+                    &[vk::BufferCopy::default()
+                        .src_offset(buffer.offset(&context))
+                        .size(size)],
+                );
+            })
+            .unwrap();
+        assert_no_validation_errors(&lazy_vulkan.core);
+        assert_eq!(
+            token.take().unwrap(),
+            vec![last_frame_index as u32; buffer.len()]
+        );
 
-        // Submit and wait
-        submit_and_wait(context, command_buffer);
-        allocator.transfers_complete();
+        allocator.free_mapped_buffer(buffer);
+        assert!(allocator.mapped_buffers.is_empty());
+    }
 
-        let readback_data = unsafe { std::slice::from_raw_parts(readback.ptr.as_ptr(), 1024) };
+    #[test]
+    fn test_memory_strategy() {
+        use super::device_buffer::MemoryStrategy;
+
+        fn memory_properties(
+            heaps: &[(u64, vk::MemoryHeapFlags)],
+            types: &[(u32, vk::MemoryPropertyFlags)],
+        ) -> vk::PhysicalDeviceMemoryProperties {
+            let mut properties = vk::PhysicalDeviceMemoryProperties {
+                memory_heap_count: heaps.len() as u32,
+                memory_type_count: types.len() as u32,
+                ..Default::default()
+            };
+            for (i, (size, flags)) in heaps.iter().enumerate() {
+                properties.memory_heaps[i] = vk::MemoryHeap {
+                    size: *size,
+                    flags: *flags,
+                };
+            }
+            for (i, (heap_index, property_flags)) in types.iter().enumerate() {
+                properties.memory_types[i] = vk::MemoryType {
+                    heap_index: *heap_index,
+                    property_flags: *property_flags,
+                };
+            }
+            properties
+        }
 
-        assert_eq!(&data_a, &readback_data[..data_a.len()]);
+        let device_local = vk::MemoryPropertyFlags::DEVICE_LOCAL;
+        let host_visible =
+            vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_COHERENT;
+
+        // Discrete GPU with a small BAR window: should NOT pick the BAR.
+        let discrete = memory_properties(
+            &[
+                (8 << 30, vk::MemoryHeapFlags::DEVICE_LOCAL),
+                (16 << 30, vk::MemoryHeapFlags::empty()),
+                (256 << 20, vk::MemoryHeapFlags::DEVICE_LOCAL),
+            ],
+            &[
+                (0, device_local),
+                (1, host_visible),
+                (2, device_local | host_visible),
+            ],
+        );
         assert_eq!(
-            &data_b,
-            &readback_data[data_a.len()..data_a.len() + data_b.len()]
+            MemoryStrategy::new(&discrete).unwrap(),
+            MemoryStrategy::Discrete(0)
+        );
+
+        // Integrated GPU / lavapipe: one big heap that's both device local and host visible.
+        let unified = memory_properties(
+            &[(16 << 30, vk::MemoryHeapFlags::DEVICE_LOCAL)],
+            &[(0, device_local), (0, device_local | host_visible)],
+        );
+        assert_eq!(
+            MemoryStrategy::new(&unified).unwrap(),
+            MemoryStrategy::Unified(1)
+        );
+
+        // Virtual GPU with no device local heap at all.
+        let virtual_gpu = memory_properties(
+            &[(4 << 30, vk::MemoryHeapFlags::empty())],
+            &[(0, host_visible)],
+        );
+        assert_eq!(
+            MemoryStrategy::new(&virtual_gpu).unwrap(),
+            MemoryStrategy::Unified(0)
         );
     }
 
-    fn get_vulkan() -> LazyVulkan<()> {
-        let core = Arc::new(Core::headless());
-        let context = Arc::new(Context::new_headless(&core));
+    /// Check for validation errors, if the validation layer is installed. It isn't on every CI
+    /// machine, and the tests are still worth running without it.
+    fn assert_no_validation_errors(core: &Core) {
+        if !(core.validation_enabled && core.debug_utils_enabled) {
+            log::warn!(
+                "[lazy_vulkan] Validation isn't enabled, so validation errors can't be checked"
+            );
+            return;
+        }
+        core.assert_no_validation_errors();
+    }
+
+    fn get_vulkan(allocator_config: AllocatorConfig) -> LazyVulkan<()> {
+        let core = Arc::new(
+            Core::builder()
+                .validation(true)
+                .fail_on_validation_error(true)
+                .build_headless()
+                .unwrap(),
+        );
+        let context = Arc::new(
+            ContextBuilder::default()
+                .allocator(allocator_config)
+                .build(&core)
+                .unwrap(),
+        );
         LazyVulkan::headless(
             core,
             context,
@@ -872,9 +1904,14 @@ mod tests {
             },
             vk::Format::R8G8B8A8_UNORM,
         )
+        .unwrap()
     }
 
-    fn submit_and_wait(context: &Context, command_buffer: vk::CommandBuffer) {
+    fn submit_and_wait(
+        context: &Context,
+        command_buffer: vk::CommandBuffer,
+        transfer_wait: Option<vk::SemaphoreSubmitInfo>,
+    ) {
         let device = &context.device;
         unsafe {
             device.end_command_buffer(command_buffer).unwrap();
@@ -882,80 +1919,18 @@ mod tests {
                 .create_fence(&vk::FenceCreateInfo::default(), None)
                 .unwrap();
             device
-                .queue_submit(
+                .queue_submit2(
                     context.graphics_queue,
-                    &[vk::SubmitInfo::default().command_buffers(&[command_buffer])],
+                    &[vk::SubmitInfo2::default()
+                        .command_buffer_infos(&[
+                            vk::CommandBufferSubmitInfo::default().command_buffer(command_buffer)
+                        ])
+                        .wait_semaphore_infos(transfer_wait.as_slice())],
                     fence,
                 )
                 .unwrap();
             device.wait_for_fences(&[fence], true, u64::MAX).unwrap();
-        }
-    }
-
-    struct ReadbackBuffer {
-        handle: vk::Buffer,
-        #[allow(unused)]
-        memory: vk::DeviceMemory,
-        ptr: std::ptr::NonNull<u8>,
-    }
-
-    fn create_readback_buffer(context: &Context) -> ReadbackBuffer {
-        let device = &context.device;
-        let memory_properties = &context.memory_properties;
-
-        // Search through the available memory types to find the one we want
-        let mut memory_type_index = None;
-        for (index, memory_type) in memory_properties.memory_types_as_slice().iter().enumerate() {
-            if memory_type.property_flags.contains(
-                vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_COHERENT,
-            ) {
-                memory_type_index = Some(index as u32);
-                break;
-            }
-        }
-
-        let memory_type_index = memory_type_index.expect("No global memory? Impossible");
-
-        // Allocate our readback memory
-        let memory = unsafe {
-            device.allocate_memory(
-                &vk::MemoryAllocateInfo::default()
-                    .memory_type_index(memory_type_index)
-                    .allocation_size(STAGING_MEMORY_SIZE),
-                None,
-            )
-        }
-        .unwrap();
-
-        // Create a readback buffer
-        let handle = unsafe {
-            device.create_buffer(
-                &vk::BufferCreateInfo::default()
-                    .size(STAGING_MEMORY_SIZE)
-                    .usage(vk::BufferUsageFlags::TRANSFER_SRC | vk::BufferUsageFlags::TRANSFER_DST),
-                None,
-            )
-        }
-        .unwrap();
-
-        context.set_debug_label(handle, "[lazy_vulkan] Readback Buffer");
-
-        // Bind its memory
-        unsafe { device.bind_buffer_memory(handle, memory, 0) }.unwrap();
-
-        // Map its memory
-        let ptr = unsafe {
-            std::ptr::NonNull::new_unchecked(
-                device
-                    .map_memory(memory, 0, vk::WHOLE_SIZE, vk::MemoryMapFlags::empty())
-                    .unwrap() as *mut u8,
-            )
-        };
-
-        ReadbackBuffer {
-            handle,
-            memory,
-            ptr,
+            device.destroy_fence(fence, None);
         }
     }
 }
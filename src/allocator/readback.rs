@@ -0,0 +1,208 @@
+use std::{
+    marker::PhantomData,
+    ptr::NonNull,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use ash::vk;
+
+use super::ring::Ring;
+use super::TransferToken;
+use crate::{Context, Error, Result};
+
+/// The data from [`crate::Allocator::read_buffer`] or [`crate::Allocator::read_image`], once the
+/// GPU has finished the submission the copy was recorded in.
+///
+/// Clones share the same data, so only one of them can [`ReadbackToken::take`] it.
+#[derive(Clone, Debug)]
+pub struct ReadbackToken<T> {
+    result: ReadbackResult,
+    _phantom: PhantomData<T>,
+}
+
+impl<T: bytemuck::Pod> ReadbackToken<T> {
+    pub(crate) fn new(result: ReadbackResult) -> Self {
+        Self {
+            result,
+            _phantom: PhantomData,
+        }
+    }
+
+    /// True once the data has been read back - or the source was freed before it could be.
+    pub fn is_complete(&self) -> bool {
+        self.result.transfer_token.is_complete()
+    }
+
+    /// Block until the data has been read back, or `timeout` has passed. See
+    /// [`TransferToken::wait`].
+    pub fn wait(&self, timeout: Duration) -> bool {
+        self.result.transfer_token.wait(timeout)
+    }
+
+    /// Take the data, if it's been read back. Returns `None` if it hasn't been yet, it's already
+    /// been taken, or the source was freed before the copy was recorded.
+    pub fn take(&self) -> Option<Vec<T>> {
+        if !self.is_complete() {
+            return None;
+        }
+
+        let bytes = self.result.data.lock().unwrap().take()?;
+        // The bytes aren't necessarily aligned for `T`, so copy them into a `Vec` that is.
+        let mut data = vec![T::zeroed(); bytes.len() / std::mem::size_of::<T>().max(1)];
+        bytemuck::cast_slice_mut::<T, u8>(&mut data).copy_from_slice(&bytes);
+        Some(data)
+    }
+
+    /// Completed along with the readback, so it can be `.await`ed or joined with other tokens.
+    pub fn transfer_token(&self) -> &TransferToken {
+        &self.result.transfer_token
+    }
+}
+
+/// Where a readback's data ends up.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct ReadbackResult {
+    transfer_token: TransferToken,
+    data: Arc<Mutex<Option<Vec<u8>>>>,
+}
+
+impl ReadbackResult {
+    pub fn is_complete(&self) -> bool {
+        self.transfer_token.is_complete()
+    }
+
+    pub fn complete(&self, data: Option<Vec<u8>>) {
+        *self.data.lock().unwrap() = data;
+        self.transfer_token.mark_completed();
+    }
+}
+
+/// A ring of host visible memory that readbacks are copied into. See [`Ring`].
+pub(crate) struct ReadbackBuffer {
+    context: Arc<Context>,
+    pub handle: vk::Buffer,
+    memory: vk::DeviceMemory,
+    ptr: NonNull<u8>,
+    ring: Ring,
+}
+
+impl ReadbackBuffer {
+    pub fn new(context: Arc<Context>, size: vk::DeviceSize) -> Result<ReadbackBuffer> {
+        let device = &context.device;
+        let memory_types = context.memory_properties.memory_types_as_slice();
+
+        // The CPU reads from this, so cached memory is much faster if there is any.
+        let host_visible =
+            vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_COHERENT;
+        let find_memory_type = |required: vk::MemoryPropertyFlags| {
+            memory_types
+                .iter()
+                .position(|memory_type| memory_type.property_flags.contains(required))
+        };
+        let memory_type_index =
+            find_memory_type(host_visible | vk::MemoryPropertyFlags::HOST_CACHED)
+                .or_else(|| find_memory_type(host_visible))
+                .ok_or(Error::NoSuitableMemoryType(host_visible))? as u32;
+
+        let memory = unsafe {
+            log::debug!("[READBACK BUFFER] Allocating {size} from memory type {memory_type_index}");
+            device.allocate_memory(
+                &vk::MemoryAllocateInfo::default()
+                    .memory_type_index(memory_type_index)
+                    .allocation_size(size),
+                None,
+            )
+        }?;
+
+        let create_and_map = || -> Result<(vk::Buffer, NonNull<u8>)> {
+            let handle = unsafe {
+                device.create_buffer(
+                    &vk::BufferCreateInfo::default()
+                        .size(size)
+                        .usage(vk::BufferUsageFlags::TRANSFER_DST),
+                    None,
+                )
+            }?;
+
+            let mapped = unsafe {
+                device.bind_buffer_memory(handle, memory, 0).and_then(|_| {
+                    device.map_memory(memory, 0, vk::WHOLE_SIZE, vk::MemoryMapFlags::empty())
+                })
+            };
+            match mapped {
+                Ok(pointer) => Ok((handle, NonNull::new(pointer.cast()).unwrap())),
+                Err(e) => {
+                    unsafe { device.destroy_buffer(handle, None) };
+                    Err(e.into())
+                }
+            }
+        };
+
+        let (handle, ptr) = match create_and_map() {
+            Ok(buffer) => buffer,
+            Err(e) => {
+                unsafe { device.free_memory(memory, None) };
+                return Err(e);
+            }
+        };
+        context.set_debug_label(handle, "[lazy_vulkan] Readback Buffer");
+
+        Ok(ReadbackBuffer {
+            context,
+            handle,
+            memory,
+            ptr,
+            ring: Ring::new(size),
+        })
+    }
+
+    /// Reserve `size` bytes, starting at a multiple of `align`, for a copy to land in.
+    pub fn reserve(&mut self, size: vk::DeviceSize, align: vk::DeviceSize) -> Result<usize> {
+        match self.ring.reserve(size, align, size) {
+            Some((offset, _)) => Ok(offset as usize),
+            None => Err(Error::ReadbackBufferFull {
+                requested: size,
+                available: self.ring.size,
+            }),
+        }
+    }
+
+    /// Copy out what the GPU wrote at `offset`. The frame that copied it must be finished!
+    pub fn read(&self, offset: usize, size: usize) -> Vec<u8> {
+        unsafe { std::slice::from_raw_parts(self.ptr.add(offset).as_ptr(), size) }.to_vec()
+    }
+
+    /// Record that the copy into `offset` was recorded in `frame`.
+    pub fn mark_executed(&mut self, offset: usize, frame: u64) {
+        self.ring.mark_executed(offset as _, frame);
+    }
+
+    /// Record that the copy into `offset` will never be recorded.
+    pub fn mark_cancelled(&mut self, offset: usize) {
+        self.ring.mark_cancelled(offset as _);
+    }
+
+    /// Release everything that's been read, now that the GPU is done with the frame before
+    /// `frame` that used the same slot.
+    pub fn begin_frame(&mut self, frame: u64) {
+        let frames_in_flight = self.context.frames_in_flight as u64;
+        self.ring.begin_frame(frame, frames_in_flight);
+    }
+
+    /// Release everything that isn't waiting for a copy to be recorded. The GPU must be done
+    /// with all of it!
+    pub fn clear(&mut self) {
+        self.ring.clear();
+    }
+}
+
+impl Drop for ReadbackBuffer {
+    fn drop(&mut self) {
+        let device = &self.context.device;
+        unsafe {
+            device.destroy_buffer(self.handle, None);
+            device.free_memory(self.memory, None);
+        }
+    }
+}
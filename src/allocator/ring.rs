@@ -0,0 +1,221 @@
+use std::collections::VecDeque;
+
+use ash::vk;
+
+/// Hands out spans of a buffer the CPU and GPU take turns with, in a ring.
+///
+/// Each span starts after the last one, wrapping around to the start of the buffer when it
+/// reaches the end. Spans are released in the order they were reserved, once the GPU has
+/// finished the frame that used them - so we only run out of room when the frames in flight have
+/// used more than the whole buffer between them.
+pub struct Ring {
+    pub size: vk::DeviceSize,
+    /// Every span that's still in use, oldest first.
+    spans: VecDeque<Span>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Span {
+    start: vk::DeviceSize,
+    end: vk::DeviceSize,
+    state: SpanState,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SpanState {
+    /// Reserved, but the copy hasn't been recorded yet.
+    Pending,
+    /// The copy was recorded in this frame.
+    Executed(u64),
+    /// The copy will never be recorded.
+    Cancelled,
+}
+
+impl Ring {
+    pub fn new(size: vk::DeviceSize) -> Self {
+        Self {
+            size,
+            spans: VecDeque::new(),
+        }
+    }
+
+    /// Reserve as much of `size` bytes as there's room for, starting at a multiple of `align`.
+    /// Unless all of it fits, only a multiple of `granularity` bytes is reserved.
+    ///
+    /// Returns where the span starts and how big it is, or `None` if there isn't room for even
+    /// `granularity` bytes.
+    pub fn reserve(
+        &mut self,
+        size: vk::DeviceSize,
+        align: vk::DeviceSize,
+        granularity: vk::DeviceSize,
+    ) -> Option<(vk::DeviceSize, vk::DeviceSize)> {
+        let (start, available) = self.free_span(size, align);
+        let size = if size <= available {
+            size
+        } else {
+            available / granularity * granularity
+        };
+        if size == 0 {
+            return None;
+        }
+
+        self.spans.push_back(Span {
+            start,
+            end: start + size,
+            state: SpanState::Pending,
+        });
+
+        Some((start, size))
+    }
+
+    /// Record that the span at `start` was used by a command buffer for `frame`.
+    pub fn mark_executed(&mut self, start: vk::DeviceSize, frame: u64) {
+        self.span_at(start).state = SpanState::Executed(frame);
+    }
+
+    /// Record that the span at `start` will never be used by the GPU.
+    pub fn mark_cancelled(&mut self, start: vk::DeviceSize) {
+        self.span_at(start).state = SpanState::Cancelled;
+    }
+
+    /// Release every span the GPU is done with, now that it's finished the frame before `frame`
+    /// that used the same slot.
+    pub fn begin_frame(&mut self, frame: u64, frames_in_flight: u64) {
+        self.release(|state| match state {
+            SpanState::Pending => false,
+            SpanState::Executed(executed) => executed + frames_in_flight <= frame,
+            SpanState::Cancelled => true,
+        });
+    }
+
+    /// Release every span that isn't waiting to be used. The GPU must be done with all of them!
+    pub fn clear(&mut self) {
+        self.release(|state| state != SpanState::Pending);
+    }
+
+    /// Spans can only be released from the oldest end, so a pending span holds up the ones
+    /// after it - but it'll be used in the next frame anyway.
+    fn release(&mut self, is_released: impl Fn(SpanState) -> bool) {
+        while self
+            .spans
+            .front()
+            .is_some_and(|span| is_released(span.state))
+        {
+            self.spans.pop_front();
+        }
+    }
+
+    /// Where the next span would start, and how big it could be. Prefers a span that fits `size`.
+    fn free_span(
+        &self,
+        size: vk::DeviceSize,
+        align: vk::DeviceSize,
+    ) -> (vk::DeviceSize, vk::DeviceSize) {
+        let (Some(oldest), Some(newest)) = (self.spans.front(), self.spans.back()) else {
+            return (0, self.size);
+        };
+
+        let start = newest.end.next_multiple_of(align);
+        if newest.start < oldest.start {
+            // We've wrapped around, so there's only the gap before the oldest span.
+            return (start, oldest.start.saturating_sub(start));
+        }
+
+        // Either the rest of the buffer, or wrap around to the start of it.
+        let after = (start, self.size.saturating_sub(start));
+        let before = (0, oldest.start);
+        if after.1 >= size || after.1 >= before.1 {
+            after
+        } else {
+            before
+        }
+    }
+
+    fn span_at(&mut self, start: vk::DeviceSize) -> &mut Span {
+        self.spans
+            .iter_mut()
+            .find(|span| span.start == start)
+            .expect("No span starts at this offset")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reserve_after_wrap() {
+        let mut ring = Ring::new(100);
+        assert_eq!(ring.reserve(40, 1, 1), Some((0, 40)));
+        assert_eq!(ring.reserve(40, 1, 1), Some((40, 40)));
+        ring.mark_executed(0, 0);
+        ring.mark_executed(40, 1);
+        ring.begin_frame(2, 2);
+
+        // Only the first span was released, and the 20 bytes at the end aren't enough.
+        assert_eq!(ring.reserve(30, 1, 1), Some((0, 30)));
+        // Now we've wrapped, the only space is the gap before the oldest span.
+        assert_eq!(ring.reserve(20, 16, 1), Some((32, 8)));
+        assert_eq!(ring.reserve(1, 1, 1), None);
+    }
+
+    #[test]
+    fn test_free_span_choice() {
+        let mut ring = Ring::new(100);
+        assert_eq!(ring.reserve(50, 1, 1), Some((0, 50)));
+        assert_eq!(ring.reserve(30, 1, 1), Some((50, 30)));
+        ring.mark_executed(0, 0);
+        ring.begin_frame(2, 2);
+
+        // Both ends have room, so don't wrap.
+        assert_eq!(ring.free_span(10, 1), (80, 20));
+        // Only the start has room.
+        assert_eq!(ring.free_span(40, 1), (0, 50));
+
+        let mut ring = Ring::new(100);
+        assert_eq!(ring.reserve(10, 1, 1), Some((0, 10)));
+        assert_eq!(ring.reserve(60, 1, 1), Some((10, 60)));
+        ring.mark_executed(0, 0);
+        ring.begin_frame(2, 2);
+
+        // Neither end has room, so take the bigger one.
+        assert_eq!(ring.free_span(50, 1), (70, 30));
+        assert_eq!(ring.reserve(50, 1, 1), Some((70, 30)));
+    }
+
+    #[test]
+    fn test_granularity() {
+        let mut ring = Ring::new(100);
+        assert_eq!(ring.reserve(150, 1, 16), Some((0, 96)));
+        assert_eq!(ring.reserve(10, 1, 16), None);
+        // Anything that fits is reserved whole.
+        assert_eq!(ring.reserve(4, 1, 16), Some((96, 4)));
+    }
+
+    #[test]
+    fn test_pending_holds_up_release() {
+        let mut ring = Ring::new(100);
+        assert_eq!(ring.reserve(50, 1, 1), Some((0, 50)));
+        assert_eq!(ring.reserve(50, 1, 1), Some((50, 50)));
+        ring.mark_executed(50, 0);
+        ring.begin_frame(10, 2);
+        ring.clear();
+        assert_eq!(ring.reserve(1, 1, 1), None);
+
+        ring.mark_executed(0, 1);
+        ring.begin_frame(10, 2);
+        assert_eq!(ring.reserve(100, 1, 1), Some((0, 100)));
+    }
+
+    #[test]
+    fn test_mark_cancelled() {
+        let mut ring = Ring::new(100);
+        assert_eq!(ring.reserve(100, 1, 1), Some((0, 100)));
+        ring.mark_cancelled(0);
+
+        // Cancelled spans don't wait for the GPU.
+        ring.begin_frame(0, 2);
+        assert_eq!(ring.reserve(100, 1, 1), Some((0, 100)));
+    }
+}
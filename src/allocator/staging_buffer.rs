@@ -1,108 +1,143 @@
-use std::ptr::NonNull;
+use std::{ptr::NonNull, sync::Arc};
 
 use ash::vk;
 
-use crate::{allocator::STAGING_MEMORY_SIZE, Context};
+use super::ring::Ring;
+use crate::{Context, Error, Result};
 
+/// A ring buffer that uploads are copied into, for the GPU to copy to their destination. See
+/// [`Ring`].
 pub struct StagingBuffer {
+    context: Arc<Context>,
     pub handle: vk::Buffer,
-    #[allow(unused)]
     pub memory: vk::DeviceMemory,
     pub ptr: NonNull<u8>,
-    size: vk::DeviceSize,
+    ring: Ring,
 }
 
 impl StagingBuffer {
-    pub fn new(context: &Context) -> StagingBuffer {
+    pub fn new(context: Arc<Context>, size: vk::DeviceSize) -> Result<StagingBuffer> {
         let device = &context.device;
         let memory_properties = &context.memory_properties;
 
         // Search through the available memory types to find the one we want
-        let mut memory_type_index = None;
-        let mut memory_heap_index = None;
-        for (index, memory_type) in memory_properties.memory_types_as_slice().iter().enumerate() {
-            if memory_type.property_flags.contains(
-                vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_COHERENT,
-            ) {
-                memory_type_index = Some(index as u32);
-                memory_heap_index = Some(memory_type.heap_index);
-                break;
-            }
-        }
-
-        let memory_type_index = memory_type_index.expect("No global memory? Impossible");
-        let memory_heap_index = memory_heap_index.expect("No global memory? Impossible");
+        let host_visible =
+            vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_COHERENT;
+        let (memory_type_index, memory_heap_index) = memory_properties
+            .memory_types_as_slice()
+            .iter()
+            .enumerate()
+            .find(|(_, memory_type)| memory_type.property_flags.contains(host_visible))
+            .map(|(index, memory_type)| (index as u32, memory_type.heap_index))
+            .ok_or(Error::NoSuitableMemoryType(host_visible))?;
 
         // Allocate our staging memory
         let memory = unsafe {
-            log::debug!("[STAGING BUFFER] Allocating {STAGING_MEMORY_SIZE} from memory type / heap : {memory_type_index}, {memory_heap_index}");
+            log::debug!("[STAGING BUFFER] Allocating {size} from memory type / heap : {memory_type_index}, {memory_heap_index}");
             device.allocate_memory(
                 &vk::MemoryAllocateInfo::default()
                     .memory_type_index(memory_type_index)
-                    .allocation_size(STAGING_MEMORY_SIZE),
+                    .allocation_size(size),
                 None,
             )
-        }
-        .unwrap();
+        }?;
 
         // Create a staging buffer
         let handle = unsafe {
             device.create_buffer(
                 &vk::BufferCreateInfo::default()
-                    .size(STAGING_MEMORY_SIZE)
+                    .size(size)
                     .usage(vk::BufferUsageFlags::TRANSFER_SRC),
                 None,
             )
-        }
-        .unwrap();
+        }?;
 
         context.set_debug_label(handle, "[lazy_vulkan] Staging Buffer");
 
         // Bind its memory
-        unsafe { device.bind_buffer_memory(handle, memory, 0) }.unwrap();
+        unsafe { device.bind_buffer_memory(handle, memory, 0) }?;
 
         // Map its memory
         let ptr = unsafe {
-            std::ptr::NonNull::new_unchecked(
-                device
-                    .map_memory(memory, 0, vk::WHOLE_SIZE, vk::MemoryMapFlags::empty())
-                    .unwrap() as *mut u8,
-            )
+            std::ptr::NonNull::new_unchecked(device.map_memory(
+                memory,
+                0,
+                vk::WHOLE_SIZE,
+                vk::MemoryMapFlags::empty(),
+            )? as *mut u8)
         };
 
-        StagingBuffer {
+        Ok(StagingBuffer {
+            context,
             handle,
             memory,
             ptr,
-            size: 0,
-        }
+            ring: Ring::new(size),
+        })
     }
 
-    pub fn stage(&mut self, data: &[u8]) -> usize {
-        // Step one: copy the data into the staging buffer
-        let staging_buffer_offset = self.size as usize;
-
-        let transfer_size = data.len();
-
-        if (staging_buffer_offset + transfer_size) > STAGING_MEMORY_SIZE as usize {
-            panic!("Staging buffer overflow. Transfer size: {transfer_size}, current staging buffer size: {}", self.size);
-        }
+    pub fn size(&self) -> vk::DeviceSize {
+        self.ring.size
+    }
 
-        // We get the staging pointer by taking the base address and adding the current size of
-        // the buffer.
-        let staging_ptr = unsafe { self.ptr.add(staging_buffer_offset).as_ptr() };
+    /// Copy as much of `data` as there's room for into the next free span, starting at a
+    /// multiple of `align`. Unless all of `data` fits, only a multiple of `granularity` bytes is
+    /// copied.
+    ///
+    /// Returns the offset it was staged at and how many bytes that was, or `None` if there isn't
+    /// room for even `granularity` bytes.
+    pub fn stage(
+        &mut self,
+        data: &[u8],
+        align: vk::DeviceSize,
+        granularity: usize,
+    ) -> Option<(usize, usize)> {
+        let (start, transfer_size) = self
+            .ring
+            .reserve(data.len() as _, align, granularity as _)?;
+        let (start, transfer_size) = (start as usize, transfer_size as usize);
+
+        // We get the staging pointer by taking the base address and adding the offset of the span.
+        let staging_ptr = unsafe { self.ptr.add(start).as_ptr() };
 
         unsafe {
-            std::ptr::copy_nonoverlapping(data.as_ptr() as *const u8, staging_ptr, transfer_size);
+            std::ptr::copy_nonoverlapping(data.as_ptr(), staging_ptr, transfer_size);
         };
 
-        // Step two: record the amount of data transferred
-        self.size += transfer_size as vk::DeviceSize;
+        Some((start, transfer_size))
+    }
+
+    /// Record that the transfer staged at `staging_buffer_offset` was executed in `frame`.
+    pub fn mark_executed(&mut self, staging_buffer_offset: usize, frame: u64) {
+        self.ring.mark_executed(staging_buffer_offset as _, frame);
+    }
+
+    /// Record that the transfer staged at `staging_buffer_offset` will never be executed, so the
+    /// GPU won't read it.
+    pub fn mark_cancelled(&mut self, staging_buffer_offset: usize) {
+        self.ring.mark_cancelled(staging_buffer_offset as _);
+    }
 
-        staging_buffer_offset as usize
+    /// Release everything the GPU is done with, now that it's finished the frame before `frame`
+    /// that used the same slot.
+    pub fn begin_frame(&mut self, frame: u64) {
+        let frames_in_flight = self.context.frames_in_flight as u64;
+        self.ring.begin_frame(frame, frames_in_flight);
     }
 
+    /// Release everything that doesn't have a transfer waiting to be executed. The GPU must be
+    /// done with all of it!
     pub fn clear(&mut self) {
-        self.size = 0;
+        self.ring.clear();
+    }
+}
+
+impl Drop for StagingBuffer {
+    fn drop(&mut self) {
+        let device = &self.context.device;
+        unsafe {
+            device.destroy_buffer(self.handle, None);
+            device.free_memory(self.memory, None);
+        }
     }
 }
@@ -0,0 +1,232 @@
+use ash::vk;
+
+/// The smallest piece of an image's data that can be copied on its own: a single texel, or a
+/// block of texels for compressed formats.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct TexelBlock {
+    /// In texels.
+    pub width: u32,
+    /// In texels.
+    pub height: u32,
+    /// In bytes.
+    pub size: u32,
+}
+
+impl TexelBlock {
+    /// `None` for formats we don't know the layout of, like depth / stencil and multi-planar
+    /// formats.
+    pub fn of(format: vk::Format) -> Option<TexelBlock> {
+        use vk::Format as F;
+
+        let texel = |size| (1, 1, size);
+        let (width, height, size) = match format {
+            F::R4G4_UNORM_PACK8
+            | F::R8_UNORM
+            | F::R8_SNORM
+            | F::R8_USCALED
+            | F::R8_SSCALED
+            | F::R8_UINT
+            | F::R8_SINT
+            | F::R8_SRGB => texel(1),
+            F::R4G4B4A4_UNORM_PACK16
+            | F::B4G4R4A4_UNORM_PACK16
+            | F::R5G6B5_UNORM_PACK16
+            | F::B5G6R5_UNORM_PACK16
+            | F::R5G5B5A1_UNORM_PACK16
+            | F::B5G5R5A1_UNORM_PACK16
+            | F::A1R5G5B5_UNORM_PACK16
+            | F::R8G8_UNORM
+            | F::R8G8_SNORM
+            | F::R8G8_USCALED
+            | F::R8G8_SSCALED
+            | F::R8G8_UINT
+            | F::R8G8_SINT
+            | F::R8G8_SRGB
+            | F::R16_UNORM
+            | F::R16_SNORM
+            | F::R16_USCALED
+            | F::R16_SSCALED
+            | F::R16_UINT
+            | F::R16_SINT
+            | F::R16_SFLOAT => texel(2),
+            F::R8G8B8_UNORM
+            | F::R8G8B8_SNORM
+            | F::R8G8B8_USCALED
+            | F::R8G8B8_SSCALED
+            | F::R8G8B8_UINT
+            | F::R8G8B8_SINT
+            | F::R8G8B8_SRGB
+            | F::B8G8R8_UNORM
+            | F::B8G8R8_SNORM
+            | F::B8G8R8_USCALED
+            | F::B8G8R8_SSCALED
+            | F::B8G8R8_UINT
+            | F::B8G8R8_SINT
+            | F::B8G8R8_SRGB => texel(3),
+            F::R8G8B8A8_UNORM
+            | F::R8G8B8A8_SNORM
+            | F::R8G8B8A8_USCALED
+            | F::R8G8B8A8_SSCALED
+            | F::R8G8B8A8_UINT
+            | F::R8G8B8A8_SINT
+            | F::R8G8B8A8_SRGB
+            | F::B8G8R8A8_UNORM
+            | F::B8G8R8A8_SNORM
+            | F::B8G8R8A8_USCALED
+            | F::B8G8R8A8_SSCALED
+            | F::B8G8R8A8_UINT
+            | F::B8G8R8A8_SINT
+            | F::B8G8R8A8_SRGB
+            | F::A8B8G8R8_UNORM_PACK32
+            | F::A8B8G8R8_SNORM_PACK32
+            | F::A8B8G8R8_USCALED_PACK32
+            | F::A8B8G8R8_SSCALED_PACK32
+            | F::A8B8G8R8_UINT_PACK32
+            | F::A8B8G8R8_SINT_PACK32
+            | F::A8B8G8R8_SRGB_PACK32
+            | F::A2R10G10B10_UNORM_PACK32
+            | F::A2R10G10B10_SNORM_PACK32
+            | F::A2R10G10B10_USCALED_PACK32
+            | F::A2R10G10B10_SSCALED_PACK32
+            | F::A2R10G10B10_UINT_PACK32
+            | F::A2R10G10B10_SINT_PACK32
+            | F::A2B10G10R10_UNORM_PACK32
+            | F::A2B10G10R10_SNORM_PACK32
+            | F::A2B10G10R10_USCALED_PACK32
+            | F::A2B10G10R10_SSCALED_PACK32
+            | F::A2B10G10R10_UINT_PACK32
+            | F::A2B10G10R10_SINT_PACK32
+            | F::R16G16_UNORM
+            | F::R16G16_SNORM
+            | F::R16G16_USCALED
+            | F::R16G16_SSCALED
+            | F::R16G16_UINT
+            | F::R16G16_SINT
+            | F::R16G16_SFLOAT
+            | F::R32_UINT
+            | F::R32_SINT
+            | F::R32_SFLOAT
+            | F::B10G11R11_UFLOAT_PACK32
+            | F::E5B9G9R9_UFLOAT_PACK32 => texel(4),
+            F::R16G16B16_UNORM
+            | F::R16G16B16_SNORM
+            | F::R16G16B16_USCALED
+            | F::R16G16B16_SSCALED
+            | F::R16G16B16_UINT
+            | F::R16G16B16_SINT
+            | F::R16G16B16_SFLOAT => texel(6),
+            F::R16G16B16A16_UNORM
+            | F::R16G16B16A16_SNORM
+            | F::R16G16B16A16_USCALED
+            | F::R16G16B16A16_SSCALED
+            | F::R16G16B16A16_UINT
+            | F::R16G16B16A16_SINT
+            | F::R16G16B16A16_SFLOAT
+            | F::R32G32_UINT
+            | F::R32G32_SINT
+            | F::R32G32_SFLOAT
+            | F::R64_UINT
+            | F::R64_SINT
+            | F::R64_SFLOAT => texel(8),
+            F::R32G32B32_UINT | F::R32G32B32_SINT | F::R32G32B32_SFLOAT => texel(12),
+            F::R32G32B32A32_UINT
+            | F::R32G32B32A32_SINT
+            | F::R32G32B32A32_SFLOAT
+            | F::R64G64_UINT
+            | F::R64G64_SINT
+            | F::R64G64_SFLOAT => texel(16),
+            F::R64G64B64_UINT | F::R64G64B64_SINT | F::R64G64B64_SFLOAT => texel(24),
+            F::R64G64B64A64_UINT | F::R64G64B64A64_SINT | F::R64G64B64A64_SFLOAT => texel(32),
+            F::BC1_RGB_UNORM_BLOCK
+            | F::BC1_RGB_SRGB_BLOCK
+            | F::BC1_RGBA_UNORM_BLOCK
+            | F::BC1_RGBA_SRGB_BLOCK
+            | F::BC4_UNORM_BLOCK
+            | F::BC4_SNORM_BLOCK
+            | F::ETC2_R8G8B8_UNORM_BLOCK
+            | F::ETC2_R8G8B8_SRGB_BLOCK
+            | F::ETC2_R8G8B8A1_UNORM_BLOCK
+            | F::ETC2_R8G8B8A1_SRGB_BLOCK
+            | F::EAC_R11_UNORM_BLOCK
+            | F::EAC_R11_SNORM_BLOCK => (4, 4, 8),
+            F::BC2_UNORM_BLOCK
+            | F::BC2_SRGB_BLOCK
+            | F::BC3_UNORM_BLOCK
+            | F::BC3_SRGB_BLOCK
+            | F::BC5_UNORM_BLOCK
+            | F::BC5_SNORM_BLOCK
+            | F::BC6H_UFLOAT_BLOCK
+            | F::BC6H_SFLOAT_BLOCK
+            | F::BC7_UNORM_BLOCK
+            | F::BC7_SRGB_BLOCK
+            | F::ETC2_R8G8B8A8_UNORM_BLOCK
+            | F::ETC2_R8G8B8A8_SRGB_BLOCK
+            | F::EAC_R11G11_UNORM_BLOCK
+            | F::EAC_R11G11_SNORM_BLOCK => (4, 4, 16),
+            F::ASTC_4X4_UNORM_BLOCK | F::ASTC_4X4_SRGB_BLOCK => (4, 4, 16),
+            F::ASTC_5X4_UNORM_BLOCK | F::ASTC_5X4_SRGB_BLOCK => (5, 4, 16),
+            F::ASTC_5X5_UNORM_BLOCK | F::ASTC_5X5_SRGB_BLOCK => (5, 5, 16),
+            F::ASTC_6X5_UNORM_BLOCK | F::ASTC_6X5_SRGB_BLOCK => (6, 5, 16),
+            F::ASTC_6X6_UNORM_BLOCK | F::ASTC_6X6_SRGB_BLOCK => (6, 6, 16),
+            F::ASTC_8X5_UNORM_BLOCK | F::ASTC_8X5_SRGB_BLOCK => (8, 5, 16),
+            F::ASTC_8X6_UNORM_BLOCK | F::ASTC_8X6_SRGB_BLOCK => (8, 6, 16),
+            F::ASTC_8X8_UNORM_BLOCK | F::ASTC_8X8_SRGB_BLOCK => (8, 8, 16),
+            F::ASTC_10X5_UNORM_BLOCK | F::ASTC_10X5_SRGB_BLOCK => (10, 5, 16),
+            F::ASTC_10X6_UNORM_BLOCK | F::ASTC_10X6_SRGB_BLOCK => (10, 6, 16),
+            F::ASTC_10X8_UNORM_BLOCK | F::ASTC_10X8_SRGB_BLOCK => (10, 8, 16),
+            F::ASTC_10X10_UNORM_BLOCK | F::ASTC_10X10_SRGB_BLOCK => (10, 10, 16),
+            F::ASTC_12X10_UNORM_BLOCK | F::ASTC_12X10_SRGB_BLOCK => (12, 10, 16),
+            F::ASTC_12X12_UNORM_BLOCK | F::ASTC_12X12_SRGB_BLOCK => (12, 12, 16),
+            _ => return None,
+        };
+
+        Some(TexelBlock {
+            width,
+            height,
+            size,
+        })
+    }
+
+    /// How many bytes one row of blocks takes up in an image `width` texels wide.
+    pub fn row_size(&self, width: u32) -> u32 {
+        width.div_ceil(self.width) * self.size
+    }
+
+    /// How many rows of texels `size` bytes of whole block rows covers, starting at `first_row`.
+    /// The last row of blocks can hang off the bottom of the image.
+    pub fn rows(&self, extent: vk::Extent2D, first_row: u32, size: vk::DeviceSize) -> u32 {
+        let block_rows = (size / self.row_size(extent.width) as vk::DeviceSize) as u32;
+        (block_rows * self.height).min(extent.height - first_row)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ash::vk;
+
+    use super::TexelBlock;
+
+    #[test]
+    fn test_texel_block() {
+        let rgba = TexelBlock::of(vk::Format::R8G8B8A8_SRGB).unwrap();
+        assert_eq!((rgba.width, rgba.height, rgba.size), (1, 1, 4));
+        assert_eq!(rgba.row_size(100), 400);
+
+        // 1 byte per texel, but it can only be copied 4 rows at a time.
+        let bc7 = TexelBlock::of(vk::Format::BC7_UNORM_BLOCK).unwrap();
+        assert_eq!((bc7.width, bc7.height, bc7.size), (4, 4, 16));
+        assert_eq!(bc7.row_size(100), 400);
+        assert_eq!(bc7.row_size(102), 416);
+        let extent = vk::Extent2D {
+            width: 100,
+            height: 10,
+        };
+        assert_eq!(bc7.rows(extent, 0, 800), 8);
+        assert_eq!(bc7.rows(extent, 8, 400), 2);
+
+        let astc = TexelBlock::of(vk::Format::ASTC_10X6_SRGB_BLOCK).unwrap();
+        assert_eq!((astc.width, astc.height, astc.size), (10, 6, 16));
+
+        assert_eq!(TexelBlock::of(vk::Format::D24_UNORM_S8_UINT), None);
+    }
+}
@@ -0,0 +1,149 @@
+use std::{collections::VecDeque, sync::Arc};
+
+use ash::vk;
+
+use crate::{Context, Result};
+
+/// Submits uploads to the device's dedicated transfer queue, so they can run alongside rendering.
+///
+/// Every submission signals the next value of a timeline semaphore. Graphics submissions that
+/// depend on an upload wait on its value - see [`crate::Allocator::take_transfer_wait`].
+pub(crate) struct TransferQueue {
+    context: Arc<Context>,
+    queue: vk::Queue,
+    pub family: u32,
+    command_pool: vk::CommandPool,
+    pub semaphore: vk::Semaphore,
+    /// The value the most recent submission will signal.
+    last_submitted: u64,
+    /// Command buffers the GPU may still be using, and the value that says it's done with them.
+    in_flight: VecDeque<(u64, vk::CommandBuffer)>,
+    /// Command buffers that are ready to be recorded again.
+    free: Vec<vk::CommandBuffer>,
+}
+
+impl TransferQueue {
+    /// Returns `None` if the device doesn't have a dedicated transfer queue.
+    pub fn new(context: Arc<Context>) -> Result<Option<Self>> {
+        let (Some(queue), Some(family)) = (context.transfer_queue, context.queue_families.transfer)
+        else {
+            return Ok(None);
+        };
+
+        let device = &context.device;
+        let command_pool = unsafe {
+            device.create_command_pool(
+                &vk::CommandPoolCreateInfo::default()
+                    .queue_family_index(family)
+                    .flags(
+                        vk::CommandPoolCreateFlags::TRANSIENT
+                            | vk::CommandPoolCreateFlags::RESET_COMMAND_BUFFER,
+                    ),
+                None,
+            )
+        }?;
+
+        let semaphore = match unsafe {
+            device.create_semaphore(
+                &vk::SemaphoreCreateInfo::default().push_next(
+                    &mut vk::SemaphoreTypeCreateInfo::default()
+                        .semaphore_type(vk::SemaphoreType::TIMELINE)
+                        .initial_value(0),
+                ),
+                None,
+            )
+        } {
+            Ok(semaphore) => semaphore,
+            Err(e) => {
+                unsafe { device.destroy_command_pool(command_pool, None) };
+                return Err(e.into());
+            }
+        };
+        context.set_debug_label(semaphore, "[lazy_vulkan] Transfer Timeline");
+
+        log::debug!("[lazy_vulkan] Uploading on transfer queue family {family}");
+
+        Ok(Some(Self {
+            context,
+            queue,
+            family,
+            command_pool,
+            semaphore,
+            last_submitted: 0,
+            in_flight: Default::default(),
+            free: Default::default(),
+        }))
+    }
+
+    /// Get a command buffer to record transfers into.
+    pub fn begin(&mut self) -> Result<vk::CommandBuffer> {
+        let device = &self.context.device;
+
+        // Recycle anything the GPU has finished with
+        let completed = unsafe { device.get_semaphore_counter_value(self.semaphore) }?;
+        while let Some(&(value, command_buffer)) = self.in_flight.front() {
+            if value > completed {
+                break;
+            }
+            self.in_flight.pop_front();
+            self.free.push(command_buffer);
+        }
+
+        let command_buffer = match self.free.pop() {
+            Some(command_buffer) => command_buffer,
+            None => unsafe {
+                device.allocate_command_buffers(
+                    &vk::CommandBufferAllocateInfo::default()
+                        .command_pool(self.command_pool)
+                        .command_buffer_count(1),
+                )
+            }?[0],
+        };
+
+        unsafe {
+            device.begin_command_buffer(
+                command_buffer,
+                &vk::CommandBufferBeginInfo::default()
+                    .flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT),
+            )
+        }?;
+
+        Ok(command_buffer)
+    }
+
+    /// Submit `command_buffer`, returning the value the timeline semaphore will have once it's
+    /// complete.
+    pub fn submit(&mut self, command_buffer: vk::CommandBuffer) -> Result<u64> {
+        let value = self.last_submitted + 1;
+        unsafe {
+            self.context.device.end_command_buffer(command_buffer)?;
+            self.context.queue_submit2(
+                self.queue,
+                &[vk::SubmitInfo2::default()
+                    .command_buffer_infos(&[
+                        vk::CommandBufferSubmitInfo::default().command_buffer(command_buffer)
+                    ])
+                    .signal_semaphore_infos(&[vk::SemaphoreSubmitInfo::default()
+                        .semaphore(self.semaphore)
+                        .value(value)
+                        .stage_mask(vk::PipelineStageFlags2::ALL_COMMANDS)])],
+                vk::Fence::null(),
+            )?;
+        }
+
+        self.last_submitted = value;
+        self.in_flight.push_back((value, command_buffer));
+        Ok(value)
+    }
+}
+
+impl Drop for TransferQueue {
+    fn drop(&mut self) {
+        unsafe {
+            self.context
+                .device
+                .destroy_command_pool(self.command_pool, None);
+            self.context.device.destroy_semaphore(self.semaphore, None);
+        }
+    }
+}
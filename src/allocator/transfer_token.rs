@@ -0,0 +1,366 @@
+use std::{
+    future::Future,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        mpsc, Arc, Condvar, Mutex,
+    },
+    task::{Context as TaskContext, Poll, Waker},
+    thread::JoinHandle,
+    time::Duration,
+};
+
+use ash::vk;
+
+use crate::{Context, Result};
+
+/// Tracks a transfer staged with the [`crate::Allocator`].
+///
+/// A token is complete once the GPU has finished the transfer - and so it's safe to read the
+/// destination from the CPU, or to use it on the GPU without a barrier. Transfers that are
+/// executed in a frame can be used later in the same frame without waiting on their token, as
+/// the allocator records the barriers for you.
+///
+/// Tokens can be polled with [`TransferToken::is_complete`], blocked on with
+/// [`TransferToken::wait`], or `.await`ed.
+#[derive(Clone, Debug, Default)]
+pub struct TransferToken {
+    state: Arc<TokenState>,
+}
+
+#[derive(Debug, Default)]
+struct TokenState {
+    inner: Mutex<TokenInner>,
+    condvar: Condvar,
+}
+
+#[derive(Debug, Default)]
+struct TokenInner {
+    complete: bool,
+    wakers: Vec<Waker>,
+    /// Tokens created with [`TransferToken::join_all`] that include this one, along with how
+    /// many of their tokens are still incomplete.
+    dependents: Vec<(Arc<AtomicUsize>, TransferToken)>,
+}
+
+impl TransferToken {
+    pub(crate) fn new() -> TransferToken {
+        TransferToken::default()
+    }
+
+    /// True once the GPU has finished the transfer.
+    pub fn is_complete(&self) -> bool {
+        self.state.inner.lock().unwrap().complete
+    }
+
+    /// Block until the transfer is complete, or `timeout` has passed. Returns true if it's
+    /// complete.
+    ///
+    /// ## NOTE
+    /// The transfer must have been executed (eg. by drawing a frame) for this to ever return
+    /// true, so don't call it on the thread that draws frames without a timeout!
+    pub fn wait(&self, timeout: Duration) -> bool {
+        let inner = self.state.inner.lock().unwrap();
+        let (inner, _) = self
+            .state
+            .condvar
+            .wait_timeout_while(inner, timeout, |inner| !inner.complete)
+            .unwrap();
+        inner.complete
+    }
+
+    /// A token that's complete once every one of `tokens` is.
+    pub fn join_all(tokens: impl IntoIterator<Item = TransferToken>) -> TransferToken {
+        let joined = TransferToken::new();
+        let tokens = tokens.into_iter().collect::<Vec<_>>();
+
+        // Start at one, so that we can't complete until every token has been registered.
+        let remaining = Arc::new(AtomicUsize::new(tokens.len() + 1));
+        for token in tokens {
+            let mut inner = token.state.inner.lock().unwrap();
+            if inner.complete {
+                remaining.fetch_sub(1, Ordering::AcqRel);
+            } else {
+                inner.dependents.push((remaining.clone(), joined.clone()));
+            }
+        }
+
+        if remaining.fetch_sub(1, Ordering::AcqRel) == 1 {
+            joined.mark_completed();
+        }
+
+        joined
+    }
+
+    pub(crate) fn mark_completed(&self) {
+        let (wakers, dependents) = {
+            let mut inner = self.state.inner.lock().unwrap();
+            if inner.complete {
+                return;
+            }
+            inner.complete = true;
+            (
+                std::mem::take(&mut inner.wakers),
+                std::mem::take(&mut inner.dependents),
+            )
+        };
+
+        self.state.condvar.notify_all();
+        for waker in wakers {
+            waker.wake();
+        }
+        for (remaining, joined) in dependents {
+            if remaining.fetch_sub(1, Ordering::AcqRel) == 1 {
+                joined.mark_completed();
+            }
+        }
+    }
+}
+
+impl Future for TransferToken {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<()> {
+        let mut inner = self.state.inner.lock().unwrap();
+        if inner.complete {
+            return Poll::Ready(());
+        }
+
+        if !inner.wakers.iter().any(|waker| waker.will_wake(cx.waker())) {
+            inner.wakers.push(cx.waker().clone());
+        }
+        Poll::Pending
+    }
+}
+
+/// Tokens that will be complete once the timeline reaches `value`.
+struct Batch {
+    value: u64,
+    tokens: Vec<TransferToken>,
+}
+
+/// Completes transfer tokens as soon as the GPU has finished the submission that used them.
+///
+/// Submissions that execute transfers signal the next value of a timeline semaphore (see
+/// [`crate::Allocator::take_transfer_signal`]), and a background thread waits on each value and
+/// completes that submission's tokens.
+pub(crate) struct CompletionTracker {
+    context: Arc<Context>,
+    pub semaphore: vk::Semaphore,
+    /// The value the most recent signal will set.
+    last_signalled: u64,
+    /// Tokens that have been executed, but not yet attached to a signal.
+    unsignalled: Vec<TransferToken>,
+    sender: Option<mpsc::Sender<Batch>>,
+    shutdown: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+/// How often the completion thread checks whether it should shut down.
+const SHUTDOWN_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+impl CompletionTracker {
+    pub fn new(context: Arc<Context>) -> Result<CompletionTracker> {
+        let semaphore = unsafe {
+            context.device.create_semaphore(
+                &vk::SemaphoreCreateInfo::default().push_next(
+                    &mut vk::SemaphoreTypeCreateInfo::default()
+                        .semaphore_type(vk::SemaphoreType::TIMELINE)
+                        .initial_value(0),
+                ),
+                None,
+            )
+        }?;
+        context.set_debug_label(semaphore, "[lazy_vulkan] Transfers Complete Timeline");
+
+        let (sender, receiver) = mpsc::channel::<Batch>();
+        let shutdown = Arc::new(AtomicBool::new(false));
+
+        let thread = std::thread::Builder::new()
+            .name("lazy_vulkan transfers".into())
+            .spawn({
+                let context = context.clone();
+                let shutdown = shutdown.clone();
+                move || {
+                    for batch in receiver {
+                        if !wait_for_value(&context, semaphore, batch.value, &shutdown) {
+                            return;
+                        }
+
+                        for token in batch.tokens {
+                            token.mark_completed();
+                        }
+                    }
+                }
+            });
+
+        let thread = match thread {
+            Ok(thread) => thread,
+            Err(e) => {
+                unsafe { context.device.destroy_semaphore(semaphore, None) };
+                return Err(e.into());
+            }
+        };
+
+        Ok(CompletionTracker {
+            context,
+            semaphore,
+            last_signalled: 0,
+            unsignalled: Vec::new(),
+            sender: Some(sender),
+            shutdown,
+            thread: Some(thread),
+        })
+    }
+
+    /// `token`'s transfer has been recorded, and will be complete once the next signal is.
+    pub fn executed(&mut self, token: TransferToken) {
+        self.unsignalled.push(token);
+    }
+
+    /// If any transfers have been executed since this was last called, the next value of the
+    /// timeline for a submission to signal.
+    pub fn take_signal(&mut self) -> Option<u64> {
+        if self.unsignalled.is_empty() {
+            return None;
+        }
+
+        self.last_signalled += 1;
+        let batch = Batch {
+            value: self.last_signalled,
+            tokens: std::mem::take(&mut self.unsignalled),
+        };
+        if let Some(sender) = &self.sender {
+            let _ = sender.send(batch);
+        }
+
+        Some(self.last_signalled)
+    }
+
+    /// Forget about any tokens that were never attached to a signal. Their frame will complete
+    /// them instead.
+    pub fn clear_unsignalled(&mut self) {
+        self.unsignalled.clear();
+    }
+}
+
+/// Block until `semaphore` reaches `value`. Returns false if we were asked to shut down first,
+/// or the wait failed.
+fn wait_for_value(
+    context: &Context,
+    semaphore: vk::Semaphore,
+    value: u64,
+    shutdown: &AtomicBool,
+) -> bool {
+    loop {
+        let result = unsafe {
+            context.device.wait_semaphores(
+                &vk::SemaphoreWaitInfo::default()
+                    .semaphores(&[semaphore])
+                    .values(&[value]),
+                SHUTDOWN_POLL_INTERVAL.as_nanos() as u64,
+            )
+        };
+
+        match result {
+            Ok(()) => return true,
+            Err(vk::Result::TIMEOUT) if !shutdown.load(Ordering::Relaxed) => continue,
+            Err(vk::Result::TIMEOUT) => return false,
+            Err(e) => {
+                log::error!("[lazy_vulkan] Failed to wait for transfers to complete: {e}");
+                return false;
+            }
+        }
+    }
+}
+
+impl Drop for CompletionTracker {
+    fn drop(&mut self) {
+        // NOTE: The allocator waits for the device to be idle before we're dropped, so the thread
+        // should finish whatever it's waiting for straight away.
+        self.shutdown.store(true, Ordering::Relaxed);
+        self.sender.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+
+        unsafe { self.context.device.destroy_semaphore(self.semaphore, None) };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        future::Future,
+        pin::Pin,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        task::{Context as TaskContext, Poll, Wake, Waker},
+        time::Duration,
+    };
+
+    use super::TransferToken;
+
+    #[test]
+    fn test_join_all() {
+        let a = TransferToken::new();
+        let b = TransferToken::new();
+        let joined = TransferToken::join_all([a.clone(), b.clone()]);
+        assert!(!joined.is_complete());
+        assert!(!joined.wait(Duration::from_millis(1)));
+
+        a.mark_completed();
+        assert!(!joined.is_complete());
+
+        let waiter = std::thread::spawn({
+            let joined = joined.clone();
+            move || joined.wait(Duration::from_secs(10))
+        });
+        b.mark_completed();
+        assert!(waiter.join().unwrap());
+        assert!(joined.is_complete());
+
+        // Joining tokens that are already complete completes straight away, as does joining none
+        assert!(TransferToken::join_all([a, b]).is_complete());
+        assert!(TransferToken::join_all([]).is_complete());
+    }
+
+    #[derive(Default)]
+    struct CountingWaker(AtomicUsize);
+
+    impl Wake for CountingWaker {
+        fn wake(self: Arc<Self>) {
+            self.0.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    #[test]
+    fn test_poll() {
+        let counter = Arc::new(CountingWaker::default());
+        let waker = Waker::from(counter.clone());
+        let mut cx = TaskContext::from_waker(&waker);
+
+        let a = TransferToken::new();
+        let b = TransferToken::new();
+        let mut token = a.clone();
+        let mut joined = TransferToken::join_all([a.clone(), b.clone()]);
+
+        // Polling twice with the same waker only registers it once..
+        assert_eq!(Pin::new(&mut token).poll(&mut cx), Poll::Pending);
+        assert_eq!(Pin::new(&mut token).poll(&mut cx), Poll::Pending);
+        assert_eq!(Pin::new(&mut joined).poll(&mut cx), Poll::Pending);
+
+        // ..so completing it wakes us once.
+        a.mark_completed();
+        assert_eq!(counter.0.load(Ordering::Relaxed), 1);
+        assert_eq!(Pin::new(&mut token).poll(&mut cx), Poll::Ready(()));
+        assert_eq!(Pin::new(&mut joined).poll(&mut cx), Poll::Pending);
+
+        // Joined tokens wake us once all of theirs are complete.
+        b.mark_completed();
+        assert_eq!(counter.0.load(Ordering::Relaxed), 2);
+        assert_eq!(Pin::new(&mut joined).poll(&mut cx), Poll::Ready(()));
+    }
+}
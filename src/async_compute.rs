@@ -0,0 +1,214 @@
+use std::sync::Arc;
+
+use ash::vk;
+
+use crate::{Context, Result};
+
+/// Every stage that might read the results of compute work. The graphics submission waits for
+/// async compute at these stages.
+pub(crate) const COMPUTE_CONSUMER_STAGES: vk::PipelineStageFlags2 =
+    vk::PipelineStageFlags2::from_raw(
+        vk::PipelineStageFlags2::DRAW_INDIRECT.as_raw()
+            | vk::PipelineStageFlags2::INDEX_INPUT.as_raw()
+            | vk::PipelineStageFlags2::VERTEX_ATTRIBUTE_INPUT.as_raw()
+            | vk::PipelineStageFlags2::VERTEX_SHADER.as_raw()
+            | vk::PipelineStageFlags2::FRAGMENT_SHADER.as_raw()
+            | vk::PipelineStageFlags2::COMPUTE_SHADER.as_raw(),
+    );
+
+/// Submits [`crate::SubRenderer::record_compute`] work to the device's dedicated compute queue,
+/// so it can run alongside rendering.
+///
+/// Every submission signals the next value of a timeline semaphore, which the frame's graphics
+/// submission waits on. Going the other way, every graphics submission signals a second timeline
+/// semaphore, and compute waits for the previous frame's before it starts - so it can't overwrite
+/// anything that frame is still reading.
+pub(crate) struct AsyncCompute {
+    context: Arc<Context>,
+    queue: vk::Queue,
+    command_pool: vk::CommandPool,
+    /// One per frame in flight. The graphics submission waits for compute, so once a frame's
+    /// fence is signalled its command buffer can be reused.
+    command_buffers: Vec<vk::CommandBuffer>,
+    semaphore: vk::Semaphore,
+    /// The value the most recent submission will signal.
+    last_submitted: u64,
+    /// Signalled by the graphics queue.
+    graphics_semaphore: vk::Semaphore,
+    /// The value the most recent graphics submission will signal.
+    graphics_submitted: u64,
+}
+
+impl AsyncCompute {
+    /// Returns `None` if the device doesn't have a dedicated compute queue.
+    pub fn new(context: Arc<Context>) -> Result<Option<Self>> {
+        let (Some(queue), Some(family)) = (context.compute_queue, context.queue_families.compute)
+        else {
+            return Ok(None);
+        };
+
+        let device = &context.device;
+        let command_pool = unsafe {
+            device.create_command_pool(
+                &vk::CommandPoolCreateInfo::default()
+                    .queue_family_index(family)
+                    .flags(vk::CommandPoolCreateFlags::RESET_COMMAND_BUFFER),
+                None,
+            )
+        }?;
+
+        let command_buffers = match unsafe {
+            device.allocate_command_buffers(
+                &vk::CommandBufferAllocateInfo::default()
+                    .command_pool(command_pool)
+                    .command_buffer_count(context.frames_in_flight as u32),
+            )
+        } {
+            Ok(command_buffers) => command_buffers,
+            Err(e) => {
+                unsafe { device.destroy_command_pool(command_pool, None) };
+                return Err(e.into());
+            }
+        };
+
+        let create_timeline = || unsafe {
+            device.create_semaphore(
+                &vk::SemaphoreCreateInfo::default().push_next(
+                    &mut vk::SemaphoreTypeCreateInfo::default()
+                        .semaphore_type(vk::SemaphoreType::TIMELINE)
+                        .initial_value(0),
+                ),
+                None,
+            )
+        };
+
+        let semaphore = match create_timeline() {
+            Ok(semaphore) => semaphore,
+            Err(e) => {
+                unsafe { device.destroy_command_pool(command_pool, None) };
+                return Err(e.into());
+            }
+        };
+        let graphics_semaphore = match create_timeline() {
+            Ok(semaphore) => semaphore,
+            Err(e) => {
+                unsafe {
+                    device.destroy_semaphore(semaphore, None);
+                    device.destroy_command_pool(command_pool, None);
+                }
+                return Err(e.into());
+            }
+        };
+        context.set_debug_label(semaphore, "[lazy_vulkan] Async Compute Timeline");
+        context.set_debug_label(graphics_semaphore, "[lazy_vulkan] Graphics Timeline");
+
+        log::debug!("[lazy_vulkan] Using async compute on queue family {family}");
+
+        Ok(Some(Self {
+            context,
+            queue,
+            command_pool,
+            command_buffers,
+            semaphore,
+            last_submitted: 0,
+            graphics_semaphore,
+            graphics_submitted: 0,
+        }))
+    }
+
+    /// Begin recording `frame_index`'s command buffer. The GPU must be done with that frame!
+    pub fn begin(&mut self, frame_index: usize) -> Result<vk::CommandBuffer> {
+        let command_buffer = self.command_buffers[frame_index];
+        unsafe {
+            self.context.device.begin_command_buffer(
+                command_buffer,
+                &vk::CommandBufferBeginInfo::default()
+                    .flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT),
+            )
+        }?;
+
+        Ok(command_buffer)
+    }
+
+    /// Submit `frame_index`'s command buffer once `wait_semaphore_infos` and the last graphics
+    /// submission have been signalled. Returns the wait the graphics submission needs.
+    pub fn submit(
+        &mut self,
+        frame_index: usize,
+        wait_semaphore_infos: &[vk::SemaphoreSubmitInfo],
+    ) -> Result<vk::SemaphoreSubmitInfo<'static>> {
+        let command_buffer = self.command_buffers[frame_index];
+        let value = self.last_submitted + 1;
+        let graphics_wait = (self.graphics_submitted > 0).then(|| {
+            vk::SemaphoreSubmitInfo::default()
+                .semaphore(self.graphics_semaphore)
+                .value(self.graphics_submitted)
+                .stage_mask(vk::PipelineStageFlags2::ALL_COMMANDS)
+        });
+        let wait_semaphore_infos = wait_semaphore_infos
+            .iter()
+            .copied()
+            .chain(graphics_wait)
+            .collect::<Vec<_>>();
+        unsafe {
+            self.context.device.end_command_buffer(command_buffer)?;
+            self.context.queue_submit2(
+                self.queue,
+                &[vk::SubmitInfo2::default()
+                    .command_buffer_infos(&[
+                        vk::CommandBufferSubmitInfo::default().command_buffer(command_buffer)
+                    ])
+                    .wait_semaphore_infos(&wait_semaphore_infos)
+                    .signal_semaphore_infos(&[vk::SemaphoreSubmitInfo::default()
+                        .semaphore(self.semaphore)
+                        .value(value)
+                        .stage_mask(vk::PipelineStageFlags2::ALL_COMMANDS)])],
+                vk::Fence::null(),
+            )?;
+        }
+
+        self.last_submitted = value;
+        Ok(vk::SemaphoreSubmitInfo::default()
+            .semaphore(self.semaphore)
+            .value(value)
+            .stage_mask(COMPUTE_CONSUMER_STAGES))
+    }
+
+    /// End `frame_index`'s command buffer without submitting it, as nothing was recorded.
+    pub fn skip(&mut self, frame_index: usize) -> Result<()> {
+        unsafe {
+            self.context
+                .device
+                .end_command_buffer(self.command_buffers[frame_index])
+        }?;
+        Ok(())
+    }
+
+    /// What the next graphics submission should signal, once everything in it is finished.
+    pub fn graphics_signal(&self) -> vk::SemaphoreSubmitInfo<'static> {
+        vk::SemaphoreSubmitInfo::default()
+            .semaphore(self.graphics_semaphore)
+            .value(self.graphics_submitted + 1)
+            .stage_mask(vk::PipelineStageFlags2::ALL_COMMANDS)
+    }
+
+    /// Record that the graphics submission with [`AsyncCompute::graphics_signal`] went through,
+    /// so the next compute submission waits for it.
+    pub fn graphics_submitted(&mut self) {
+        self.graphics_submitted += 1;
+    }
+}
+
+impl Drop for AsyncCompute {
+    fn drop(&mut self) {
+        unsafe {
+            self.context
+                .device
+                .destroy_command_pool(self.command_pool, None);
+            self.context.device.destroy_semaphore(self.semaphore, None);
+            self.context
+                .device
+                .destroy_semaphore(self.graphics_semaphore, None);
+        }
+    }
+}
@@ -0,0 +1,346 @@
+use std::ptr::NonNull;
+
+use ash::vk;
+
+use crate::{Context, Error, Result};
+
+/// Each recorder gets two markers per frame in flight: the last of its events the GPU reached,
+/// and the last one it finished everything before.
+const MARKERS_PER_FRAME: usize = 2;
+
+/// Records which marker scopes the GPU has started and finished, so that if the device is lost we
+/// can tell which one it was working on.
+///
+/// Every [`Context::begin_marker`] and [`Context::end_marker`] in the frame's command buffers is
+/// numbered, and the number is written to a host-visible buffer with `VK_AMD_buffer_marker` -
+/// once at the top of the pipe (the GPU has started everything before it), and once at the
+/// bottom (the GPU has finished everything before it). Those writes survive a hang, so we can
+/// read them back afterwards.
+///
+/// Like [`crate::profiler::Profiler`], events come from a number of recorders: the frame's own
+/// command buffer, followed by the secondary command buffers recorded on each thread. The GPU
+/// executes each recorder's events in the order they were recorded, but not in order with the
+/// other recorders', so each one is numbered and marked separately.
+pub(crate) struct Breadcrumbs {
+    buffer_marker: ash::amd::buffer_marker::Device,
+    /// One per recorder.
+    recorders: Vec<MarkerBuffer>,
+    /// One per frame in flight.
+    frames: Vec<FrameTrail>,
+}
+
+// SAFETY: `markers` is only accessed through `&mut self`, and points to memory we own.
+unsafe impl Send for Breadcrumbs {}
+
+struct MarkerBuffer {
+    buffer: vk::Buffer,
+    memory: vk::DeviceMemory,
+    /// `MARKERS_PER_FRAME` per frame in flight.
+    markers: NonNull<u32>,
+}
+
+#[derive(Default)]
+struct FrameTrail {
+    frame: u64,
+    /// The number of the last event each recorder recorded.
+    last_events: Vec<u32>,
+    scopes: Vec<Crumb>,
+    /// Indices into `scopes` that haven't been ended yet, for each recorder.
+    stacks: Vec<Vec<usize>>,
+}
+
+impl FrameTrail {
+    /// `scope`'s name, prefixed with the names of the scopes it's nested in.
+    fn path_to(&self, scope: &Crumb) -> String {
+        let mut path = vec![scope.name.as_str()];
+        let mut parent = scope.parent;
+        while let Some(index) = parent {
+            path.push(&self.scopes[index].name);
+            parent = self.scopes[index].parent;
+        }
+        path.reverse();
+        path.join(" > ")
+    }
+}
+
+struct Crumb {
+    name: String,
+    recorder: usize,
+    parent: Option<usize>,
+    begin: u32,
+    end: Option<u32>,
+}
+
+impl Breadcrumbs {
+    /// Only the frame's own command buffer is marked until [`Breadcrumbs::reserve_recorders`] is
+    /// called.
+    pub fn new(context: &Context) -> Result<Self> {
+        let mut breadcrumbs = Self {
+            buffer_marker: ash::amd::buffer_marker::Device::new(&context.instance, &context.device),
+            recorders: Vec::new(),
+            frames: Vec::new(),
+        };
+        breadcrumbs
+            .frames
+            .resize_with(context.frames_in_flight, Default::default);
+
+        if let Err(e) = breadcrumbs.reserve_recorders(context, 1) {
+            breadcrumbs.destroy(&context.device);
+            return Err(e);
+        }
+        Ok(breadcrumbs)
+    }
+
+    /// Make sure there are markers for at least `recorders` recorders.
+    pub fn reserve_recorders(&mut self, context: &Context, recorders: usize) -> Result<()> {
+        while self.recorders.len() < recorders {
+            let mut marker_buffer = MarkerBuffer::new(context)?;
+            for frame_index in 0..self.frames.len() {
+                marker_buffer.reset(frame_index);
+            }
+            self.recorders.push(marker_buffer);
+        }
+
+        for trail in &mut self.frames {
+            trail.last_events.resize(self.recorders.len(), 0);
+            trail.stacks.resize_with(self.recorders.len(), Vec::new);
+        }
+        Ok(())
+    }
+
+    /// Forget the last frame that used `frame_index`'s slot. The GPU must be done with it!
+    pub fn begin_frame(&mut self, frame_index: usize, frame: u64) {
+        for recorder in &mut self.recorders {
+            recorder.reset(frame_index);
+        }
+        let trail = &mut self.frames[frame_index];
+        trail.frame = frame;
+        trail.last_events.fill(0);
+        trail.scopes.clear();
+        trail.stacks.iter_mut().for_each(Vec::clear);
+    }
+
+    pub fn begin_scope(
+        &mut self,
+        command_buffer: vk::CommandBuffer,
+        frame_index: usize,
+        recorder: usize,
+        name: &str,
+    ) {
+        // Secondary command buffers are executed inside whichever of the frame's scopes was open
+        // while they were recorded.
+        let stacks = &self.frames[frame_index].stacks;
+        let parent = match stacks[recorder].last() {
+            Some(&parent) => Some(parent),
+            None if recorder > 0 => stacks[0].last().copied(),
+            None => None,
+        };
+
+        let event = self.write_event(command_buffer, frame_index, recorder);
+        let trail = &mut self.frames[frame_index];
+        trail.stacks[recorder].push(trail.scopes.len());
+        trail.scopes.push(Crumb {
+            name: name.to_string(),
+            recorder,
+            parent,
+            begin: event,
+            end: None,
+        });
+    }
+
+    pub fn end_scope(
+        &mut self,
+        command_buffer: vk::CommandBuffer,
+        frame_index: usize,
+        recorder: usize,
+    ) {
+        if self.frames[frame_index].stacks[recorder].is_empty() {
+            return;
+        }
+
+        let event = self.write_event(command_buffer, frame_index, recorder);
+        let trail = &mut self.frames[frame_index];
+        let index = trail.stacks[recorder].pop().unwrap();
+        trail.scopes[index].end = Some(event);
+    }
+
+    /// Every scope the GPU had started but not finished, in every frame in flight, oldest frame
+    /// first - along with the scope each recorder finished last.
+    pub fn trail(&mut self) -> Vec<String> {
+        let mut frame_indices = (0..self.frames.len()).collect::<Vec<_>>();
+        frame_indices.sort_by_key(|&frame_index| self.frames[frame_index].frame);
+
+        let mut lines = Vec::new();
+        for frame_index in frame_indices {
+            let markers = self
+                .recorders
+                .iter_mut()
+                .map(|recorder| recorder.read(frame_index))
+                .collect::<Vec<_>>();
+            let trail = &self.frames[frame_index];
+            if trail.scopes.is_empty() {
+                continue;
+            }
+
+            for (recorder, [reached, finished]) in markers.into_iter().enumerate() {
+                let last_event = trail.last_events[recorder];
+                if finished >= last_event {
+                    continue;
+                }
+
+                let recorder_name = match recorder {
+                    0 => "frame command buffer".to_string(),
+                    recorder => format!("secondary command buffers {}", recorder - 1),
+                };
+                lines.push(format!(
+                    "Frame {}, {recorder_name}: reached event {reached}, finished event {finished} of {last_event}",
+                    trail.frame
+                ));
+
+                let scopes = trail
+                    .scopes
+                    .iter()
+                    .filter(|scope| scope.recorder == recorder);
+                let last_finished = scopes
+                    .clone()
+                    .filter(|scope| scope.end.is_some_and(|end| end <= finished))
+                    .max_by_key(|scope| scope.end);
+                if let Some(scope) = last_finished {
+                    lines.push(format!("  last finished: {}", scope.name));
+                }
+
+                for scope in scopes {
+                    let finished = scope.end.is_some_and(|end| end <= finished);
+                    if scope.begin <= reached && !finished {
+                        lines.push(format!("  in progress:   {}", trail.path_to(scope)));
+                    }
+                }
+            }
+        }
+
+        lines
+    }
+
+    pub fn destroy(&mut self, device: &ash::Device) {
+        for recorder in self.recorders.drain(..) {
+            unsafe {
+                device.destroy_buffer(recorder.buffer, None);
+                device.free_memory(recorder.memory, None);
+            }
+        }
+    }
+
+    fn write_event(
+        &mut self,
+        command_buffer: vk::CommandBuffer,
+        frame_index: usize,
+        recorder: usize,
+    ) -> u32 {
+        let trail = &mut self.frames[frame_index];
+        trail.last_events[recorder] += 1;
+        let event = trail.last_events[recorder];
+
+        let offset = (frame_index * MARKERS_PER_FRAME * std::mem::size_of::<u32>()) as u64;
+        let buffer = self.recorders[recorder].buffer;
+        unsafe {
+            self.buffer_marker.cmd_write_buffer_marker(
+                command_buffer,
+                vk::PipelineStageFlags::TOP_OF_PIPE,
+                buffer,
+                offset,
+                event,
+            );
+            self.buffer_marker.cmd_write_buffer_marker(
+                command_buffer,
+                vk::PipelineStageFlags::BOTTOM_OF_PIPE,
+                buffer,
+                offset + std::mem::size_of::<u32>() as u64,
+                event,
+            );
+        }
+
+        event
+    }
+}
+
+impl MarkerBuffer {
+    fn new(context: &Context) -> Result<Self> {
+        let device = &context.device;
+        let size = (context.frames_in_flight * MARKERS_PER_FRAME * std::mem::size_of::<u32>())
+            as vk::DeviceSize;
+
+        let buffer = unsafe {
+            device.create_buffer(
+                &vk::BufferCreateInfo::default()
+                    .size(size)
+                    .usage(vk::BufferUsageFlags::TRANSFER_DST),
+                None,
+            )
+        }?;
+
+        let requirements = unsafe { device.get_buffer_memory_requirements(buffer) };
+        let memory_properties =
+            vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_COHERENT;
+        let Some(memory_type_index) =
+            context.find_memory_type_index(&requirements, memory_properties)
+        else {
+            unsafe { device.destroy_buffer(buffer, None) };
+            return Err(Error::NoSuitableMemoryType(memory_properties));
+        };
+
+        let allocate_and_map = || -> Result<(vk::DeviceMemory, NonNull<u32>)> {
+            let memory = unsafe {
+                device.allocate_memory(
+                    &vk::MemoryAllocateInfo::default()
+                        .allocation_size(requirements.size)
+                        .memory_type_index(memory_type_index),
+                    None,
+                )
+            }?;
+
+            let mapped = unsafe {
+                device.bind_buffer_memory(buffer, memory, 0).and_then(|_| {
+                    device.map_memory(memory, 0, vk::WHOLE_SIZE, vk::MemoryMapFlags::empty())
+                })
+            };
+            match mapped {
+                Ok(pointer) => Ok((memory, NonNull::new(pointer.cast()).unwrap())),
+                Err(e) => {
+                    unsafe { device.free_memory(memory, None) };
+                    Err(e.into())
+                }
+            }
+        };
+
+        let (memory, markers) = match allocate_and_map() {
+            Ok(allocation) => allocation,
+            Err(e) => {
+                unsafe { device.destroy_buffer(buffer, None) };
+                return Err(e);
+            }
+        };
+        context.set_debug_label(buffer, "[lazy_vulkan] Breadcrumbs");
+
+        Ok(Self {
+            buffer,
+            memory,
+            markers,
+        })
+    }
+
+    fn markers(&mut self, frame_index: usize) -> *mut u32 {
+        unsafe { self.markers.as_ptr().add(frame_index * MARKERS_PER_FRAME) }
+    }
+
+    fn reset(&mut self, frame_index: usize) {
+        let markers = self.markers(frame_index);
+        for i in 0..MARKERS_PER_FRAME {
+            unsafe { markers.add(i).write_volatile(0) };
+        }
+    }
+
+    fn read(&mut self, frame_index: usize) -> [u32; MARKERS_PER_FRAME] {
+        let markers = self.markers(frame_index);
+        std::array::from_fn(|i| unsafe { markers.add(i).read_volatile() })
+    }
+}
@@ -0,0 +1,355 @@
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    path::Path,
+    time::{Duration, Instant},
+};
+
+use ash::vk;
+
+use crate::Result;
+
+const PROCESS_ID: u32 = 1;
+const CPU_TRACK: u32 = 1;
+const GPU_TRACK: u32 = 2;
+/// Secondary command buffers recorded on other threads get a CPU track each, starting here.
+const SECONDARY_CPU_TRACKS: u32 = 3;
+/// Calibrations whose host and device timestamps might be further apart than this are retried.
+const MAX_DEVIATION_NS: u64 = 50_000;
+const CALIBRATION_ATTEMPTS: usize = 4;
+
+/// The time domain `Instant` is measured in, if Vulkan can calibrate against it.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub(crate) const HOST_TIME_DOMAIN: Option<vk::TimeDomainEXT> =
+    Some(vk::TimeDomainEXT::CLOCK_MONOTONIC);
+#[cfg(windows)]
+pub(crate) const HOST_TIME_DOMAIN: Option<vk::TimeDomainEXT> =
+    Some(vk::TimeDomainEXT::QUERY_PERFORMANCE_COUNTER);
+#[cfg(not(any(target_os = "linux", target_os = "android", windows)))]
+pub(crate) const HOST_TIME_DOMAIN: Option<vk::TimeDomainEXT> = None;
+
+/// Streams profiled frames to a JSON file in the Chrome trace event format, which Perfetto and
+/// `chrome://tracing` can open.
+///
+/// Every scope is written to two tracks: how long it took to record on the CPU, and how long it
+/// took to execute on the GPU. Scopes recorded into secondary command buffers get a CPU track for
+/// each thread, as they overlap. GPU timestamps are moved onto the CPU's clock with
+/// `VK_EXT_calibrated_timestamps` - without it, only the CPU track is written.
+pub(crate) struct ChromeTrace {
+    writer: BufWriter<File>,
+    /// Every event is timed relative to this. Scopes that began before it are skipped.
+    epoch: Instant,
+    calibrated_timestamps: Option<ash::ext::calibrated_timestamps::Device>,
+    /// [`HOST_TIME_DOMAIN`], if the device supports it.
+    host_time_domain: Option<vk::TimeDomainEXT>,
+    /// Whether the next event needs a comma before it.
+    wrote_event: bool,
+    /// How many of the secondary command buffer CPU tracks have been named.
+    secondary_cpu_tracks: u32,
+}
+
+/// The GPU's timestamp counter at a known point on the CPU's clock.
+pub(crate) struct Calibration {
+    pub instant: Instant,
+    pub ticks: u64,
+}
+
+impl ChromeTrace {
+    pub fn create(
+        path: &Path,
+        calibrated_timestamps: Option<ash::ext::calibrated_timestamps::Device>,
+        host_time_domain: Option<vk::TimeDomainEXT>,
+    ) -> Result<Self> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        writer.write_all(b"{\"traceEvents\":[\n")?;
+
+        let mut trace = Self {
+            writer,
+            epoch: Instant::now(),
+            calibrated_timestamps,
+            host_time_domain,
+            wrote_event: false,
+            secondary_cpu_tracks: 0,
+        };
+        trace.metadata("process_name", 0, "lazy_vulkan")?;
+        trace.metadata("thread_name", CPU_TRACK, "CPU")?;
+        if trace.calibrated_timestamps.is_some() {
+            trace.metadata("thread_name", GPU_TRACK, "GPU")?;
+        }
+
+        Ok(trace)
+    }
+
+    /// Read the GPU's timestamp counter. `None` if the device can't tell us.
+    pub fn calibrate(&self) -> Option<Calibration> {
+        let calibrated_timestamps = self.calibrated_timestamps.as_ref()?;
+        let Some(host_time_domain) = self.host_time_domain else {
+            return self.calibrate_device_only(calibrated_timestamps);
+        };
+
+        // The host and device clocks are read together, so keep the closest pair we can get.
+        let mut best: Option<(Calibration, u64)> = None;
+        for _ in 0..CALIBRATION_ATTEMPTS {
+            let result = unsafe {
+                calibrated_timestamps.get_calibrated_timestamps(&[
+                    vk::CalibratedTimestampInfoEXT::default()
+                        .time_domain(vk::TimeDomainEXT::DEVICE),
+                    vk::CalibratedTimestampInfoEXT::default().time_domain(host_time_domain),
+                ])
+            };
+            let (timestamps, max_deviation) = match result {
+                Ok(result) => result,
+                Err(e) => {
+                    log::warn!("[lazy_vulkan] Unable to calibrate timestamps: {e}");
+                    return None;
+                }
+            };
+
+            let calibration = Calibration {
+                instant: host_instant(timestamps[1])?,
+                ticks: timestamps[0],
+            };
+            if best.as_ref().is_none_or(|(_, best)| max_deviation < *best) {
+                best = Some((calibration, max_deviation));
+            }
+            if max_deviation <= MAX_DEVIATION_NS {
+                break;
+            }
+        }
+
+        best.map(|(calibration, _)| calibration)
+    }
+
+    /// Without a host time domain, time the device's clock with `Instant` either side of the
+    /// call instead.
+    fn calibrate_device_only(
+        &self,
+        calibrated_timestamps: &ash::ext::calibrated_timestamps::Device,
+    ) -> Option<Calibration> {
+        let before = Instant::now();
+        let result = unsafe {
+            calibrated_timestamps
+                .get_calibrated_timestamps(&[vk::CalibratedTimestampInfoEXT::default()
+                    .time_domain(vk::TimeDomainEXT::DEVICE)])
+        };
+        let after = Instant::now();
+
+        match result {
+            Ok((timestamps, _)) => Some(Calibration {
+                instant: before + (after - before) / 2,
+                ticks: timestamps[0],
+            }),
+            Err(e) => {
+                log::warn!("[lazy_vulkan] Unable to calibrate timestamps: {e}");
+                None
+            }
+        }
+    }
+
+    /// `recorder` is the profiler's: `0` for the frame's own command buffer, and one per
+    /// secondary command buffer thread after that.
+    pub fn cpu_scope(
+        &mut self,
+        name: &str,
+        frame: u64,
+        recorder: usize,
+        start: Instant,
+        duration: Duration,
+    ) -> Result<()> {
+        let track = match recorder {
+            0 => CPU_TRACK,
+            recorder => {
+                let thread = recorder as u32 - 1;
+                while self.secondary_cpu_tracks <= thread {
+                    let name = format!("CPU (secondary {})", self.secondary_cpu_tracks);
+                    self.metadata(
+                        "thread_name",
+                        SECONDARY_CPU_TRACKS + self.secondary_cpu_tracks,
+                        &name,
+                    )?;
+                    self.secondary_cpu_tracks += 1;
+                }
+                SECONDARY_CPU_TRACKS + thread
+            }
+        };
+        self.complete_event(track, name, frame, start, duration)
+    }
+
+    pub fn gpu_scope(
+        &mut self,
+        name: &str,
+        frame: u64,
+        start: Instant,
+        duration: Duration,
+    ) -> Result<()> {
+        self.complete_event(GPU_TRACK, name, frame, start, duration)
+    }
+
+    /// Close the JSON and flush it to disk.
+    pub fn finish(mut self) -> Result<()> {
+        self.writer.write_all(b"\n]}\n")?;
+        self.writer.flush()?;
+        Ok(())
+    }
+
+    fn complete_event(
+        &mut self,
+        track: u32,
+        name: &str,
+        frame: u64,
+        start: Instant,
+        duration: Duration,
+    ) -> Result<()> {
+        let Some(since_epoch) = start.checked_duration_since(self.epoch) else {
+            return Ok(());
+        };
+
+        self.begin_event()?;
+        self.writer.write_all(b"{\"name\":")?;
+        write_json_string(&mut self.writer, name)?;
+        write!(
+            self.writer,
+            ",\"ph\":\"X\",\"ts\":{:.3},\"dur\":{:.3},\"pid\":{PROCESS_ID},\"tid\":{track},\"args\":{{\"frame\":{frame}}}}}",
+            micros(since_epoch),
+            micros(duration),
+        )?;
+        Ok(())
+    }
+
+    fn metadata(&mut self, kind: &str, track: u32, name: &str) -> Result<()> {
+        self.begin_event()?;
+        write!(
+            self.writer,
+            "{{\"name\":\"{kind}\",\"ph\":\"M\",\"pid\":{PROCESS_ID},\"tid\":{track},\"args\":{{\"name\":"
+        )?;
+        write_json_string(&mut self.writer, name)?;
+        self.writer.write_all(b"}}")?;
+        Ok(())
+    }
+
+    fn begin_event(&mut self) -> Result<()> {
+        if self.wrote_event {
+            self.writer.write_all(b",\n")?;
+        }
+        self.wrote_event = true;
+        Ok(())
+    }
+}
+
+fn micros(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1_000_000.
+}
+
+fn write_json_string(writer: &mut impl Write, value: &str) -> std::io::Result<()> {
+    writer.write_all(b"\"")?;
+    for c in value.chars() {
+        match c {
+            '"' => writer.write_all(b"\\\"")?,
+            '\\' => writer.write_all(b"\\\\")?,
+            '\n' => writer.write_all(b"\\n")?,
+            c if (c as u32) < 0x20 => write!(writer, "\\u{:04x}", c as u32)?,
+            c => write!(writer, "{c}")?,
+        }
+    }
+    writer.write_all(b"\"")
+}
+
+/// Turn a timestamp from [`HOST_TIME_DOMAIN`] into an `Instant`, by reading the same clock again
+/// alongside `Instant::now`.
+fn host_instant(timestamp: u64) -> Option<Instant> {
+    let now = Instant::now();
+    let host_now = host_clock::now();
+    match host_now.checked_sub(timestamp) {
+        Some(ago) => now.checked_sub(host_clock::to_duration(ago)),
+        None => now.checked_add(host_clock::to_duration(timestamp - host_now)),
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+mod host_clock {
+    use std::{ffi::c_long, time::Duration};
+
+    const CLOCK_MONOTONIC: i32 = 1;
+
+    #[repr(C)]
+    struct Timespec {
+        tv_sec: c_long,
+        tv_nsec: c_long,
+    }
+
+    extern "C" {
+        fn clock_gettime(clock_id: i32, tp: *mut Timespec) -> i32;
+    }
+
+    /// `CLOCK_MONOTONIC`, in nanoseconds.
+    pub fn now() -> u64 {
+        let mut time = Timespec {
+            tv_sec: 0,
+            tv_nsec: 0,
+        };
+        // SAFETY: `time` is a valid `struct timespec`, and `CLOCK_MONOTONIC` always exists.
+        unsafe { clock_gettime(CLOCK_MONOTONIC, &mut time) };
+        time.tv_sec as u64 * 1_000_000_000 + time.tv_nsec as u64
+    }
+
+    pub fn to_duration(ticks: u64) -> Duration {
+        Duration::from_nanos(ticks)
+    }
+}
+
+#[cfg(windows)]
+mod host_clock {
+    use std::time::Duration;
+
+    extern "system" {
+        fn QueryPerformanceCounter(count: *mut i64) -> i32;
+        fn QueryPerformanceFrequency(frequency: *mut i64) -> i32;
+    }
+
+    /// The performance counter, in its own ticks.
+    pub fn now() -> u64 {
+        let mut count = 0;
+        // SAFETY: This can't fail on Windows XP or later.
+        unsafe { QueryPerformanceCounter(&mut count) };
+        count as u64
+    }
+
+    pub fn to_duration(ticks: u64) -> Duration {
+        let mut frequency = 0;
+        // SAFETY: As above.
+        unsafe { QueryPerformanceFrequency(&mut frequency) };
+        Duration::from_secs_f64(ticks as f64 / frequency as f64)
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android", windows)))]
+mod host_clock {
+    use std::time::Duration;
+
+    // There's no `HOST_TIME_DOMAIN` here, so these are never called.
+    pub fn now() -> u64 {
+        unreachable!()
+    }
+
+    pub fn to_duration(_ticks: u64) -> Duration {
+        unreachable!()
+    }
+}
+
+#[cfg(all(test, any(target_os = "linux", target_os = "android", windows)))]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::{host_clock, host_instant};
+
+    #[test]
+    fn test_host_instant() {
+        let before = Instant::now();
+        let instant = host_instant(host_clock::now()).unwrap();
+        let after = Instant::now();
+
+        // Allow a little slop for the clocks being read at slightly different times.
+        let slop = Duration::from_millis(1);
+        assert!(instant + slop >= before && instant <= after + slop);
+    }
+}
@@ -1,21 +1,68 @@
-#[cfg(any(target_os = "macos", target_os = "ios"))]
-use std::ffi::c_char;
-#[cfg(not(any(target_os = "macos", target_os = "ios")))]
-use std::os::raw::c_char;
+use std::{
+    cell::Cell,
+    ffi::{c_char, CStr, CString},
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+};
 
 use ash::vk::{self, MemoryRequirements};
 
-use super::core::Core;
+use super::core::{Core, Ownership};
+use crate::{
+    breadcrumbs::Breadcrumbs,
+    chrome_trace::{ChromeTrace, HOST_TIME_DOMAIN},
+    profiler::{FrameProfile, Profiler},
+    Allocator, AllocatorConfig, DeviceFeatures, Error, QueueFamilies, Result,
+};
 
 pub struct Context {
+    /// Keeps the instance alive for as long as the device is.
+    pub core: Arc<Core>,
+    pub instance: ash::Instance,
+    pub physical_device: vk::PhysicalDevice,
     pub device: ash::Device,
-    #[allow(unused)]
     pub command_pool: vk::CommandPool,
-    pub draw_command_buffer: vk::CommandBuffer,
+    /// One command buffer per frame in flight. Use [`Context::draw_command_buffer`] to get the
+    /// one for the frame that's currently being recorded.
+    pub draw_command_buffers: Vec<vk::CommandBuffer>,
+    /// How many frames the CPU can get ahead of the GPU.
+    pub frames_in_flight: usize,
+    /// Read by [`Allocator::new`]. Set by [`ContextBuilder::allocator`], or before wrapping a
+    /// [`Context::from_raw`] in an `Arc`.
+    pub allocator_config: AllocatorConfig,
+    /// Which of `draw_command_buffers` is being recorded. Set by [`crate::Renderer`].
+    frame_index: AtomicUsize,
+    /// Used by [`Context::immediate_submit`], so it never has to touch the frame's command pool.
+    immediate_command_pool: Mutex<vk::CommandPool>,
+    /// Only present once [`Context::enable_profiling`] has been called.
+    profiler: Mutex<Option<Profiler>>,
+    /// Only present if `VK_AMD_buffer_marker` is enabled - see [`ContextBuilder::breadcrumbs`].
+    breadcrumbs: Mutex<Option<Breadcrumbs>>,
+    /// The most threads secondary command buffers have been recorded on - see
+    /// [`Context::reserve_secondary_threads`].
+    secondary_threads: AtomicUsize,
+    device_lost: AtomicBool,
+    pub queue_families: QueueFamilies,
     pub graphics_queue: vk::Queue,
+    /// The same queue as `graphics_queue`, unless the graphics family can't present.
+    pub present_queue: vk::Queue,
+    /// A queue from [`QueueFamilies::compute`], if the device has one.
+    pub compute_queue: Option<vk::Queue>,
+    /// A queue from [`QueueFamilies::transfer`], if the device has one.
+    pub transfer_queue: Option<vk::Queue>,
     pub memory_properties: vk::PhysicalDeviceMemoryProperties,
     pub device_type: vk::PhysicalDeviceType,
     pub device_properties: vk::PhysicalDeviceProperties,
+    /// Every feature that was enabled when the device was created - the required ones, plus
+    /// whichever optional ones the device supports.
+    pub enabled_features: DeviceFeatures,
+    /// Every extension that was enabled when the device was created.
+    pub enabled_extensions: Vec<CString>,
+    /// Whether we're responsible for destroying the device.
+    pub ownership: Ownership,
     debug_utils: Option<ash::ext::debug_utils::Device>,
     #[cfg(not(target_vendor = "apple"))]
     pub acceleration_structure_pfn: ash::khr::acceleration_structure::Device,
@@ -25,6 +72,14 @@ pub struct Context {
     pub raytracing_properties: RaytracingProperties,
 }
 
+thread_local! {
+    /// Set while something other than the frame's command buffer is being recorded on this
+    /// thread: a sub-renderer's secondary command buffer, or [`Context::immediate_submit`]'s.
+    /// Secondary command buffers also have the thread they're being recorded for.
+    static RECORDING_COMMAND_BUFFER: Cell<Option<(vk::CommandBuffer, Option<usize>)>> =
+        const { Cell::new(None) };
+}
+
 pub struct RaytracingProperties {
     pub min_acceleration_structure_scratch_offset_alignment: u32,
     pub shader_group_handle_size: u32,
@@ -32,52 +87,344 @@ pub struct RaytracingProperties {
     pub shader_group_base_alignment: u32,
 }
 
-impl Context {
-    pub(crate) fn new_from_window(core: &Core) -> Self {
+/// Decides which device extensions and features [`Context`] enables.
+///
+/// Required extensions and features must be supported or [`ContextBuilder::build`] will fail.
+/// Optional ones are only enabled if the device supports them - check
+/// [`Context::enabled_features`] and [`Context::has_extension`] to find out what you got.
+#[derive(Debug, Clone)]
+pub struct ContextBuilder {
+    /// `VK_KHR_swapchain` is added for you if [`Core`] has a surface.
+    pub required_extensions: Vec<&'static CStr>,
+    pub optional_extensions: Vec<&'static CStr>,
+    /// [`DeviceFeatures::minimum`] is always required, whatever you put here.
+    pub required_features: DeviceFeatures,
+    pub optional_features: DeviceFeatures,
+    /// Enable `VK_KHR_acceleration_structure` and `VK_KHR_ray_tracing_pipeline`, so that
+    /// [`crate::Allocator::build_blas`] and friends can be used.
+    pub ray_tracing: bool,
+    /// How many frames the CPU can record while the GPU is still working on earlier ones.
+    /// Defaults to 2.
+    pub frames_in_flight: usize,
+    /// Enable `VK_AMD_buffer_marker` if it's supported, and use it to log which marker scopes the
+    /// GPU was working on if the device is lost.
+    pub breadcrumbs: bool,
+    /// How much device and staging memory the [`Allocator`] sets aside.
+    pub allocator: AllocatorConfig,
+}
+
+/// The extensions [`ContextBuilder::ray_tracing`] requires.
+pub const RAY_TRACING_EXTENSIONS: [&CStr; 3] = [
+    ash::khr::acceleration_structure::NAME,
+    ash::khr::ray_tracing_pipeline::NAME,
+    ash::khr::deferred_host_operations::NAME,
+];
+
+impl Default for ContextBuilder {
+    fn default() -> Self {
+        Self {
+            required_extensions: Vec::new(),
+            optional_extensions: Vec::new(),
+            required_features: DeviceFeatures::default(),
+            optional_features: default_optional_features(),
+            ray_tracing: false,
+            frames_in_flight: 2,
+            breadcrumbs: false,
+            allocator: AllocatorConfig::default(),
+        }
+    }
+}
+
+impl ContextBuilder {
+    pub fn require_extension(mut self, extension: &'static CStr) -> Self {
+        self.required_extensions.push(extension);
+        self
+    }
+
+    pub fn request_extension(mut self, extension: &'static CStr) -> Self {
+        self.optional_extensions.push(extension);
+        self
+    }
+
+    pub fn require_features(mut self, features: impl Into<DeviceFeatures>) -> Self {
+        self.required_features = self.required_features.union(&features.into());
+        self
+    }
+
+    /// Replaces the optional features entirely, including the defaults.
+    pub fn optional_features(mut self, features: impl Into<DeviceFeatures>) -> Self {
+        self.optional_features = features.into();
+        self
+    }
+
+    pub fn ray_tracing(mut self, ray_tracing: bool) -> Self {
+        self.ray_tracing = ray_tracing;
+        self
+    }
+
+    pub fn frames_in_flight(mut self, frames_in_flight: usize) -> Self {
+        assert!(
+            frames_in_flight > 0,
+            "Must have at least one frame in flight"
+        );
+        self.frames_in_flight = frames_in_flight;
+        self
+    }
+
+    pub fn breadcrumbs(mut self, breadcrumbs: bool) -> Self {
+        self.breadcrumbs = breadcrumbs;
+        self
+    }
+
+    pub fn allocator(mut self, allocator: AllocatorConfig) -> Self {
+        self.allocator = allocator;
+        self
+    }
+
+    /// Every extension that must be supported, not counting `VK_KHR_swapchain`.
+    pub(crate) fn all_required_extensions(&self) -> Vec<&'static CStr> {
+        let mut required_extensions = self.required_extensions.clone();
+        if self.ray_tracing {
+            required_extensions.extend_from_slice(&RAY_TRACING_EXTENSIONS);
+        }
+        required_extensions
+    }
+
+    pub fn build(&self, core: &Arc<Core>) -> Result<Context> {
         let instance = &core.instance;
         let physical_device = core.physical_device;
 
+        // Extensions
+        let available_extensions =
+            unsafe { instance.enumerate_device_extension_properties(physical_device) }?;
+        let is_available = |name: &CStr| {
+            available_extensions
+                .iter()
+                .any(|e| e.extension_name_as_c_str() == Ok(name))
+        };
+
+        let mut required_extensions = self.all_required_extensions();
+        if core.surface.is_some() {
+            required_extensions.push(ash::khr::swapchain::NAME);
+        }
+
+        // If the device advertises this one (eg. MoltenVK), the spec says we have to enable it.
+        let mut optional_extensions = self.optional_extensions.clone();
+        optional_extensions.push(ash::khr::portability_subset::NAME);
+        // Lets Chrome traces line GPU timings up with the CPU's.
+        optional_extensions.push(ash::ext::calibrated_timestamps::NAME);
+        if self.breadcrumbs {
+            optional_extensions.push(ash::amd::buffer_marker::NAME);
+        }
+
+        let mut enabled_extensions: Vec<&CStr> = Vec::new();
+        for extension in required_extensions {
+            if !is_available(extension) {
+                return Err(Error::MissingDeviceExtension(
+                    extension.to_string_lossy().into_owned(),
+                ));
+            }
+            if !enabled_extensions.contains(&extension) {
+                enabled_extensions.push(extension);
+            }
+        }
+
+        for extension in optional_extensions {
+            if !is_available(extension) {
+                log::debug!("[lazy_vulkan] Optional extension {extension:?} is not supported");
+                continue;
+            }
+            if !enabled_extensions.contains(&extension) {
+                enabled_extensions.push(extension);
+            }
+        }
+
+        // Queues
+        let queue_families = QueueFamilies::find(
+            instance,
+            physical_device,
+            core.surface.map(|surface| (&core.surface_fn, surface)),
+        )
+        .map_err(|reason| {
+            log::error!("[lazy_vulkan] Unable to find queue families: {reason}");
+            Error::NoSuitableDevice
+        })?;
+        log::debug!("[lazy_vulkan] Using queue families {queue_families:?}");
+
+        // Features
+        let supported_features = DeviceFeatures::query(instance, physical_device);
+        let required_features = DeviceFeatures::minimum().union(&self.required_features);
+        let missing_features = required_features.missing_from(&supported_features);
+        if !missing_features.is_empty() {
+            return Err(Error::MissingDeviceFeatures(Box::new(missing_features)));
+        }
+
+        let enabled_features =
+            required_features.union(&self.optional_features.intersection(&supported_features));
+
+        let extension_names = enabled_extensions
+            .iter()
+            .map(|e| e.as_ptr())
+            .collect::<Vec<_>>();
         let device = create_device(
             instance,
             physical_device,
-            &mut vec![ash::khr::swapchain::NAME.as_ptr()],
-        );
+            &extension_names,
+            enabled_features,
+            &queue_families,
+            self.ray_tracing,
+        )?;
 
-        Context::new(core, device)
+        let mut context = Context::new(
+            core,
+            device,
+            queue_families,
+            enabled_features,
+            enabled_extensions.into_iter().map(CStr::to_owned).collect(),
+            self.frames_in_flight,
+            Ownership::Owned,
+        )?;
+        context.allocator_config = self.allocator;
+        Ok(context)
     }
+}
 
-    pub fn new_headless(core: &Core) -> Context {
-        let instance = &core.instance;
-        let physical_device = core.physical_device;
+/// The features lazy_vulkan has always turned on, and that our shaders tend to assume.
+fn default_optional_features() -> DeviceFeatures {
+    DeviceFeatures {
+        core: vk::PhysicalDeviceFeatures::default()
+            .fill_mode_non_solid(true)
+            .sampler_anisotropy(true)
+            .shader_int64(true)
+            .multi_draw_indirect(true)
+            // Used by `QuerySet`
+            .occlusion_query_precise(true)
+            .pipeline_statistics_query(true),
+        vulkan_11: vk::PhysicalDeviceVulkan11Features::default()
+            .variable_pointers(true)
+            .variable_pointers_storage_buffer(true)
+            .shader_draw_parameters(true),
+        vulkan_12: vk::PhysicalDeviceVulkan12Features::default()
+            .descriptor_indexing(true)
+            .descriptor_binding_storage_buffer_update_after_bind(true)
+            .descriptor_binding_uniform_buffer_update_after_bind(true)
+            .scalar_block_layout(true),
+        ..Default::default()
+    }
+}
+
+impl Context {
+    pub fn new_headless(core: &Arc<Core>) -> Result<Context> {
+        ContextBuilder::default().build(core)
+    }
+
+    pub fn builder() -> ContextBuilder {
+        ContextBuilder::default()
+    }
 
-        let device = create_device(instance, physical_device, &mut vec![]);
-        Context::new(core, device)
+    /// Wrap a device that was created elsewhere, eg. by an XR runtime. We'll still create our
+    /// own command pool, with a command buffer for each of `frames_in_flight`.
+    ///
+    /// # Safety
+    /// - `device` must have been created from `core.physical_device`
+    /// - `queue_families` must describe the queues `device` was created with: queue 0 of each
+    ///   family is used
+    /// - `enabled_features` and `enabled_extensions` must have been enabled on `device`, and
+    ///   must include [`DeviceFeatures::minimum`]
+    /// - If `ownership` is [`Ownership::Borrowed`], `device` must outlive this `Context`
+    ///
+    /// If this fails and `ownership` is [`Ownership::Owned`], `device` is destroyed.
+    pub unsafe fn from_raw(
+        core: &Arc<Core>,
+        device: ash::Device,
+        queue_families: QueueFamilies,
+        enabled_features: DeviceFeatures,
+        enabled_extensions: Vec<CString>,
+        frames_in_flight: usize,
+        ownership: Ownership,
+    ) -> Result<Context> {
+        assert!(
+            frames_in_flight > 0,
+            "Must have at least one frame in flight"
+        );
+        Context::new(
+            core,
+            device,
+            queue_families,
+            enabled_features,
+            enabled_extensions,
+            frames_in_flight,
+            ownership,
+        )
     }
 
-    fn new(core: &Core, device: ash::Device) -> Self {
+    fn new(
+        core: &Arc<Core>,
+        device: ash::Device,
+        queue_families: QueueFamilies,
+        enabled_features: DeviceFeatures,
+        enabled_extensions: Vec<CString>,
+        frames_in_flight: usize,
+        ownership: Ownership,
+    ) -> Result<Self> {
         let instance = &core.instance;
         let physical_device = core.physical_device;
 
+        // Until `Self` exists to do it on drop, we have to clean up the device ourselves.
+        let destroy_device = |e: vk::Result| {
+            if ownership == Ownership::Owned {
+                unsafe { device.destroy_device(None) };
+            }
+            Error::from(e)
+        };
+
         let command_pool = unsafe {
             device.create_command_pool(
                 &vk::CommandPoolCreateInfo::default()
-                    .queue_family_index(0)
+                    .queue_family_index(queue_families.graphics)
                     .flags(vk::CommandPoolCreateFlags::RESET_COMMAND_BUFFER),
                 None,
             )
         }
-        .unwrap();
+        .map_err(destroy_device)?;
 
-        let draw_command_buffer = unsafe {
+        let draw_command_buffers = match unsafe {
             device.allocate_command_buffers(
                 &vk::CommandBufferAllocateInfo::default()
                     .command_pool(command_pool)
-                    .command_buffer_count(1),
+                    .command_buffer_count(frames_in_flight as u32),
             )
-        }
-        .unwrap()[0];
+        } {
+            Ok(command_buffers) => command_buffers,
+            Err(e) => {
+                unsafe { device.destroy_command_pool(command_pool, None) };
+                return Err(destroy_device(e));
+            }
+        };
+
+        let immediate_command_pool = match unsafe {
+            device.create_command_pool(
+                &vk::CommandPoolCreateInfo::default()
+                    .queue_family_index(queue_families.graphics)
+                    .flags(vk::CommandPoolCreateFlags::TRANSIENT),
+                None,
+            )
+        } {
+            Ok(command_pool) => command_pool,
+            Err(e) => {
+                unsafe { device.destroy_command_pool(command_pool, None) };
+                return Err(destroy_device(e));
+            }
+        };
 
-        let graphics_queue = unsafe { device.get_device_queue(0, 0) };
+        let graphics_queue = unsafe { device.get_device_queue(queue_families.graphics, 0) };
+        let present_queue = unsafe { device.get_device_queue(queue_families.present, 0) };
+        let compute_queue = queue_families
+            .compute
+            .map(|family| unsafe { device.get_device_queue(family, 0) });
+        let transfer_queue = queue_families
+            .transfer
+            .map(|family| unsafe { device.get_device_queue(family, 0) });
 
         let memory_properties =
             unsafe { instance.get_physical_device_memory_properties(physical_device) };
@@ -121,35 +468,272 @@ impl Context {
             shader_group_base_alignment: ray_tracing_properties.shader_group_base_alignment,
         };
 
-        // TODO: Make this dependent on an env var or something
-        let debug_utils = Some(ash::ext::debug_utils::Device::new(&core.instance, &device));
+        let debug_utils = core
+            .debug_utils_enabled
+            .then(|| ash::ext::debug_utils::Device::new(&core.instance, &device));
 
-        Self {
+        let context = Self {
+            core: core.clone(),
+            instance: instance.clone(),
+            physical_device,
             device,
             command_pool,
-            draw_command_buffer,
+            draw_command_buffers,
+            frames_in_flight,
+            allocator_config: AllocatorConfig::default(),
+            frame_index: AtomicUsize::new(0),
+            immediate_command_pool: Mutex::new(immediate_command_pool),
+            profiler: Mutex::new(None),
+            breadcrumbs: Mutex::new(None),
+            secondary_threads: AtomicUsize::new(0),
+            device_lost: AtomicBool::new(false),
+            queue_families,
             graphics_queue,
+            present_queue,
+            compute_queue,
+            transfer_queue,
             memory_properties,
             debug_utils,
             device_type: physical_device_properties.device_type,
             device_properties: physical_device_properties,
+            enabled_features,
+            enabled_extensions,
+            ownership,
             #[cfg(not(target_vendor = "apple"))]
             acceleration_structure_pfn,
             #[cfg(not(target_vendor = "apple"))]
             ray_tracing_pipeline_pfn,
             #[cfg(not(target_vendor = "apple"))]
             raytracing_properties,
+        };
+
+        if context.has_extension(ash::amd::buffer_marker::NAME) {
+            match Breadcrumbs::new(&context) {
+                Ok(breadcrumbs) => *context.breadcrumbs.lock().unwrap() = Some(breadcrumbs),
+                Err(e) => log::warn!("[lazy_vulkan] Unable to create breadcrumbs: {e}"),
+            }
+        }
+
+        Ok(context)
+    }
+
+    pub fn has_extension(&self, name: &CStr) -> bool {
+        self.enabled_extensions.iter().any(|e| e.as_c_str() == name)
+    }
+
+    /// The queue families buffers are shared between. Empty unless the device has a dedicated
+    /// compute queue, in which case buffers are created with [`vk::SharingMode::CONCURRENT`] so
+    /// that async compute can use them without transferring ownership.
+    pub fn buffer_queue_families(&self) -> Vec<u32> {
+        let Some(compute) = self.queue_families.compute else {
+            return Vec::new();
+        };
+
+        let mut families = vec![self.queue_families.graphics, compute];
+        families.extend(self.queue_families.transfer);
+        families
+    }
+
+    /// The queue families an image with `usage` is shared between. Storage images are shared
+    /// like buffers (see [`Context::buffer_queue_families`]), so that async compute can use them
+    /// too. Anything else is owned by the graphics family.
+    pub fn image_queue_families(&self, usage: vk::ImageUsageFlags) -> Vec<u32> {
+        if usage.contains(vk::ImageUsageFlags::STORAGE) {
+            self.buffer_queue_families()
+        } else {
+            Vec::new()
+        }
+    }
+
+    /// Whether [`ContextBuilder::ray_tracing`] was set.
+    pub fn ray_tracing_enabled(&self) -> bool {
+        self.has_extension(ash::khr::acceleration_structure::NAME)
+    }
+
+    /// The command buffer for the frame that's currently being recorded - or, if a sub-renderer
+    /// is being recorded in parallel, the secondary command buffer it should record into. Inside
+    /// [`Context::immediate_submit`], it's the immediate command buffer.
+    pub fn draw_command_buffer(&self) -> vk::CommandBuffer {
+        RECORDING_COMMAND_BUFFER
+            .get()
+            .map(|(command_buffer, _)| command_buffer)
+            .unwrap_or_else(|| self.draw_command_buffers[self.frame_index()])
+    }
+
+    /// Make [`Context::draw_command_buffer`] return `command_buffer` on this thread for the
+    /// duration of `record`. Its markers are only labelled, not profiled or breadcrumbed.
+    pub(crate) fn record_into<R>(
+        &self,
+        command_buffer: vk::CommandBuffer,
+        record: impl FnOnce() -> R,
+    ) -> R {
+        let previous = RECORDING_COMMAND_BUFFER.replace(Some((command_buffer, None)));
+        let result = record();
+        RECORDING_COMMAND_BUFFER.set(previous);
+        result
+    }
+
+    /// Like [`Context::record_into`], but `command_buffer` is a secondary command buffer that the
+    /// frame's command buffer will execute. Its markers are profiled and breadcrumbed along with
+    /// the frame's, separately from any other `thread`'s.
+    pub(crate) fn record_secondary<R>(
+        &self,
+        command_buffer: vk::CommandBuffer,
+        thread: usize,
+        record: impl FnOnce() -> R,
+    ) -> R {
+        let previous = RECORDING_COMMAND_BUFFER.replace(Some((command_buffer, Some(thread))));
+        let result = record();
+        RECORDING_COMMAND_BUFFER.set(previous);
+        result
+    }
+
+    /// Make room in the profiler and breadcrumbs for secondary command buffers recorded on
+    /// `threads` threads with [`Context::record_secondary`].
+    pub(crate) fn reserve_secondary_threads(&self, threads: usize) -> Result<()> {
+        self.secondary_threads.fetch_max(threads, Ordering::Relaxed);
+        if let Some(profiler) = self.profiler.lock().unwrap().as_mut() {
+            profiler.reserve_recorders(&self.device, threads + 1)?;
+        }
+        if let Some(breadcrumbs) = self.breadcrumbs.lock().unwrap().as_mut() {
+            breadcrumbs.reserve_recorders(self, threads + 1)?;
         }
+        Ok(())
     }
 
-    pub fn begin_command_buffer(&self) {
+    /// Record some commands with `record`, submit them to the graphics queue and block until
+    /// the GPU has finished them. Useful for loading screens, offline bakes and tests.
+    ///
+    /// Any transfers pending in `allocator` are executed first, so their results can be used
+    /// by `record` - and their tokens are complete by the time this returns. The frame's command
+    /// buffer isn't touched, though [`Context::draw_command_buffer`] returns the immediate
+    /// command buffer while `record` runs.
+    ///
+    /// ## NOTE
+    /// The graphics queue mustn't be used on another thread while this is running. If no frames
+    /// are being drawn, call [`Allocator::transfers_complete`] every so often to make the
+    /// staging buffer's space available again.
+    pub fn immediate_submit<R>(
+        &self,
+        allocator: &mut Allocator,
+        record: impl FnOnce(vk::CommandBuffer) -> R,
+    ) -> Result<R> {
+        let device = &self.device;
+        let command_pool = self.immediate_command_pool.lock().unwrap();
+
+        let command_buffer = unsafe {
+            device.allocate_command_buffers(
+                &vk::CommandBufferAllocateInfo::default()
+                    .command_pool(*command_pool)
+                    .command_buffer_count(1),
+            )
+        }?[0];
+
+        let result = self.record_into(command_buffer, || {
+            self.immediate_submit_inner(allocator, command_buffer, record)
+        });
+
+        unsafe { device.free_command_buffers(*command_pool, &[command_buffer]) };
+        result
+    }
+
+    fn immediate_submit_inner<R>(
+        &self,
+        allocator: &mut Allocator,
+        command_buffer: vk::CommandBuffer,
+        record: impl FnOnce(vk::CommandBuffer) -> R,
+    ) -> Result<R> {
+        let device = &self.device;
+        unsafe {
+            device.begin_command_buffer(
+                command_buffer,
+                &vk::CommandBufferBeginInfo::default()
+                    .flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT),
+            )
+        }?;
+
+        let transfer_tokens = allocator.pending_transfer_tokens();
+        allocator.execute_transfers(command_buffer)?;
+        let result = record(command_buffer);
+        let readbacks = allocator.record_readbacks(command_buffer);
+
+        let wait_semaphore_infos = allocator
+            .take_transfer_wait()
+            .into_iter()
+            .collect::<Vec<_>>();
+        let signal_semaphore_infos = allocator
+            .take_transfer_signal()
+            .into_iter()
+            .collect::<Vec<_>>();
+
+        let fence = unsafe { device.create_fence(&vk::FenceCreateInfo::default(), None) }?;
+        let submit_and_wait = || -> Result<()> {
+            unsafe {
+                device.end_command_buffer(command_buffer)?;
+                self.queue_submit2(
+                    self.graphics_queue,
+                    &[vk::SubmitInfo2::default()
+                        .command_buffer_infos(&[
+                            vk::CommandBufferSubmitInfo::default().command_buffer(command_buffer)
+                        ])
+                        .wait_semaphore_infos(&wait_semaphore_infos)
+                        .signal_semaphore_infos(&signal_semaphore_infos)],
+                    fence,
+                )?;
+                device.wait_for_fences(&[fence], true, u64::MAX)?;
+            }
+            Ok(())
+        };
+        let submitted = self.check_device_lost(submit_and_wait());
+        unsafe { device.destroy_fence(fence, None) };
+        submitted?;
+
+        // The GPU is done, so there's no need to wait for the completion thread to notice.
+        for token in transfer_tokens {
+            token.mark_completed();
+        }
+        allocator.complete_readbacks(&readbacks);
+
+        Ok(result)
+    }
+
+    /// Which of the frames in flight is currently being recorded, from `0` to
+    /// `frames_in_flight - 1`.
+    pub fn frame_index(&self) -> usize {
+        self.frame_index.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn set_frame_index(&self, frame_index: usize) {
+        self.frame_index.store(frame_index, Ordering::Relaxed);
+    }
+
+    pub fn begin_command_buffer(&self) -> Result<()> {
         unsafe {
             self.device.begin_command_buffer(
-                self.draw_command_buffer,
+                self.draw_command_buffer(),
                 &vk::CommandBufferBeginInfo::default(),
             )
+        }?;
+        Ok(())
+    }
+
+    /// Returns [`Error::UnsupportedFormat`] if an optimally tiled 2D image with `format` can't be
+    /// created with `usage` on this device.
+    pub fn check_image_format(&self, format: vk::Format, usage: vk::ImageUsageFlags) -> Result<()> {
+        match unsafe {
+            self.instance.get_physical_device_image_format_properties(
+                self.physical_device,
+                format,
+                vk::ImageType::TYPE_2D,
+                vk::ImageTiling::OPTIMAL,
+                usage,
+                vk::ImageCreateFlags::empty(),
+            )
+        } {
+            Ok(_) => Ok(()),
+            Err(vk::Result::ERROR_FORMAT_NOT_SUPPORTED) => Err(Error::UnsupportedFormat(format)),
+            Err(e) => Err(e.into()),
         }
-        .unwrap()
     }
 
     pub fn find_memory_type_index(
@@ -226,8 +810,9 @@ impl Context {
         queue: vk::Queue,
         submits: &[vk::SubmitInfo2KHR],
         fence: vk::Fence,
-    ) {
-        self.device.queue_submit2(queue, submits, fence).unwrap()
+    ) -> Result<()> {
+        let result = self.device.queue_submit2(queue, submits, fence);
+        self.check_device_lost(result.map_err(Error::from))
     }
 
     // #[cfg(any(target_os = "macos", target_os = "ios"))]
@@ -257,149 +842,291 @@ impl Context {
     }
 
     pub fn begin_marker(&self, name: &str, colour: glam::Vec4) {
+        if let Some(debug_utils) = &self.debug_utils {
+            unsafe {
+                let label_name = std::ffi::CString::new(name).unwrap();
+                debug_utils.cmd_begin_debug_utils_label(
+                    self.draw_command_buffer(),
+                    &vk::DebugUtilsLabelEXT::default()
+                        .label_name(label_name.as_c_str())
+                        .color(colour.into()),
+                );
+            };
+        }
+
+        self.with_frame_profiler(|profiler, command_buffer, frame_index, recorder| {
+            profiler.begin_scope(&self.device, command_buffer, frame_index, recorder, name)
+        });
+        self.with_frame_breadcrumbs(|breadcrumbs, command_buffer, frame_index, recorder| {
+            breadcrumbs.begin_scope(command_buffer, frame_index, recorder, name)
+        });
+    }
+
+    pub fn end_marker(&self) {
+        self.with_frame_profiler(|profiler, command_buffer, frame_index, recorder| {
+            profiler.end_scope(&self.device, command_buffer, frame_index, recorder)
+        });
+        self.with_frame_breadcrumbs(|breadcrumbs, command_buffer, frame_index, recorder| {
+            breadcrumbs.end_scope(command_buffer, frame_index, recorder)
+        });
+
         let Some(debug_utils) = &self.debug_utils else {
             return;
         };
 
         unsafe {
-            let label_name = std::ffi::CString::new(name).unwrap();
-            debug_utils.cmd_begin_debug_utils_label(
-                self.draw_command_buffer,
-                &vk::DebugUtilsLabelEXT::default()
-                    .label_name(label_name.as_c_str())
-                    .color(colour.into()),
-            );
+            debug_utils.cmd_end_debug_utils_label(self.draw_command_buffer());
         };
     }
-    pub fn end_marker(&self) {
-        let Some(debug_utils) = &self.debug_utils else {
+
+    /// Start writing GPU timestamps for every marker scope recorded into the frame's command
+    /// buffer, and the secondary command buffers it executes. See [`Context::latest_profile`].
+    ///
+    /// Markers in async compute and [`Context::immediate_submit`] are only labelled, not timed.
+    pub fn enable_profiling(&self) -> Result<()> {
+        let mut profiler = self.profiler.lock().unwrap();
+        if profiler.is_some() {
+            return Ok(());
+        }
+
+        let queue_family_properties = unsafe {
+            self.instance
+                .get_physical_device_queue_family_properties(self.physical_device)
+        };
+        let timestamp_valid_bits =
+            queue_family_properties[self.queue_families.graphics as usize].timestamp_valid_bits;
+
+        *profiler = Some(Profiler::new(
+            &self.device,
+            self.frames_in_flight,
+            self.secondary_threads.load(Ordering::Relaxed) + 1,
+            self.device_properties.limits.timestamp_period,
+            timestamp_valid_bits,
+        )?);
+        Ok(())
+    }
+
+    /// The timings of the most recent frame the GPU has finished - usually `frames_in_flight`
+    /// frames behind the one being recorded. `None` until [`Context::enable_profiling`] has been
+    /// called and a frame has completed.
+    pub fn latest_profile(&self) -> Option<FrameProfile> {
+        self.profiler.lock().unwrap().as_ref()?.latest().cloned()
+    }
+
+    /// Stream every profiled frame to a Chrome trace JSON file at `path` (for Perfetto or
+    /// `chrome://tracing`) until [`Context::end_chrome_trace`] is called. Enables profiling if it
+    /// isn't already.
+    ///
+    /// GPU timings are only included if the device supports `VK_EXT_calibrated_timestamps`.
+    pub fn begin_chrome_trace(&self, path: impl AsRef<Path>) -> Result<()> {
+        self.enable_profiling()?;
+
+        let (calibrated_timestamps, host_time_domain) = match self.calibrated_timestamps() {
+            Some((calibrated_timestamps, host_time_domain)) => {
+                (Some(calibrated_timestamps), host_time_domain)
+            }
+            None => {
+                log::warn!(
+                    "[lazy_vulkan] Device can't calibrate timestamps, Chrome trace will be CPU only"
+                );
+                (None, None)
+            }
+        };
+
+        let trace = ChromeTrace::create(path.as_ref(), calibrated_timestamps, host_time_domain)?;
+        self.profiler
+            .lock()
+            .unwrap()
+            .as_mut()
+            .unwrap()
+            .begin_trace(trace)
+    }
+
+    /// Finish the trace started by [`Context::begin_chrome_trace`]. Frames the GPU hasn't
+    /// finished yet aren't included.
+    pub fn end_chrome_trace(&self) -> Result<()> {
+        match self.profiler.lock().unwrap().as_mut() {
+            Some(profiler) => profiler.end_trace(),
+            None => Ok(()),
+        }
+    }
+
+    /// `None` unless `VK_EXT_calibrated_timestamps` is enabled and can read the device's clock.
+    /// Also returns [`HOST_TIME_DOMAIN`], if it can be read alongside.
+    fn calibrated_timestamps(
+        &self,
+    ) -> Option<(
+        ash::ext::calibrated_timestamps::Device,
+        Option<vk::TimeDomainEXT>,
+    )> {
+        if !self.has_extension(ash::ext::calibrated_timestamps::NAME) {
+            return None;
+        }
+
+        let time_domains = unsafe {
+            ash::ext::calibrated_timestamps::Instance::new(&self.core.entry, &self.instance)
+                .get_physical_device_calibrateable_time_domains(self.physical_device)
+        }
+        .unwrap_or_default();
+        if !time_domains.contains(&vk::TimeDomainEXT::DEVICE) {
+            return None;
+        }
+
+        let host_time_domain = HOST_TIME_DOMAIN.filter(|domain| time_domains.contains(domain));
+        if host_time_domain.is_none() {
+            log::debug!("[lazy_vulkan] Device can't calibrate against the host's clock, GPU timings in Chrome traces will be approximate");
+        }
+
+        Some((
+            ash::ext::calibrated_timestamps::Device::new(&self.instance, &self.device),
+            host_time_domain,
+        ))
+    }
+
+    /// Called once the frame's command buffer has begun. Reads back the timings of the last
+    /// frame that used this slot and resets its queries, and starts a new breadcrumb trail.
+    pub(crate) fn begin_frame(&self, frame: u64) {
+        self.with_frame_profiler(|profiler, command_buffer, frame_index, _| {
+            profiler.begin_frame(&self.device, command_buffer, frame_index, frame)
+        });
+        self.with_frame_breadcrumbs(|breadcrumbs, _, frame_index, _| {
+            breadcrumbs.begin_frame(frame_index, frame)
+        });
+    }
+
+    /// True once any of our calls have returned [`Error::DeviceLost`]. The only way forward is to
+    /// recreate everything - see [`crate::LazyVulkan::recover_from_device_lost`].
+    pub fn is_device_lost(&self) -> bool {
+        self.device_lost.load(Ordering::Relaxed)
+    }
+
+    /// If `result` is [`Error::DeviceLost`], log which marker scopes the GPU was working on - the
+    /// first time only.
+    pub(crate) fn check_device_lost<T>(&self, result: Result<T>) -> Result<T> {
+        if matches!(result, Err(Error::DeviceLost))
+            && !self.device_lost.swap(true, Ordering::Relaxed)
+        {
+            self.log_breadcrumbs();
+        }
+        result
+    }
+
+    fn log_breadcrumbs(&self) {
+        log::error!("[lazy_vulkan] Device lost!");
+        let mut breadcrumbs = self.breadcrumbs.lock().unwrap();
+        let Some(breadcrumbs) = breadcrumbs.as_mut() else {
+            log::error!("[lazy_vulkan] No breadcrumbs available - see ContextBuilder::breadcrumbs");
             return;
         };
 
-        unsafe {
-            debug_utils.cmd_end_debug_utils_label(self.draw_command_buffer);
+        let trail = breadcrumbs.trail();
+        if trail.is_empty() {
+            log::error!("[lazy_vulkan] The GPU had finished every marker scope");
+        }
+        for line in trail {
+            log::error!("[lazy_vulkan] {line}");
+        }
+    }
+
+    /// The command buffer being recorded for the frame, its index, and which recorder it belongs
+    /// to: `0` for the frame's own command buffer, and one more than the thread for secondary
+    /// command buffers. `None` if something else is being recorded.
+    fn recording_frame(&self) -> Option<(vk::CommandBuffer, usize, usize)> {
+        let frame_index = self.frame_index();
+        match RECORDING_COMMAND_BUFFER.get() {
+            None => Some((self.draw_command_buffers[frame_index], frame_index, 0)),
+            Some((command_buffer, Some(thread))) => Some((command_buffer, frame_index, thread + 1)),
+            Some((_, None)) => None,
+        }
+    }
+
+    /// Run `f` if profiling is enabled and the frame's command buffers are being recorded.
+    fn with_frame_profiler(&self, f: impl FnOnce(&mut Profiler, vk::CommandBuffer, usize, usize)) {
+        let Some((command_buffer, frame_index, recorder)) = self.recording_frame() else {
+            return;
+        };
+
+        if let Some(profiler) = self.profiler.lock().unwrap().as_mut() {
+            f(profiler, command_buffer, frame_index, recorder);
+        }
+    }
+
+    /// Run `f` if breadcrumbs are enabled and the frame's command buffers are being recorded.
+    fn with_frame_breadcrumbs(
+        &self,
+        f: impl FnOnce(&mut Breadcrumbs, vk::CommandBuffer, usize, usize),
+    ) {
+        let Some((command_buffer, frame_index, recorder)) = self.recording_frame() else {
+            return;
         };
+
+        if let Some(breadcrumbs) = self.breadcrumbs.lock().unwrap().as_mut() {
+            f(breadcrumbs, command_buffer, frame_index, recorder);
+        }
     }
 }
 
-#[cfg(any(target_os = "macos", target_os = "ios"))]
-fn create_device(
-    instance: &ash::Instance,
-    physical_device: vk::PhysicalDevice,
-    enabled_extension_names: &mut Vec<*const c_char>,
-) -> ash::Device {
-    enabled_extension_names.extend_from_slice(&[ash::khr::portability_subset::NAME.as_ptr()]);
+impl Drop for Context {
+    fn drop(&mut self) {
+        unsafe {
+            // Anything that was submitted must be finished before we can destroy anything.
+            let _ = self.device.device_wait_idle();
+            self.device.destroy_command_pool(self.command_pool, None);
+            self.device
+                .destroy_command_pool(*self.immediate_command_pool.get_mut().unwrap(), None);
+            if let Some(profiler) = self.profiler.get_mut().unwrap() {
+                profiler.destroy(&self.device);
+            }
+            if let Some(breadcrumbs) = self.breadcrumbs.get_mut().unwrap() {
+                breadcrumbs.destroy(&self.device);
+            }
 
-    let device = unsafe {
-        instance.create_device(
-            physical_device,
-            &vk::DeviceCreateInfo::default()
-                .enabled_extension_names(&enabled_extension_names)
-                .queue_create_infos(&[vk::DeviceQueueCreateInfo::default()
-                    .queue_family_index(0)
-                    .queue_priorities(&[1.0])])
-                .enabled_features(
-                    &vk::PhysicalDeviceFeatures::default()
-                        .fill_mode_non_solid(true)
-                        .sampler_anisotropy(true),
-                )
-                .push_next(
-                    &mut vk::PhysicalDeviceVulkan12Features::default()
-                        .runtime_descriptor_array(true)
-                        .descriptor_indexing(true)
-                        .descriptor_binding_partially_bound(true)
-                        .descriptor_binding_sampled_image_update_after_bind(true)
-                        .shader_sampled_image_array_non_uniform_indexing(true)
-                        .buffer_device_address(true)
-                        .scalar_block_layout(true),
-                )
-                .push_next(
-                    &mut vk::PhysicalDeviceVulkan13Features::default()
-                        .dynamic_rendering(true)
-                        .synchronization2(true),
-                )
-                .push_next(
-                    &mut vk::PhysicalDeviceVulkan11Features::default()
-                        .variable_pointers(true)
-                        .shader_draw_parameters(true)
-                        .variable_pointers_storage_buffer(true),
-                ),
-            None,
-        )
+            if self.ownership == Ownership::Owned {
+                self.device.destroy_device(None);
+            }
+        }
     }
-    .unwrap();
-    device
 }
 
-#[cfg(not(any(target_os = "macos", target_os = "ios")))]
 fn create_device(
     instance: &ash::Instance,
     physical_device: vk::PhysicalDevice,
-    enabled_extension_names: &mut Vec<*const c_char>,
-) -> ash::Device {
-    // TODO: hide this behind an "RTX ON"
+    enabled_extension_names: &[*const c_char],
+    mut features: DeviceFeatures,
+    queue_families: &QueueFamilies,
+    ray_tracing: bool,
+) -> Result<ash::Device> {
+    let queue_create_infos = queue_families
+        .unique()
+        .into_iter()
+        .map(|family| {
+            vk::DeviceQueueCreateInfo::default()
+                .queue_family_index(family)
+                .queue_priorities(&[1.0])
+        })
+        .collect::<Vec<_>>();
 
-    let rtx_on = false;
-    if rtx_on {
-        enabled_extension_names.push(ash::khr::acceleration_structure::NAME.as_ptr());
-        enabled_extension_names.push(ash::khr::ray_tracing_pipeline::NAME.as_ptr());
-        enabled_extension_names.push(ash::khr::deferred_host_operations::NAME.as_ptr());
-        enabled_extension_names.push(ash::khr::shader_clock::NAME.as_ptr());
-    }
+    // These are guaranteed to be supported if their extensions are, so there's no need to
+    // query them.
+    let mut acceleration_structure_features =
+        vk::PhysicalDeviceAccelerationStructureFeaturesKHR::default().acceleration_structure(true);
+    let mut ray_tracing_pipeline_features =
+        vk::PhysicalDeviceRayTracingPipelineFeaturesKHR::default().ray_tracing_pipeline(true);
 
-    let device = unsafe {
-        instance.create_device(
-            physical_device,
-            &vk::DeviceCreateInfo::default()
-                .enabled_extension_names(enabled_extension_names)
-                .queue_create_infos(&[vk::DeviceQueueCreateInfo::default()
-                    .queue_family_index(0)
-                    .queue_priorities(&[1.0])])
-                .enabled_features(
-                    &vk::PhysicalDeviceFeatures::default()
-                        .fill_mode_non_solid(true)
-                        .sampler_anisotropy(true)
-                        .shader_int64(true)
-                        .multi_draw_indirect(true),
-                )
-                .push_next(
-                    &mut vk::PhysicalDeviceVulkan11Features::default()
-                        .variable_pointers(true)
-                        .variable_pointers_storage_buffer(true)
-                        .shader_draw_parameters(true),
-                )
-                .push_next(
-                    &mut vk::PhysicalDeviceVulkan12Features::default()
-                        .runtime_descriptor_array(true)
-                        .descriptor_indexing(true)
-                        .descriptor_binding_partially_bound(true)
-                        .descriptor_binding_sampled_image_update_after_bind(true)
-                        .descriptor_binding_storage_buffer_update_after_bind(true)
-                        .descriptor_binding_uniform_buffer_update_after_bind(true)
-                        .shader_sampled_image_array_non_uniform_indexing(true)
-                        .buffer_device_address(true)
-                        .scalar_block_layout(true),
-                )
-                // .push_next(
-                //     &mut vk::PhysicalDeviceAccelerationStructureFeaturesKHR::default()
-                //         .acceleration_structure(true),
-                // )
-                // .push_next(
-                //     &mut vk::PhysicalDeviceRayTracingPipelineFeaturesKHR::default()
-                //         .ray_tracing_pipeline(true),
-                // )
-                // .push_next(
-                //     &mut vk::PhysicalDeviceShaderClockFeaturesKHR::default()
-                //         .shader_subgroup_clock(true),
-                // )
-                .push_next(
-                    &mut vk::PhysicalDeviceVulkan13Features::default()
-                        .dynamic_rendering(true)
-                        .synchronization2(true),
-                ),
-            None,
-        )
+    let mut create_info = vk::DeviceCreateInfo::default()
+        .enabled_extension_names(enabled_extension_names)
+        .queue_create_infos(&queue_create_infos)
+        .enabled_features(&features.core)
+        .push_next(&mut features.vulkan_11)
+        .push_next(&mut features.vulkan_12)
+        .push_next(&mut features.vulkan_13);
+
+    if ray_tracing {
+        create_info = create_info
+            .push_next(&mut acceleration_structure_features)
+            .push_next(&mut ray_tracing_pipeline_features);
     }
-    .unwrap();
-    device
+
+    let device = unsafe { instance.create_device(physical_device, &create_info, None) }?;
+    Ok(device)
 }
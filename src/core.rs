@@ -1,150 +1,474 @@
-use std::ffi::CStr;
+use std::ffi::{c_char, CStr};
 
 use ash::vk::{self, LayerSettingTypeEXT};
-use winit::raw_window_handle::HasDisplayHandle;
+use winit::raw_window_handle::{HasDisplayHandle, HasWindowHandle};
+
+use crate::{
+    device_selection::{DeviceSelector, SelectedDevice, SelectionReason},
+    Error, Result,
+};
+
+const VALIDATION_LAYER: &CStr = c"VK_LAYER_KHRONOS_validation";
 
 pub struct Core {
     pub entry: ash::Entry,
     pub instance: ash::Instance,
     pub physical_device: vk::PhysicalDevice,
+    /// Which device was picked, and why
+    pub selected_device: SelectedDevice,
+    /// Only present if this `Core` was created from a window
+    pub surface: Option<vk::SurfaceKHR>,
+    pub surface_fn: ash::khr::surface::Instance,
+    /// Whether `VK_LAYER_KHRONOS_validation` was actually enabled
+    pub validation_enabled: bool,
+    /// Whether `VK_EXT_debug_utils` was actually enabled
+    pub debug_utils_enabled: bool,
+    /// Every warning and error reported by the validation layer ends up in here.
+    pub validation_messages: Arc<ValidationMessages>,
+    pub debug_messenger: Option<DebugMessenger>,
+    /// Whether we're responsible for destroying the instance and surface.
+    pub ownership: Ownership,
+}
+
+/// Whether lazy_vulkan is responsible for destroying a Vulkan object.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ownership {
+    /// We created it, so we destroy it.
+    Owned,
+    /// Someone else created it, and will destroy it once we're done with it.
+    Borrowed,
 }
 
 impl Core {
-    pub(crate) fn from_window(window: &winit::window::Window) -> Self {
+    pub fn builder() -> CoreBuilder {
+        CoreBuilder::default()
+    }
+
+    pub fn from_window(window: &winit::window::Window) -> Result<Self> {
+        CoreBuilder::default().build_for_window(window)
+    }
+
+    pub fn headless() -> Result<Self> {
+        CoreBuilder::default().build_headless()
+    }
+
+    /// Wrap an instance (and optionally a surface) that was created elsewhere, eg. by an XR
+    /// runtime. No validation messages will be captured, as we don't own the debug messenger.
+    ///
+    /// # Safety
+    /// - `instance` must have been created from `entry`, with an API version of at least 1.3
+    /// - `physical_device` and `surface` must belong to `instance`
+    /// - If `ownership` is [`Ownership::Borrowed`], `instance` and `surface` must outlive this
+    ///   `Core` and everything created from it
+    pub unsafe fn from_raw(
+        entry: ash::Entry,
+        instance: ash::Instance,
+        physical_device: vk::PhysicalDevice,
+        surface: Option<vk::SurfaceKHR>,
+        ownership: Ownership,
+    ) -> Result<Self> {
+        let properties = instance.get_physical_device_properties(physical_device);
+        let index = instance
+            .enumerate_physical_devices()?
+            .iter()
+            .position(|d| *d == physical_device)
+            .unwrap_or_default();
+        let selected_device = SelectedDevice {
+            index,
+            name: properties
+                .device_name_as_c_str()
+                .map(|n| n.to_string_lossy().into_owned())
+                .unwrap_or_default(),
+            device_type: properties.device_type,
+            reason: SelectionReason::Provided,
+        };
+
+        log::info!(
+            "[lazy_vulkan] Using device {} ({:?}): {}",
+            selected_device.name,
+            selected_device.device_type,
+            selected_device.reason
+        );
+
+        let surface_fn = ash::khr::surface::Instance::new(&entry, &instance);
+
+        Ok(Core {
+            entry,
+            instance,
+            physical_device,
+            selected_device,
+            surface,
+            surface_fn,
+            validation_enabled: false,
+            debug_utils_enabled: false,
+            validation_messages: Arc::new(ValidationMessages::new(false)),
+            debug_messenger: None,
+            ownership,
+        })
+    }
+
+    /// Take all the validation messages received since the last call. Handy to call once per
+    /// frame.
+    pub fn drain_validation_messages(&self) -> Vec<ValidationMessage> {
+        self.validation_messages.drain()
+    }
+
+    /// Panics if the validation layer has reported any errors. Intended for tests.
+    ///
+    /// Also panics if validation (or the `debug_utils` messenger that reports its messages)
+    /// wasn't actually enabled - eg. because the layer isn't installed - as there would be
+    /// nothing to check.
+    #[track_caller]
+    pub fn assert_no_validation_errors(&self) {
+        assert!(
+            self.validation_enabled && self.debug_utils_enabled,
+            "Validation errors can't be checked: {VALIDATION_LAYER:?} or debug_utils isn't enabled"
+        );
+
+        let errors = self.validation_messages.drain_errors();
+        if errors.is_empty() {
+            return;
+        }
+
+        let errors = errors
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>()
+            .join("\n");
+        panic!("Validation errors were reported:\n{errors}");
+    }
+}
+
+impl Drop for Core {
+    fn drop(&mut self) {
+        // The messenger has to go before the instance does.
+        self.debug_messenger.take();
+
+        if self.ownership == Ownership::Borrowed {
+            return;
+        }
+
+        unsafe {
+            if let Some(surface) = self.surface {
+                self.surface_fn.destroy_surface(surface, None);
+            }
+            self.instance.destroy_instance(None);
+        }
+    }
+}
+
+/// Configures how the Vulkan instance is created, and which physical device is used.
+///
+/// All of the validation options are best-effort: if the validation layer (or the extensions
+/// needed to configure it) isn't installed, we log a warning and carry on without it.
+#[derive(Debug, Clone)]
+pub struct CoreBuilder {
+    /// Enable `VK_LAYER_KHRONOS_validation`. Defaults to on in debug builds.
+    pub validation: bool,
+    /// Enable synchronization validation. Requires `validation`.
+    pub synchronization_validation: bool,
+    /// Enable GPU-assisted validation. Requires `validation`. This is *slow*.
+    pub gpu_assisted_validation: bool,
+    /// Enable best practices warnings. Requires `validation`.
+    pub best_practices: bool,
+    /// Enable `VK_EXT_debug_utils` for object names and debug markers.
+    pub debug_utils: bool,
+    /// Treat any validation error as fatal: the frame in which it was reported will fail.
+    pub fail_on_validation_error: bool,
+    pub device_selector: DeviceSelector,
+}
+
+impl Default for CoreBuilder {
+    fn default() -> Self {
+        Self {
+            validation: cfg!(debug_assertions),
+            synchronization_validation: false,
+            gpu_assisted_validation: false,
+            best_practices: false,
+            debug_utils: true,
+            fail_on_validation_error: false,
+            device_selector: DeviceSelector::default(),
+        }
+    }
+}
+
+impl CoreBuilder {
+    pub fn validation(mut self, validation: bool) -> Self {
+        self.validation = validation;
+        self
+    }
+
+    pub fn synchronization_validation(mut self, synchronization_validation: bool) -> Self {
+        self.synchronization_validation = synchronization_validation;
+        self
+    }
+
+    pub fn gpu_assisted_validation(mut self, gpu_assisted_validation: bool) -> Self {
+        self.gpu_assisted_validation = gpu_assisted_validation;
+        self
+    }
+
+    pub fn best_practices(mut self, best_practices: bool) -> Self {
+        self.best_practices = best_practices;
+        self
+    }
+
+    pub fn debug_utils(mut self, debug_utils: bool) -> Self {
+        self.debug_utils = debug_utils;
+        self
+    }
+
+    pub fn fail_on_validation_error(mut self, fail_on_validation_error: bool) -> Self {
+        self.fail_on_validation_error = fail_on_validation_error;
+        self
+    }
+
+    pub fn device_selector(mut self, device_selector: DeviceSelector) -> Self {
+        self.device_selector = device_selector;
+        self
+    }
+
+    pub fn build_for_window(&self, window: &winit::window::Window) -> Result<Core> {
         // #[cfg(any(target_os = "windows", target_vendor = "apple"))]
         let entry = ash::Entry::linked();
 
         // #[cfg(not(any(target_os = "windows", target_vendor = "apple")))]
         // let entry = unsafe { ash::Entry::load() }.unwrap();
-        println!("Layers:");
-        for layer in unsafe { entry.enumerate_instance_layer_properties().unwrap() } {
-            let name = layer.layer_name_as_c_str().unwrap();
-            println!("Layer name: {name:?}");
-        }
 
         let display_handle = window.display_handle().unwrap().as_raw();
+        let window_handle = window.window_handle().unwrap().as_raw();
 
-        let mut instance_extensions = ash_window::enumerate_required_extensions(display_handle)
-            .unwrap()
-            .to_vec();
+        let instance_extensions =
+            ash_window::enumerate_required_extensions(display_handle)?.to_vec();
 
-        // TODO: Make this optional
-        instance_extensions.push(ash::ext::debug_utils::NAME.as_ptr());
+        let validation_messages = Arc::new(ValidationMessages::new(self.fail_on_validation_error));
+        let (instance, validation_enabled, debug_utils_enabled) = self.create_instance(
+            &entry,
+            instance_extensions,
+            vk::API_VERSION_1_3,
+            &validation_messages,
+        )?;
 
-        let version;
-        let instance_create_flags;
+        let surface = unsafe {
+            ash_window::create_surface(&entry, &instance, display_handle, window_handle, None)
+        }?;
 
-        #[cfg(target_vendor = "apple")]
-        {
-            instance_extensions.push(ash::khr::portability_enumeration::NAME.as_ptr());
-            instance_extensions.push(ash::khr::get_physical_device_properties2::NAME.as_ptr());
-            instance_extensions.push(ash::ext::layer_settings::NAME.as_ptr());
-            version = vk::API_VERSION_1_3;
-            instance_create_flags = vk::InstanceCreateFlags::ENUMERATE_PORTABILITY_KHR;
-        }
+        self.finish(
+            entry,
+            instance,
+            Some(surface),
+            validation_enabled,
+            debug_utils_enabled,
+            validation_messages,
+        )
+    }
+
+    pub fn build_headless(&self) -> Result<Core> {
+        let entry = unsafe { ash::Entry::load()? };
 
+        #[cfg(any(target_os = "macos", target_os = "ios"))]
+        let api_version = vk::API_VERSION_1_2;
         #[cfg(not(any(target_os = "macos", target_os = "ios")))]
-        {
-            version = vk::API_VERSION_1_3;
-            instance_create_flags = vk::InstanceCreateFlags::default();
-        }
+        let api_version = vk::API_VERSION_1_3;
+
+        let validation_messages = Arc::new(ValidationMessages::new(self.fail_on_validation_error));
+        let (instance, validation_enabled, debug_utils_enabled) =
+            self.create_instance(&entry, Vec::new(), api_version, &validation_messages)?;
+
+        self.finish(
+            entry,
+            instance,
+            None,
+            validation_enabled,
+            debug_utils_enabled,
+            validation_messages,
+        )
+    }
 
-        let mut debug_messenger_create_info = debug_messenger_create_info();
+    fn create_instance(
+        &self,
+        entry: &ash::Entry,
+        mut instance_extensions: Vec<*const c_char>,
+        api_version: u32,
+        validation_messages: &Arc<ValidationMessages>,
+    ) -> Result<(ash::Instance, bool, bool)> {
+        let available_layers = unsafe { entry.enumerate_instance_layer_properties()? };
+        for layer in &available_layers {
+            log::debug!(
+                "Available layer: {:?}",
+                layer.layer_name_as_c_str().unwrap()
+            );
+        }
 
-        let validation_layer = c"VK_LAYER_KHRONOS_validation";
+        let validation_enabled = self.validation
+            && available_layers
+                .iter()
+                .any(|layer| layer.layer_name_as_c_str() == Ok(VALIDATION_LAYER));
 
-        let layer_setting = vk::LayerSettingEXT::default()
-            .layer_name(c"khronos_validation")
-            .setting_name(c"validate_core")
-            .ty(LayerSettingTypeEXT::BOOL32)
-            .values(&[1]);
+        if self.validation && !validation_enabled {
+            log::warn!("[lazy_vulkan] Validation was requested, but {VALIDATION_LAYER:?} is not installed. Continuing without it.");
+        }
 
-        let binding = [layer_setting];
-        let mut layer_settings_create_info =
-            vk::LayerSettingsCreateInfoEXT::default().settings(&binding);
-
-        let instance = unsafe {
-            entry
-                .create_instance(
-                    &vk::InstanceCreateInfo::default()
-                        .flags(instance_create_flags)
-                        .enabled_extension_names(&instance_extensions)
-                        .enabled_layer_names(&[validation_layer.as_ptr()])
-                        .application_info(&vk::ApplicationInfo::default().api_version(version))
-                        .push_next(&mut layer_settings_create_info)
-                        .push_next(&mut debug_messenger_create_info),
-                    None,
-                )
-                .unwrap()
+        let available_extensions = unsafe { entry.enumerate_instance_extension_properties(None) }?;
+        let validation_extensions = if validation_enabled {
+            unsafe { entry.enumerate_instance_extension_properties(Some(VALIDATION_LAYER)) }
+                .unwrap_or_default()
+        } else {
+            Vec::new()
         };
 
-        let debug_messenger = DebugMessenger::new(&entry, &instance).unwrap();
-
-        let physical_device = unsafe { instance.enumerate_physical_devices() }
-            .unwrap()
-            .first()
-            .copied()
-            .unwrap();
+        let has_extension = |name: &CStr| {
+            available_extensions
+                .iter()
+                .chain(&validation_extensions)
+                .any(|e| e.extension_name_as_c_str() == Ok(name))
+        };
 
-        Self {
-            entry,
-            instance,
-            physical_device,
+        let debug_utils_enabled = self.debug_utils && has_extension(ash::ext::debug_utils::NAME);
+        if self.debug_utils && !debug_utils_enabled {
+            log::warn!("[lazy_vulkan] debug_utils was requested, but is not available. Continuing without it.");
         }
-    }
 
-    pub fn headless() -> Self {
-        let entry = unsafe { ash::Entry::load().unwrap() };
-
-        let mut instance_extensions = Vec::new();
+        if debug_utils_enabled {
+            instance_extensions.push(ash::ext::debug_utils::NAME.as_ptr());
+        }
 
-        instance_extensions.push(ash::ext::debug_utils::NAME.as_ptr());
-        let version;
         let instance_create_flags;
 
         #[cfg(any(target_os = "macos", target_os = "ios"))]
         {
             instance_extensions.push(ash::khr::portability_enumeration::NAME.as_ptr());
             instance_extensions.push(ash::khr::get_physical_device_properties2::NAME.as_ptr());
-            version = vk::API_VERSION_1_2;
             instance_create_flags = vk::InstanceCreateFlags::ENUMERATE_PORTABILITY_KHR;
         }
 
         #[cfg(not(any(target_os = "macos", target_os = "ios")))]
         {
-            version = vk::API_VERSION_1_3;
             instance_create_flags = vk::InstanceCreateFlags::default();
         }
 
-        let instance = unsafe {
-            entry
-                .create_instance(
-                    &vk::InstanceCreateInfo::default()
-                        .flags(instance_create_flags)
-                        .enabled_extension_names(&instance_extensions)
-                        .application_info(&vk::ApplicationInfo::default().api_version(version)),
-                    None,
-                )
-                .unwrap()
+        // Configure the validation layer. The preferred way to do this is VK_EXT_layer_settings,
+        // but older SDKs only support VK_EXT_validation_features.
+        let enabled_layers = if validation_enabled {
+            vec![VALIDATION_LAYER.as_ptr()]
+        } else {
+            vec![]
         };
 
-        let physical_device = unsafe { instance.enumerate_physical_devices() }
-            .unwrap()
-            .first()
-            .copied()
-            .unwrap();
+        let mut layer_settings = vec![validation_setting(c"validate_core", true)];
+        let mut enabled_validation_features = vec![];
+        if self.synchronization_validation {
+            layer_settings.push(validation_setting(c"validate_sync", true));
+            enabled_validation_features
+                .push(vk::ValidationFeatureEnableEXT::SYNCHRONIZATION_VALIDATION);
+        }
+        if self.gpu_assisted_validation {
+            layer_settings.push(validation_setting(c"gpuav_enable", true));
+            enabled_validation_features.push(vk::ValidationFeatureEnableEXT::GPU_ASSISTED);
+        }
+        if self.best_practices {
+            layer_settings.push(validation_setting(c"validate_best_practices", true));
+            enabled_validation_features.push(vk::ValidationFeatureEnableEXT::BEST_PRACTICES);
+        }
 
-        Self {
+        let mut layer_settings_create_info =
+            vk::LayerSettingsCreateInfoEXT::default().settings(&layer_settings);
+        let mut validation_features = vk::ValidationFeaturesEXT::default()
+            .enabled_validation_features(&enabled_validation_features);
+        let mut debug_messenger_create_info = debug_messenger_create_info(validation_messages);
+        let application_info = vk::ApplicationInfo::default().api_version(api_version);
+
+        let mut instance_create_info = vk::InstanceCreateInfo::default()
+            .flags(instance_create_flags)
+            .enabled_layer_names(&enabled_layers)
+            .application_info(&application_info);
+
+        if validation_enabled {
+            if has_extension(ash::ext::layer_settings::NAME) {
+                instance_extensions.push(ash::ext::layer_settings::NAME.as_ptr());
+                instance_create_info =
+                    instance_create_info.push_next(&mut layer_settings_create_info);
+            } else if has_extension(ash::ext::validation_features::NAME) {
+                instance_extensions.push(ash::ext::validation_features::NAME.as_ptr());
+                instance_create_info = instance_create_info.push_next(&mut validation_features);
+            } else if !enabled_validation_features.is_empty() {
+                log::warn!("[lazy_vulkan] Unable to configure the validation layer; only core validation will be enabled.");
+            }
+        }
+
+        if debug_utils_enabled {
+            instance_create_info = instance_create_info.push_next(&mut debug_messenger_create_info);
+        }
+
+        let instance_create_info =
+            instance_create_info.enabled_extension_names(&instance_extensions);
+        let instance = unsafe { entry.create_instance(&instance_create_info, None) }?;
+
+        Ok((instance, validation_enabled, debug_utils_enabled))
+    }
+
+    fn finish(
+        &self,
+        entry: ash::Entry,
+        instance: ash::Instance,
+        surface: Option<vk::SurfaceKHR>,
+        validation_enabled: bool,
+        debug_utils_enabled: bool,
+        validation_messages: Arc<ValidationMessages>,
+    ) -> Result<Core> {
+        let debug_messenger = debug_utils_enabled
+            .then(|| DebugMessenger::new(&entry, &instance, &validation_messages))
+            .transpose()?;
+
+        let surface_fn = ash::khr::surface::Instance::new(&entry, &instance);
+
+        let (physical_device, selected_device) = self
+            .device_selector
+            .select(&instance, surface.map(|surface| (&surface_fn, surface)))
+            .ok_or(Error::NoSuitableDevice)?;
+
+        log::info!(
+            "[lazy_vulkan] Using device {} ({:?}): {}",
+            selected_device.name,
+            selected_device.device_type,
+            selected_device.reason
+        );
+
+        Ok(Core {
             entry,
             instance,
             physical_device,
-        }
+            selected_device,
+            surface,
+            surface_fn,
+            validation_enabled,
+            debug_utils_enabled,
+            validation_messages,
+            debug_messenger,
+            ownership: Ownership::Owned,
+        })
     }
 }
+
+fn validation_setting(name: &'static CStr, value: bool) -> vk::LayerSettingEXT<'static> {
+    static TRUE: vk::Bool32 = vk::TRUE;
+    static FALSE: vk::Bool32 = vk::FALSE;
+
+    // NOTE: ash's `values` builder assumes the values are bytes, which is not true for BOOL32.
+    let mut setting = vk::LayerSettingEXT::default()
+        .layer_name(c"khronos_validation")
+        .setting_name(name)
+        .ty(LayerSettingTypeEXT::BOOL32);
+    setting.value_count = 1;
+    setting.p_values = if value { &TRUE } else { &FALSE } as *const vk::Bool32 as *const _;
+    setting
+}
+
 use ash::ext::debug_utils;
 use log::{debug, error, info, trace, warn};
 use std::borrow::Cow;
 use std::os::raw::c_void;
+use std::sync::{Arc, Mutex};
 
 /// Basic owned wrapper so cleanup is obvious.
 pub struct DebugMessenger {
@@ -153,9 +477,13 @@ pub struct DebugMessenger {
 }
 
 impl DebugMessenger {
-    pub fn new(entry: &ash::Entry, instance: &ash::Instance) -> Result<Self, vk::Result> {
+    pub fn new(
+        entry: &ash::Entry,
+        instance: &ash::Instance,
+        messages: &Arc<ValidationMessages>,
+    ) -> Result<Self, vk::Result> {
         let loader = debug_utils::Instance::new(entry, instance);
-        let create_info = debug_messenger_create_info();
+        let create_info = debug_messenger_create_info(messages);
 
         let messenger = unsafe { loader.create_debug_utils_messenger(&create_info, None)? };
 
@@ -165,8 +493,10 @@ impl DebugMessenger {
     pub fn raw(&self) -> vk::DebugUtilsMessengerEXT {
         self.messenger
     }
+}
 
-    pub fn destroy(&self) {
+impl Drop for DebugMessenger {
+    fn drop(&mut self) {
         unsafe {
             self.loader
                 .destroy_debug_utils_messenger(self.messenger, None);
@@ -175,7 +505,13 @@ impl DebugMessenger {
 }
 
 /// Call this when building your instance if you want debug messages during instance creation too.
-pub fn debug_messenger_create_info() -> vk::DebugUtilsMessengerCreateInfoEXT<'static> {
+///
+/// ## NOTE
+/// `messages` is passed to the driver as a raw pointer, so it must outlive the messenger (or the
+/// instance, if this is chained into `VkInstanceCreateInfo`).
+pub fn debug_messenger_create_info(
+    messages: &Arc<ValidationMessages>,
+) -> vk::DebugUtilsMessengerCreateInfoEXT<'static> {
     vk::DebugUtilsMessengerCreateInfoEXT::default()
         .message_severity(
             vk::DebugUtilsMessageSeverityFlagsEXT::ERROR
@@ -189,6 +525,7 @@ pub fn debug_messenger_create_info() -> vk::DebugUtilsMessengerCreateInfoEXT<'st
                 | vk::DebugUtilsMessageTypeFlagsEXT::PERFORMANCE,
         )
         .pfn_user_callback(Some(vulkan_debug_callback))
+        .user_data(Arc::as_ptr(messages) as *mut c_void)
 }
 
 /// Optional convenience: chain this into InstanceCreateInfo via push_next(...)
@@ -207,11 +544,103 @@ pub fn instance_create_info_with_debug<'a>(
         .push_next(debug_ci)
 }
 
+/// A single message received from the validation layer (or the driver).
+#[derive(Debug, Clone)]
+pub struct ValidationMessage {
+    pub severity: vk::DebugUtilsMessageSeverityFlagsEXT,
+    pub message_type: vk::DebugUtilsMessageTypeFlagsEXT,
+    pub message_id_name: String,
+    pub message_id_number: i32,
+    pub message: String,
+    /// The debug labels (see [`crate::Context::begin_marker`]) that were active on the command
+    /// buffer, outermost first.
+    pub command_buffer_labels: Vec<String>,
+    /// The debug labels that were active on the queue, outermost first.
+    pub queue_labels: Vec<String>,
+    /// The objects involved, with their debug names if they have them.
+    pub objects: Vec<String>,
+}
+
+impl ValidationMessage {
+    pub fn is_error(&self) -> bool {
+        self.severity
+            .contains(vk::DebugUtilsMessageSeverityFlagsEXT::ERROR)
+    }
+}
+
+impl std::fmt::Display for ValidationMessage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "[Vulkan][{:?}][{:?}][{}:{}] {}",
+            self.severity,
+            self.message_type,
+            self.message_id_name,
+            self.message_id_number,
+            self.message
+        )?;
+
+        if !self.command_buffer_labels.is_empty() {
+            write!(f, " (in {})", self.command_buffer_labels.join(" > "))?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Collects every message the debug messenger receives, so they can be inspected later.
+///
+/// Messages are still forwarded to `log` as they arrive.
+#[derive(Debug, Default)]
+pub struct ValidationMessages {
+    messages: Mutex<Vec<ValidationMessage>>,
+    /// If set, any validation error will cause the frame to fail.
+    pub fail_on_error: bool,
+}
+
+impl ValidationMessages {
+    pub fn new(fail_on_error: bool) -> Self {
+        Self {
+            messages: Default::default(),
+            fail_on_error,
+        }
+    }
+
+    /// Take all the messages received since the last call to `drain`.
+    pub fn drain(&self) -> Vec<ValidationMessage> {
+        std::mem::take(&mut *self.messages.lock().unwrap())
+    }
+
+    /// Take all the *errors* received since the last call to `drain` or `drain_errors`.
+    ///
+    /// Any other messages are left alone.
+    pub fn drain_errors(&self) -> Vec<ValidationMessage> {
+        let mut messages = self.messages.lock().unwrap();
+        let (errors, rest) = std::mem::take(&mut *messages)
+            .into_iter()
+            .partition(ValidationMessage::is_error);
+        *messages = rest;
+        errors
+    }
+
+    pub fn has_errors(&self) -> bool {
+        self.messages
+            .lock()
+            .unwrap()
+            .iter()
+            .any(ValidationMessage::is_error)
+    }
+
+    fn push(&self, message: ValidationMessage) {
+        self.messages.lock().unwrap().push(message);
+    }
+}
+
 unsafe extern "system" fn vulkan_debug_callback(
     message_severity: vk::DebugUtilsMessageSeverityFlagsEXT,
     message_type: vk::DebugUtilsMessageTypeFlagsEXT,
     p_callback_data: *const vk::DebugUtilsMessengerCallbackDataEXT<'_>,
-    _p_user_data: *mut c_void,
+    p_user_data: *mut c_void,
 ) -> vk::Bool32 {
     let callback_data = unsafe { &*p_callback_data };
 
@@ -246,5 +675,68 @@ unsafe extern "system" fn vulkan_debug_callback(
         trace!("{text}");
     }
 
+    // INFO messages are mostly noise (eg. loader chatter), so don't bother keeping them.
+    if p_user_data.is_null()
+        || !message_severity.intersects(
+            vk::DebugUtilsMessageSeverityFlagsEXT::ERROR
+                | vk::DebugUtilsMessageSeverityFlagsEXT::WARNING,
+        )
+    {
+        return vk::FALSE;
+    }
+
+    let messages = unsafe { &*(p_user_data as *const ValidationMessages) };
+    let labels = |p_labels: *const vk::DebugUtilsLabelEXT, count: u32| {
+        if p_labels.is_null() {
+            return Vec::new();
+        }
+        unsafe { std::slice::from_raw_parts(p_labels, count as usize) }
+            .iter()
+            .map(|label| {
+                label
+                    .label_name_as_c_str()
+                    .map(|name| name.to_string_lossy().into_owned())
+                    .unwrap_or_default()
+            })
+            .collect()
+    };
+
+    let objects = if callback_data.p_objects.is_null() {
+        Vec::new()
+    } else {
+        unsafe {
+            std::slice::from_raw_parts(callback_data.p_objects, callback_data.object_count as usize)
+        }
+        .iter()
+        .map(|object| {
+            let name = object
+                .object_name_as_c_str()
+                .map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or_default();
+            format!(
+                "{:?} {:#x} {name}",
+                object.object_type, object.object_handle
+            )
+        })
+        .collect()
+    };
+
+    messages.push(ValidationMessage {
+        severity: message_severity,
+        message_type,
+        message_id_name: message_id_name.into_owned(),
+        message_id_number,
+        message: message.into_owned(),
+        command_buffer_labels: labels(
+            callback_data.p_cmd_buf_labels,
+            callback_data.cmd_buf_label_count,
+        ),
+        queue_labels: labels(
+            callback_data.p_queue_labels,
+            callback_data.queue_label_count,
+        ),
+        objects,
+    });
+
     vk::FALSE
 }
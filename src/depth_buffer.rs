@@ -1,6 +1,7 @@
 use ash::vk;
 
 use super::context::Context;
+use crate::{Error, Result};
 
 #[derive(Debug, Copy, Clone)]
 pub struct DepthBuffer {
@@ -11,7 +12,7 @@ pub struct DepthBuffer {
 }
 
 impl DepthBuffer {
-    pub(crate) fn new(context: &Context, extent: vk::Extent2D) -> Self {
+    pub(crate) fn new(context: &Context, extent: vk::Extent2D) -> Result<Self> {
         let device = &context.device;
 
         let image = unsafe {
@@ -29,14 +30,15 @@ impl DepthBuffer {
                     .format(DEPTH_FORMAT),
                 None,
             )
-        }
-        .unwrap();
+        }?;
 
         let memory_requirements = unsafe { device.get_image_memory_requirements(image) };
 
         let memory_type_index = context
             .find_memory_type_index(&memory_requirements, vk::MemoryPropertyFlags::DEVICE_LOCAL)
-            .expect("No memory type index for depth buffer - impossible");
+            .ok_or(Error::NoSuitableMemoryType(
+                vk::MemoryPropertyFlags::DEVICE_LOCAL,
+            ))?;
 
         let memory = unsafe {
             device.allocate_memory(
@@ -45,15 +47,13 @@ impl DepthBuffer {
                     .memory_type_index(memory_type_index),
                 None,
             )
-        }
-        .expect("Failed to allocate memory - impossible");
+        }?;
 
         unsafe {
             device.bind_image_memory2(&[vk::BindImageMemoryInfo::default()
                 .image(image)
                 .memory(memory)])
-        }
-        .unwrap();
+        }?;
 
         let view = unsafe {
             device.create_image_view(
@@ -65,30 +65,30 @@ impl DepthBuffer {
                     .subresource_range(DEPTH_RANGE),
                 None,
             )
-        }
-        .unwrap();
+        }?;
 
-        Self {
+        Ok(Self {
             image,
             view,
             memory,
             extent,
-        }
+        })
     }
 
-    pub fn resize(&mut self, context: &Context, new_extent: vk::Extent2D) {
+    pub fn resize(&mut self, context: &Context, new_extent: vk::Extent2D) -> Result<()> {
         if new_extent == self.extent {
             // Sizes are identical, nothing to do.
-            return;
+            return Ok(());
         }
 
-        unsafe { context.device.device_wait_idle().unwrap() };
+        unsafe { context.device.device_wait_idle()? };
 
         unsafe { self.destroy(context) };
-        *self = DepthBuffer::new(context, new_extent)
+        *self = DepthBuffer::new(context, new_extent)?;
+        Ok(())
     }
 
-    unsafe fn destroy(&self, context: &Context) {
+    pub(crate) unsafe fn destroy(&self, context: &Context) {
         let device = &context.device;
 
         device.destroy_image_view(self.view, None);
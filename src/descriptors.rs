@@ -2,7 +2,7 @@ use std::sync::Arc;
 
 use ash::vk;
 
-use crate::Context;
+use crate::{Context, Result};
 
 pub struct Descriptors {
     context: Arc<Context>,
@@ -14,7 +14,7 @@ pub struct Descriptors {
 impl Descriptors {
     pub const TEXTURE_BINDING: u32 = 0;
 
-    pub fn new(context: Arc<Context>) -> Descriptors {
+    pub fn new(context: Arc<Context>) -> Result<Descriptors> {
         let device = &context.device;
 
         let pool_sizes = [vk::DescriptorPoolSize {
@@ -30,8 +30,7 @@ impl Descriptors {
                     .flags(vk::DescriptorPoolCreateFlags::UPDATE_AFTER_BIND),
                 None,
             )
-        }
-        .unwrap();
+        }?;
 
         let flags = [vk::DescriptorBindingFlags::PARTIALLY_BOUND
             | vk::DescriptorBindingFlags::UPDATE_AFTER_BIND];
@@ -56,25 +55,22 @@ impl Descriptors {
                     .push_next(&mut binding_flags),
                 None,
             )
-        }
-        .unwrap();
+        }?;
 
         let set = unsafe {
-            device
-                .allocate_descriptor_sets(
-                    &vk::DescriptorSetAllocateInfo::default()
-                        .descriptor_pool(pool)
-                        .set_layouts(std::slice::from_ref(&layout)),
-                )
-                .unwrap()[0]
+            device.allocate_descriptor_sets(
+                &vk::DescriptorSetAllocateInfo::default()
+                    .descriptor_pool(pool)
+                    .set_layouts(std::slice::from_ref(&layout)),
+            )?[0]
         };
 
-        Descriptors {
+        Ok(Descriptors {
             context,
             pool,
             set,
             layout,
-        }
+        })
     }
 
     pub unsafe fn update_texture_descriptor_set(
@@ -129,3 +125,14 @@ impl Descriptors {
     //     );
     // }
 }
+
+impl Drop for Descriptors {
+    fn drop(&mut self) {
+        let device = &self.context.device;
+        unsafe {
+            // Also frees `set`
+            device.destroy_descriptor_pool(self.pool, None);
+            device.destroy_descriptor_set_layout(self.layout, None);
+        }
+    }
+}
@@ -0,0 +1,176 @@
+use std::mem::{offset_of, size_of};
+
+use ash::vk;
+
+/// The core, 1.1, 1.2 and 1.3 device features, all in one place.
+///
+/// Every feature struct is nothing but a bunch of `VkBool32`s, so we treat them as slices to
+/// compare, combine and check them without having to name every single field.
+///
+/// ## NOTE
+/// The `p_next` pointers are always null - we build the chain ourselves when we need it.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DeviceFeatures {
+    pub core: vk::PhysicalDeviceFeatures,
+    pub vulkan_11: vk::PhysicalDeviceVulkan11Features<'static>,
+    pub vulkan_12: vk::PhysicalDeviceVulkan12Features<'static>,
+    pub vulkan_13: vk::PhysicalDeviceVulkan13Features<'static>,
+}
+
+// SAFETY: The only pointers in here are the `p_next` pointers, which are always null.
+unsafe impl Send for DeviceFeatures {}
+unsafe impl Sync for DeviceFeatures {}
+
+impl DeviceFeatures {
+    /// The features lazy_vulkan can't live without.
+    pub fn minimum() -> DeviceFeatures {
+        DeviceFeatures {
+            vulkan_12: vk::PhysicalDeviceVulkan12Features::default()
+                .buffer_device_address(true)
+                // Used to wait on the transfer queue
+                .timeline_semaphore(true)
+                // Used by the "all the images" descriptor set
+                .runtime_descriptor_array(true)
+                .descriptor_binding_partially_bound(true)
+                .descriptor_binding_sampled_image_update_after_bind(true)
+                .shader_sampled_image_array_non_uniform_indexing(true),
+            vulkan_13: vk::PhysicalDeviceVulkan13Features::default()
+                .dynamic_rendering(true)
+                .synchronization2(true),
+            ..Default::default()
+        }
+    }
+
+    /// Ask the driver which features `physical_device` supports.
+    pub fn query(instance: &ash::Instance, physical_device: vk::PhysicalDevice) -> DeviceFeatures {
+        let mut features = DeviceFeatures::default();
+        let mut features2 = vk::PhysicalDeviceFeatures2::default()
+            .push_next(&mut features.vulkan_11)
+            .push_next(&mut features.vulkan_12)
+            .push_next(&mut features.vulkan_13);
+        unsafe { instance.get_physical_device_features2(physical_device, &mut features2) };
+        features.core = features2.features;
+
+        features.clear_p_next();
+        features
+    }
+
+    /// Every feature that's enabled in either `self` or `other`.
+    pub fn union(&self, other: &DeviceFeatures) -> DeviceFeatures {
+        self.combine(other, |a, b| a | b)
+    }
+
+    /// Every feature that's enabled in both `self` and `other`.
+    pub fn intersection(&self, other: &DeviceFeatures) -> DeviceFeatures {
+        self.combine(other, |a, b| a & b)
+    }
+
+    /// Every feature that's enabled in `self`, but not in `supported`.
+    pub fn missing_from(&self, supported: &DeviceFeatures) -> DeviceFeatures {
+        self.combine(supported, |a, b| a & !b)
+    }
+
+    /// Returns true if every feature in `required` is enabled in `self`.
+    pub fn contains(&self, required: &DeviceFeatures) -> bool {
+        required.missing_from(self).is_empty()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.as_slices()
+            .iter()
+            .all(|slice| slice.iter().all(|b| *b == vk::FALSE))
+    }
+
+    fn combine(&self, other: &DeviceFeatures, f: impl Fn(bool, bool) -> bool) -> DeviceFeatures {
+        let mut result = *self;
+        for (result, other) in result.as_slices_mut().into_iter().zip(other.as_slices()) {
+            for (a, b) in result.iter_mut().zip(other) {
+                *a = f(*a == vk::TRUE, *b == vk::TRUE).into();
+            }
+        }
+        result
+    }
+
+    fn clear_p_next(&mut self) {
+        self.vulkan_11.p_next = std::ptr::null_mut();
+        self.vulkan_12.p_next = std::ptr::null_mut();
+        self.vulkan_13.p_next = std::ptr::null_mut();
+    }
+
+    fn as_slices(&self) -> [&[vk::Bool32]; 4] {
+        let [core, vulkan_11, vulkan_12, vulkan_13] = BOOL_RANGES;
+        unsafe {
+            [
+                bool_slice(&self.core, core),
+                bool_slice(&self.vulkan_11, vulkan_11),
+                bool_slice(&self.vulkan_12, vulkan_12),
+                bool_slice(&self.vulkan_13, vulkan_13),
+            ]
+        }
+    }
+
+    fn as_slices_mut(&mut self) -> [&mut [vk::Bool32]; 4] {
+        let [core, vulkan_11, vulkan_12, vulkan_13] = BOOL_RANGES;
+        unsafe {
+            [
+                bool_slice_mut(&mut self.core, core),
+                bool_slice_mut(&mut self.vulkan_11, vulkan_11),
+                bool_slice_mut(&mut self.vulkan_12, vulkan_12),
+                bool_slice_mut(&mut self.vulkan_13, vulkan_13),
+            ]
+        }
+    }
+}
+
+impl From<vk::PhysicalDeviceFeatures> for DeviceFeatures {
+    fn from(core: vk::PhysicalDeviceFeatures) -> Self {
+        DeviceFeatures {
+            core,
+            ..Default::default()
+        }
+    }
+}
+
+/// Where the `VkBool32`s live in each of the feature structs, as `(offset, count)`.
+const BOOL_RANGES: [(usize, usize); 4] = [
+    (0, size_of::<vk::PhysicalDeviceFeatures>() / BOOL_SIZE),
+    bool_range(
+        offset_of!(
+            vk::PhysicalDeviceVulkan11Features,
+            storage_buffer16_bit_access
+        ),
+        offset_of!(vk::PhysicalDeviceVulkan11Features, shader_draw_parameters),
+    ),
+    bool_range(
+        offset_of!(
+            vk::PhysicalDeviceVulkan12Features,
+            sampler_mirror_clamp_to_edge
+        ),
+        offset_of!(
+            vk::PhysicalDeviceVulkan12Features,
+            subgroup_broadcast_dynamic_id
+        ),
+    ),
+    bool_range(
+        offset_of!(vk::PhysicalDeviceVulkan13Features, robust_image_access),
+        offset_of!(vk::PhysicalDeviceVulkan13Features, maintenance4),
+    ),
+];
+
+const BOOL_SIZE: usize = size_of::<vk::Bool32>();
+
+const fn bool_range(first: usize, last: usize) -> (usize, usize) {
+    (first, (last - first) / BOOL_SIZE + 1)
+}
+
+/// SAFETY: `range` must describe a run of `VkBool32`s within `T`.
+unsafe fn bool_slice<T>(value: &T, (offset, count): (usize, usize)) -> &[vk::Bool32] {
+    let ptr = (value as *const T as *const u8).add(offset) as *const vk::Bool32;
+    std::slice::from_raw_parts(ptr, count)
+}
+
+/// SAFETY: `range` must describe a run of `VkBool32`s within `T`.
+unsafe fn bool_slice_mut<T>(value: &mut T, (offset, count): (usize, usize)) -> &mut [vk::Bool32] {
+    let ptr = (value as *mut T as *mut u8).add(offset) as *mut vk::Bool32;
+    std::slice::from_raw_parts_mut(ptr, count)
+}
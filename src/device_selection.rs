@@ -0,0 +1,414 @@
+use std::ffi::CStr;
+
+use ash::vk;
+
+use crate::{DeviceFeatures, QueueFamilies};
+
+/// The environment variable that will be checked by [`DeviceSelector::default`]. It can contain
+/// either the index of the device (as reported by `vkEnumeratePhysicalDevices`), or some part of
+/// its name, eg. `LAZY_VULKAN_DEVICE=1` or `LAZY_VULKAN_DEVICE=llvmpipe`.
+pub const DEVICE_ENV_VAR: &str = "LAZY_VULKAN_DEVICE";
+
+/// Decides which physical device [`crate::Core`] should use.
+///
+/// Devices that don't meet the requirements (extensions, features, surface support) are never
+/// picked. Out of the ones that remain:
+///
+/// 1. If the environment variable is set and matches a device, that device wins
+/// 2. If `name_contains` is set and matches a device, that device wins
+/// 3. Otherwise, the device with the highest score from `type_score` wins
+#[derive(Debug, Clone)]
+pub struct DeviceSelector {
+    /// Scores a device based on its type. Higher is better.
+    pub type_score: fn(vk::PhysicalDeviceType) -> i32,
+    /// Prefer devices whose name contains this string (case insensitive).
+    pub name_contains: Option<String>,
+    /// An environment variable that can be used to override the selection.
+    pub env_var: Option<String>,
+    /// Device extensions that must be supported, in addition to the ones lazy_vulkan requires.
+    pub required_extensions: Vec<&'static CStr>,
+    /// Features that must be supported, in addition to [`DeviceFeatures::minimum`].
+    pub required_features: DeviceFeatures,
+}
+
+impl Default for DeviceSelector {
+    fn default() -> Self {
+        Self {
+            type_score: default_type_score,
+            name_contains: None,
+            env_var: Some(DEVICE_ENV_VAR.to_string()),
+            required_extensions: Vec::new(),
+            required_features: DeviceFeatures::default(),
+        }
+    }
+}
+
+impl DeviceSelector {
+    pub fn type_score(mut self, type_score: fn(vk::PhysicalDeviceType) -> i32) -> Self {
+        self.type_score = type_score;
+        self
+    }
+
+    pub fn name_contains(mut self, name: impl Into<String>) -> Self {
+        self.name_contains = Some(name.into());
+        self
+    }
+
+    /// Pass `None` to ignore the environment entirely.
+    pub fn env_var(mut self, env_var: Option<&str>) -> Self {
+        self.env_var = env_var.map(str::to_string);
+        self
+    }
+
+    pub fn require_extension(mut self, extension: &'static CStr) -> Self {
+        self.required_extensions.push(extension);
+        self
+    }
+
+    pub fn require_features(mut self, features: impl Into<DeviceFeatures>) -> Self {
+        self.required_features = self.required_features.union(&features.into());
+        self
+    }
+
+    /// Pick a device. `surface` should be provided if the device will be used to present.
+    pub fn select(
+        &self,
+        instance: &ash::Instance,
+        surface: Option<(&ash::khr::surface::Instance, vk::SurfaceKHR)>,
+    ) -> Option<(vk::PhysicalDevice, SelectedDevice)> {
+        let physical_devices = unsafe { instance.enumerate_physical_devices() }.ok()?;
+
+        let mut candidates = Vec::new();
+        for (index, physical_device) in physical_devices.into_iter().enumerate() {
+            let properties = unsafe { instance.get_physical_device_properties(physical_device) };
+            let candidate = Candidate::new(physical_device, index, &properties);
+
+            if let Err(reason) = self.check_requirements(instance, physical_device, surface) {
+                log::info!(
+                    "[lazy_vulkan] Rejecting device {index} ({}): {reason}",
+                    candidate.name
+                );
+                continue;
+            }
+
+            candidates.push(candidate);
+        }
+
+        let env = self
+            .env_var
+            .as_ref()
+            .and_then(|env_var| Some((env_var.clone(), std::env::var(env_var).ok()?)));
+        let (candidate, reason) = self.choose(&candidates, env)?;
+        Some(candidate.select(reason))
+    }
+
+    /// Pick one of the devices that met the requirements. `env` is the environment variable
+    /// and its value, if it's set.
+    fn choose<'a>(
+        &self,
+        candidates: &'a [Candidate],
+        env: Option<(String, String)>,
+    ) -> Option<(&'a Candidate, SelectionReason)> {
+        // Environment override
+        if let Some((env_var, value)) = env {
+            let found = match value.parse::<usize>() {
+                Ok(index) => candidates.iter().find(|c| c.index == index),
+                Err(_) => candidates.iter().find(|c| name_matches(&c.name, &value)),
+            };
+
+            match found {
+                Some(candidate) => {
+                    return Some((
+                        candidate,
+                        SelectionReason::EnvironmentOverride { env_var, value },
+                    ))
+                }
+                None => log::warn!(
+                    "[lazy_vulkan] {env_var}={value} did not match any suitable device, ignoring"
+                ),
+            }
+        }
+
+        // Name match
+        if let Some(pattern) = &self.name_contains {
+            match candidates.iter().find(|c| name_matches(&c.name, pattern)) {
+                Some(candidate) => {
+                    return Some((candidate, SelectionReason::NameMatch(pattern.clone())))
+                }
+                None => log::warn!(
+                    "[lazy_vulkan] No suitable device matched name {pattern:?}, falling back to score"
+                ),
+            }
+        }
+
+        // Score. NOTE: `max_by_key` returns the *last* maximum, so reverse to prefer the
+        // first device the driver gave us when there's a tie.
+        candidates
+            .iter()
+            .rev()
+            .max_by_key(|c| (self.type_score)(c.device_type))
+            .map(|c| {
+                (
+                    c,
+                    SelectionReason::HighestScore((self.type_score)(c.device_type)),
+                )
+            })
+    }
+
+    fn check_requirements(
+        &self,
+        instance: &ash::Instance,
+        physical_device: vk::PhysicalDevice,
+        surface: Option<(&ash::khr::surface::Instance, vk::SurfaceKHR)>,
+    ) -> Result<(), String> {
+        // Extensions
+        let available_extensions =
+            unsafe { instance.enumerate_device_extension_properties(physical_device) }
+                .map_err(|e| format!("unable to enumerate extensions: {e:?}"))?;
+
+        let mut required_extensions = self.required_extensions.clone();
+        if surface.is_some() {
+            required_extensions.push(ash::khr::swapchain::NAME);
+        }
+
+        for required in required_extensions {
+            if !available_extensions
+                .iter()
+                .any(|e| e.extension_name_as_c_str() == Ok(required))
+            {
+                return Err(format!("missing extension {required:?}"));
+            }
+        }
+
+        // Features - the ones lazy_vulkan can't live without, and the ones the user asked for.
+        let required = DeviceFeatures::minimum().union(&self.required_features);
+        let supported = DeviceFeatures::query(instance, physical_device);
+        if !supported.contains(&required) {
+            return Err("missing one or more required features".into());
+        }
+
+        // Queues, and surface support - `Context` will need a queue that can present to it.
+        QueueFamilies::find(instance, physical_device, surface)?;
+
+        Ok(())
+    }
+}
+
+/// The device [`crate::Core`] ended up with, and why.
+#[derive(Debug, Clone)]
+pub struct SelectedDevice {
+    /// Index into the list returned by `vkEnumeratePhysicalDevices`
+    pub index: usize,
+    pub name: String,
+    pub device_type: vk::PhysicalDeviceType,
+    pub reason: SelectionReason,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SelectionReason {
+    EnvironmentOverride {
+        env_var: String,
+        value: String,
+    },
+    NameMatch(String),
+    HighestScore(i32),
+    /// The device was handed to us by the application.
+    Provided,
+}
+
+impl std::fmt::Display for SelectionReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SelectionReason::EnvironmentOverride { env_var, value } => {
+                write!(f, "selected by environment variable {env_var}={value}")
+            }
+            SelectionReason::NameMatch(pattern) => write!(f, "name matched {pattern:?}"),
+            SelectionReason::HighestScore(score) => write!(f, "highest score ({score})"),
+            SelectionReason::Provided => write!(f, "provided by the application"),
+        }
+    }
+}
+
+pub fn default_type_score(device_type: vk::PhysicalDeviceType) -> i32 {
+    match device_type {
+        vk::PhysicalDeviceType::DISCRETE_GPU => 1000,
+        vk::PhysicalDeviceType::INTEGRATED_GPU => 500,
+        vk::PhysicalDeviceType::VIRTUAL_GPU => 100,
+        vk::PhysicalDeviceType::CPU => 10,
+        _ => 1,
+    }
+}
+
+struct Candidate {
+    physical_device: vk::PhysicalDevice,
+    index: usize,
+    name: String,
+    device_type: vk::PhysicalDeviceType,
+}
+
+impl Candidate {
+    fn new(
+        physical_device: vk::PhysicalDevice,
+        index: usize,
+        properties: &vk::PhysicalDeviceProperties,
+    ) -> Self {
+        Self {
+            physical_device,
+            index,
+            name: properties
+                .device_name_as_c_str()
+                .map(|n| n.to_string_lossy().into_owned())
+                .unwrap_or_default(),
+            device_type: properties.device_type,
+        }
+    }
+
+    fn select(&self, reason: SelectionReason) -> (vk::PhysicalDevice, SelectedDevice) {
+        (
+            self.physical_device,
+            SelectedDevice {
+                index: self.index,
+                name: self.name.clone(),
+                device_type: self.device_type,
+                reason,
+            },
+        )
+    }
+}
+
+fn name_matches(name: &str, pattern: &str) -> bool {
+    name.to_lowercase().contains(&pattern.to_lowercase())
+}
+
+#[cfg(test)]
+mod tests {
+    use ash::vk;
+
+    use super::{Candidate, DeviceSelector, SelectionReason};
+
+    fn candidates(devices: &[(&str, vk::PhysicalDeviceType)]) -> Vec<Candidate> {
+        devices
+            .iter()
+            .enumerate()
+            .map(|(index, (name, device_type))| {
+                let name = std::ffi::CString::new(*name).unwrap();
+                let properties = vk::PhysicalDeviceProperties::default()
+                    .device_type(*device_type)
+                    .device_name(&name)
+                    .unwrap();
+                Candidate::new(vk::PhysicalDevice::null(), index, &properties)
+            })
+            .collect()
+    }
+
+    fn chosen(
+        selector: &DeviceSelector,
+        candidates: &[Candidate],
+        env: Option<(&str, &str)>,
+    ) -> Option<(usize, SelectionReason)> {
+        let env = env.map(|(env_var, value)| (env_var.to_string(), value.to_string()));
+        selector
+            .choose(candidates, env)
+            .map(|(candidate, reason)| (candidate.index, reason))
+    }
+
+    #[test]
+    fn test_highest_score() {
+        let candidates = candidates(&[
+            (
+                "llvmpipe (LLVM 17.0.6, 256 bits)",
+                vk::PhysicalDeviceType::CPU,
+            ),
+            (
+                "Intel(R) UHD Graphics 630",
+                vk::PhysicalDeviceType::INTEGRATED_GPU,
+            ),
+            (
+                "NVIDIA GeForce RTX 3080",
+                vk::PhysicalDeviceType::DISCRETE_GPU,
+            ),
+        ]);
+        let selector = DeviceSelector::default();
+        assert_eq!(
+            chosen(&selector, &candidates, None),
+            Some((2, SelectionReason::HighestScore(1000)))
+        );
+
+        // Ties go to the first device.
+        let selector = selector.type_score(|_| 0);
+        assert_eq!(
+            chosen(&selector, &candidates, None),
+            Some((0, SelectionReason::HighestScore(0)))
+        );
+
+        assert_eq!(chosen(&selector, &[], None), None);
+    }
+
+    #[test]
+    fn test_name_match() {
+        let candidates = candidates(&[
+            (
+                "NVIDIA GeForce RTX 3080",
+                vk::PhysicalDeviceType::DISCRETE_GPU,
+            ),
+            (
+                "Intel(R) UHD Graphics 630",
+                vk::PhysicalDeviceType::INTEGRATED_GPU,
+            ),
+        ]);
+
+        let selector = DeviceSelector::default().name_contains("intel");
+        assert_eq!(
+            chosen(&selector, &candidates, None),
+            Some((1, SelectionReason::NameMatch("intel".into())))
+        );
+
+        // No match falls back to the score.
+        let selector = DeviceSelector::default().name_contains("radeon");
+        assert_eq!(
+            chosen(&selector, &candidates, None),
+            Some((0, SelectionReason::HighestScore(1000)))
+        );
+    }
+
+    #[test]
+    fn test_env_override() {
+        let candidates = candidates(&[
+            (
+                "NVIDIA GeForce RTX 3080",
+                vk::PhysicalDeviceType::DISCRETE_GPU,
+            ),
+            (
+                "llvmpipe (LLVM 17.0.6, 256 bits)",
+                vk::PhysicalDeviceType::CPU,
+            ),
+        ]);
+        let selector = DeviceSelector::default().name_contains("nvidia");
+        let env_override = |value: &str| SelectionReason::EnvironmentOverride {
+            env_var: "LAZY_VULKAN_DEVICE".into(),
+            value: value.into(),
+        };
+
+        // By index, or by name - either way it beats `name_contains`.
+        assert_eq!(
+            chosen(&selector, &candidates, Some(("LAZY_VULKAN_DEVICE", "1"))),
+            Some((1, env_override("1")))
+        );
+        assert_eq!(
+            chosen(
+                &selector,
+                &candidates,
+                Some(("LAZY_VULKAN_DEVICE", "LLVMpipe"))
+            ),
+            Some((1, env_override("LLVMpipe")))
+        );
+
+        // Anything that doesn't match is ignored.
+        for value in ["2", "radeon"] {
+            assert_eq!(
+                chosen(&selector, &candidates, Some(("LAZY_VULKAN_DEVICE", value))),
+                Some((0, SelectionReason::NameMatch("nvidia".into())))
+            );
+        }
+    }
+}
@@ -0,0 +1,143 @@
+use ash::vk;
+
+use crate::{core::ValidationMessage, DeviceFeatures};
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// Everything that can go wrong in lazy_vulkan that you might reasonably want to recover from.
+///
+/// Programming errors (eg. asking for a render attachment that doesn't exist) still panic.
+#[derive(Debug)]
+pub enum Error {
+    /// A Vulkan call failed with a result we don't have a more specific variant for.
+    Vulkan(vk::Result),
+    /// Either the host or the device ran out of memory.
+    OutOfMemory(vk::Result),
+    /// Our global device memory is full.
+    OutOfDeviceMemory {
+        requested: vk::DeviceSize,
+    },
+    /// An upload can't be split into pieces small enough for the staging buffer - eg. one row of
+    /// an image is bigger than the whole thing.
+    StagingBufferFull {
+        requested: vk::DeviceSize,
+        available: vk::DeviceSize,
+    },
+    /// The readback buffer doesn't have room for the copy. Try again once earlier readbacks have
+    /// completed, or make [`crate::AllocatorConfig::readback_size`] bigger.
+    ReadbackBufferFull {
+        requested: vk::DeviceSize,
+        available: vk::DeviceSize,
+    },
+    /// The Vulkan loader couldn't be found.
+    Loading(ash::LoadingError),
+    /// No physical device met our requirements.
+    NoSuitableDevice,
+    /// A device extension in [`crate::ContextBuilder::required_extensions`] isn't supported.
+    MissingDeviceExtension(String),
+    /// Some of the features in [`crate::ContextBuilder::required_features`] aren't supported.
+    /// Contains just the missing ones.
+    MissingDeviceFeatures(Box<DeviceFeatures>),
+    /// No memory type met our requirements.
+    NoSuitableMemoryType(vk::MemoryPropertyFlags),
+    /// The format isn't supported for the requested usage.
+    UnsupportedFormat(vk::Format),
+    /// The surface went away - usually the window was closed or the display changed. The
+    /// surface belongs to [`crate::Core`], so drop the [`crate::LazyVulkan`] and build a new one
+    /// for the window (eg. with [`crate::LazyVulkan::from_window`]).
+    SurfaceLost,
+    /// The device has been lost. Everything must be recreated.
+    DeviceLost,
+    /// [`crate::CoreBuilder::fail_on_validation_error`] is set, and the validation layer reported
+    /// some errors.
+    Validation(Vec<ValidationMessage>),
+    Io(std::io::Error),
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Vulkan(result) => write!(f, "Vulkan error: {result}"),
+            Error::OutOfMemory(result) => write!(f, "Out of memory: {result}"),
+            Error::OutOfDeviceMemory { requested } => {
+                write!(f, "Unable to allocate {requested} bytes of device memory")
+            }
+            Error::StagingBufferFull {
+                requested,
+                available,
+            } => write!(
+                f,
+                "Staging buffer too small: a {requested} byte piece won't fit in {available} bytes"
+            ),
+            Error::ReadbackBufferFull {
+                requested,
+                available,
+            } => write!(
+                f,
+                "Readback buffer full: no room for {requested} bytes in {available} bytes"
+            ),
+            Error::Loading(error) => write!(f, "Unable to load Vulkan: {error}"),
+            Error::NoSuitableDevice => write!(f, "No suitable physical device found"),
+            Error::MissingDeviceExtension(name) => {
+                write!(f, "Missing required device extension {name}")
+            }
+            Error::MissingDeviceFeatures(_) => write!(f, "Missing required device features"),
+            Error::NoSuitableMemoryType(flags) => {
+                write!(f, "No memory type with properties {flags:?}")
+            }
+            Error::UnsupportedFormat(format) => write!(f, "Unsupported format: {format:?}"),
+            Error::SurfaceLost => write!(f, "Surface lost"),
+            Error::DeviceLost => write!(f, "Device lost"),
+            Error::Validation(messages) => {
+                write!(f, "{} validation error(s)", messages.len())?;
+                for message in messages {
+                    write!(f, "\n{message}")?;
+                }
+                Ok(())
+            }
+            Error::Io(error) => write!(f, "IO error: {error}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Vulkan(result) | Error::OutOfMemory(result) => Some(result),
+            Error::Loading(error) => Some(error),
+            Error::Io(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<vk::Result> for Error {
+    fn from(result: vk::Result) -> Self {
+        match result {
+            vk::Result::ERROR_OUT_OF_HOST_MEMORY | vk::Result::ERROR_OUT_OF_DEVICE_MEMORY => {
+                Error::OutOfMemory(result)
+            }
+            vk::Result::ERROR_DEVICE_LOST => Error::DeviceLost,
+            vk::Result::ERROR_SURFACE_LOST_KHR => Error::SurfaceLost,
+            _ => Error::Vulkan(result),
+        }
+    }
+}
+
+impl From<ash::LoadingError> for Error {
+    fn from(error: ash::LoadingError) -> Self {
+        Error::Loading(error)
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(error: std::io::Error) -> Self {
+        Error::Io(error)
+    }
+}
+
+impl From<png::DecodingError> for Error {
+    fn from(error: png::DecodingError) -> Self {
+        Error::Io(error.into())
+    }
+}
@@ -1,4 +1,4 @@
-use crate::{swapchain::Drawable, Context, FULL_IMAGE};
+use crate::{swapchain::Drawable, Context, Error, Result, FULL_IMAGE};
 use ash::vk::{self};
 use std::sync::Arc;
 
@@ -11,31 +11,34 @@ pub struct HeadlessSwapchain {
 }
 
 impl HeadlessSwapchain {
-    pub(crate) fn new(context: Arc<Context>, extent: vk::Extent2D, format: vk::Format) -> Self {
-        let image = HeadlessSwapchainImage::new(&context, extent, format);
+    pub(crate) fn new(
+        context: Arc<Context>,
+        extent: vk::Extent2D,
+        format: vk::Format,
+    ) -> Result<Self> {
+        let image = HeadlessSwapchainImage::new(&context, extent, format)?;
         let render_complete = unsafe {
             context
                 .device
                 .create_semaphore(&vk::SemaphoreCreateInfo::default(), None)
-        }
-        .unwrap();
+        }?;
 
-        Self {
+        Ok(Self {
             context,
             extent,
             format,
             image,
             render_complete,
-        }
+        })
     }
 
-    pub(crate) fn resize(&mut self, new_extent: vk::Extent2D) {
+    pub(crate) fn resize(&mut self, new_extent: vk::Extent2D) -> Result<()> {
         if self.extent == new_extent {
-            return;
+            return Ok(());
         }
 
         self.extent = new_extent;
-        self.image.resize(&self.context, self.extent, self.format);
+        self.image.resize(&self.context, self.extent, self.format)
     }
 
     pub(crate) fn get_drawable(&self) -> Drawable {
@@ -50,6 +53,17 @@ impl HeadlessSwapchain {
     }
 }
 
+impl Drop for HeadlessSwapchain {
+    fn drop(&mut self) {
+        unsafe {
+            self.image.destroy(&self.context);
+            self.context
+                .device
+                .destroy_semaphore(self.render_complete, None);
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct HeadlessSwapchainImage {
     pub image: vk::Image,
@@ -58,7 +72,16 @@ pub struct HeadlessSwapchainImage {
 }
 
 impl HeadlessSwapchainImage {
-    fn new(context: &Context, extent: vk::Extent2D, format: vk::Format) -> HeadlessSwapchainImage {
+    fn new(
+        context: &Context,
+        extent: vk::Extent2D,
+        format: vk::Format,
+    ) -> Result<HeadlessSwapchainImage> {
+        context.check_image_format(
+            format,
+            vk::ImageUsageFlags::COLOR_ATTACHMENT | vk::ImageUsageFlags::SAMPLED,
+        )?;
+
         let device = &context.device;
 
         let image = unsafe {
@@ -76,14 +99,15 @@ impl HeadlessSwapchainImage {
                     .format(format),
                 None,
             )
-        }
-        .unwrap();
+        }?;
 
         let memory_requirements = unsafe { device.get_image_memory_requirements(image) };
 
         let memory_type_index = context
             .find_memory_type_index(&memory_requirements, vk::MemoryPropertyFlags::DEVICE_LOCAL)
-            .expect("No memory type index for depth buffer - impossible");
+            .ok_or(Error::NoSuitableMemoryType(
+                vk::MemoryPropertyFlags::DEVICE_LOCAL,
+            ))?;
 
         let memory = unsafe {
             device.allocate_memory(
@@ -92,15 +116,13 @@ impl HeadlessSwapchainImage {
                     .memory_type_index(memory_type_index),
                 None,
             )
-        }
-        .expect("Failed to allocate memory - impossible");
+        }?;
 
         unsafe {
             device.bind_image_memory2(&[vk::BindImageMemoryInfo::default()
                 .image(image)
                 .memory(memory)])
-        }
-        .unwrap();
+        }?;
 
         let view = unsafe {
             device.create_image_view(
@@ -112,26 +134,35 @@ impl HeadlessSwapchainImage {
                     .subresource_range(FULL_IMAGE),
                 None,
             )
-        }
-        .unwrap();
+        }?;
 
-        HeadlessSwapchainImage {
+        Ok(HeadlessSwapchainImage {
             image,
             memory,
             view,
-        }
+        })
     }
 
-    pub fn resize(&mut self, context: &Context, new_extent: vk::Extent2D, format: vk::Format) {
-        let device = &context.device;
+    pub fn resize(
+        &mut self,
+        context: &Context,
+        new_extent: vk::Extent2D,
+        format: vk::Format,
+    ) -> Result<()> {
         unsafe {
-            device.device_wait_idle().unwrap();
-            device.destroy_image_view(self.view, None);
-            device.destroy_image(self.image, None);
-            device.free_memory(self.memory, None);
+            context.device.device_wait_idle()?;
+            self.destroy(context);
         }
 
-        *self = Self::new(context, new_extent, format);
+        *self = Self::new(context, new_extent, format)?;
         log::debug!("Resized! Image: {:?}", self.image);
+        Ok(())
+    }
+
+    unsafe fn destroy(&self, context: &Context) {
+        let device = &context.device;
+        device.destroy_image_view(self.view, None);
+        device.destroy_image(self.image, None);
+        device.free_memory(self.memory, None);
     }
 }
@@ -2,7 +2,10 @@ use std::sync::Arc;
 
 use ash::vk;
 
-use crate::{descriptors::Descriptors, Allocator, Context, TransferToken, FULL_IMAGE};
+use crate::{
+    allocator::sharing_mode, descriptors::Descriptors, Allocator, Context, Result, TransferToken,
+    FULL_IMAGE,
+};
 
 #[derive(Debug, Clone)]
 pub struct Image {
@@ -20,6 +23,8 @@ pub struct ImageManager {
     context: Arc<Context>,
     current_id: u32,
     texture_descriptor_set: vk::DescriptorSet,
+    /// Everything we've created, so it can be destroyed when we are.
+    images: Vec<Image>,
 }
 
 impl ImageManager {
@@ -28,6 +33,7 @@ impl ImageManager {
             context,
             current_id: 0,
             texture_descriptor_set,
+            images: Vec::new(),
         }
     }
 
@@ -42,6 +48,9 @@ impl ImageManager {
     /// - If `format` is a depth format, we'll set the correct aspect flags on the iamge view
     ///
     /// Does not yet support mipmaps or multiple image layers.
+    ///
+    /// Returns [`crate::Error::UnsupportedFormat`] if the device can't use `format` for
+    /// `image_usage_flags`.
     pub fn create_image(
         &mut self,
         name: impl AsRef<str>,
@@ -50,7 +59,13 @@ impl ImageManager {
         extent: vk::Extent2D,
         image_bytes: impl AsRef<[u8]>,
         image_usage_flags: vk::ImageUsageFlags,
-    ) -> Image {
+    ) -> Result<Image> {
+        // TRANSFER_SRC lets it be read back with `Allocator::read_image`.
+        let usage = image_usage_flags
+            | vk::ImageUsageFlags::TRANSFER_DST
+            | vk::ImageUsageFlags::TRANSFER_SRC;
+        self.context.check_image_format(format, usage)?;
+
         let id = if image_usage_flags.contains(vk::ImageUsageFlags::SAMPLED) {
             self.allocate_id()
         } else {
@@ -59,29 +74,36 @@ impl ImageManager {
 
         let device = &self.context.device;
         let image_bytes = image_bytes.as_ref();
+        let queue_family_indices = self.context.image_queue_families(usage);
 
         let handle = unsafe {
-            device
-                .create_image(
-                    &vk::ImageCreateInfo::default()
-                        .image_type(vk::ImageType::TYPE_2D)
-                        .format(format)
-                        .extent(extent.into())
-                        .mip_levels(1)
-                        .array_layers(1)
-                        .samples(vk::SampleCountFlags::TYPE_1)
-                        .tiling(vk::ImageTiling::OPTIMAL)
-                        .usage(image_usage_flags | vk::ImageUsageFlags::TRANSFER_DST)
-                        .sharing_mode(vk::SharingMode::EXCLUSIVE)
-                        .initial_layout(vk::ImageLayout::UNDEFINED),
-                    None,
-                )
-                .unwrap()
-        };
+            device.create_image(
+                &vk::ImageCreateInfo::default()
+                    .image_type(vk::ImageType::TYPE_2D)
+                    .format(format)
+                    .extent(extent.into())
+                    .mip_levels(1)
+                    .array_layers(1)
+                    .samples(vk::SampleCountFlags::TYPE_1)
+                    .tiling(vk::ImageTiling::OPTIMAL)
+                    .usage(usage)
+                    .sharing_mode(sharing_mode(&queue_family_indices))
+                    .queue_family_indices(&queue_family_indices)
+                    .initial_layout(vk::ImageLayout::UNDEFINED),
+                None,
+            )
+        }?;
 
         self.context.set_debug_label(handle, name.as_ref());
 
-        let transfer_complete = allocator.allocate_image(image_bytes, extent, handle);
+        let transfer_complete =
+            match allocator.allocate_image(image_bytes, format, extent, usage, handle) {
+                Ok(transfer_complete) => transfer_complete,
+                Err(e) => {
+                    unsafe { device.destroy_image(handle, None) };
+                    return Err(e);
+                }
+            };
 
         let view = unsafe {
             // Another little hack.
@@ -100,8 +122,7 @@ impl ImageManager {
                     .subresource_range(subresource_range),
                 None,
             )
-        }
-        .unwrap();
+        }?;
 
         let mut sampler = vk::Sampler::null();
 
@@ -112,7 +133,9 @@ impl ImageManager {
                     .mag_filter(vk::Filter::LINEAR)
                     .address_mode_u(vk::SamplerAddressMode::REPEAT)
                     .address_mode_v(vk::SamplerAddressMode::REPEAT)
-                    .anisotropy_enable(true)
+                    .anisotropy_enable(
+                        self.context.enabled_features.core.sampler_anisotropy == vk::TRUE,
+                    )
                     .max_anisotropy(self.context.device_properties.limits.max_sampler_anisotropy);
 
                 // This is a little bit hacky, but reasonable. It doesn't really make a lot of
@@ -126,19 +149,36 @@ impl ImageManager {
                 }
 
                 device.create_sampler(&sampler_create_info, None)
-            }
-            .unwrap();
+            }?;
             unsafe { self.update_texture_descriptor_set(id, view, sampler) };
         }
 
-        Image {
+        let image = Image {
             handle,
             view,
             extent,
             id,
             sampler,
             transfer_complete,
-        }
+        };
+        self.images.push(image.clone());
+
+        Ok(image)
+    }
+
+    /// Destroy an image made with [`ImageManager::create_image`], once the GPU has finished with
+    /// the frame that's being recorded.
+    ///
+    /// ## NOTE
+    /// Texture IDs aren't reused, and the descriptor still points at the old image - so make sure
+    /// nothing samples it after this frame.
+    pub fn destroy_image(&mut self, handle: vk::Image, allocator: &mut Allocator) {
+        let index = self
+            .images
+            .iter()
+            .position(|image| image.handle == handle)
+            .expect("Image wasn't created by this ImageManager");
+        allocator.free_image(self.images.swap_remove(index));
     }
 
     pub unsafe fn update_texture_descriptor_set(
@@ -171,3 +211,17 @@ impl ImageManager {
         id
     }
 }
+
+impl Drop for ImageManager {
+    fn drop(&mut self) {
+        let device = &self.context.device;
+        for image in self.images.drain(..) {
+            unsafe {
+                // Not every image has a sampler, but destroying a null handle is fine.
+                device.destroy_sampler(image.sampler, None);
+                device.destroy_image_view(image.view, None);
+                device.destroy_image(image.handle, None);
+            }
+        }
+    }
+}
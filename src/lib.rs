@@ -1,30 +1,60 @@
 pub use crate::swapchain::Drawable;
-pub use allocator::{Allocator, BufferAllocation, SlabUpload, TransferToken};
+#[cfg(not(target_vendor = "apple"))]
+pub use allocator::{AccelerationStructure, TriangleGeometry};
+pub use allocator::{
+    Allocator, AllocatorConfig, BufferAllocation, MappedBuffer, MemoryUsage, ReadbackToken,
+    SlabUpload, TransferToken,
+};
 pub use ash::{self, vk};
-pub use context::Context;
-pub use core::Core;
+pub use context::{Context, ContextBuilder};
+pub use core::{Core, CoreBuilder, Ownership, ValidationMessage, ValidationMessages};
+pub use device_features::DeviceFeatures;
+pub use device_selection::{DeviceSelector, SelectedDevice, SelectionReason};
 pub use draw_params::DrawParams;
+pub use error::{Error, Result};
 pub use headless_swapchain::HeadlessSwapchainImage;
 pub use image_manager::{Image, ImageManager};
 pub use pipeline::{load_module, BlendMode, Pipeline, PipelineOptions};
+pub use profiler::{FrameProfile, ProfileScope};
+pub use query_set::{QueryKind, QueryResults, QuerySet};
+pub use queue_families::QueueFamilies;
 pub use render_plan::{RenderAttachment, RenderPass, RenderPlan, RenderStage};
 pub use renderer::Renderer;
 use std::sync::Arc;
 pub use sub_renderer::{AttachmentInfo, LayerInfo, StateFamily, SubRenderer};
 use swapchain::Swapchain;
 
+/// Enter a `tracing` span until the end of the enclosing block. Does nothing without the
+/// `tracing` feature.
+macro_rules! trace_span {
+    ($($args:tt)*) => {
+        #[cfg(feature = "tracing")]
+        let _span = tracing::info_span!($($args)*).entered();
+    };
+}
+
 mod allocator;
+mod async_compute;
+mod breadcrumbs;
+mod chrome_trace;
 mod context;
 mod core;
 mod depth_buffer;
 mod descriptors;
+mod device_features;
+mod device_selection;
 mod draw_params;
+mod error;
 pub mod geometry;
 mod headless_swapchain;
 mod image_manager;
 mod pipeline;
+mod profiler;
+mod query_set;
+mod queue_families;
 mod render_plan;
 mod renderer;
+mod secondary_command_buffers;
 mod sub_renderer;
 mod swapchain;
 
@@ -35,17 +65,12 @@ pub struct LazyVulkan<SF: StateFamily> {
 }
 
 impl<SF: StateFamily> LazyVulkan<SF> {
-    pub fn from_window(window: &winit::window::Window) -> Self {
-        let core = Arc::new(Core::from_window(window));
-        let context = Arc::new(Context::new_from_window(&core));
-        let swapchain = Swapchain::new(&context.device, &core, window, vk::SwapchainKHR::null());
-        let renderer = Renderer::from_wsi(context.clone(), swapchain);
+    pub fn builder() -> LazyVulkanBuilder {
+        LazyVulkanBuilder::default()
+    }
 
-        LazyVulkan {
-            core,
-            context,
-            renderer,
-        }
+    pub fn from_window(window: &winit::window::Window) -> Result<Self> {
+        LazyVulkanBuilder::default().build_for_window(window)
     }
 
     pub fn headless(
@@ -53,44 +78,54 @@ impl<SF: StateFamily> LazyVulkan<SF> {
         context: Arc<Context>,
         extent: vk::Extent2D,
         format: vk::Format,
-    ) -> Self {
-        let renderer = Renderer::headless(context.clone(), extent, format);
+    ) -> Result<Self> {
+        let renderer = Renderer::headless(context.clone(), extent, format)?;
 
-        LazyVulkan {
+        Ok(LazyVulkan {
             core,
             context,
             renderer,
-        }
+        })
     }
 
-    pub fn draw<'s>(&mut self, state: &SF::For<'s>) {
-        let drawable = self.renderer.get_drawable();
-        self.begin_commands();
-        self.renderer.stage_and_execute_transfers(state);
+    pub fn draw<'s>(&mut self, state: &SF::For<'s>) -> Result<()> {
+        let drawable = self.renderer.get_drawable()?;
+        self.begin_commands()?;
+        self.renderer.stage_and_execute_transfers(state)?;
+        self.renderer.record_compute(state)?;
         self.renderer.draw(state, &drawable);
-        self.renderer.submit_and_present(drawable);
+        self.submit_and_present(drawable)
     }
 
-    pub fn draw_render_plan<'s>(&mut self, state: &SF::For<'s>, plan: RenderPlan) {
-        let drawable = self.renderer.get_drawable();
-        self.begin_commands();
-        self.renderer.stage_and_execute_transfers(state);
-        self.renderer.draw_render_plan(state, plan, &drawable);
-        self.renderer.submit_and_present(drawable);
+    /// Like [`LazyVulkan::draw`], but sub-renderers are recorded in parallel. See
+    /// [`Renderer::draw_parallel`].
+    pub fn draw_parallel<'s>(&mut self, state: &SF::For<'s>) -> Result<()>
+    where
+        SF::For<'s>: Sync,
+    {
+        let drawable = self.renderer.get_drawable()?;
+        self.begin_commands()?;
+        self.renderer.stage_and_execute_transfers(state)?;
+        self.renderer.record_compute(state)?;
+        self.renderer.draw_parallel(state, &drawable)?;
+        self.submit_and_present(drawable)
     }
 
-    pub fn begin_commands(&mut self) {
-        self.renderer.begin_command_buffer();
+    pub fn draw_render_plan<'s>(&mut self, state: &SF::For<'s>, plan: RenderPlan) -> Result<()> {
+        let drawable = self.renderer.get_drawable()?;
+        self.begin_commands()?;
+        self.renderer.stage_and_execute_transfers(state)?;
+        self.renderer.record_compute(state)?;
+        self.renderer.draw_render_plan(state, plan, &drawable);
+        self.submit_and_present(drawable)
+    }
 
-        // If any transfers were staged on the first frame, we don't want to obliterate them.
-        if self.renderer.frame != 0 {
-            self.renderer.allocator.transfers_complete();
-        }
+    pub fn begin_commands(&mut self) -> Result<()> {
+        self.renderer.begin_command_buffer()
     }
 
-    pub fn get_drawable(&mut self) -> Drawable {
-        let drawable = self.renderer.get_drawable();
-        drawable
+    pub fn get_drawable(&mut self) -> Result<Drawable> {
+        self.renderer.get_drawable()
     }
 
     pub fn draw_to_drawable<'s>(&mut self, state: &SF::For<'s>, drawable: &Drawable) {
@@ -101,19 +136,29 @@ impl<SF: StateFamily> LazyVulkan<SF> {
         &mut self,
         sub_renderer: Box<dyn for<'s> SubRenderer<'s, State = SF::For<'s>>>,
     ) {
-        self.renderer
-            .sub_renderers
-            .insert(sub_renderer.label().to_string(), sub_renderer);
+        self.renderer.add_sub_renderer(sub_renderer);
+    }
+
+    /// Add a sub-renderer that [`LazyVulkan::draw_parallel`] can record on a worker thread - see
+    /// [`Renderer::add_parallel_sub_renderer`].
+    pub fn add_parallel_sub_renderer(
+        &mut self,
+        sub_renderer: Box<dyn for<'s> SubRenderer<'s, State = SF::For<'s>> + Send>,
+    ) {
+        self.renderer.add_parallel_sub_renderer(sub_renderer);
     }
 
-    pub fn create_render_attachment(&mut self, attachment_info: RenderAttachmentInfo) {
+    pub fn create_render_attachment(
+        &mut self,
+        attachment_info: RenderAttachmentInfo,
+    ) -> Result<()> {
         let image = self.renderer.create_image(
             &attachment_info.name,
             attachment_info.format,
             attachment_info.extent,
             &[],
             attachment_info.usage,
-        );
+        )?;
 
         self.renderer.render_attachments.insert(
             attachment_info.name,
@@ -126,17 +171,77 @@ impl<SF: StateFamily> LazyVulkan<SF> {
                 usage: attachment_info.usage,
             },
         );
+
+        Ok(())
+    }
+
+    pub fn submit_and_present(&mut self, drawable: Drawable) -> Result<()> {
+        self.renderer.submit_and_present(drawable)?;
+        self.check_validation_messages()
     }
 
-    pub fn submit_and_present(&mut self, drawable: Drawable) {
-        self.renderer.submit_and_present(drawable);
+    /// If we've been asked to fail on validation errors, this is where it happens.
+    fn check_validation_messages(&self) -> Result<()> {
+        if !self.core.validation_messages.fail_on_error {
+            return Ok(());
+        }
+
+        let errors = self.core.validation_messages.drain_errors();
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(Error::Validation(errors))
+        }
+    }
+
+    /// Once anything has returned [`Error::DeviceLost`], tear down the renderer and context and
+    /// build new ones from `context` on the same physical device. `window` is the window this was
+    /// built for, or `None` if it's headless.
+    ///
+    /// Nothing that was created with the old context survives the trip: sub-renderers and render
+    /// attachments must be added again, and their buffers and images re-uploaded. Anything else
+    /// still holding the old [`Context`] keeps its device alive until it's dropped.
+    pub fn recover_from_device_lost(
+        self,
+        context: &ContextBuilder,
+        window: Option<&winit::window::Window>,
+    ) -> Result<Self> {
+        let LazyVulkan {
+            core,
+            context: lost_context,
+            renderer,
+        } = self;
+        let extent = renderer.get_drawable_extent();
+        let format = renderer.get_drawable_format();
+
+        // The old swapchain has to be gone before we can make a new one for the same surface.
+        drop(renderer);
+        if Arc::strong_count(&lost_context) > 1 {
+            log::warn!("[lazy_vulkan] The lost context is still in use, so its device won't be destroyed yet");
+        }
+        drop(lost_context);
+
+        let context = Arc::new(context.build(&core)?);
+        log::info!("[lazy_vulkan] Recreated context after device lost");
+        match window {
+            Some(window) => {
+                let swapchain = Swapchain::new(&context, &core, window, vk::SwapchainKHR::null())?;
+                let renderer = Renderer::from_wsi(context.clone(), swapchain)?;
+                Ok(LazyVulkan {
+                    core,
+                    context,
+                    renderer,
+                })
+            }
+            None => LazyVulkan::headless(core, context, extent, format),
+        }
     }
 
-    pub fn resize(&mut self, new_extent: impl IntoExtent) {
-        self.renderer.resize(new_extent.into_extent());
+    pub fn resize(&mut self, new_extent: impl IntoExtent) -> Result<()> {
+        self.renderer.resize(new_extent.into_extent())
     }
 
-    pub fn resize_render_attachment(&mut self, name: &str, new_extent: vk::Extent2D) {
+    pub fn resize_render_attachment(&mut self, name: &str, new_extent: vk::Extent2D) -> Result<()> {
         let attachment_info = self
             .renderer
             .render_attachments
@@ -148,7 +253,7 @@ impl<SF: StateFamily> LazyVulkan<SF> {
             self.renderer
                 .render_attachments
                 .insert(name.to_string(), attachment_info);
-            return;
+            return Ok(());
         }
 
         let image = self.renderer.create_image(
@@ -157,7 +262,7 @@ impl<SF: StateFamily> LazyVulkan<SF> {
             new_extent,
             &[],
             attachment_info.usage,
-        );
+        )?;
 
         self.renderer.render_attachments.insert(
             name.to_string(),
@@ -171,8 +276,67 @@ impl<SF: StateFamily> LazyVulkan<SF> {
             },
         );
 
-        // TODO
-        // self.renderer.destroy_image(attachment_info.handle);
+        self.renderer.destroy_image(attachment_info.handle);
+
+        Ok(())
+    }
+}
+
+/// Use this if you want more control over how [`LazyVulkan`] is created.
+#[derive(Debug, Clone, Default)]
+pub struct LazyVulkanBuilder {
+    pub core: CoreBuilder,
+    pub context: ContextBuilder,
+}
+
+impl LazyVulkanBuilder {
+    pub fn core(mut self, core: CoreBuilder) -> Self {
+        self.core = core;
+        self
+    }
+
+    pub fn context(mut self, context: ContextBuilder) -> Self {
+        self.context = context;
+        self
+    }
+
+    /// Make sure we don't select a device that can't satisfy [`Self::context`].
+    fn core_builder(&self) -> CoreBuilder {
+        let mut core = self.core.clone();
+        let device_selector = &mut core.device_selector;
+        device_selector
+            .required_extensions
+            .extend(self.context.all_required_extensions());
+        device_selector.required_features = device_selector
+            .required_features
+            .union(&self.context.required_features);
+        core
+    }
+
+    pub fn build_for_window<SF: StateFamily>(
+        &self,
+        window: &winit::window::Window,
+    ) -> Result<LazyVulkan<SF>> {
+        let core = Arc::new(self.core_builder().build_for_window(window)?);
+        let context = Arc::new(self.context.build(&core)?);
+        let swapchain = Swapchain::new(&context, &core, window, vk::SwapchainKHR::null())?;
+        let renderer = Renderer::from_wsi(context.clone(), swapchain)?;
+
+        Ok(LazyVulkan {
+            core,
+            context,
+            renderer,
+        })
+    }
+
+    pub fn build_headless<SF: StateFamily>(
+        &self,
+        extent: vk::Extent2D,
+        format: vk::Format,
+    ) -> Result<LazyVulkan<SF>> {
+        let core = Arc::new(self.core_builder().build_headless()?);
+        let context = Arc::new(self.context.build(&core)?);
+        LazyVulkan::headless(core, context, extent, format)
     }
 }
 
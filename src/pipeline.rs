@@ -2,11 +2,10 @@ use std::sync::Arc;
 
 use ash::vk;
 
-use crate::descriptors::Descriptors;
+use crate::{descriptors::Descriptors, Result};
 
 use super::{context::Context, depth_buffer::DEPTH_FORMAT};
 
-#[derive(Clone)]
 pub struct Pipeline {
     pub handle: vk::Pipeline,
     pub layout: vk::PipelineLayout,
@@ -28,7 +27,7 @@ impl Pipeline {
         vertex_shader: &[u8],
         fragment_shader: &[u8],
         options: PipelineOptions,
-    ) -> Self {
+    ) -> Result<Self> {
         let device = &context.device;
         let descriptor_layout = options
             .custom_descriptor_layout
@@ -44,30 +43,35 @@ impl Pipeline {
                         .stage_flags(vk::ShaderStageFlags::ALL_GRAPHICS)]),
                 None,
             )
-        }
-        .unwrap();
+        }?;
 
-        let handle = create_pipeline::<Registers>(
+        let handle = match create_pipeline::<Registers>(
             &context,
             colour_format,
             &options,
             layout,
             vertex_shader,
             fragment_shader,
-        );
+        ) {
+            Ok(handle) => handle,
+            Err(e) => {
+                unsafe { device.destroy_pipeline_layout(layout, None) };
+                return Err(e);
+            }
+        };
 
-        Self {
+        Ok(Self {
             context,
             layout,
             handle,
             descriptor_set,
             format: colour_format,
             options,
-        }
+        })
     }
 
     pub fn update_registers<Registers: bytemuck::Pod>(&self, registers: &Registers) {
-        let draw_command_buffer = self.context.draw_command_buffer;
+        let draw_command_buffer = self.context.draw_command_buffer();
         unsafe {
             self.context.device.cmd_push_constants(
                 draw_command_buffer,
@@ -80,7 +84,7 @@ impl Pipeline {
     }
 
     pub fn bind_descriptor_sets(&self) {
-        let command_buffer = self.context.draw_command_buffer;
+        let command_buffer = self.context.draw_command_buffer();
         unsafe {
             self.context.device.cmd_bind_descriptor_sets(
                 command_buffer,
@@ -117,6 +121,17 @@ impl Pipeline {
     // }
 }
 
+impl Drop for Pipeline {
+    fn drop(&mut self) {
+        unsafe {
+            self.context.device.destroy_pipeline(self.handle, None);
+            self.context
+                .device
+                .destroy_pipeline_layout(self.layout, None);
+        }
+    }
+}
+
 fn create_pipeline<Registers>(
     context: &Arc<Context>,
     colour_format: vk::Format,
@@ -124,7 +139,7 @@ fn create_pipeline<Registers>(
     layout: vk::PipelineLayout,
     vertex_shader: &[u8],
     fragment_shader: &[u8],
-) -> vk::Pipeline {
+) -> Result<vk::Pipeline> {
     let device = &context.device;
 
     // Extract options
@@ -145,18 +160,27 @@ fn create_pipeline<Registers>(
         &[colour_format]
     };
 
-    unsafe {
+    let vertex_module = load_module(vertex_shader, context)?;
+    let fragment_module = match load_module(fragment_shader, context) {
+        Ok(module) => module,
+        Err(e) => {
+            unsafe { device.destroy_shader_module(vertex_module, None) };
+            return Err(e);
+        }
+    };
+
+    let result = unsafe {
         device.create_graphics_pipelines(
             vk::PipelineCache::null(),
             &[vk::GraphicsPipelineCreateInfo::default()
                 .stages(&[
                     vk::PipelineShaderStageCreateInfo::default()
                         .name(c"main")
-                        .module(load_module(vertex_shader, context))
+                        .module(vertex_module)
                         .stage(vk::ShaderStageFlags::VERTEX),
                     vk::PipelineShaderStageCreateInfo::default()
                         .name(c"main")
-                        .module(load_module(fragment_shader, context))
+                        .module(fragment_module)
                         .stage(vk::ShaderStageFlags::FRAGMENT),
                 ])
                 .vertex_input_state(&vk::PipelineVertexInputStateCreateInfo::default())
@@ -207,8 +231,18 @@ fn create_pipeline<Registers>(
                 )],
             None,
         )
+    };
+
+    // The modules are baked into the pipeline, so we don't need them anymore.
+    unsafe {
+        device.destroy_shader_module(vertex_module, None);
+        device.destroy_shader_module(fragment_module, None);
+    }
+
+    match result {
+        Ok(pipelines) => Ok(pipelines[0]),
+        Err((_, e)) => Err(e.into()),
     }
-    .unwrap()[0]
 }
 
 fn get_blend_attachment(blend_mode: BlendMode) -> vk::PipelineColorBlendAttachmentState {
@@ -229,16 +263,17 @@ fn get_blend_attachment(blend_mode: BlendMode) -> vk::PipelineColorBlendAttachme
     }
 }
 
-pub fn load_module(module: &[u8], context: &Context) -> vk::ShaderModule {
+pub fn load_module(module: &[u8], context: &Context) -> Result<vk::ShaderModule> {
     let mut module_cursor = std::io::Cursor::new(module);
-    let words = ash::util::read_spv(&mut module_cursor).unwrap();
+    let words = ash::util::read_spv(&mut module_cursor)?;
 
-    unsafe {
+    let module = unsafe {
         context
             .device
             .create_shader_module(&vk::ShaderModuleCreateInfo::default().code(&words), None)
-    }
-    .unwrap()
+    }?;
+
+    Ok(module)
 }
 
 #[derive(Debug, Clone)]
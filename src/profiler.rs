@@ -0,0 +1,391 @@
+use std::time::{Duration, Instant};
+
+use ash::vk;
+
+use crate::{chrome_trace::ChromeTrace, Error, Result};
+
+/// How many timestamps each recorder can write per frame. Scopes past this are still timed on
+/// the CPU.
+const QUERIES_PER_FRAME: u32 = 1024;
+
+/// How long one scope took: one per [`crate::Context::begin_marker`] /
+/// [`crate::Context::end_marker`] pair.
+#[derive(Debug, Clone)]
+pub struct ProfileScope {
+    pub name: String,
+    /// From `begin_marker` to `end_marker` on the CPU - ie. how long it took to record.
+    pub cpu_time: Duration,
+    /// How long the GPU took to execute the scope's commands. `None` if the scope wasn't
+    /// finished, or the frame ran out of timestamp queries.
+    pub gpu_time: Option<Duration>,
+    /// Scopes that were begun while this one was in progress.
+    pub children: Vec<ProfileScope>,
+}
+
+/// Every scope that was recorded into a frame's command buffer, or the secondary command buffers
+/// it executed.
+#[derive(Debug, Clone, Default)]
+pub struct FrameProfile {
+    pub frame: u64,
+    pub scopes: Vec<ProfileScope>,
+}
+
+/// Writes a timestamp at the start and end of every marker scope in the frame's command buffer,
+/// and reads them back once the frame has finished.
+///
+/// Scopes come from a number of recorders: the frame's own command buffer is recorder `0`, and
+/// the secondary command buffers recorded on each thread by [`crate::Renderer::draw_parallel`]
+/// follow it. Each recorder has its own queries, so they can be recorded at the same time.
+pub(crate) struct Profiler {
+    /// One per frame in flight.
+    frames: Vec<FrameQueries>,
+    /// Nanoseconds per timestamp tick.
+    timestamp_period: f64,
+    /// Timestamps wrap at this many bits.
+    valid_bits_mask: u64,
+    latest: Option<FrameProfile>,
+    /// Only present between [`crate::Context::begin_chrome_trace`] and
+    /// [`crate::Context::end_chrome_trace`].
+    trace: Option<ChromeTrace>,
+}
+
+struct FrameQueries {
+    frame: u64,
+    /// False until the frame has been begun.
+    recording: bool,
+    /// Indexed by recorder.
+    recorders: Vec<RecorderQueries>,
+    scopes: Vec<PendingScope>,
+}
+
+struct RecorderQueries {
+    pool: vk::QueryPool,
+    /// False until the pool has been reset - recorders added partway through a frame have to
+    /// wait for the next one that uses the same slot.
+    reset: bool,
+    next_query: u32,
+    /// Indices into [`FrameQueries::scopes`] that haven't been ended yet.
+    stack: Vec<usize>,
+}
+
+struct PendingScope {
+    name: String,
+    recorder: usize,
+    parent: Option<usize>,
+    cpu_start: Instant,
+    cpu_time: Option<Duration>,
+    start_query: Option<u32>,
+    end_query: Option<u32>,
+}
+
+impl Profiler {
+    pub fn new(
+        device: &ash::Device,
+        frames_in_flight: usize,
+        recorders: usize,
+        timestamp_period: f32,
+        timestamp_valid_bits: u32,
+    ) -> Result<Self> {
+        if timestamp_valid_bits == 0 {
+            return Err(Error::Vulkan(vk::Result::ERROR_FEATURE_NOT_PRESENT));
+        }
+
+        let valid_bits_mask = if timestamp_valid_bits >= 64 {
+            u64::MAX
+        } else {
+            (1 << timestamp_valid_bits) - 1
+        };
+
+        let mut profiler = Self {
+            frames: Vec::with_capacity(frames_in_flight),
+            timestamp_period: timestamp_period as f64,
+            valid_bits_mask,
+            latest: None,
+            trace: None,
+        };
+        profiler
+            .frames
+            .resize_with(frames_in_flight, || FrameQueries {
+                frame: 0,
+                recording: false,
+                recorders: Vec::new(),
+                scopes: Vec::new(),
+            });
+
+        if let Err(e) = profiler.reserve_recorders(device, recorders) {
+            profiler.destroy(device);
+            return Err(e);
+        }
+        Ok(profiler)
+    }
+
+    /// Make sure there are queries for at least `recorders` recorders.
+    pub fn reserve_recorders(&mut self, device: &ash::Device, recorders: usize) -> Result<()> {
+        for queries in &mut self.frames {
+            while queries.recorders.len() < recorders {
+                let pool = unsafe {
+                    device.create_query_pool(
+                        &vk::QueryPoolCreateInfo::default()
+                            .query_type(vk::QueryType::TIMESTAMP)
+                            .query_count(QUERIES_PER_FRAME),
+                        None,
+                    )
+                }?;
+                queries.recorders.push(RecorderQueries {
+                    pool,
+                    reset: false,
+                    next_query: 0,
+                    stack: Vec::new(),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Read back whatever the last frame in `frame_index`'s slot recorded, then reset its queries
+    /// in `command_buffer`. The GPU must be done with that frame!
+    pub fn begin_frame(
+        &mut self,
+        device: &ash::Device,
+        command_buffer: vk::CommandBuffer,
+        frame_index: usize,
+        frame: u64,
+    ) {
+        let profile = self.resolve(device, frame_index);
+        if profile.is_some() {
+            self.latest = profile;
+        }
+
+        let queries = &mut self.frames[frame_index];
+        for recorder in &mut queries.recorders {
+            unsafe {
+                device.cmd_reset_query_pool(command_buffer, recorder.pool, 0, QUERIES_PER_FRAME)
+            };
+            recorder.reset = true;
+            recorder.next_query = 0;
+            recorder.stack.clear();
+        }
+        queries.frame = frame;
+        queries.recording = true;
+        queries.scopes.clear();
+    }
+
+    pub fn begin_scope(
+        &mut self,
+        device: &ash::Device,
+        command_buffer: vk::CommandBuffer,
+        frame_index: usize,
+        recorder: usize,
+        name: &str,
+    ) {
+        let queries = &mut self.frames[frame_index];
+        if !queries.recording || !queries.recorders[recorder].reset {
+            return;
+        }
+
+        // Secondary command buffers are executed inside whichever of the frame's scopes was open
+        // while they were recorded.
+        let parent = match queries.recorders[recorder].stack.last() {
+            Some(&parent) => Some(parent),
+            None if recorder > 0 => queries.recorders[0].stack.last().copied(),
+            None => None,
+        };
+
+        let index = queries.scopes.len();
+        let recorder_queries = &mut queries.recorders[recorder];
+        let start_query = recorder_queries.write_timestamp(device, command_buffer);
+        recorder_queries.stack.push(index);
+        queries.scopes.push(PendingScope {
+            name: name.to_string(),
+            recorder,
+            parent,
+            cpu_start: Instant::now(),
+            cpu_time: None,
+            start_query,
+            end_query: None,
+        });
+    }
+
+    pub fn end_scope(
+        &mut self,
+        device: &ash::Device,
+        command_buffer: vk::CommandBuffer,
+        frame_index: usize,
+        recorder: usize,
+    ) {
+        let queries = &mut self.frames[frame_index];
+        if !queries.recording {
+            return;
+        }
+        let recorder_queries = &mut queries.recorders[recorder];
+        let Some(index) = recorder_queries.stack.pop() else {
+            return;
+        };
+
+        // Only bother with the end timestamp if we've got the start one.
+        let end_query = match queries.scopes[index].start_query {
+            Some(_) => recorder_queries.write_timestamp(device, command_buffer),
+            None => None,
+        };
+
+        let scope = &mut queries.scopes[index];
+        scope.cpu_time = Some(scope.cpu_start.elapsed());
+        scope.end_query = end_query;
+    }
+
+    /// The most recent frame that's been read back.
+    pub fn latest(&self) -> Option<&FrameProfile> {
+        self.latest.as_ref()
+    }
+
+    /// Write every frame that's read back from now on to `trace`, replacing any trace that was
+    /// already in progress.
+    pub fn begin_trace(&mut self, trace: ChromeTrace) -> Result<()> {
+        match self.trace.replace(trace) {
+            Some(previous) => previous.finish(),
+            None => Ok(()),
+        }
+    }
+
+    pub fn end_trace(&mut self) -> Result<()> {
+        match self.trace.take() {
+            Some(trace) => trace.finish(),
+            None => Ok(()),
+        }
+    }
+
+    pub fn destroy(&mut self, device: &ash::Device) {
+        if let Err(e) = self.end_trace() {
+            log::warn!("[lazy_vulkan] Unable to finish Chrome trace: {e}");
+        }
+
+        for recorder in self.frames.drain(..).flat_map(|queries| queries.recorders) {
+            unsafe { device.destroy_query_pool(recorder.pool, None) };
+        }
+    }
+
+    fn resolve(&mut self, device: &ash::Device, frame_index: usize) -> Option<FrameProfile> {
+        let queries = &mut self.frames[frame_index];
+        if !queries.recording || queries.scopes.is_empty() {
+            return None;
+        }
+
+        let mut timestamps = Vec::with_capacity(queries.recorders.len());
+        for recorder in &queries.recorders {
+            let mut recorder_timestamps = vec![0u64; recorder.next_query as usize];
+            if !recorder_timestamps.is_empty() {
+                if let Err(e) = unsafe {
+                    device.get_query_pool_results(
+                        recorder.pool,
+                        0,
+                        &mut recorder_timestamps,
+                        vk::QueryResultFlags::TYPE_64,
+                    )
+                } {
+                    log::warn!("[lazy_vulkan] Unable to read back timestamps: {e}");
+                    return None;
+                }
+            }
+            timestamps.push(recorder_timestamps);
+        }
+
+        let gpu_time = |scope: &PendingScope| {
+            let (start, end) = (scope.start_query?, scope.end_query?);
+            let timestamps = &timestamps[scope.recorder];
+            let ticks = timestamps[end as usize].wrapping_sub(timestamps[start as usize])
+                & self.valid_bits_mask;
+            Some(Duration::from_nanos(
+                (ticks as f64 * self.timestamp_period) as u64,
+            ))
+        };
+
+        if let Some(trace) = &mut self.trace {
+            let calibration = trace.calibrate();
+            let gpu_start = |scope: &PendingScope| {
+                // The frame has finished, so its timestamps were all taken before we calibrated.
+                let calibration = calibration.as_ref()?;
+                let timestamp = timestamps[scope.recorder][scope.start_query? as usize];
+                let ticks = calibration.ticks.wrapping_sub(timestamp) & self.valid_bits_mask;
+                calibration.instant.checked_sub(Duration::from_nanos(
+                    (ticks as f64 * self.timestamp_period) as u64,
+                ))
+            };
+
+            let written = queries.scopes.iter().try_for_each(|scope| {
+                let frame = queries.frame;
+                if let Some(cpu_time) = scope.cpu_time {
+                    trace.cpu_scope(
+                        &scope.name,
+                        frame,
+                        scope.recorder,
+                        scope.cpu_start,
+                        cpu_time,
+                    )?;
+                }
+
+                let gpu_start = gpu_start(scope);
+                if let (Some(gpu_start), Some(gpu_time)) = (gpu_start, gpu_time(scope)) {
+                    trace.gpu_scope(&scope.name, frame, gpu_start, gpu_time)?;
+                }
+                Ok::<_, Error>(())
+            });
+
+            if let Err(e) = written {
+                log::warn!("[lazy_vulkan] Unable to write Chrome trace, stopping: {e}");
+                self.trace = None;
+            }
+        }
+
+        // Parents always come before their children, so build the tree from the back.
+        let mut nodes = queries
+            .scopes
+            .iter()
+            .map(|scope| {
+                Some(ProfileScope {
+                    name: scope.name.clone(),
+                    cpu_time: scope.cpu_time.unwrap_or_default(),
+                    gpu_time: gpu_time(scope),
+                    children: Vec::new(),
+                })
+            })
+            .collect::<Vec<_>>();
+
+        let mut roots = Vec::new();
+        for (index, scope) in queries.scopes.iter().enumerate().rev() {
+            let node = nodes[index].take().unwrap();
+            match scope.parent {
+                Some(parent) => nodes[parent].as_mut().unwrap().children.insert(0, node),
+                None => roots.insert(0, node),
+            }
+        }
+
+        Some(FrameProfile {
+            frame: queries.frame,
+            scopes: roots,
+        })
+    }
+}
+
+impl RecorderQueries {
+    fn write_timestamp(
+        &mut self,
+        device: &ash::Device,
+        command_buffer: vk::CommandBuffer,
+    ) -> Option<u32> {
+        if self.next_query == QUERIES_PER_FRAME {
+            return None;
+        }
+
+        let query = self.next_query;
+        self.next_query += 1;
+        unsafe {
+            device.cmd_write_timestamp2(
+                command_buffer,
+                vk::PipelineStageFlags2::ALL_COMMANDS,
+                self.pool,
+                query,
+            )
+        };
+        Some(query)
+    }
+}
@@ -0,0 +1,272 @@
+use std::sync::{Arc, Mutex};
+
+use ash::vk;
+
+use crate::{Context, DeviceFeatures, Error, Result};
+
+/// What a [`QuerySet`] measures.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QueryKind {
+    /// How many samples passed the depth and stencil tests. If `precise` is false, the result is
+    /// only guaranteed to be zero or non-zero - which is all occlusion culling needs, and can be
+    /// cheaper.
+    Occlusion { precise: bool },
+    /// One counter for each statistic in the flags, in bit order.
+    PipelineStatistics(vk::QueryPipelineStatisticFlags),
+}
+
+impl QueryKind {
+    /// How many values each query produces.
+    fn values_per_query(&self) -> usize {
+        match self {
+            QueryKind::Occlusion { .. } => 1,
+            QueryKind::PipelineStatistics(flags) => flags.as_raw().count_ones() as usize,
+        }
+    }
+
+    fn required_features(&self) -> DeviceFeatures {
+        let core = match self {
+            QueryKind::Occlusion { precise: false } => return DeviceFeatures::default(),
+            QueryKind::Occlusion { precise: true } => {
+                vk::PhysicalDeviceFeatures::default().occlusion_query_precise(true)
+            }
+            QueryKind::PipelineStatistics(_) => {
+                vk::PhysicalDeviceFeatures::default().pipeline_statistics_query(true)
+            }
+        };
+
+        DeviceFeatures {
+            core,
+            ..Default::default()
+        }
+    }
+}
+
+/// A set of occlusion or pipeline statistics queries that can be used once per frame, created
+/// with [`crate::Renderer::create_query_set`].
+///
+/// Begin and end queries from inside the draw callbacks, then read the results with
+/// [`QuerySet::results`] a few frames later - they're read back as soon as the GPU has finished
+/// the frame, so it never has to stall.
+///
+/// Clones refer to the same queries.
+#[derive(Clone)]
+pub struct QuerySet {
+    inner: Arc<Mutex<QuerySetInner>>,
+}
+
+pub(crate) struct QuerySetInner {
+    context: Arc<Context>,
+    kind: QueryKind,
+    count: u32,
+    /// One per frame in flight.
+    pools: Vec<vk::QueryPool>,
+    /// The frame each pool was last reset for.
+    pool_frames: Vec<Option<u64>>,
+    latest: Option<QueryResults>,
+}
+
+/// The results of a [`QuerySet`] for one frame.
+#[derive(Debug, Clone)]
+pub struct QueryResults {
+    pub frame: u64,
+    values_per_query: usize,
+    /// `values_per_query` values per query, followed by its availability.
+    data: Vec<u64>,
+}
+
+impl QueryResults {
+    /// The value(s) query `index` produced, or `None` if it wasn't used that frame.
+    pub fn get(&self, index: u32) -> Option<&[u64]> {
+        let stride = self.values_per_query + 1;
+        let query = self
+            .data
+            .get(index as usize * stride..(index as usize + 1) * stride)?;
+        let (values, available) = query.split_at(self.values_per_query);
+        (available[0] != 0).then_some(values)
+    }
+}
+
+impl QuerySet {
+    pub(crate) fn new(context: Arc<Context>, kind: QueryKind, count: u32) -> Result<Self> {
+        let missing = kind
+            .required_features()
+            .missing_from(&context.enabled_features);
+        if !missing.is_empty() {
+            return Err(Error::MissingDeviceFeatures(Box::new(missing)));
+        }
+
+        let (query_type, pipeline_statistics) = match kind {
+            QueryKind::Occlusion { .. } => (
+                vk::QueryType::OCCLUSION,
+                vk::QueryPipelineStatisticFlags::empty(),
+            ),
+            QueryKind::PipelineStatistics(flags) => (vk::QueryType::PIPELINE_STATISTICS, flags),
+        };
+
+        let mut pools = Vec::with_capacity(context.frames_in_flight);
+        for _ in 0..context.frames_in_flight {
+            let pool = unsafe {
+                context.device.create_query_pool(
+                    &vk::QueryPoolCreateInfo::default()
+                        .query_type(query_type)
+                        .query_count(count)
+                        .pipeline_statistics(pipeline_statistics),
+                    None,
+                )
+            };
+            match pool {
+                Ok(pool) => pools.push(pool),
+                Err(e) => {
+                    for pool in pools {
+                        unsafe { context.device.destroy_query_pool(pool, None) };
+                    }
+                    return Err(e.into());
+                }
+            }
+        }
+
+        let pool_frames = vec![None; pools.len()];
+        Ok(Self {
+            inner: Arc::new(Mutex::new(QuerySetInner {
+                context,
+                kind,
+                count,
+                pools,
+                pool_frames,
+                latest: None,
+            })),
+        })
+    }
+
+    pub fn kind(&self) -> QueryKind {
+        self.inner.lock().unwrap().kind
+    }
+
+    /// How many queries there are, per frame.
+    pub fn count(&self) -> u32 {
+        self.inner.lock().unwrap().count
+    }
+
+    /// Begin query `index` in [`Context::draw_command_buffer`]. Each query can only be used once
+    /// per frame.
+    ///
+    /// ## NOTE
+    /// Queries are only reset at the start of a frame, so this does nothing in the frame the set
+    /// was created in.
+    pub fn begin(&self, context: &Context, index: u32) {
+        let inner = self.inner.lock().unwrap();
+        assert!(index < inner.count, "Query {index} out of range");
+        if inner.pool_frames[context.frame_index()].is_none() {
+            return;
+        }
+
+        let flags = match inner.kind {
+            QueryKind::Occlusion { precise: true } => vk::QueryControlFlags::PRECISE,
+            _ => vk::QueryControlFlags::empty(),
+        };
+
+        unsafe {
+            context.device.cmd_begin_query(
+                context.draw_command_buffer(),
+                inner.pools[context.frame_index()],
+                index,
+                flags,
+            )
+        };
+    }
+
+    /// End query `index`, which must have been begun in the same command buffer.
+    pub fn end(&self, context: &Context, index: u32) {
+        let inner = self.inner.lock().unwrap();
+        if inner.pool_frames[context.frame_index()].is_none() {
+            return;
+        }
+
+        unsafe {
+            context.device.cmd_end_query(
+                context.draw_command_buffer(),
+                inner.pools[context.frame_index()],
+                index,
+            )
+        };
+    }
+
+    /// The results of the most recent frame the GPU has finished that used this set - usually
+    /// `frames_in_flight` frames behind the one being recorded.
+    pub fn results(&self) -> Option<QueryResults> {
+        self.inner.lock().unwrap().latest.clone()
+    }
+
+    /// Nobody but the renderer is holding on to this set.
+    pub(crate) fn is_unused(&self) -> bool {
+        Arc::strong_count(&self.inner) == 1
+    }
+
+    /// Read back the results of the last frame that used `frame_index`'s pool, then reset it in
+    /// `command_buffer` for `frame`. The GPU must be done with that frame, and no render pass can
+    /// be in progress.
+    pub(crate) fn begin_frame(
+        &self,
+        command_buffer: vk::CommandBuffer,
+        frame_index: usize,
+        frame: u64,
+    ) {
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(results) = inner.read_back(frame_index) {
+            inner.latest = Some(results);
+        }
+
+        let device = &inner.context.device;
+        unsafe {
+            device.cmd_reset_query_pool(command_buffer, inner.pools[frame_index], 0, inner.count)
+        };
+        inner.pool_frames[frame_index] = Some(frame);
+    }
+}
+
+impl QuerySetInner {
+    fn read_back(&self, frame_index: usize) -> Option<QueryResults> {
+        let frame = self.pool_frames[frame_index]?;
+        let values_per_query = self.kind.values_per_query();
+        let mut data = vec![0u64; self.count as usize * (values_per_query + 1)];
+
+        // ash assumes one `T` per query, but we've got several values per query - so go direct.
+        let device = &self.context.device;
+        let result = unsafe {
+            (device.fp_v1_0().get_query_pool_results)(
+                device.handle(),
+                self.pools[frame_index],
+                0,
+                self.count,
+                std::mem::size_of_val(data.as_slice()),
+                data.as_mut_ptr().cast(),
+                ((values_per_query + 1) * std::mem::size_of::<u64>()) as vk::DeviceSize,
+                vk::QueryResultFlags::TYPE_64 | vk::QueryResultFlags::WITH_AVAILABILITY,
+            )
+            .result()
+        };
+
+        match result {
+            // Queries that weren't used are never available, which is fine.
+            Ok(()) | Err(vk::Result::NOT_READY) => Some(QueryResults {
+                frame,
+                values_per_query,
+                data,
+            }),
+            Err(e) => {
+                log::warn!("[lazy_vulkan] Unable to read back queries: {e}");
+                None
+            }
+        }
+    }
+}
+
+impl Drop for QuerySetInner {
+    fn drop(&mut self) {
+        // NOTE: `Renderer` only lets go of a set once the GPU is done with all of its pools.
+        for pool in self.pools.drain(..) {
+            unsafe { self.context.device.destroy_query_pool(pool, None) };
+        }
+    }
+}
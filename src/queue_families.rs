@@ -0,0 +1,161 @@
+use ash::vk;
+
+/// The queue families [`crate::Context`] uses, as reported by
+/// `vkGetPhysicalDeviceQueueFamilyProperties`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QueueFamilies {
+    /// Supports graphics (and therefore transfer). Prefers a family that can also present.
+    pub graphics: u32,
+    /// Can present to the surface. Usually the same as `graphics`, and always the same as
+    /// `graphics` if there's no surface.
+    pub present: u32,
+    /// A family that supports compute, but not graphics - if there is one.
+    pub compute: Option<u32>,
+    /// A family that supports transfer, but not graphics or compute - if there is one.
+    pub transfer: Option<u32>,
+}
+
+impl QueueFamilies {
+    /// Find the families we want on `physical_device`. If `surface` is provided, one of them must
+    /// be able to present to it.
+    pub fn find(
+        instance: &ash::Instance,
+        physical_device: vk::PhysicalDevice,
+        surface: Option<(&ash::khr::surface::Instance, vk::SurfaceKHR)>,
+    ) -> Result<QueueFamilies, &'static str> {
+        let properties =
+            unsafe { instance.get_physical_device_queue_family_properties(physical_device) };
+
+        Self::choose(&properties, |family| match surface {
+            Some((surface_fn, surface)) => unsafe {
+                surface_fn
+                    .get_physical_device_surface_support(physical_device, family, surface)
+                    .unwrap_or(false)
+            },
+            None => true,
+        })
+    }
+
+    /// Pick families out of `properties`. The graphics or present family must be one that
+    /// `can_present`.
+    pub(crate) fn choose(
+        properties: &[vk::QueueFamilyProperties],
+        can_present: impl Fn(u32) -> bool,
+    ) -> Result<QueueFamilies, &'static str> {
+        let families = properties
+            .iter()
+            .enumerate()
+            .filter(|(_, family)| family.queue_count > 0)
+            .map(|(index, family)| (index as u32, family.queue_flags))
+            .collect::<Vec<_>>();
+
+        let graphics_families = families
+            .iter()
+            .filter(|(_, flags)| flags.contains(vk::QueueFlags::GRAPHICS))
+            .collect::<Vec<_>>();
+
+        // Graphics and present on the same family is by far the most common (and the simplest),
+        // so prefer that if we can get it.
+        let graphics = graphics_families
+            .iter()
+            .find(|(index, flags)| flags.contains(vk::QueueFlags::COMPUTE) && can_present(*index))
+            .or_else(|| {
+                graphics_families
+                    .iter()
+                    .find(|(_, flags)| flags.contains(vk::QueueFlags::COMPUTE))
+            })
+            .or_else(|| graphics_families.first())
+            .map(|(index, _)| *index)
+            .ok_or("no graphics queue")?;
+
+        let present = if can_present(graphics) {
+            graphics
+        } else {
+            families
+                .iter()
+                .map(|(index, _)| *index)
+                .find(|index| can_present(*index))
+                .ok_or("unable to present to surface")?
+        };
+
+        let compute = families
+            .iter()
+            .find(|(_, flags)| {
+                flags.contains(vk::QueueFlags::COMPUTE) && !flags.contains(vk::QueueFlags::GRAPHICS)
+            })
+            .map(|(index, _)| *index);
+
+        let transfer = families
+            .iter()
+            .find(|(_, flags)| {
+                flags.contains(vk::QueueFlags::TRANSFER)
+                    && !flags.intersects(vk::QueueFlags::GRAPHICS | vk::QueueFlags::COMPUTE)
+            })
+            .map(|(index, _)| *index);
+
+        Ok(QueueFamilies {
+            graphics,
+            present,
+            compute,
+            transfer,
+        })
+    }
+
+    /// Every distinct family, for creating the device's queues.
+    pub fn unique(&self) -> Vec<u32> {
+        let mut families = vec![self.graphics];
+        for family in [Some(self.present), self.compute, self.transfer]
+            .into_iter()
+            .flatten()
+        {
+            if !families.contains(&family) {
+                families.push(family);
+            }
+        }
+        families
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ash::vk;
+
+    use super::QueueFamilies;
+
+    #[test]
+    fn test_queue_families() {
+        let family = |queue_flags| {
+            vk::QueueFamilyProperties::default()
+                .queue_flags(queue_flags)
+                .queue_count(1)
+        };
+        let graphics =
+            vk::QueueFlags::GRAPHICS | vk::QueueFlags::COMPUTE | vk::QueueFlags::TRANSFER;
+        let compute = vk::QueueFlags::COMPUTE | vk::QueueFlags::TRANSFER;
+        let transfer = vk::QueueFlags::TRANSFER;
+
+        // Dedicated compute and transfer families are used if there are any..
+        let properties = [family(graphics), family(compute), family(transfer)];
+        let families = QueueFamilies::choose(&properties, |_| true).unwrap();
+        assert_eq!(families.graphics, 0);
+        assert_eq!(families.present, 0);
+        assert_eq!(families.compute, Some(1));
+        assert_eq!(families.transfer, Some(2));
+
+        // ..and families with no queues are skipped.
+        let properties = [family(graphics), family(compute).queue_count(0)];
+        let families = QueueFamilies::choose(&properties, |_| true).unwrap();
+        assert_eq!((families.compute, families.transfer), (None, None));
+
+        // Prefer a graphics family that can present, otherwise present from another family.
+        let properties = [family(graphics), family(graphics)];
+        let families = QueueFamilies::choose(&properties, |family| family == 1).unwrap();
+        assert_eq!((families.graphics, families.present), (1, 1));
+        let properties = [family(graphics), family(compute)];
+        let families = QueueFamilies::choose(&properties, |family| family == 1).unwrap();
+        assert_eq!((families.graphics, families.present), (0, 1));
+
+        assert!(QueueFamilies::choose(&properties, |_| false).is_err());
+        assert!(QueueFamilies::choose(&[family(compute)], |_| true).is_err());
+    }
+}
@@ -6,30 +6,58 @@ use super::{
     FULL_IMAGE,
 };
 use crate::{
+    async_compute::{AsyncCompute, COMPUTE_CONSUMER_STAGES},
     descriptors::Descriptors,
     headless_swapchain::HeadlessSwapchain,
     image_manager::ImageManager,
+    query_set::{QueryKind, QuerySet},
     render_plan::{AttachmentState, RenderStage},
+    secondary_command_buffers::{Inheritance, SecondaryCommandBuffers, WorkerPool},
     sub_renderer::{AttachmentInfo, LayerInfo, StateFamily, SubRenderer},
-    HeadlessSwapchainImage, Image, Pipeline, PipelineOptions, RenderAttachment, RenderPlan,
+    Error, HeadlessSwapchainImage, Image, Pipeline, PipelineOptions, RenderAttachment, RenderPlan,
+    Result,
 };
 use ash::vk::{self};
 use std::{collections::HashMap, path::Path, sync::Arc, u64};
 
+type BoxedSubRenderer<SF> = Box<dyn for<'s> SubRenderer<'s, State = <SF as StateFamily>::For<'s>>>;
+/// A sub-renderer that [`Renderer::draw_parallel`] can record on a worker thread.
+type ParallelSubRenderer<SF> =
+    Box<dyn for<'s> SubRenderer<'s, State = <SF as StateFamily>::For<'s>> + Send>;
+type SubRendererMut<'a, SF> =
+    &'a mut dyn for<'s> SubRenderer<'s, State = <SF as StateFamily>::For<'s>>;
+
 enum SwapchainBackend {
     WSI(Swapchain),
     Headless(HeadlessSwapchain),
 }
 
+// NOTE: Fields are dropped in declaration order, so the things that live in the allocator's
+// memory need to come before it.
 pub struct Renderer<SF: StateFamily> {
     pub context: Arc<Context>,
-    pub fence: vk::Fence,
+    /// One per frame in flight, signalled when the GPU has finished with that frame.
+    pub fences: Vec<vk::Fence>,
     pub depth_buffer: DepthBuffer,
-    pub allocator: Allocator,
+    pub sub_renderers: HashMap<String, BoxedSubRenderer<SF>>,
+    /// Sub-renderers added with [`Renderer::add_parallel_sub_renderer`].
+    parallel_sub_renderers: HashMap<String, ParallelSubRenderer<SF>>,
+    /// Only created once parallel recording is used.
+    secondary_command_buffers: Option<SecondaryCommandBuffers>,
+    /// Only present if the device has a dedicated compute queue - otherwise compute work is
+    /// recorded straight into the graphics command buffer.
+    async_compute: Option<AsyncCompute>,
+    /// The async compute wait the next graphics submission needs.
+    compute_wait: Option<vk::SemaphoreSubmitInfo<'static>>,
+    /// Every query set that's been created, so they can be read back and reset each frame.
+    query_sets: Vec<QuerySet>,
+    /// Query sets that have been dropped by everyone else, and the frame they were dropped in.
+    /// They're destroyed once the GPU can't be using them any more.
+    retired_query_sets: Vec<(u32, QuerySet)>,
+    pub render_attachments: HashMap<String, RenderAttachment>,
     pub image_manager: ImageManager,
     pub descriptors: Descriptors,
-    pub sub_renderers: HashMap<String, Box<dyn for<'s> SubRenderer<'s, State = SF::For<'s>>>>,
-    pub render_attachments: HashMap<String, RenderAttachment>,
+    pub allocator: Allocator,
     swapchain: SwapchainBackend,
     /// Monotonically increasing frame counter
     pub frame: u32,
@@ -40,45 +68,57 @@ impl<SF: StateFamily> Renderer<SF> {
         context: Arc<Context>,
         swapchain: SwapchainBackend,
         drawable_size: vk::Extent2D,
-    ) -> Self {
+    ) -> Result<Self> {
         let device = &context.device;
 
-        let fence = unsafe {
+        let fences = std::iter::repeat_with(|| unsafe {
             device.create_fence(
                 &vk::FenceCreateInfo::default().flags(vk::FenceCreateFlags::SIGNALED),
                 None,
             )
-        }
-        .unwrap();
+        })
+        .take(context.frames_in_flight)
+        .collect::<Result<_, _>>()?;
 
-        let allocator = Allocator::new(context.clone());
-        let descriptors = Descriptors::new(context.clone());
+        let allocator = Allocator::new(context.clone())?;
+        let async_compute = AsyncCompute::new(context.clone())?;
+        let descriptors = Descriptors::new(context.clone())?;
         let image_manager = ImageManager::new(context.clone(), descriptors.set);
-        let depth_buffer = DepthBuffer::new(&context, drawable_size);
+        let depth_buffer = DepthBuffer::new(&context, drawable_size)?;
 
-        Self {
+        Ok(Self {
             context,
-            fence,
+            fences,
             swapchain,
             depth_buffer,
             allocator,
             image_manager,
             descriptors,
             sub_renderers: Default::default(),
+            parallel_sub_renderers: Default::default(),
+            secondary_command_buffers: None,
+            async_compute,
+            compute_wait: None,
+            query_sets: Vec::new(),
+            retired_query_sets: Vec::new(),
             render_attachments: Default::default(),
             frame: 0,
-        }
+        })
     }
 
-    pub fn from_wsi(context: Arc<Context>, swapchain: Swapchain) -> Self {
+    pub fn from_wsi(context: Arc<Context>, swapchain: Swapchain) -> Result<Self> {
         let extent = swapchain.extent;
         Self::new(context, SwapchainBackend::WSI(swapchain), extent)
     }
 
-    pub fn headless(context: Arc<Context>, extent: vk::Extent2D, format: vk::Format) -> Self {
+    pub fn headless(
+        context: Arc<Context>,
+        extent: vk::Extent2D,
+        format: vk::Format,
+    ) -> Result<Self> {
         Self::new(
             context.clone(),
-            SwapchainBackend::Headless(HeadlessSwapchain::new(context, extent, format)),
+            SwapchainBackend::Headless(HeadlessSwapchain::new(context, extent, format)?),
             extent,
         )
     }
@@ -98,6 +138,7 @@ impl<SF: StateFamily> Renderer<SF> {
 
         // Do the main passes
         for pass in &plan.passes {
+            trace_span!("render_pass", name = %pass.name);
             self.context
                 .begin_marker(&pass.name, glam::vec4(1.0, 0.2, 0.4, 1.0));
 
@@ -177,12 +218,14 @@ impl<SF: StateFamily> Renderer<SF> {
                 }
             }
 
-            let subrenderer = self
-                .sub_renderers
-                .get_mut(&pass.subrenderer)
-                .expect(&format!("Subrenderer not found: {}", pass.subrenderer));
+            let subrenderer = find_sub_renderer(
+                &mut self.sub_renderers,
+                &mut self.parallel_sub_renderers,
+                &pass.subrenderer,
+            )
+            .expect(&format!("Subrenderer not found: {}", pass.subrenderer));
 
-            let command_buffer = self.context.draw_command_buffer;
+            let command_buffer = self.context.draw_command_buffer();
             let device = &self.context.device;
             match pass.stage {
                 // The contract for a shadow renderer is that begin rendering will have been
@@ -356,7 +399,7 @@ impl<SF: StateFamily> Renderer<SF> {
             unsafe {
                 let context = &self.context;
                 let device = &context.device;
-                let command_buffer = self.context.draw_command_buffer;
+                let command_buffer = self.context.draw_command_buffer();
                 let render_area = drawable.extent;
 
                 device.cmd_set_scissor(command_buffer, 0, &[render_area.into()]);
@@ -387,10 +430,12 @@ impl<SF: StateFamily> Renderer<SF> {
                             })]),
                 );
 
-                let composite_pass = self
-                    .sub_renderers
-                    .get_mut(&plan.compositor_subrenderer)
-                    .expect("Couldn't find compositor subrenderer");
+                let composite_pass = find_sub_renderer(
+                    &mut self.sub_renderers,
+                    &mut self.parallel_sub_renderers,
+                    &plan.compositor_subrenderer,
+                )
+                .expect("Couldn't find compositor subrenderer");
 
                 composite_pass.draw_opaque(state, context);
 
@@ -414,7 +459,9 @@ impl<SF: StateFamily> Renderer<SF> {
         // Shadow pass
         self.context
             .begin_marker("Draw Shadow", glam::vec4(1.0, 0.2, 0.4, 1.0));
-        for subrenderer in self.sub_renderers.values_mut() {
+        for subrenderer in
+            all_sub_renderers(&mut self.sub_renderers, &mut self.parallel_sub_renderers)
+        {
             let label = format!("{} Shadow Pass", subrenderer.label());
             self.context
                 .begin_marker(&label, glam::vec4(1.0, 0.2, 0.4, 1.0));
@@ -430,17 +477,22 @@ impl<SF: StateFamily> Renderer<SF> {
         self.context
             .begin_marker("Draw Opaque", glam::vec4(1.0, 0.0, 1.0, 1.0));
         // Begin opaque render pass
-        self.begin_rendering(&RenderAttachment {
-            handle: drawable.image,
-            view: drawable.view,
-            extent: drawable.extent,
-            format: self.get_drawable_format(),
-            id: 0, // is is invalid to sample from the colour image during the opaque pass
-            usage: vk::ImageUsageFlags::COLOR_ATTACHMENT,
-        });
+        self.begin_rendering(
+            &RenderAttachment {
+                handle: drawable.image,
+                view: drawable.view,
+                extent: drawable.extent,
+                format: self.get_drawable_format(),
+                id: 0, // is is invalid to sample from the colour image during the opaque pass
+                usage: vk::ImageUsageFlags::COLOR_ATTACHMENT,
+            },
+            vk::RenderingFlags::empty(),
+        );
 
         let drawable = drawable.clone(); // TODO
-        for subrenderer in self.sub_renderers.values_mut() {
+        for subrenderer in
+            all_sub_renderers(&mut self.sub_renderers, &mut self.parallel_sub_renderers)
+        {
             let label = format!("{} Opaque Pass", subrenderer.label());
             self.context
                 .begin_marker(&label, glam::vec4(1.0, 0.0, 1.0, 1.0));
@@ -452,7 +504,7 @@ impl<SF: StateFamily> Renderer<SF> {
         // End opaque render pass
         unsafe {
             self.context
-                .cmd_end_rendering(self.context.draw_command_buffer)
+                .cmd_end_rendering(self.context.draw_command_buffer())
         };
 
         // end draw opaque marker
@@ -461,7 +513,9 @@ impl<SF: StateFamily> Renderer<SF> {
         // Draw layers
         self.context
             .begin_marker("Draw Layer", glam::vec4(0.5, 1.0, 0.2, 1.0));
-        for subrenderer in self.sub_renderers.values_mut() {
+        for subrenderer in
+            all_sub_renderers(&mut self.sub_renderers, &mut self.parallel_sub_renderers)
+        {
             let label = format!("{} Layer Pass", subrenderer.label());
             self.context
                 .begin_marker(&label, glam::vec4(0.5, 1.0, 0.2, 1.0));
@@ -491,39 +545,303 @@ impl<SF: StateFamily> Renderer<SF> {
         self.context.end_marker();
     }
 
-    pub fn begin_command_buffer(&mut self) {
-        let device = &self.context.device;
-        // Block the CPU until we're done rendering the previous frame
+    /// Like [`Renderer::draw`], but each sub-renderer records each of its passes into its own
+    /// secondary command buffer. Those added with [`Renderer::add_parallel_sub_renderer`] are
+    /// recorded on a pool of worker threads, while the rest are recorded on this one. The
+    /// secondary command buffers are then executed in order of their sub-renderer's label.
+    ///
+    /// If [`Renderer::enable_parallel_recording`] hasn't been called, one worker per core is
+    /// started.
+    pub fn draw_parallel<'s>(&mut self, state: &SF::For<'s>, drawable: &Drawable) -> Result<()>
+    where
+        SF::For<'s>: Sync,
+    {
+        if self.secondary_command_buffers.is_none() {
+            let threads = std::thread::available_parallelism().map_or(1, |n| n.get());
+            self.enable_parallel_recording(threads)?;
+        }
+
+        let drawable = *drawable;
+        let no_rendering = Inheritance {
+            extent: drawable.extent,
+            colour_format: None,
+        };
+
+        self.context
+            .begin_marker("Drawing", glam::vec4(0.0, 0.0, 1.0, 1.0));
+
+        // Shadow pass
+        self.context
+            .begin_marker("Draw Shadow", glam::vec4(1.0, 0.2, 0.4, 1.0));
+        self.record_in_parallel(
+            no_rendering,
+            ("Shadow Pass", glam::vec4(1.0, 0.2, 0.4, 1.0)),
+            |subrenderer, context| subrenderer.draw_shadow(state, context),
+        )?;
+        // end draw shadow marker
+        self.context.end_marker();
+
+        // Draw opaque
+        self.context
+            .begin_marker("Draw Opaque", glam::vec4(1.0, 0.0, 1.0, 1.0));
+        let colour_format = self.get_drawable_format();
+        self.begin_rendering(
+            &RenderAttachment {
+                handle: drawable.image,
+                view: drawable.view,
+                extent: drawable.extent,
+                format: colour_format,
+                id: 0, // is is invalid to sample from the colour image during the opaque pass
+                usage: vk::ImageUsageFlags::COLOR_ATTACHMENT,
+            },
+            vk::RenderingFlags::CONTENTS_SECONDARY_COMMAND_BUFFERS,
+        );
+        self.record_in_parallel(
+            Inheritance {
+                extent: drawable.extent,
+                colour_format: Some(colour_format),
+            },
+            ("Opaque Pass", glam::vec4(1.0, 0.0, 1.0, 1.0)),
+            |subrenderer, context| subrenderer.draw_opaque(state, context),
+        )?;
         unsafe {
-            device
-                .wait_for_fences(&[self.fence], true, u64::MAX)
-                .unwrap();
-            device.reset_fences(&[self.fence]).unwrap();
+            self.context
+                .cmd_end_rendering(self.context.draw_command_buffer())
+        };
+        // end draw opaque marker
+        self.context.end_marker();
+
+        // Draw layers
+        self.context
+            .begin_marker("Draw Layer", glam::vec4(0.5, 1.0, 0.2, 1.0));
+        let depth_buffer = self.depth_buffer;
+        self.record_in_parallel(
+            no_rendering,
+            ("Layer Pass", glam::vec4(0.5, 1.0, 0.2, 1.0)),
+            |subrenderer, context| {
+                subrenderer.draw_layer(
+                    state,
+                    context,
+                    LayerInfo {
+                        colour_attachment: Some(AttachmentInfo {
+                            extent: drawable.extent,
+                            view: drawable.view,
+                            handle: drawable.image,
+                        }),
+                        depth_attachment: Some(AttachmentInfo {
+                            extent: drawable.extent,
+                            view: depth_buffer.view,
+                            handle: depth_buffer.image,
+                        }),
+                    },
+                )
+            },
+        )?;
+        // end draw layer marker
+        self.context.end_marker();
+
+        // end "drawing"
+        self.context.end_marker();
+        Ok(())
+    }
+
+    /// Record sub-renderers on `threads` worker threads with [`Renderer::draw_parallel`]. The
+    /// threads are kept around until this is called again, or the renderer is dropped.
+    pub fn enable_parallel_recording(&mut self, threads: usize) -> Result<()> {
+        assert!(threads > 0, "Must record on at least one thread");
+        if let Some(secondary_command_buffers) = &self.secondary_command_buffers {
+            if secondary_command_buffers.threads() == threads {
+                return Ok(());
+            }
         }
 
-        self.context.begin_command_buffer();
+        // The old pools might still be in use.
+        unsafe { self.context.device.device_wait_idle() }?;
+        self.secondary_command_buffers =
+            Some(SecondaryCommandBuffers::new(self.context.clone(), threads)?);
+        Ok(())
     }
 
-    pub fn submit_and_present(&mut self, drawable: Drawable) {
+    /// Record every sub-renderer into its own secondary command buffer - the parallel ones split
+    /// evenly across the worker threads, the rest on this thread - then execute them on the draw
+    /// command buffer.
+    fn record_in_parallel(
+        &mut self,
+        inheritance: Inheritance,
+        (pass, colour): (&str, glam::Vec4),
+        record: impl Fn(SubRendererMut<SF>, &Context) + Sync,
+    ) -> Result<()> {
+        let context = &self.context;
+        let secondary_command_buffers = self
+            .secondary_command_buffers
+            .as_mut()
+            .expect("Parallel recording is not enabled");
+
+        let record_one = |pool: &mut WorkerPool, subrenderer: SubRendererMut<SF>| {
+            pool.record(context, inheritance, || {
+                let label = format!("{} {pass}", subrenderer.label());
+                context.begin_marker(&label, colour);
+                record(subrenderer, context);
+                // end pass marker
+                context.end_marker();
+            })
+        };
+
+        // HashMap order isn't stable, so sort them to keep the draw order deterministic. Each
+        // thread records its share in order, too.
+        let mut sub_renderers = self.sub_renderers.iter_mut().collect::<Vec<_>>();
+        sub_renderers.sort_by_key(|(label, _)| *label);
+        let mut parallel_sub_renderers = self.parallel_sub_renderers.iter_mut().collect::<Vec<_>>();
+        parallel_sub_renderers.sort_by_key(|(label, _)| *label);
+        let chunk_size = parallel_sub_renderers
+            .len()
+            .div_ceil(secondary_command_buffers.threads())
+            .max(1);
+
+        let command_buffers = secondary_command_buffers.record(
+            context.frame_index(),
+            |pool| {
+                sub_renderers
+                    .iter_mut()
+                    .map(|(label, subrenderer)| {
+                        Ok((*label, record_one(pool, subrenderer.as_mut())?))
+                    })
+                    .collect::<Result<Vec<_>>>()
+            },
+            parallel_sub_renderers.chunks_mut(chunk_size).map(|chunk| {
+                let record_one = &record_one;
+                move |pool: &mut WorkerPool| {
+                    chunk
+                        .iter_mut()
+                        .map(|(label, subrenderer)| {
+                            Ok((*label, record_one(pool, subrenderer.as_mut())?))
+                        })
+                        .collect::<Result<Vec<_>>>()
+                }
+            }),
+        );
+
+        let mut command_buffers = command_buffers
+            .into_iter()
+            .collect::<Result<Vec<_>>>()?
+            .concat();
+        if command_buffers.is_empty() {
+            return Ok(());
+        }
+        // Merge each thread's share back together.
+        command_buffers.sort_by_key(|(label, _)| *label);
+        let command_buffers = command_buffers
+            .into_iter()
+            .map(|(_, command_buffer)| command_buffer)
+            .collect::<Vec<_>>();
+
+        unsafe {
+            context
+                .device
+                .cmd_execute_commands(context.draw_command_buffer(), &command_buffers)
+        };
+        Ok(())
+    }
+
+    /// Add a sub-renderer, replacing any with the same label. [`Renderer::draw_parallel`] records
+    /// it on the calling thread.
+    pub fn add_sub_renderer(&mut self, sub_renderer: BoxedSubRenderer<SF>) {
+        let label = sub_renderer.label().to_string();
+        self.parallel_sub_renderers.remove(&label);
+        self.sub_renderers.insert(label, sub_renderer);
+    }
+
+    /// Like [`Renderer::add_sub_renderer`], but [`Renderer::draw_parallel`] can record it on a
+    /// worker thread.
+    pub fn add_parallel_sub_renderer(&mut self, sub_renderer: ParallelSubRenderer<SF>) {
+        let label = sub_renderer.label().to_string();
+        self.sub_renderers.remove(&label);
+        self.parallel_sub_renderers.insert(label, sub_renderer);
+    }
+
+    pub fn begin_command_buffer(&mut self) -> Result<()> {
+        trace_span!("begin_commands");
+        let device = &self.context.device;
+        let frame_index = self.frame_index();
+        let fence = self.fences[frame_index];
+
+        // Block the CPU until the GPU is done with the last frame that used this slot
+        let waited = unsafe { device.wait_for_fences(&[fence], true, u64::MAX) };
+        self.context
+            .check_device_lost(waited.map_err(Error::from))?;
+
+        // Anything that frame was holding on to can now be released
+        self.context.set_frame_index(frame_index);
+        self.allocator.begin_frame(self.frame as u64);
+        if let Some(secondary_command_buffers) = &mut self.secondary_command_buffers {
+            secondary_command_buffers.reset(frame_index)?;
+        }
+
+        self.context.begin_command_buffer()?;
+        self.context.begin_frame(self.frame as u64);
+        self.begin_query_sets();
+        Ok(())
+    }
+
+    /// Create a set of `count` queries that can be used from the draw callbacks - see
+    /// [`QuerySet`].
+    pub fn create_query_set(&mut self, kind: QueryKind, count: u32) -> Result<QuerySet> {
+        let query_set = QuerySet::new(self.context.clone(), kind, count)?;
+        self.query_sets.push(query_set.clone());
+        Ok(query_set)
+    }
+
+    fn begin_query_sets(&mut self) {
+        let frame = self.frame;
+        let frames_in_flight = self.fences.len() as u32;
+        self.retired_query_sets
+            .retain(|(retired, _)| frame < retired + frames_in_flight);
+
+        let command_buffer = self.context.draw_command_buffer();
+        let frame_index = self.frame_index();
+        for query_set in std::mem::take(&mut self.query_sets) {
+            if query_set.is_unused() {
+                self.retired_query_sets.push((frame, query_set));
+                continue;
+            }
+
+            query_set.begin_frame(command_buffer, frame_index, frame as u64);
+            self.query_sets.push(query_set);
+        }
+    }
+
+    /// Which of the frames in flight is being recorded.
+    pub fn frame_index(&self) -> usize {
+        self.frame as usize % self.fences.len()
+    }
+
+    pub fn submit_and_present(&mut self, drawable: Drawable) -> Result<()> {
+        trace_span!("submit_and_present", frame = self.frame);
         self.context.begin_marker(
             &format!("Submit frame {}", self.frame),
             glam::Vec4::new(0.5, 0.5, 0., 1.),
         );
         // Transition the colour image to the present layout and submit all work
-        self.submit_rendering(&drawable);
+        self.submit_rendering(&drawable)?;
 
         // Present
         if let SwapchainBackend::WSI(swapchain) = &mut self.swapchain {
-            swapchain.present(drawable, self.context.graphics_queue);
+            let presented = swapchain.present(drawable, self.context.present_queue);
+            self.context.check_device_lost(presented)?;
         }
 
         self.frame += 1;
+        Ok(())
     }
 
-    fn begin_rendering(&mut self, colour_attachment: &RenderAttachment) {
+    fn begin_rendering(
+        &mut self,
+        colour_attachment: &RenderAttachment,
+        rendering_flags: vk::RenderingFlags,
+    ) {
         let context = &self.context;
         let device = &context.device;
-        let command_buffer = context.draw_command_buffer;
+        let command_buffer = context.draw_command_buffer();
 
         // Get a `Drawable` from the swapchain
         let render_area = colour_attachment.extent;
@@ -537,22 +855,26 @@ impl<SF: StateFamily> Renderer<SF> {
             context.cmd_pipeline_barrier2(
                 command_buffer,
                 &vk::DependencyInfo::default().image_memory_barriers(&[
-                    // Swapchain image
+                    // Swapchain image. Waiting on COLOR_ATTACHMENT_OUTPUT chains onto the
+                    // `image_available` semaphore wait. The headless swapchain has no semaphore,
+                    // and one image for every frame in flight, so the previous frame's writes
+                    // have to be waited on too.
                     vk::ImageMemoryBarrier2::default()
                         .subresource_range(FULL_IMAGE)
                         .image(colour_attachment.handle)
-                        .src_access_mask(vk::AccessFlags2::NONE)
-                        .src_stage_mask(vk::PipelineStageFlags2::NONE)
+                        .src_access_mask(vk::AccessFlags2::COLOR_ATTACHMENT_WRITE)
+                        .src_stage_mask(vk::PipelineStageFlags2::COLOR_ATTACHMENT_OUTPUT)
                         .dst_access_mask(vk::AccessFlags2::COLOR_ATTACHMENT_WRITE)
                         .dst_stage_mask(vk::PipelineStageFlags2::COLOR_ATTACHMENT_OUTPUT)
                         .old_layout(vk::ImageLayout::UNDEFINED)
                         .new_layout(vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL),
-                    // Depth buffer
+                    // Depth buffer. It's shared between frames in flight, so wait for the
+                    // previous frame to finish with it.
                     vk::ImageMemoryBarrier2::default()
                         .subresource_range(DEPTH_RANGE)
                         .image(self.depth_buffer.image)
-                        .src_access_mask(vk::AccessFlags2::NONE)
-                        .src_stage_mask(vk::PipelineStageFlags2::NONE)
+                        .src_access_mask(vk::AccessFlags2::DEPTH_STENCIL_ATTACHMENT_WRITE)
+                        .src_stage_mask(vk::PipelineStageFlags2::LATE_FRAGMENT_TESTS)
                         .dst_access_mask(
                             vk::AccessFlags2::DEPTH_STENCIL_ATTACHMENT_READ
                                 | vk::AccessFlags2::DEPTH_STENCIL_ATTACHMENT_WRITE,
@@ -563,10 +885,22 @@ impl<SF: StateFamily> Renderer<SF> {
                 ]),
             );
 
+            // Set the dynamic state
+            device.cmd_set_scissor(command_buffer, 0, &[render_area.into()]);
+            device.cmd_set_viewport(
+                command_buffer,
+                0,
+                &[vk::Viewport::default()
+                    .width(render_area.width as _)
+                    .height(render_area.height as _)
+                    .max_depth(1.)],
+            );
+
             // Begin rendering
             context.cmd_begin_rendering(
                 command_buffer,
                 &vk::RenderingInfo::default()
+                    .flags(rendering_flags)
                     .render_area(render_area.into())
                     .layer_count(1)
                     .depth_attachment(
@@ -593,82 +927,170 @@ impl<SF: StateFamily> Renderer<SF> {
                             },
                         })]),
             );
-
-            // Set the dynamic state
-            device.cmd_set_scissor(command_buffer, 0, &[render_area.into()]);
-            device.cmd_set_viewport(
-                command_buffer,
-                0,
-                &[vk::Viewport::default()
-                    .width(render_area.width as _)
-                    .height(render_area.height as _)
-                    .max_depth(1.)],
-            );
         }
 
         // end begin rendering marker
         self.context.end_marker();
     }
 
-    pub fn stage_and_execute_transfers<'s>(&mut self, state: &<SF as StateFamily>::For<'s>) {
-        let command_buffer = self.context.draw_command_buffer;
+    pub fn stage_and_execute_transfers<'s>(
+        &mut self,
+        state: &<SF as StateFamily>::For<'s>,
+    ) -> Result<()> {
+        trace_span!("stage_and_execute_transfers");
+        let command_buffer = self.context.draw_command_buffer();
         // Stage transfers for this frame
         self.context
             .begin_marker("Stage Transfers", glam::vec4(1.0, 0.0, 1.0, 1.0));
-        for subrenderer in self.sub_renderers.values_mut() {
+        for subrenderer in
+            all_sub_renderers(&mut self.sub_renderers, &mut self.parallel_sub_renderers)
+        {
             self.context
                 .begin_marker(subrenderer.label(), glam::vec4(1.0, 0.0, 1.0, 1.0));
-            subrenderer.stage_transfers(state, &mut self.allocator, &mut self.image_manager);
+            let result =
+                subrenderer.stage_transfers(state, &mut self.allocator, &mut self.image_manager);
             self.context.end_marker();
+            result?;
         }
         self.context.end_marker();
 
         // Execute them
-        self.allocator.execute_transfers(command_buffer);
+        self.allocator.execute_transfers(command_buffer)
+    }
+
+    /// Record every sub-renderer's [`SubRenderer::record_compute`]. Call this after
+    /// [`Renderer::stage_and_execute_transfers`], and before drawing.
+    ///
+    /// If the device has a dedicated compute queue, the work is submitted to it straight away and
+    /// the frame's graphics submission waits for it - and it waits for the previous frame's
+    /// graphics submission. Otherwise, it's recorded into the frame's command buffer, followed by
+    /// a barrier that makes its results visible to drawing. If no sub-renderer records anything,
+    /// neither happens.
+    pub fn record_compute<'s>(&mut self, state: &<SF as StateFamily>::For<'s>) -> Result<()> {
+        trace_span!("record_compute");
+        let frame_index = self.frame_index();
+        let Some(async_compute) = &mut self.async_compute else {
+            let context = &self.context;
+            context.begin_marker("Compute", glam::vec4(0.0, 1.0, 1.0, 1.0));
+            let mut recorded = false;
+            for subrenderer in
+                all_sub_renderers(&mut self.sub_renderers, &mut self.parallel_sub_renderers)
+            {
+                context.begin_marker(subrenderer.label(), glam::vec4(0.0, 1.0, 1.0, 1.0));
+                recorded |= subrenderer.record_compute(state, context);
+                context.end_marker();
+            }
+
+            if !recorded {
+                context.end_marker();
+                return Ok(());
+            }
+
+            unsafe {
+                context.cmd_pipeline_barrier2(
+                    context.draw_command_buffer(),
+                    &vk::DependencyInfo::default().memory_barriers(&[vk::MemoryBarrier2::default(
+                    )
+                    .src_access_mask(vk::AccessFlags2::SHADER_STORAGE_WRITE)
+                    .src_stage_mask(vk::PipelineStageFlags2::COMPUTE_SHADER)
+                    .dst_access_mask(
+                        vk::AccessFlags2::SHADER_READ
+                            | vk::AccessFlags2::SHADER_WRITE
+                            | vk::AccessFlags2::INDIRECT_COMMAND_READ
+                            | vk::AccessFlags2::INDEX_READ
+                            | vk::AccessFlags2::VERTEX_ATTRIBUTE_READ,
+                    )
+                    .dst_stage_mask(COMPUTE_CONSUMER_STAGES)]),
+                );
+            }
+            context.end_marker();
+            return Ok(());
+        };
+
+        let command_buffer = async_compute.begin(frame_index)?;
+        let context = &self.context;
+        let sub_renderers = &mut self.sub_renderers;
+        let parallel_sub_renderers = &mut self.parallel_sub_renderers;
+        let recorded = context.record_into(command_buffer, || {
+            let mut recorded = false;
+            context.begin_marker("Async Compute", glam::vec4(0.0, 1.0, 1.0, 1.0));
+            for subrenderer in all_sub_renderers(sub_renderers, parallel_sub_renderers) {
+                context.begin_marker(subrenderer.label(), glam::vec4(0.0, 1.0, 1.0, 1.0));
+                recorded |= subrenderer.record_compute(state, context);
+                context.end_marker();
+            }
+            context.end_marker();
+            recorded
+        });
+
+        // Nothing to do, so don't bother the compute queue
+        if !recorded {
+            return async_compute.skip(frame_index);
+        }
+
+        // Uploads on the transfer queue need to land before compute can use them
+        let transfer_wait = self
+            .allocator
+            .transfer_wait_at(vk::PipelineStageFlags2::COMPUTE_SHADER);
+        self.compute_wait = Some(async_compute.submit(frame_index, transfer_wait.as_slice())?);
+        Ok(())
     }
 
-    pub fn resize(&mut self, extent: vk::Extent2D) {
+    pub fn resize(&mut self, extent: vk::Extent2D) -> Result<()> {
         match &mut self.swapchain {
             SwapchainBackend::WSI(swapchain) => {
                 swapchain.extent = extent;
                 swapchain.needs_update = true;
             }
-            SwapchainBackend::Headless(headless_swapchain) => headless_swapchain.resize(extent),
+            SwapchainBackend::Headless(headless_swapchain) => headless_swapchain.resize(extent)?,
         }
 
-        self.depth_buffer.resize(&self.context, extent);
+        self.depth_buffer.resize(&self.context, extent)
     }
 
-    pub(crate) fn get_drawable(&mut self) -> Drawable {
+    pub(crate) fn get_drawable(&mut self) -> Result<Drawable> {
         let device = &self.context.device;
 
         match &mut self.swapchain {
             SwapchainBackend::WSI(swapchain) => {
                 if swapchain.needs_update {
-                    unsafe { device.device_wait_idle().unwrap() };
-                    swapchain.resize(&self.context.device);
+                    unsafe { device.device_wait_idle()? };
+                    swapchain.resize(&self.context.device)?;
                 }
 
                 let drawable = loop {
-                    if let Some(drawable) = swapchain.get_drawable() {
+                    let acquired = swapchain.get_drawable();
+                    if let Some(drawable) = self.context.check_device_lost(acquired)? {
                         break drawable;
                     }
 
-                    unsafe { device.device_wait_idle().unwrap() };
-                    swapchain.resize(&self.context.device);
+                    unsafe { device.device_wait_idle()? };
+                    swapchain.resize(&self.context.device)?;
                 };
 
-                drawable
+                Ok(drawable)
             }
-            SwapchainBackend::Headless(headless_swapchain) => headless_swapchain.get_drawable(),
+            SwapchainBackend::Headless(headless_swapchain) => Ok(headless_swapchain.get_drawable()),
         }
     }
 
-    fn submit_rendering(&self, drawable: &Drawable) {
+    fn submit_rendering(&mut self, drawable: &Drawable) -> Result<()> {
+        // Read back whatever this frame produced
+        self.allocator
+            .record_readbacks(self.context.draw_command_buffer());
+
+        let transfer_wait = self.allocator.take_transfer_wait();
+        let transfer_signal = self.allocator.take_transfer_signal();
+        let compute_wait = self.compute_wait.take();
+        let graphics_signal = self
+            .async_compute
+            .as_ref()
+            .map(AsyncCompute::graphics_signal);
         let context = &self.context;
         let device = &context.device;
         let queue = context.graphics_queue;
-        let command_buffer = context.draw_command_buffer;
+        let command_buffer = context.draw_command_buffer();
+        let fence = self.fences[self.frame_index()];
         unsafe {
             // First, transition the color attachment into the present state
             context.cmd_pipeline_barrier2(
@@ -689,48 +1111,62 @@ impl<SF: StateFamily> Renderer<SF> {
             self.context.end_marker();
 
             // End the command buffer
-            device.end_command_buffer(command_buffer).unwrap();
+            device.end_command_buffer(command_buffer)?;
+
+            // Wait for the swapchain image, any uploads on the transfer queue, and async compute
+            let wait_semaphore_infos = drawable
+                .image_available
+                .map(|image_available| {
+                    vk::SemaphoreSubmitInfo::default()
+                        .semaphore(image_available)
+                        .stage_mask(vk::PipelineStageFlags2::COLOR_ATTACHMENT_OUTPUT)
+                })
+                .into_iter()
+                .chain(transfer_wait)
+                .chain(compute_wait)
+                .collect::<Vec<_>>();
+
+            // Signal that we're done with the swapchain image, that our uploads are complete, and
+            // that async compute can start overwriting what this frame read
+            let signal_semaphore_infos = std::iter::once(
+                vk::SemaphoreSubmitInfo::default()
+                    .semaphore(drawable.rendering_complete)
+                    .stage_mask(vk::PipelineStageFlags2::ALL_COMMANDS),
+            )
+            .chain(transfer_signal)
+            .chain(graphics_signal)
+            .collect::<Vec<_>>();
+
+            // Only reset the fence once we're sure to submit - if anything before this fails,
+            // it has to stay signalled or the next wait on this slot would never return.
+            device.reset_fences(&[fence])?;
 
             // Submit the work to the queue
-            // blegh
-            if let Some(image_available) = drawable.image_available {
-                context.queue_submit2(
-                    queue,
-                    &[vk::SubmitInfo2::default()
-                        .command_buffer_infos(&[
-                            vk::CommandBufferSubmitInfo::default().command_buffer(command_buffer)
-                        ])
-                        .wait_semaphore_infos(&[vk::SemaphoreSubmitInfo::default()
-                            .semaphore(image_available)
-                            .stage_mask(vk::PipelineStageFlags2::COLOR_ATTACHMENT_OUTPUT)])
-                        .signal_semaphore_infos(&[vk::SemaphoreSubmitInfo::default()
-                            .semaphore(drawable.rendering_complete)
-                            .stage_mask(vk::PipelineStageFlags2::ALL_COMMANDS)])],
-                    self.fence,
-                );
-            } else {
-                context.queue_submit2(
-                    queue,
-                    &[vk::SubmitInfo2::default()
-                        .command_buffer_infos(&[
-                            vk::CommandBufferSubmitInfo::default().command_buffer(command_buffer)
-                        ])
-                        .signal_semaphore_infos(&[vk::SemaphoreSubmitInfo::default()
-                            .semaphore(drawable.rendering_complete)
-                            .stage_mask(vk::PipelineStageFlags2::ALL_COMMANDS)])],
-                    self.fence,
-                );
-            }
+            context.queue_submit2(
+                queue,
+                &[vk::SubmitInfo2::default()
+                    .command_buffer_infos(&[
+                        vk::CommandBufferSubmitInfo::default().command_buffer(command_buffer)
+                    ])
+                    .wait_semaphore_infos(&wait_semaphore_infos)
+                    .signal_semaphore_infos(&signal_semaphore_infos)],
+                fence,
+            )?;
         }
+
+        if let Some(async_compute) = &mut self.async_compute {
+            async_compute.graphics_submitted();
+        }
+        Ok(())
     }
 
     pub fn create_pipeline<R>(
         &self,
         vertex_shader_path: impl AsRef<Path>,
         fragment_shader_path: impl AsRef<Path>,
-    ) -> Pipeline {
-        let vertex_shader = std::fs::read(vertex_shader_path).unwrap();
-        let fragment_shader = std::fs::read(fragment_shader_path).unwrap();
+    ) -> Result<Pipeline> {
+        let vertex_shader = std::fs::read(vertex_shader_path)?;
+        let fragment_shader = std::fs::read(fragment_shader_path)?;
 
         Pipeline::new::<R>(
             self.context.clone(),
@@ -747,7 +1183,7 @@ impl<SF: StateFamily> Renderer<SF> {
         vertex_shader: &[u8],
         fragment_shader: &[u8],
         options: PipelineOptions,
-    ) -> Pipeline {
+    ) -> Result<Pipeline> {
         let colour_format = options.colour_format.unwrap_or(self.get_drawable_format());
         Pipeline::new::<R>(
             self.context.clone(),
@@ -766,7 +1202,7 @@ impl<SF: StateFamily> Renderer<SF> {
         extent: vk::Extent2D,
         image_bytes: impl AsRef<[u8]>,
         image_usage_flags: vk::ImageUsageFlags,
-    ) -> Image {
+    ) -> Result<Image> {
         self.image_manager.create_image(
             name,
             &mut self.allocator,
@@ -777,22 +1213,29 @@ impl<SF: StateFamily> Renderer<SF> {
         )
     }
 
+    /// Destroy an image made with [`Renderer::create_image`] once the GPU is done with it. See
+    /// [`ImageManager::destroy_image`].
+    pub fn destroy_image(&mut self, handle: vk::Image) {
+        self.image_manager
+            .destroy_image(handle, &mut self.allocator);
+    }
+
     pub fn create_sampled_image_from_png(
         &mut self,
         name: impl AsRef<str>,
         path: impl AsRef<Path>,
         format: vk::Format,
-    ) -> Image {
-        let image_data = std::fs::read(path).unwrap();
+    ) -> Result<Image> {
+        let image_data = std::fs::read(path)?;
         let mut decoder = png::Decoder::new(&image_data[..]);
         decoder.set_transformations(png::Transformations::ALPHA);
-        let mut reader = decoder.read_info().unwrap();
+        let mut reader = decoder.read_info()?;
 
         // Allocate the output buffer.
         let mut buf = vec![0; reader.output_buffer_size()];
 
         // Read the next frame. An APNG might contain multiple frames.
-        let info = reader.next_frame(&mut buf).unwrap();
+        let info = reader.next_frame(&mut buf)?;
 
         // Grab the bytes of the image.
         let image_bytes = buf[..info.buffer_size()].to_vec();
@@ -842,7 +1285,7 @@ impl<SF: StateFamily> Renderer<SF> {
         desired_state: AttachmentState,
     ) {
         let context = &self.context;
-        let command_buffer = context.draw_command_buffer;
+        let command_buffer = context.draw_command_buffer();
 
         let (src_access_mask, src_stage_mask, old_layout) = get_flags_for_state(*current_state);
         let (dst_access_mask, dst_stage_mask, new_layout) = get_flags_for_state(desired_state);
@@ -876,6 +1319,48 @@ impl<SF: StateFamily> Renderer<SF> {
     }
 }
 
+impl<SF: StateFamily> Drop for Renderer<SF> {
+    fn drop(&mut self) {
+        unsafe {
+            // Wait for the last frame (and anything else) to finish before tearing anything down.
+            // Sub-renderers, pipelines, query sets and command pools don't wait for the GPU when
+            // they're dropped, so this has to happen first.
+            let _ = self.context.device.device_wait_idle();
+            self.depth_buffer.destroy(&self.context);
+            for fence in self.fences.drain(..) {
+                self.context.device.destroy_fence(fence, None);
+            }
+        }
+    }
+}
+
+/// Every sub-renderer, whether or not it was added for parallel recording.
+fn all_sub_renderers<'a, SF: StateFamily + 'a>(
+    sub_renderers: &'a mut HashMap<String, BoxedSubRenderer<SF>>,
+    parallel_sub_renderers: &'a mut HashMap<String, ParallelSubRenderer<SF>>,
+) -> impl Iterator<Item = SubRendererMut<'a, SF>> {
+    let parallel_sub_renderers = parallel_sub_renderers
+        .values_mut()
+        .map(|subrenderer| subrenderer.as_mut() as SubRendererMut<SF>);
+    sub_renderers
+        .values_mut()
+        .map(|subrenderer| subrenderer.as_mut() as SubRendererMut<SF>)
+        .chain(parallel_sub_renderers)
+}
+
+fn find_sub_renderer<'a, SF: StateFamily + 'a>(
+    sub_renderers: &'a mut HashMap<String, BoxedSubRenderer<SF>>,
+    parallel_sub_renderers: &'a mut HashMap<String, ParallelSubRenderer<SF>>,
+    label: &str,
+) -> Option<SubRendererMut<'a, SF>> {
+    match sub_renderers.get_mut(label) {
+        Some(subrenderer) => Some(subrenderer.as_mut()),
+        None => parallel_sub_renderers
+            .get_mut(label)
+            .map(|subrenderer| subrenderer.as_mut() as SubRendererMut<SF>),
+    }
+}
+
 fn get_flags_for_state(
     current_state: AttachmentState,
 ) -> (vk::AccessFlags2, vk::PipelineStageFlags2, vk::ImageLayout) {
@@ -896,9 +1381,14 @@ fn get_flags_for_state(
             vk::PipelineStageFlags2::FRAGMENT_SHADER,
             vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
         ),
+        // Attachments are shared between frames in flight, so even though we don't care about
+        // their contents we still have to wait for the previous frame to finish with them.
         AttachmentState::Undefined => (
-            vk::AccessFlags2::NONE,
-            vk::PipelineStageFlags2::NONE,
+            vk::AccessFlags2::COLOR_ATTACHMENT_WRITE
+                | vk::AccessFlags2::DEPTH_STENCIL_ATTACHMENT_WRITE,
+            vk::PipelineStageFlags2::COLOR_ATTACHMENT_OUTPUT
+                | vk::PipelineStageFlags2::LATE_FRAGMENT_TESTS
+                | vk::PipelineStageFlags2::FRAGMENT_SHADER,
             vk::ImageLayout::UNDEFINED,
         ),
         AttachmentState::Swapchain => (
@@ -0,0 +1,260 @@
+use std::{
+    panic::{self, AssertUnwindSafe},
+    sync::{mpsc, Arc},
+    thread::JoinHandle,
+};
+
+use ash::vk;
+
+use crate::{depth_buffer::DEPTH_FORMAT, Context, Result};
+
+/// Worker threads, and command pools for recording secondary command buffers on them.
+///
+/// Command pools can only be used by one thread at a time, and can't be reset while the GPU is
+/// still using any of their command buffers, so there's one pool per thread, per frame in flight.
+/// The thread that's driving the workers gets a pool of its own too.
+pub(crate) struct SecondaryCommandBuffers {
+    context: Arc<Context>,
+    /// Indexed by `[frame_index][thread]`, where thread `0` is the driving thread and the workers
+    /// come after it.
+    frames: Vec<Vec<WorkerPool>>,
+    workers: Vec<Worker>,
+}
+
+/// Something for a worker thread to run. Its borrows have been erased - see
+/// [`SecondaryCommandBuffers::record`].
+type Job = Box<dyn FnOnce() + Send>;
+
+struct Worker {
+    jobs: mpsc::Sender<Job>,
+    thread: JoinHandle<()>,
+}
+
+pub(crate) struct WorkerPool {
+    /// Which thread this belongs to, from `0` for the driving thread.
+    thread: usize,
+    pool: vk::CommandPool,
+    command_buffers: Vec<vk::CommandBuffer>,
+    /// How many of `command_buffers` have been handed out this frame.
+    used: usize,
+}
+
+/// What a secondary command buffer needs to know about the primary it'll be executed in.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Inheritance {
+    /// The area to set the viewport and scissor to - dynamic state isn't inherited.
+    pub extent: vk::Extent2D,
+    /// If set, the command buffer will be executed inside a dynamic render pass with this colour
+    /// format and a [`DEPTH_FORMAT`] depth attachment.
+    pub colour_format: Option<vk::Format>,
+}
+
+impl SecondaryCommandBuffers {
+    pub fn new(context: Arc<Context>, threads: usize) -> Result<Self> {
+        // Anything that's already been created is cleaned up by our `Drop` if this fails.
+        let mut secondary_command_buffers = Self {
+            context: context.clone(),
+            frames: (0..context.frames_in_flight)
+                .map(|_| Vec::with_capacity(threads + 1))
+                .collect(),
+            workers: Vec::with_capacity(threads),
+        };
+
+        for frame_index in 0..context.frames_in_flight {
+            for thread in 0..threads + 1 {
+                let pool = unsafe {
+                    context.device.create_command_pool(
+                        &vk::CommandPoolCreateInfo::default()
+                            .queue_family_index(context.queue_families.graphics)
+                            .flags(vk::CommandPoolCreateFlags::TRANSIENT),
+                        None,
+                    )
+                }?;
+                secondary_command_buffers.frames[frame_index].push(WorkerPool {
+                    thread,
+                    pool,
+                    command_buffers: Vec::new(),
+                    used: 0,
+                });
+            }
+        }
+
+        context.reserve_secondary_threads(threads + 1)?;
+        for thread in 0..threads {
+            let (jobs, receiver) = mpsc::channel::<Job>();
+            let thread = std::thread::Builder::new()
+                .name(format!("lazy_vulkan worker {thread}"))
+                .spawn(move || {
+                    for job in receiver {
+                        job();
+                    }
+                })?;
+            secondary_command_buffers
+                .workers
+                .push(Worker { jobs, thread });
+        }
+
+        Ok(secondary_command_buffers)
+    }
+
+    /// How many worker threads there are, not counting the driving thread.
+    pub fn threads(&self) -> usize {
+        self.workers.len()
+    }
+
+    /// Reset every pool belonging to `frame_index`. The GPU must be done with that frame!
+    pub fn reset(&mut self, frame_index: usize) -> Result<()> {
+        for worker in &mut self.frames[frame_index] {
+            unsafe {
+                self.context
+                    .device
+                    .reset_command_pool(worker.pool, vk::CommandPoolResetFlags::empty())
+            }?;
+            worker.used = 0;
+        }
+        Ok(())
+    }
+
+    /// Call each of `jobs` on its own worker thread with that worker's pool for `frame_index`,
+    /// and `local` on this thread with its own pool while they run. There can't be more jobs than
+    /// [`SecondaryCommandBuffers::threads`].
+    ///
+    /// Returns `local`'s result followed by each job's, once they've all finished. If any of them
+    /// panicked, the panic is resumed here.
+    pub fn record<T, J>(
+        &mut self,
+        frame_index: usize,
+        local: impl FnOnce(&mut WorkerPool) -> T,
+        jobs: impl IntoIterator<Item = J>,
+    ) -> Vec<T>
+    where
+        T: Send,
+        J: FnOnce(&mut WorkerPool) -> T + Send,
+    {
+        let jobs = jobs.into_iter().collect::<Vec<_>>();
+        let job_count = jobs.len();
+        assert!(
+            job_count <= self.workers.len(),
+            "More jobs than worker threads"
+        );
+
+        let (local_pool, worker_pools) = self.frames[frame_index].split_first_mut().unwrap();
+        let (results, finished) = mpsc::channel();
+        let workers = worker_pools.iter_mut().zip(&self.workers);
+        for (index, (job, (pool, worker))) in jobs.into_iter().zip(workers).enumerate() {
+            let results = results.clone();
+            let job: Box<dyn FnOnce() + Send + '_> = Box::new(move || {
+                let result = panic::catch_unwind(AssertUnwindSafe(|| job(pool)));
+                let _ = results.send((index, result));
+            });
+
+            // SAFETY: `job` borrows from our caller and `self`, but we don't return until every
+            // job's `results` sender has been dropped - which only happens once it has finished,
+            // or if it's dropped without ever being run.
+            let job = unsafe { std::mem::transmute::<Box<dyn FnOnce() + Send + '_>, Job>(job) };
+            if worker.jobs.send(job).is_err() {
+                break;
+            }
+        }
+        drop(results);
+
+        let local = panic::catch_unwind(AssertUnwindSafe(|| local(local_pool)));
+        let mut job_results = std::iter::repeat_with(|| None)
+            .take(job_count)
+            .collect::<Vec<_>>();
+        for (index, result) in finished {
+            job_results[index] = Some(result);
+        }
+
+        std::iter::once(Some(local))
+            .chain(job_results)
+            .map(|result| match result.expect("Worker thread exited") {
+                Ok(result) => result,
+                Err(panic) => panic::resume_unwind(panic),
+            })
+            .collect()
+    }
+}
+
+impl WorkerPool {
+    /// Record a command buffer from this pool with `record`, which can get it from
+    /// [`Context::draw_command_buffer`].
+    pub fn record(
+        &mut self,
+        context: &Context,
+        inheritance: Inheritance,
+        record: impl FnOnce(),
+    ) -> Result<vk::CommandBuffer> {
+        let command_buffer = self.begin(context, inheritance)?;
+        context.record_secondary(command_buffer, self.thread, record);
+        unsafe { context.device.end_command_buffer(command_buffer) }?;
+        Ok(command_buffer)
+    }
+
+    /// Get a command buffer from this pool and begin recording it.
+    fn begin(&mut self, context: &Context, inheritance: Inheritance) -> Result<vk::CommandBuffer> {
+        if self.used == self.command_buffers.len() {
+            let command_buffer = unsafe {
+                context.device.allocate_command_buffers(
+                    &vk::CommandBufferAllocateInfo::default()
+                        .command_pool(self.pool)
+                        .level(vk::CommandBufferLevel::SECONDARY)
+                        .command_buffer_count(1),
+                )
+            }?[0];
+            self.command_buffers.push(command_buffer);
+        }
+
+        let command_buffer = self.command_buffers[self.used];
+        self.used += 1;
+
+        let colour_formats = inheritance.colour_format.as_slice();
+        let mut rendering_info = vk::CommandBufferInheritanceRenderingInfo::default()
+            .color_attachment_formats(colour_formats)
+            .depth_attachment_format(DEPTH_FORMAT)
+            .rasterization_samples(vk::SampleCountFlags::TYPE_1);
+        let mut inheritance_info = vk::CommandBufferInheritanceInfo::default();
+        let mut flags = vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT;
+        if inheritance.colour_format.is_some() {
+            inheritance_info = inheritance_info.push_next(&mut rendering_info);
+            flags |= vk::CommandBufferUsageFlags::RENDER_PASS_CONTINUE;
+        }
+
+        let device = &context.device;
+        unsafe {
+            device.begin_command_buffer(
+                command_buffer,
+                &vk::CommandBufferBeginInfo::default()
+                    .flags(flags)
+                    .inheritance_info(&inheritance_info),
+            )?;
+
+            let render_area = inheritance.extent;
+            device.cmd_set_scissor(command_buffer, 0, &[render_area.into()]);
+            device.cmd_set_viewport(
+                command_buffer,
+                0,
+                &[vk::Viewport::default()
+                    .width(render_area.width as _)
+                    .height(render_area.height as _)
+                    .max_depth(1.)],
+            );
+        }
+
+        Ok(command_buffer)
+    }
+}
+
+impl Drop for SecondaryCommandBuffers {
+    fn drop(&mut self) {
+        // Hanging up on the workers ends their loops.
+        for Worker { jobs, thread } in self.workers.drain(..) {
+            drop(jobs);
+            let _ = thread.join();
+        }
+
+        for worker in self.frames.drain(..).flatten() {
+            unsafe { self.context.device.destroy_command_pool(worker.pool, None) };
+        }
+    }
+}
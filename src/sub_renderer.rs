@@ -1,6 +1,6 @@
 use ash::vk;
 
-use crate::{allocator::Allocator, context::Context, pipeline::Pipeline, ImageManager};
+use crate::{allocator::Allocator, context::Context, pipeline::Pipeline, ImageManager, Result};
 
 /// A family of state types parameterized by a borrow lifetime.
 pub trait StateFamily {
@@ -11,6 +11,11 @@ impl StateFamily for () {
     type For<'s> = ();
 }
 
+/// ## NOTE
+/// With [`crate::Renderer::draw_parallel`], each draw callback records into its own secondary
+/// command buffer - on a worker thread, if the sub-renderer was added with
+/// [`crate::Renderer::add_parallel_sub_renderer`]. [`Context::draw_command_buffer`] always
+/// returns the right command buffer to record into, so use that rather than holding on to one.
 pub trait SubRenderer<'s> {
     type State;
 
@@ -26,13 +31,34 @@ pub trait SubRenderer<'s> {
 
     /// Override this method if you'd like to perform any transfer operations BEFORE any drawing
     /// begins.
+    ///
+    /// Any error returned here will abort the frame.
     #[allow(unused)]
     fn stage_transfers(
         &mut self,
         state: &Self::State,
         allocator: &mut Allocator,
         image_manager: &mut ImageManager,
-    ) {
+    ) -> Result<()> {
+        Ok(())
+    }
+
+    /// Override this method to record compute work - eg. particle simulation or culling - that
+    /// the rest of the frame depends on.
+    ///
+    /// - [`Context::draw_command_buffer`] will be in the recording state. If the device has a
+    ///   dedicated compute queue it's submitted there, so only compute commands can be recorded.
+    /// - Everything drawn this frame will see the results.
+    ///
+    /// ## NOTE
+    /// Only buffers and storage images (see [`Context::image_queue_families`]) can be shared with
+    /// the compute queue - other images are owned by the graphics queue family. Data uploaded
+    /// this frame is only visible if the device also has a dedicated transfer queue.
+    ///
+    /// Return `true` if anything was recorded. If no sub-renderer did, nothing is submitted.
+    #[allow(unused)]
+    fn record_compute(&mut self, state: &Self::State, context: &Context) -> bool {
+        false
     }
 
     /// Useful for
@@ -67,7 +93,7 @@ pub trait SubRenderer<'s> {
     /// - no other rendering is in progress
     fn begin_rendering(&self, context: &Context, pipeline: &Pipeline) {
         let device = &context.device;
-        let draw_command_buffer = context.draw_command_buffer;
+        let draw_command_buffer = context.draw_command_buffer();
 
         unsafe {
             // Bind the pipeline
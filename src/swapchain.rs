@@ -1,7 +1,11 @@
+use std::sync::Arc;
+
 use ash::vk;
-use winit::raw_window_handle::{HasDisplayHandle, HasWindowHandle};
+
+use crate::{Context, Error, Result};
 
 pub struct Swapchain {
+    context: Arc<Context>,
     pub surface_handle: vk::SurfaceKHR,
     #[allow(unused)]
     pub surface_fn: ash::khr::surface::Instance,
@@ -12,6 +16,9 @@ pub struct Swapchain {
     pub extent: vk::Extent2D,
     pub format: vk::Format,
     pub needs_update: bool,
+    /// Empty unless graphics and present are on different families, in which case the images
+    /// are shared between them.
+    queue_family_indices: Vec<u32>,
     image_available_semaphores: SemaphoreRingBuffer,
     capabilities: vk::SurfaceCapabilitiesKHR,
     rendering_complete_semaphores: Vec<vk::Semaphore>,
@@ -19,46 +26,47 @@ pub struct Swapchain {
 
 impl Swapchain {
     pub(crate) fn new(
-        device: &ash::Device,
+        context: &Arc<Context>,
         core: &super::core::Core,
         window: &winit::window::Window,
         old_swapchain: vk::SwapchainKHR,
-    ) -> Self {
-        let entry = &core.entry;
+    ) -> Result<Self> {
         let instance = &core.instance;
-        let window_handle = window.window_handle().unwrap().as_raw();
-        let display_handle = window.display_handle().unwrap().as_raw();
+        let device = &context.device;
         let extent = vk::Extent2D {
             width: window.inner_size().width,
             height: window.inner_size().height,
         };
 
-        let surface_handle = unsafe {
-            ash_window::create_surface(entry, instance, display_handle, window_handle, None)
-        }
-        .unwrap();
-
-        let surface_fn = ash::khr::surface::Instance::new(entry, instance);
+        let surface_handle = core
+            .surface
+            .expect("Attempted to create a swapchain without a surface");
+        let surface_fn = core.surface_fn.clone();
         let surface_formats = unsafe {
             surface_fn.get_physical_device_surface_formats(core.physical_device, surface_handle)
-        }
-        .unwrap();
+        }?;
 
         let format_preferences = [vk::Format::B8G8R8A8_SRGB, vk::Format::R8G8B8A8_SRGB];
 
         let format = *format_preferences
             .iter()
             .find(|&&f| surface_formats.iter().any(|sf| sf.format == f))
-            .expect("Desired swapchain format unavailable");
+            .ok_or(Error::UnsupportedFormat(format_preferences[0]))?;
 
         let capabilities = unsafe {
             surface_fn
                 .get_physical_device_surface_capabilities(core.physical_device, surface_handle)
-        }
-        .unwrap();
+        }?;
 
         let swapchain_fn = ash::khr::swapchain::Device::new(instance, device);
 
+        let queue_families = context.queue_families;
+        let queue_family_indices = if queue_families.graphics == queue_families.present {
+            Vec::new()
+        } else {
+            vec![queue_families.graphics, queue_families.present]
+        };
+
         let (swapchain_handle, images, image_views) = build_swapchain(
             device,
             old_swapchain,
@@ -66,18 +74,23 @@ impl Swapchain {
             surface_handle,
             format,
             capabilities,
+            &queue_family_indices,
             &swapchain_fn,
-        );
+        )?;
 
-        let image_available_semaphores = SemaphoreRingBuffer::new(device);
+        // A semaphore can't be reused until the frame that waited on it has finished, and we
+        // acquire before waiting on that frame's fence.
+        let image_available_semaphores =
+            SemaphoreRingBuffer::new(device, context.frames_in_flight + 1)?;
 
-        let rendering_complete_semaphores = std::iter::repeat_with(|| {
-            unsafe { device.create_semaphore(&vk::SemaphoreCreateInfo::default(), None) }.unwrap()
+        let rendering_complete_semaphores = std::iter::repeat_with(|| unsafe {
+            device.create_semaphore(&vk::SemaphoreCreateInfo::default(), None)
         })
         .take(images.len())
-        .collect();
+        .collect::<Result<_, _>>()?;
 
-        Self {
+        Ok(Self {
+            context: context.clone(),
             surface_handle,
             surface_fn,
             swapchain_handle,
@@ -87,13 +100,15 @@ impl Swapchain {
             extent,
             format,
             needs_update: false,
+            queue_family_indices,
             image_available_semaphores,
             capabilities,
             rendering_complete_semaphores,
-        }
+        })
     }
 
-    pub fn get_drawable(&mut self) -> Option<Drawable> {
+    /// Returns `None` if the swapchain is out of date and needs to be resized.
+    pub fn get_drawable(&mut self) -> Result<Option<Drawable>> {
         let image_available;
         let (index, suboptimal) = match unsafe {
             image_available = self.image_available_semaphores.next();
@@ -107,26 +122,26 @@ impl Swapchain {
             Ok(x) => x,
             Err(vk::Result::ERROR_OUT_OF_DATE_KHR) => {
                 self.needs_update = true;
-                return None;
+                return Ok(None);
             }
-            Err(e) => panic!("{e:?}"),
+            Err(e) => return Err(e.into()),
         };
 
         if suboptimal {
             // self.needs_update = true;
         }
 
-        Some(Drawable {
+        Ok(Some(Drawable {
             image: self.images[index as usize],
             view: self.image_views[index as usize],
             image_available: Some(image_available),
             index,
             extent: self.extent,
             rendering_complete: self.rendering_complete_semaphores[index as usize],
-        })
+        }))
     }
 
-    pub fn resize(&mut self, device: &ash::Device) {
+    pub fn resize(&mut self, device: &ash::Device) -> Result<()> {
         println!("Resizing swapchain!");
         // Create a new swapchain
         let (swapchain_handle, images, image_views) = build_swapchain(
@@ -136,8 +151,9 @@ impl Swapchain {
             self.surface_handle,
             self.format,
             self.capabilities,
+            &self.queue_family_indices,
             &self.swapchain_fn,
-        );
+        )?;
 
         // Destroy the old one
         unsafe {
@@ -154,9 +170,10 @@ impl Swapchain {
         self.images = images;
         self.image_views = image_views;
         self.needs_update = false;
+        Ok(())
     }
 
-    pub fn present(&mut self, drawable: Drawable, queue: vk::Queue) {
+    pub fn present(&mut self, drawable: Drawable, queue: vk::Queue) -> Result<()> {
         unsafe {
             match self.swapchain_fn.queue_present(
                 queue,
@@ -168,13 +185,36 @@ impl Swapchain {
                 Ok(_) => {}
                 Err(err) => match err {
                     vk::Result::ERROR_OUT_OF_DATE_KHR => self.needs_update = true,
-                    _ => panic!("Failed to present swapchain image: {:?}", err),
+                    _ => return Err(err.into()),
                 },
             }
         }
+
+        Ok(())
     }
 }
 
+impl Drop for Swapchain {
+    fn drop(&mut self) {
+        let device = &self.context.device;
+        unsafe {
+            // Presentation isn't covered by any of our fences.
+            let _ = device.device_wait_idle();
+
+            for image_view in self.image_views.drain(..) {
+                device.destroy_image_view(image_view, None);
+            }
+            self.swapchain_fn
+                .destroy_swapchain(self.swapchain_handle, None);
+            for semaphore in self.rendering_complete_semaphores.drain(..) {
+                device.destroy_semaphore(semaphore, None);
+            }
+            self.image_available_semaphores.destroy(device);
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
 fn build_swapchain(
     device: &ash::Device,
     old_swapchain: vk::SwapchainKHR,
@@ -182,9 +222,16 @@ fn build_swapchain(
     surface_handle: vk::SurfaceKHR,
     format: vk::Format,
     capabilities: vk::SurfaceCapabilitiesKHR,
+    queue_family_indices: &[u32],
     swapchain_fn: &ash::khr::swapchain::Device,
-) -> (vk::SwapchainKHR, Vec<vk::Image>, Vec<vk::ImageView>) {
+) -> Result<(vk::SwapchainKHR, Vec<vk::Image>, Vec<vk::ImageView>)> {
     log::debug!("Building swapchain with extent {extent:?}");
+    let sharing_mode = if queue_family_indices.is_empty() {
+        vk::SharingMode::EXCLUSIVE
+    } else {
+        vk::SharingMode::CONCURRENT
+    };
+
     let swapchain_handle = unsafe {
         swapchain_fn.create_swapchain(
             &vk::SwapchainCreateInfoKHR::default()
@@ -197,8 +244,8 @@ fn build_swapchain(
                 .image_usage(
                     vk::ImageUsageFlags::COLOR_ATTACHMENT | vk::ImageUsageFlags::TRANSFER_DST,
                 )
-                .image_sharing_mode(vk::SharingMode::EXCLUSIVE)
-                .queue_family_indices(&[0])
+                .image_sharing_mode(sharing_mode)
+                .queue_family_indices(queue_family_indices)
                 .clipped(true)
                 .present_mode(vk::PresentModeKHR::FIFO)
                 .pre_transform(capabilities.current_transform)
@@ -206,36 +253,31 @@ fn build_swapchain(
                 .old_swapchain(old_swapchain),
             None,
         )
-    }
-    .unwrap();
-
-    let (images, image_views) = unsafe { swapchain_fn.get_swapchain_images(swapchain_handle) }
-        .unwrap()
-        .into_iter()
-        .map(|image| {
-            let view = unsafe {
-                device.create_image_view(
-                    &vk::ImageViewCreateInfo::default()
-                        .view_type(vk::ImageViewType::TYPE_2D)
-                        .image(image)
-                        .format(format)
-                        .subresource_range(
-                            vk::ImageSubresourceRange::default()
-                                .aspect_mask(vk::ImageAspectFlags::COLOR)
-                                .base_mip_level(0)
-                                .level_count(1)
-                                .base_array_layer(0)
-                                .layer_count(1),
-                        ),
-                    None,
-                )
-            }
-            .unwrap();
+    }?;
 
-            (image, view)
+    let images = unsafe { swapchain_fn.get_swapchain_images(swapchain_handle) }?;
+    let image_views = images
+        .iter()
+        .map(|&image| unsafe {
+            device.create_image_view(
+                &vk::ImageViewCreateInfo::default()
+                    .view_type(vk::ImageViewType::TYPE_2D)
+                    .image(image)
+                    .format(format)
+                    .subresource_range(
+                        vk::ImageSubresourceRange::default()
+                            .aspect_mask(vk::ImageAspectFlags::COLOR)
+                            .base_mip_level(0)
+                            .level_count(1)
+                            .base_array_layer(0)
+                            .layer_count(1),
+                    ),
+                None,
+            )
         })
-        .unzip();
-    (swapchain_handle, images, image_views)
+        .collect::<Result<_, _>>()?;
+
+    Ok((swapchain_handle, images, image_views))
 }
 
 #[derive(Debug, Copy, Clone)]
@@ -250,24 +292,31 @@ pub struct Drawable {
 
 struct SemaphoreRingBuffer {
     index: usize,
-    semaphores: [vk::Semaphore; 3],
+    semaphores: Vec<vk::Semaphore>,
 }
 
 impl SemaphoreRingBuffer {
-    pub fn new(device: &ash::Device) -> SemaphoreRingBuffer {
-        // good
-        let semaphores = std::array::from_fn(|_| {
-            unsafe { device.create_semaphore(&vk::SemaphoreCreateInfo::default(), None) }.unwrap()
-        });
+    pub fn new(device: &ash::Device, count: usize) -> Result<SemaphoreRingBuffer> {
+        let semaphores = std::iter::repeat_with(|| unsafe {
+            device.create_semaphore(&vk::SemaphoreCreateInfo::default(), None)
+        })
+        .take(count)
+        .collect::<Result<_, _>>()?;
 
-        SemaphoreRingBuffer {
+        Ok(SemaphoreRingBuffer {
             index: 0,
             semaphores,
+        })
+    }
+
+    unsafe fn destroy(&self, device: &ash::Device) {
+        for &semaphore in &self.semaphores {
+            device.destroy_semaphore(semaphore, None);
         }
     }
 
     pub fn next(&mut self) -> vk::Semaphore {
-        self.index = (self.index + 1) % 3;
+        self.index = (self.index + 1) % self.semaphores.len();
         self.semaphores[self.index]
     }
 }